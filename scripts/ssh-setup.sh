@@ -22,6 +22,8 @@ for file in test-runner $CURRENT_APP $PREVIOUS_APP $UI_RUNNER openvpn.ca.crt; do
 done
 
 chown -R root "$RUNNER_DIR/"
+chmod 755 "$RUNNER_DIR/test-runner" "$RUNNER_DIR/$UI_RUNNER"
+chmod 644 "$RUNNER_DIR/openvpn.ca.crt"
 
 # Create service
 
@@ -4,8 +4,10 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
     io,
+    net::SocketAddr,
     ops::Deref,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 #[derive(err_derive::Error, Debug)]
@@ -16,6 +18,12 @@ pub enum Error {
     InvalidConfig(serde_json::Error),
     #[error(display = "Failed to write config")]
     Write(io::Error),
+    #[error(display = "File to provision does not exist: {}", _0)]
+    MissingProvisionFile(String),
+    #[error(display = "Invalid file to provision, expected LOCAL=REMOTE: {}", _0)]
+    InvalidProvisionFile(String),
+    #[error(display = "Either an SSH password or an SSH key is required for the SSH provisioner")]
+    MissingSshCredentials,
 }
 
 #[derive(Default, Serialize, Deserialize, Clone)]
@@ -24,12 +32,18 @@ pub struct Config {
     pub runtime_opts: RuntimeOptions,
     pub vms: BTreeMap<String, VmConfig>,
     pub mullvad_host: Option<String>,
+    /// Maximum combined size, in bytes, of the installed app and its data directories.
+    pub max_install_footprint: Option<u64>,
+    /// Number of connect/disconnect cycles in `test_connect_disconnect_churn`.
+    pub connect_churn_cycles: Option<usize>,
 }
 
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct RuntimeOptions {
     pub display: Display,
     pub keep_changes: bool,
+    /// Maximum time to wait for provisioning. A default is used if this is not set
+    pub provision_timeout: Option<Duration>,
 }
 
 #[derive(Default, Serialize, Deserialize, Clone)]
@@ -37,7 +51,8 @@ pub enum Display {
     #[default]
     None,
     Local,
-    Vnc,
+    /// Expose the display of the guest using VNC on the given port on localhost
+    Vnc(u16),
 }
 
 impl Config {
@@ -122,11 +137,20 @@ pub struct VmConfig {
     #[arg(long, required_if_eq("provisioner", "ssh"))]
     pub ssh_user: Option<String>,
 
-    /// Password to use for SSH
-    #[arg(long, required_if_eq("provisioner", "ssh"))]
+    /// Password to use for SSH. Either this or `ssh_key_path` is required for the SSH provisioner
+    #[arg(long)]
     pub ssh_password: Option<String>,
 
-    /// Additional disk images to mount/include
+    /// Private key to use for SSH. If set, this is used instead of `ssh_password`
+    #[arg(long)]
+    pub ssh_key_path: Option<PathBuf>,
+
+    /// Host to connect to over SSH. If not set, the IP of the guest is used
+    #[arg(long)]
+    pub ssh_host: Option<String>,
+
+    /// Additional disk images to mount/include. For containers, these are volumes given as
+    /// `HOST_PATH:CONTAINER_PATH`
     #[arg(long)]
     pub disks: Vec<String>,
 
@@ -139,12 +163,62 @@ pub struct VmConfig {
     #[serde(default)]
     #[arg(long)]
     pub tpm: bool,
+
+    /// Attach a second serial device for guest console output, which is forwarded to the log.
+    /// Only supported for QEMU VMs
+    #[serde(default)]
+    #[arg(long)]
+    pub console_serial: bool,
+
+    /// API host to use instead of `api.<mullvad_host>`, e.g. a staging API
+    #[arg(long)]
+    pub api_host: Option<String>,
+
+    /// Address of the API. If not set, the API host is resolved
+    #[arg(long)]
+    pub api_address: Option<SocketAddr>,
+
+    /// Additional file to copy to the guest after the app packages, given as `LOCAL=REMOTE`,
+    /// where `REMOTE` is an absolute path on the guest. This requires SSH credentials, even for
+    /// the noop provisioner
+    #[serde(default)]
+    #[arg(long = "provision-file")]
+    pub provision_files: Vec<ProvisionFile>,
 }
 
 impl VmConfig {
-    /// Combine authentication details, if all are present
-    pub fn get_ssh_options(&self) -> Option<(&str, &str)> {
-        Some((self.ssh_user.as_ref()?, self.ssh_password.as_ref()?))
+    /// Combine authentication details, if all are present. A private key takes precedence over a
+    /// password
+    pub fn get_ssh_options(&self) -> Option<(&str, SshAuth)> {
+        let auth = match (&self.ssh_key_path, &self.ssh_password) {
+            (Some(key_path), _) => SshAuth::PrivateKey(key_path.clone()),
+            (None, Some(password)) => SshAuth::Password(password.clone()),
+            (None, None) => return None,
+        };
+        Some((self.ssh_user.as_ref()?, auth))
+    }
+
+    /// Make sure that all files in `provision_files` exist on the host
+    pub fn check_provision_files(&self) -> Result<(), Error> {
+        for file in &self.provision_files {
+            if !file.local_path.is_file() {
+                return Err(Error::MissingProvisionFile(
+                    file.local_path.display().to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure that SSH credentials are given if the SSH provisioner is used
+    pub fn check_ssh_options(&self) -> Result<(), Error> {
+        if self.provisioner == Provisioner::Ssh
+            && self.ssh_password.is_none()
+            && self.ssh_key_path.is_none()
+        {
+            return Err(Error::MissingSshCredentials);
+        }
+        Ok(())
     }
 
     pub fn get_runner_dir(&self) -> &Path {
@@ -185,6 +259,9 @@ pub enum VmType {
     Qemu,
     /// Tart VM
     Tart,
+    /// Linux container run using podman. The container shares the kernel of the host, so this
+    /// is only suitable for tests that do not depend on the firewall
+    Container,
 }
 
 #[derive(clap::ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -218,6 +295,38 @@ impl Architecture {
     }
 }
 
+/// A file that is copied from the host to the guest during provisioning
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProvisionFile {
+    pub local_path: PathBuf,
+    pub remote_path: String,
+}
+
+impl std::str::FromStr for ProvisionFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((local_path, remote_path))
+                if !local_path.is_empty() && !remote_path.is_empty() =>
+            {
+                Ok(ProvisionFile {
+                    local_path: PathBuf::from(local_path),
+                    remote_path: remote_path.to_owned(),
+                })
+            }
+            _ => Err(Error::InvalidProvisionFile(s.to_owned())),
+        }
+    }
+}
+
+/// How to authenticate over SSH
+#[derive(Debug, Clone)]
+pub enum SshAuth {
+    Password(String),
+    PrivateKey(PathBuf),
+}
+
 #[derive(clap::ValueEnum, Default, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum Provisioner {
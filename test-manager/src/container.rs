@@ -5,11 +5,34 @@ use tokio::process::Command;
 /// Re-launch self with rootlesskit if we're not root.
 /// Allows for rootless and containerized networking.
 /// The VNC port is published to localhost.
-pub async fn relaunch_with_rootlesskit(vnc_port: Option<u16>) {
+///
+/// If `namespace_pid` is set, the namespaces of that process are entered instead of creating
+/// new ones. This is used to reach a VM started by another test manager.
+pub async fn relaunch_with_rootlesskit(vnc_port: Option<u16>, namespace_pid: Option<u32>) {
     if unsafe { libc::geteuid() } == 0 {
         return;
     }
 
+    if let Some(pid) = namespace_pid {
+        log::debug!("Entering namespaces of process {pid}");
+
+        let status = Command::new("nsenter")
+            .args([
+                "--preserve-credentials",
+                "--user",
+                "--mount",
+                "--net",
+                "--target",
+                &pid.to_string(),
+            ])
+            .args(std::env::args())
+            .status()
+            .await
+            .unwrap();
+
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
     let mut cmd = Command::new("rootlesskit");
     cmd.args(["--net", "slirp4netns", "--copy-up=/etc"]);
 
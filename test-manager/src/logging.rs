@@ -1,9 +1,10 @@
 use crate::tests::Error;
 use colored::Colorize;
 use futures::FutureExt;
+use std::io::Write;
 use std::panic;
 use std::sync::Mutex;
-use std::{future::Future, sync::Arc};
+use std::{fs::File, future::Future, path::Path, sync::Arc};
 use test_rpc::{
     logging::{LogOutput, Output},
     ServiceClient,
@@ -19,6 +20,7 @@ struct LoggerInner {
     env_logger: env_logger::Logger,
     buffer: bool,
     stored_records: Vec<StoredRecord>,
+    log_file: Option<File>,
 }
 
 struct StoredRecord {
@@ -31,15 +33,7 @@ struct StoredRecord {
 impl Logger {
     pub fn get_or_init() -> Self {
         static LOGGER: once_cell::sync::Lazy<Logger> = once_cell::sync::Lazy::new(|| {
-            let mut logger = env_logger::Builder::new();
-            logger.filter_module("h2", log::LevelFilter::Info);
-            logger.filter_module("tower", log::LevelFilter::Info);
-            logger.filter_module("hyper", log::LevelFilter::Info);
-            logger.filter_module("rustls", log::LevelFilter::Info);
-            logger.filter_level(log::LevelFilter::Debug);
-            logger.parse_env(env_logger::DEFAULT_FILTER_ENV);
-
-            let env_logger = logger.build();
+            let env_logger = build_env_logger(None);
             let max_level = env_logger.filter();
 
             let logger = Logger {
@@ -47,6 +41,7 @@ impl Logger {
                     env_logger,
                     buffer: false,
                     stored_records: vec![],
+                    log_file: None,
                 })),
             };
 
@@ -60,6 +55,14 @@ impl Logger {
         LOGGER.clone()
     }
 
+    /// Override the default log level, and optionally write all records to `log_file` as well
+    pub fn configure(&self, level: Option<log::LevelFilter>, log_file: Option<File>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.env_logger = build_env_logger(level);
+        inner.log_file = log_file;
+        log::set_max_level(inner.env_logger.filter());
+    }
+
     /// Set whether to buffer logs instead of printing them to stdout and stderr
     pub fn store_records(&self, state: bool) {
         let mut inner = self.inner.lock().unwrap();
@@ -97,6 +100,17 @@ impl log::Log for Logger {
 
         let mut inner = self.inner.lock().unwrap();
 
+        if let Some(log_file) = inner.log_file.as_mut() {
+            let _ = writeln!(
+                log_file,
+                "[{} {} {}] {}",
+                chrono::Local::now(),
+                record.level(),
+                record.module_path().unwrap_or(""),
+                record.args()
+            );
+        }
+
         if inner.buffer {
             let mod_path = record.module_path().unwrap_or("");
             inner.stored_records.push(StoredRecord {
@@ -113,6 +127,19 @@ impl log::Log for Logger {
     fn flush(&self) {}
 }
 
+/// Build the logger used for stdout and stderr. `level` overrides the default level, but noisy
+/// modules are still limited to `Info` unless `RUST_LOG` says otherwise.
+fn build_env_logger(level: Option<log::LevelFilter>) -> env_logger::Logger {
+    let mut logger = env_logger::Builder::new();
+    logger.filter_module("h2", log::LevelFilter::Info);
+    logger.filter_module("tower", log::LevelFilter::Info);
+    logger.filter_module("hyper", log::LevelFilter::Info);
+    logger.filter_module("rustls", log::LevelFilter::Info);
+    logger.filter_level(level.unwrap_or(log::LevelFilter::Debug));
+    logger.parse_env(env_logger::DEFAULT_FILTER_ENV);
+    logger.build()
+}
+
 #[derive(Debug, err_derive::Error)]
 #[error(display = "Test panic: {}", _0)]
 pub struct PanicMessage(String);
@@ -122,6 +149,8 @@ pub struct TestOutput {
     test_name: &'static str,
     pub result: Result<Result<(), Error>, PanicMessage>,
     log_output: LogOutput,
+    /// Kernel logs of the guest. These are only collected if the test failed.
+    kernel_logs: Option<Result<String, test_rpc::Error>>,
 }
 
 impl TestOutput {
@@ -173,6 +202,12 @@ impl TestOutput {
             Err(e) => println!("Could not get logs: {}", e),
         }
 
+        match &self.kernel_logs {
+            Some(Ok(kernel_logs)) => println!("Kernel logs:\n{}", kernel_logs),
+            Some(Err(e)) => println!("Could not get kernel logs: {}", e),
+            None => (),
+        }
+
         println!(
             "{}",
             format!("TEST RUNNER {} HAD RUNTIME OUTPUT:", self.test_name).red()
@@ -187,6 +222,71 @@ impl TestOutput {
 
         println!("{}", format!("TEST {} END OF OUTPUT", self.test_name).red());
     }
+
+    /// Write the result, logs, and runner output of the test to `path`, along with the tunnel
+    /// state, which should be obtained right after the test failed.
+    pub async fn save(&self, path: &Path, tunnel_state: &str) -> std::io::Result<()> {
+        use std::fmt::Write as _;
+
+        let mut log = String::new();
+
+        match &self.result {
+            Ok(Ok(_)) => writeln!(log, "TEST {} SUCCEEDED", self.test_name),
+            Ok(Err(e)) => writeln!(log, "TEST {} RETURNED ERROR: {:?}", self.test_name, e),
+            Err(panic_msg) => writeln!(
+                log,
+                "TEST {} PANICKED WITH MESSAGE: {}",
+                self.test_name, panic_msg.0
+            ),
+        }
+        .unwrap();
+
+        writeln!(log, "\nTunnel state: {tunnel_state}").unwrap();
+
+        match &self.log_output.settings_json {
+            Ok(settings) => writeln!(log, "\nsettings.json: {}", settings),
+            Err(e) => writeln!(log, "\nCould not get settings.json: {}", e),
+        }
+        .unwrap();
+
+        match &self.log_output.log_files {
+            Ok(log_files) => {
+                for log_file in log_files {
+                    match log_file {
+                        Ok(log_file) => writeln!(
+                            log,
+                            "\nLog {}:\n{}",
+                            log_file.name.display(),
+                            log_file.content
+                        ),
+                        Err(e) => writeln!(log, "\nCould not get log: {}", e),
+                    }
+                    .unwrap();
+                }
+            }
+            Err(e) => writeln!(log, "\nCould not get logs: {}", e).unwrap(),
+        }
+
+        match &self.kernel_logs {
+            Some(Ok(kernel_logs)) => writeln!(log, "\nKernel logs:\n{}", kernel_logs).unwrap(),
+            Some(Err(e)) => writeln!(log, "\nCould not get kernel logs: {}", e).unwrap(),
+            None => (),
+        }
+
+        writeln!(log, "\nTest runner output:").unwrap();
+        if self.error_messages.is_empty() {
+            writeln!(log, "<no output>").unwrap();
+        }
+        for msg in &self.error_messages {
+            let (Output::Error(text)
+            | Output::Warning(text)
+            | Output::Info(text)
+            | Output::Other(text)) = msg;
+            writeln!(log, "{}", text).unwrap();
+        }
+
+        tokio::fs::write(path, log).await
+    }
 }
 
 pub async fn run_test<F, R, MullvadClient>(
@@ -195,6 +295,8 @@ pub async fn run_test<F, R, MullvadClient>(
     test: &F,
     test_name: &'static str,
     test_context: super::tests::TestContext,
+    timeout: Option<std::time::Duration>,
+    abort: impl Future<Output = ()>,
 ) -> Result<TestOutput, Error>
 where
     F: Fn(super::tests::TestContext, ServiceClient, MullvadClient) -> R,
@@ -206,12 +308,32 @@ where
     // assertion being incorrect can not lead to memory unsafety however it could theoretically
     // lead to logic bugs. The problem of forcing the test to be unwind safe is that it causes a
     // large amount of unergonomic design.
-    let result = panic::AssertUnwindSafe(test(test_context, runner_rpc.clone(), mullvad_rpc))
-        .catch_unwind()
-        .await
-        .map_err(panic_as_string);
+    let test_future =
+        panic::AssertUnwindSafe(test(test_context, runner_rpc.clone(), mullvad_rpc)).catch_unwind();
+
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => futures::future::pending().await,
+        }
+    };
+
+    // Dropping the test future cancels the test if `abort` or `deadline` completes first.
+    let result = tokio::select! {
+        result = test_future => result.map_err(panic_as_string),
+        _ = abort => {
+            log::warn!("Aborting {test_name}");
+            Ok(Err(Error::Aborted))
+        }
+        _ = deadline => {
+            let timeout = timeout.unwrap_or_default().as_secs();
+            log::error!("{test_name} timed out after {timeout} s");
+            Ok(Err(Error::TimedOut(timeout)))
+        }
+    };
 
     let mut output = vec![];
+    let mut kernel_logs = None;
     if matches!(result, Ok(Err(_)) | Err(_)) {
         let output_after_test = runner_rpc.try_poll_output().await;
         match output_after_test {
@@ -222,9 +344,10 @@ where
                 output.push(Output::Other(format!("could not get logs: {:?}", e)));
             }
         }
+        kernel_logs = Some(runner_rpc.get_kernel_logs().await);
     }
     let log_output = runner_rpc
-        .get_mullvad_app_logs()
+        .get_mullvad_app_logs(None)
         .await
         .map_err(Error::Rpc)?;
 
@@ -233,6 +356,7 @@ where
         test_name,
         error_messages: output,
         result,
+        kernel_logs,
     })
 }
 
@@ -7,14 +7,17 @@ mod package;
 mod run_tests;
 mod summary;
 mod tests;
+mod timing;
 mod vm;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, process::ExitCode};
 
 use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
-use tests::config::DEFAULT_MULLVAD_HOST;
+use tests::config::{
+    DEFAULT_CONNECT_CHURN_CYCLES, DEFAULT_MAX_INSTALL_FOOTPRINT, DEFAULT_MULLVAD_HOST,
+};
 
 /// Test manager for Mullvad VPN app
 #[derive(Parser, Debug)]
@@ -22,6 +25,14 @@ use tests::config::DEFAULT_MULLVAD_HOST;
 struct Args {
     #[clap(subcommand)]
     cmd: Commands,
+
+    /// Log level of the test manager. `RUST_LOG` may be used for more fine-grained filtering
+    #[arg(long, global = true)]
+    log_level: Option<log::LevelFilter>,
+
+    /// Also write the test manager's logs to this file
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -76,6 +87,11 @@ enum Commands {
         #[arg(long, short)]
         account: String,
 
+        /// Account number of an account without time left. Tests that require an expired
+        /// account are skipped if this is not specified
+        #[arg(long)]
+        expired_account: Option<String>,
+
         /// App package to test.
         ///
         /// # Note
@@ -102,18 +118,70 @@ enum Commands {
         /// Output test results in a structured format.
         #[arg(long)]
         test_report: Option<PathBuf>,
+
+        /// Output the time spent in setup, the test itself, and cleanup for each test as CSV
+        #[arg(long)]
+        trace_timing: Option<PathBuf>,
+
+        /// Directory on the host where the logs and tunnel state of failed tests are written,
+        /// one file named `<test_name>-<attempt>.log` per failed attempt
+        #[arg(long, default_value = "failure-logs")]
+        failure_log_dir: PathBuf,
+
+        /// Fail tests whose cleanup does not restore the default settings
+        #[arg(long)]
+        strict_cleanup: bool,
+
+        /// Send the app packages to the guest over the serial connection when they are
+        /// installed, instead of installing the provisioned copies
+        #[arg(long)]
+        stream_packages: bool,
+
+        /// Maximum time, in seconds, to wait for provisioning of the VM to complete
+        #[arg(long)]
+        provision_timeout: Option<u64>,
+
+        /// Maximum time, in minutes, that the whole test run may take. When it is exceeded, the
+        /// running test is aborted and the remaining tests are skipped
+        #[arg(long)]
+        max_runtime: Option<u64>,
+
+        /// Keep the VM running after the tests have finished, so that later runs can reuse it
+        /// using `--attach`
+        #[arg(long, conflicts_with = "attach")]
+        persist_vm: bool,
+
+        /// Run tests on a VM kept running by `--persist-vm` instead of starting a new one. The
+        /// VM is not provisioned again, so the same app packages must be used
+        #[arg(long)]
+        attach: bool,
+
+        /// Snapshot the VM before each test, and roll it back and reboot it if the test fails
+        /// instead of only resetting the settings. This is only supported for QEMU VMs. If the VM
+        /// cannot be snapshotted, the tests are run without rollback
+        #[arg(long, conflicts_with = "attach")]
+        rollback_per_test: bool,
+
+        /// Stop running tests after the first test that fails. The remaining tests are reported
+        /// as skipped. Combine with `--display` to inspect the VM after the failure
+        #[arg(long)]
+        fail_fast: bool,
     },
 
     /// Output an HTML-formatted summary of one or more reports
     FormatTestReports {
         /// One or more test reports output by 'test-manager run-tests --test-report'
         reports: Vec<PathBuf>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: summary::ReportFormat,
     },
 
     /// Update the system image
     ///
     /// Note that in order for the updates to take place, the VM's config need
-    /// to have `provisioner` set to `ssh`, `ssh_user` & `ssh_password` set and
+    /// to have `provisioner` set to `ssh`, `ssh_user` & `ssh_password` (or `ssh_key_path`) set and
     /// the `ssh_user` should be able to execute commands with sudo/ as root.
     Update {
         /// Name of the runner config
@@ -129,27 +197,106 @@ impl Args {
             _ => None,
         }
     }
+
+    /// Return the PID of the test manager that owns the VM to attach to, if any.
+    async fn get_attach_pid(&self) -> Option<u32> {
+        match &self.cmd {
+            Commands::RunTests {
+                name, attach: true, ..
+            } => vm::persist::load(name).await.ok().map(|vm| vm.pid),
+            _ => None,
+        }
+    }
+}
+
+/// Exit code of the test manager
+#[derive(Clone, Copy, Debug)]
+enum ExitStatus {
+    /// The command succeeded. For `run-tests`, this means that all tests passed
+    Success = 0,
+    /// The tests were run, but one or more of them did not pass
+    TestsFailed = 1,
+    /// The command failed for some other reason, e.g. the VM could not be started or provisioned
+    InfrastructureError = 2,
+    /// The configuration or the arguments are invalid
+    ConfigError = 3,
+}
+
+impl ExitStatus {
+    fn from_error(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<run_tests::TestsFailed>().is_some() {
+            ExitStatus::TestsFailed
+        } else if error.downcast_ref::<ConfigError>().is_some() {
+            ExitStatus::ConfigError
+        } else {
+            ExitStatus::InfrastructureError
+        }
+    }
 }
 
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// An error caused by an invalid configuration or invalid arguments
+#[derive(err_derive::Error, Debug)]
+#[error(display = "{}", _0)]
+struct ConfigError(&'static str);
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    logging::Logger::get_or_init();
+async fn main() -> ExitCode {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(error) => {
+            let _ = error.print();
+            return if error.use_stderr() {
+                ExitStatus::ConfigError.into()
+            } else {
+                ExitStatus::Success.into()
+            };
+        }
+    };
 
-    let args = Args::parse();
+    match run(args).await {
+        Ok(()) => ExitStatus::Success.into(),
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            ExitStatus::from_error(&error).into()
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let logger = logging::Logger::get_or_init();
 
     #[cfg(target_os = "linux")]
-    container::relaunch_with_rootlesskit(args.get_vnc_port()).await;
+    container::relaunch_with_rootlesskit(args.get_vnc_port(), args.get_attach_pid().await).await;
+
+    let log_file = args
+        .log_file
+        .as_ref()
+        .map(std::fs::File::create)
+        .transpose()
+        .context("Failed to create log file")?;
+    logger.configure(args.log_level, log_file);
 
     let mut config = config::ConfigFile::load_or_default("config.json")
         .await
-        .context("Failed to load config")?;
+        .context(ConfigError("Failed to load config"))?;
     match args.cmd {
         Commands::Set {
             name,
             config: vm_config,
-        } => vm::set_config(&mut config, &name, vm_config)
-            .await
-            .context("Failed to edit or create VM config"),
+        } => {
+            vm_config
+                .check_ssh_options()
+                .context(ConfigError("Invalid SSH options"))?;
+            vm::set_config(&mut config, &name, vm_config)
+                .await
+                .context("Failed to edit or create VM config")
+        }
         Commands::Remove { name } => {
             if config.get_vm(&name).is_none() {
                 println!("No such configuration");
@@ -178,10 +325,9 @@ async fn main() -> Result<()> {
         } => {
             let mut config = config.clone();
             config.runtime_opts.keep_changes = keep_changes;
-            config.runtime_opts.display = if vnc.is_some() {
-                config::Display::Vnc
-            } else {
-                config::Display::Local
+            config.runtime_opts.display = match vnc {
+                Some(port) => config::Display::Vnc(port),
+                None => config::Display::Local,
             };
 
             let mut instance = vm::run(&config, &name)
@@ -197,18 +343,31 @@ async fn main() -> Result<()> {
             display,
             vnc,
             account,
+            expired_account,
             current_app,
             previous_app,
             test_filters,
             verbose,
             test_report,
+            trace_timing,
+            failure_log_dir,
+            strict_cleanup,
+            stream_packages,
+            provision_timeout,
+            max_runtime,
+            persist_vm,
+            attach,
+            rollback_per_test,
+            fail_fast,
         } => {
             let mut config = config.clone();
-            config.runtime_opts.display = match (display, vnc.is_some()) {
-                (false, false) => config::Display::None,
-                (true, false) => config::Display::Local,
-                (false, true) => config::Display::Vnc,
-                (true, true) => unreachable!("invalid combination"),
+            config.runtime_opts.provision_timeout =
+                provision_timeout.map(std::time::Duration::from_secs);
+            config.runtime_opts.display = match (display, vnc) {
+                (false, None) => config::Display::None,
+                (true, None) => config::Display::Local,
+                (false, Some(port)) => config::Display::Vnc(port),
+                (true, Some(_)) => unreachable!("invalid combination"),
             };
 
             let mullvad_host = config
@@ -217,20 +376,63 @@ async fn main() -> Result<()> {
                 .unwrap_or(DEFAULT_MULLVAD_HOST.to_owned());
             log::debug!("Mullvad host: {mullvad_host}");
 
-            let vm_config = vm::get_vm_config(&config, &name).context("Cannot get VM config")?;
+            let max_install_footprint = config
+                .max_install_footprint
+                .unwrap_or(DEFAULT_MAX_INSTALL_FOOTPRINT);
+            let connect_churn_cycles = config
+                .connect_churn_cycles
+                .unwrap_or(DEFAULT_CONNECT_CHURN_CYCLES);
+
+            let vm_config =
+                vm::get_vm_config(&config, &name).context(ConfigError("Cannot get VM config"))?;
+
+            if rollback_per_test {
+                if config.runtime_opts.keep_changes {
+                    return Err(ConfigError(
+                        "Rollback cannot be used when changes to the VM are kept",
+                    )
+                    .into());
+                }
+                if vm_config.vm_type != config::VmType::Qemu {
+                    return Err(ConfigError("Rollback is only supported for QEMU VMs").into());
+                }
+            }
 
             let manifest = package::get_app_manifest(vm_config, current_app, previous_app)
                 .await
-                .context("Could not find the specified app packages")?;
-
-            let mut instance = vm::run(&config, &name)
-                .await
-                .context("Failed to start VM")?;
-            let artifacts_dir = vm::provision(&config, &name, &*instance, &manifest)
-                .await
-                .context("Failed to run provisioning for VM")?;
-
-            let skip_wait = vm_config.provisioner != config::Provisioner::Noop;
+                .context(ConfigError("Could not find the specified app packages"))?;
+
+            let (mut instance, artifacts_dir, skip_wait) = if attach {
+                let persisted = vm::persist::load(&name)
+                    .await
+                    .context(ConfigError("Failed to attach to VM"))?;
+                log::info!("Attaching to VM at {}", persisted.ip_addr);
+                let artifacts_dir = persisted.artifacts_dir.clone();
+                (
+                    Box::new(persisted) as Box<dyn vm::VmInstance>,
+                    artifacts_dir,
+                    false,
+                )
+            } else {
+                // Fail before starting the VM if any file to provision is missing
+                vm_config
+                    .check_provision_files()
+                    .context(ConfigError("Could not find the files to provision"))?;
+                vm_config
+                    .check_ssh_options()
+                    .context(ConfigError("Invalid SSH options"))?;
+
+                let instance = vm::run(&config, &name)
+                    .await
+                    .context("Failed to start VM")?;
+                let artifacts_dir = vm::provision(&config, &name, &*instance, &manifest)
+                    .await
+                    .context("Failed to run provisioning for VM")?;
+
+                let skip_wait = vm_config.provisioner != config::Provisioner::Noop;
+
+                (instance, artifacts_dir, skip_wait)
+            };
 
             let summary_logger = match test_report {
                 Some(path) => Some(
@@ -241,10 +443,20 @@ async fn main() -> Result<()> {
                 None => None,
             };
 
+            let timing_logger = match trace_timing {
+                Some(path) => Some(
+                    timing::TimingLogger::new(&path)
+                        .await
+                        .context("Failed to create timing logger")?,
+                ),
+                None => None,
+            };
+
             let result = run_tests::run(
                 tests::config::TestConfig {
                     account_number: account,
-                    artifacts_dir,
+                    expired_account_number: expired_account,
+                    artifacts_dir: artifacts_dir.clone(),
                     current_app_filename: manifest
                         .current_app_path
                         .file_name()
@@ -263,34 +475,67 @@ async fn main() -> Result<()> {
                         .unwrap()
                         .to_string_lossy()
                         .into_owned(),
+                    current_app_path: manifest.current_app_path.clone(),
+                    previous_app_path: manifest.previous_app_path.clone(),
+                    stream_packages,
                     mullvad_host,
+                    api_host: vm_config.api_host.clone(),
+                    api_address: vm_config.api_address,
                     #[cfg(target_os = "macos")]
                     host_bridge_name: crate::vm::network::macos::find_vm_bridge()?,
                     #[cfg(not(target_os = "macos"))]
                     host_bridge_name: crate::vm::network::linux::BRIDGE_NAME.to_owned(),
+                    max_install_footprint,
+                    connect_churn_cycles,
                 },
-                &*instance,
+                &mut *instance,
                 &test_filters,
                 skip_wait,
+                rollback_per_test,
+                fail_fast,
                 !verbose,
+                strict_cleanup,
+                max_runtime.map(|minutes| std::time::Duration::from_secs(minutes * 60)),
+                &failure_log_dir,
                 summary_logger,
+                timing_logger,
             )
             .await
             .context("Tests failed");
 
-            if display {
+            if persist_vm {
+                vm::persist::save(&name, &*instance, &artifacts_dir)
+                    .await
+                    .context("Failed to store VM details")?;
+
+                log::info!(
+                    "Keeping the VM running. Pass --attach to reuse it, or press Ctrl+C to stop it"
+                );
+                log::info!("Serial device: {}", instance.get_pty());
+                log::info!("IP: {}", instance.get_ip());
+
+                tokio::select! {
+                    _ = instance.wait() => (),
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+
+                vm::persist::remove(&name).await;
+            } else if display {
                 instance.wait().await;
             }
             result
         }
-        Commands::FormatTestReports { reports } => {
-            summary::print_summary_table(&reports)
-                .await
-                .context("Print report")?;
+        Commands::FormatTestReports { reports, format } => {
+            match format {
+                summary::ReportFormat::Html => summary::print_summary_table(&reports).await,
+                summary::ReportFormat::Json => summary::print_summary_json(&reports).await,
+            }
+            .context("Print report")?;
             Ok(())
         }
         Commands::Update { name } => {
-            let vm_config = vm::get_vm_config(&config, &name).context("Cannot get VM config")?;
+            let vm_config =
+                vm::get_vm_config(&config, &name).context(ConfigError("Cannot get VM config"))?;
 
             let instance = vm::run(&config, &name)
                 .await
@@ -77,7 +77,7 @@ impl RpcClientProvider {
         ManagementServiceClient::new(channel)
     }
 
-    async fn old_client(&self) -> old_mullvad_management_interface::ManagementServiceClient {
+    pub async fn old_client(&self) -> old_mullvad_management_interface::ManagementServiceClient {
         tokio::time::sleep(std::time::Duration::from_millis(500)).await;
         log::debug!("Mullvad daemon (old): connecting");
         let channel = old_mullvad_management_interface::Channel::builder(Uri::from_static(
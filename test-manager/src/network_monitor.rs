@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     future::poll_fn,
     net::{IpAddr, SocketAddr},
     time::Duration,
@@ -23,6 +24,7 @@ use crate::vm::network::CUSTOM_TUN_INTERFACE_NAME;
 
 struct Codec {
     no_frame: bool,
+    interface: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -30,23 +32,125 @@ pub struct ParsedPacket {
     pub source: SocketAddr,
     pub destination: SocketAddr,
     pub protocol: IpNextHeaderProtocol,
+    /// Interface that the packet was captured on
+    pub interface: String,
+    /// First question of the DNS message in the packet, if it is a valid DNS message on port 53
+    pub dns_question: Option<DnsQuestion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    /// Queried domain name, without a trailing dot
+    pub name: String,
+    /// Numeric record type, e.g. 1 for A or 28 for AAAA
+    pub record_type: u16,
+}
+
+impl DnsQuestion {
+    const DNS_PORT: u16 = 53;
+    const HEADER_SIZE: usize = 12;
+    const MAX_NAME_LEN: usize = 255;
+
+    /// Parse the first question in `payload`, if the segment is sent to or from port 53.
+    fn parse_segment(
+        payload: &[u8],
+        protocol: IpNextHeaderProtocol,
+        source: &SocketAddr,
+        destination: &SocketAddr,
+    ) -> Option<Self> {
+        if source.port() != Self::DNS_PORT && destination.port() != Self::DNS_PORT {
+            return None;
+        }
+        match protocol {
+            IpHeaderProtocols::Udp => Self::parse(payload),
+            // DNS messages over TCP are prefixed with their length
+            IpHeaderProtocols::Tcp => Self::parse(payload.get(2..)?),
+            _ => None,
+        }
+    }
+
+    /// Parse the first question of a DNS message. This returns `None` if `message` is not a
+    /// valid DNS message, or if it contains no questions.
+    fn parse(message: &[u8]) -> Option<Self> {
+        let header = message.get(..Self::HEADER_SIZE)?;
+        let question_count = u16::from_be_bytes([header[4], header[5]]);
+        if question_count == 0 {
+            return None;
+        }
+
+        let mut labels = vec![];
+        let mut name_len = 0;
+        let mut offset = Self::HEADER_SIZE;
+        loop {
+            let label_len = usize::from(*message.get(offset)?);
+            offset += 1;
+            if label_len == 0 {
+                break;
+            }
+            // Compression is not used for the first name in a message, so a label must follow
+            if label_len > 63 {
+                return None;
+            }
+            name_len += label_len + 1;
+            if name_len > Self::MAX_NAME_LEN {
+                return None;
+            }
+            let label = message.get(offset..offset + label_len)?;
+            labels.push(std::str::from_utf8(label).ok()?);
+            offset += label_len;
+        }
+
+        // The question ends with the record type and class
+        let record_type = message.get(offset..offset + 4)?;
+
+        Some(Self {
+            name: labels.join("."),
+            record_type: u16::from_be_bytes([record_type[0], record_type[1]]),
+        })
+    }
+
+    /// Encode a recursive DNS query containing only this question, in class IN.
+    pub fn to_query(&self, id: u16) -> Vec<u8> {
+        let mut message = Vec::with_capacity(Self::HEADER_SIZE + self.name.len() + 6);
+        message.extend(id.to_be_bytes());
+        // Flags: recursion desired
+        message.extend([0x01, 0x00]);
+        // One question, no answer, authority or additional records
+        message.extend([0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in self.name.split('.').filter(|label| !label.is_empty()) {
+            message.push(u8::try_from(label.len()).expect("DNS label is too long"));
+            message.extend(label.as_bytes());
+        }
+        message.push(0);
+        message.extend(self.record_type.to_be_bytes());
+        // Class IN
+        message.extend([0, 1]);
+        message
+    }
 }
 
 impl PacketCodec for Codec {
-    type Item = Option<ParsedPacket>;
+    /// The parsed packet, and its length on the wire
+    type Item = Option<(ParsedPacket, usize)>;
 
     fn decode(&mut self, packet: pcap::Packet) -> Self::Item {
+        let length = usize::try_from(packet.header.len).unwrap();
+        self.parse_packet(packet.data)
+            .map(|parsed_packet| (parsed_packet, length))
+    }
+}
+
+impl Codec {
+    fn parse_packet(&self, data: &[u8]) -> Option<ParsedPacket> {
         if self.no_frame {
             // skip utun header specifying an address family
             #[cfg(target_os = "macos")]
-            let data = &packet.data[4..];
-            #[cfg(not(target_os = "macos"))]
-            let data = packet.data;
+            let data = &data[4..];
             let ip_version = (data[0] & 0xf0) >> 4;
 
             return match ip_version {
-                4 => Self::parse_ipv4(data),
-                6 => Self::parse_ipv6(data),
+                4 => self.parse_ipv4(data),
+                6 => self.parse_ipv6(data),
                 version => {
                     log::debug!("Ignoring unknown IP version: {version}");
                     None
@@ -54,24 +158,22 @@ impl PacketCodec for Codec {
             };
         }
 
-        let frame = pnet_packet::ethernet::EthernetPacket::new(packet.data).or_else(|| {
+        let frame = pnet_packet::ethernet::EthernetPacket::new(data).or_else(|| {
             log::error!("Received invalid ethernet frame");
             None
         })?;
 
         match frame.get_ethertype() {
-            EtherTypes::Ipv4 => Self::parse_ipv4(frame.payload()),
-            EtherTypes::Ipv6 => Self::parse_ipv6(frame.payload()),
+            EtherTypes::Ipv4 => self.parse_ipv4(frame.payload()),
+            EtherTypes::Ipv6 => self.parse_ipv6(frame.payload()),
             ethertype => {
                 log::debug!("Ignoring unknown ethertype: {ethertype}");
                 None
             }
         }
     }
-}
 
-impl Codec {
-    fn parse_ipv4(payload: &[u8]) -> Option<ParsedPacket> {
+    fn parse_ipv4(&self, payload: &[u8]) -> Option<ParsedPacket> {
         let packet = Ipv4Packet::new(payload).or_else(|| {
             log::error!("invalid v4 packet");
             None
@@ -82,7 +184,7 @@ impl Codec {
 
         let protocol = packet.get_next_level_protocol();
 
-        match protocol {
+        let dns_question = match protocol {
             IpHeaderProtocols::Tcp => {
                 let seg = TcpPacket::new(packet.payload()).or_else(|| {
                     log::error!("invalid TCP segment");
@@ -90,6 +192,7 @@ impl Codec {
                 })?;
                 source.set_port(seg.get_source());
                 destination.set_port(seg.get_destination());
+                DnsQuestion::parse_segment(seg.payload(), protocol, &source, &destination)
             }
             IpHeaderProtocols::Udp => {
                 let seg = UdpPacket::new(packet.payload()).or_else(|| {
@@ -98,19 +201,25 @@ impl Codec {
                 })?;
                 source.set_port(seg.get_source());
                 destination.set_port(seg.get_destination());
+                DnsQuestion::parse_segment(seg.payload(), protocol, &source, &destination)
             }
-            IpHeaderProtocols::Icmp => {}
-            proto => log::debug!("ignoring v4 packet, transport/protocol type {proto}"),
-        }
+            IpHeaderProtocols::Icmp => None,
+            proto => {
+                log::debug!("ignoring v4 packet, transport/protocol type {proto}");
+                None
+            }
+        };
 
         Some(ParsedPacket {
             source,
             destination,
             protocol,
+            interface: self.interface.clone(),
+            dns_question,
         })
     }
 
-    fn parse_ipv6(payload: &[u8]) -> Option<ParsedPacket> {
+    fn parse_ipv6(&self, payload: &[u8]) -> Option<ParsedPacket> {
         let packet = Ipv6Packet::new(payload).or_else(|| {
             log::error!("invalid v6 packet");
             None
@@ -120,7 +229,7 @@ impl Codec {
         let mut destination = SocketAddr::new(IpAddr::V6(packet.get_destination()), 0);
 
         let protocol = packet.get_next_header();
-        match protocol {
+        let dns_question = match protocol {
             IpHeaderProtocols::Tcp => {
                 let seg = TcpPacket::new(packet.payload()).or_else(|| {
                     log::error!("invalid TCP segment");
@@ -128,6 +237,7 @@ impl Codec {
                 })?;
                 source.set_port(seg.get_source());
                 destination.set_port(seg.get_destination());
+                DnsQuestion::parse_segment(seg.payload(), protocol, &source, &destination)
             }
             IpHeaderProtocols::Udp => {
                 let seg = UdpPacket::new(packet.payload()).or_else(|| {
@@ -136,15 +246,21 @@ impl Codec {
                 })?;
                 source.set_port(seg.get_source());
                 destination.set_port(seg.get_destination());
+                DnsQuestion::parse_segment(seg.payload(), protocol, &source, &destination)
             }
-            IpHeaderProtocols::Icmpv6 => {}
-            proto => log::debug!("ignoring v6 packet, transport/protocol type {proto}"),
-        }
+            IpHeaderProtocols::Icmpv6 => None,
+            proto => {
+                log::debug!("ignoring v6 packet, transport/protocol type {proto}");
+                None
+            }
+        };
 
         Some(ParsedPacket {
             source,
             destination,
             protocol,
+            interface: self.interface.clone(),
+            dns_question,
         })
     }
 }
@@ -152,6 +268,14 @@ impl Codec {
 #[derive(Debug)]
 pub struct MonitorUnexpectedlyStopped(());
 
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "Failed to start capture on {}: {}", _0, _1)]
+    StartCapture(String, pcap::Error),
+    #[error(display = "Invalid BPF filter \"{}\" for {}: {}", _0, _1, _2)]
+    InvalidBpfFilter(String, String, pcap::Error),
+}
+
 pub struct PacketMonitor {
     handle: tokio::task::JoinHandle<Result<MonitorResult, MonitorUnexpectedlyStopped>>,
     stop_tx: oneshot::Sender<()>,
@@ -160,6 +284,22 @@ pub struct PacketMonitor {
 pub struct MonitorResult {
     pub packets: Vec<ParsedPacket>,
     pub discarded_packets: usize,
+    /// Total length of the packets in `packets`, including all headers, per protocol
+    pub byte_count: BTreeMap<IpNextHeaderProtocol, usize>,
+}
+
+impl MonitorResult {
+    /// Record a captured packet that is `length` bytes long on the wire.
+    fn add_packet(&mut self, packet: ParsedPacket, length: usize) {
+        let byte_count = self.byte_count.entry(packet.protocol).or_insert(0);
+        *byte_count = byte_count.saturating_add(length);
+        self.packets.push(packet);
+    }
+
+    /// Return the total length of all packets in `packets`, including all headers.
+    pub fn total_bytes(&self) -> usize {
+        self.byte_count.values().sum()
+    }
 }
 
 impl PacketMonitor {
@@ -180,12 +320,17 @@ pub struct MonitorOptions {
     pub timeout: Option<Duration>,
     pub direction: Option<Direction>,
     pub no_frame: bool,
+    /// BPF filter expression applied by the kernel before packets reach the codec, e.g.
+    /// `"host 1.1.1.1 and port 53"`. `filter_fn` is still applied to the remaining packets.
+    /// Starting the monitor fails with [`Error::InvalidBpfFilter`] if the expression does not
+    /// compile.
+    pub bpf_filter: Option<String>,
 }
 
 pub async fn start_packet_monitor(
     filter_fn: impl Fn(&ParsedPacket) -> bool + Send + 'static,
     monitor_options: MonitorOptions,
-) -> PacketMonitor {
+) -> Result<PacketMonitor, Error> {
     start_packet_monitor_until(filter_fn, |_| true, monitor_options).await
 }
 
@@ -193,7 +338,7 @@ pub async fn start_packet_monitor_until(
     filter_fn: impl Fn(&ParsedPacket) -> bool + Send + 'static,
     should_continue_fn: impl FnMut(&ParsedPacket) -> bool + Send + 'static,
     monitor_options: MonitorOptions,
-) -> PacketMonitor {
+) -> Result<PacketMonitor, Error> {
     start_packet_monitor_for_interface(
         &TEST_CONFIG.host_bridge_name,
         filter_fn,
@@ -207,10 +352,10 @@ pub async fn start_tunnel_packet_monitor_until(
     filter_fn: impl Fn(&ParsedPacket) -> bool + Send + 'static,
     should_continue_fn: impl FnMut(&ParsedPacket) -> bool + Send + 'static,
     mut monitor_options: MonitorOptions,
-) -> PacketMonitor {
+) -> Result<PacketMonitor, Error> {
     monitor_options.no_frame = true;
-    start_packet_monitor_for_interface(
-        CUSTOM_TUN_INTERFACE_NAME,
+    start_packet_monitor_multi(
+        &[CUSTOM_TUN_INTERFACE_NAME],
         filter_fn,
         should_continue_fn,
         monitor_options,
@@ -221,36 +366,65 @@ pub async fn start_tunnel_packet_monitor_until(
 async fn start_packet_monitor_for_interface(
     interface: &str,
     filter_fn: impl Fn(&ParsedPacket) -> bool + Send + 'static,
+    should_continue_fn: impl FnMut(&ParsedPacket) -> bool + Send + 'static,
+    monitor_options: MonitorOptions,
+) -> Result<PacketMonitor, Error> {
+    start_packet_monitor_multi(&[interface], filter_fn, should_continue_fn, monitor_options).await
+}
+
+/// Capture packets on all of `interfaces` at once. Packets captured on the custom tun interface
+/// never have a link-layer header, so `no_frame` is implied for it. This fails if a capture cannot
+/// be started on any of the interfaces, or if `bpf_filter` is invalid.
+pub async fn start_packet_monitor_multi(
+    interfaces: &[&str],
+    filter_fn: impl Fn(&ParsedPacket) -> bool + Send + 'static,
     mut should_continue_fn: impl FnMut(&ParsedPacket) -> bool + Send + 'static,
     monitor_options: MonitorOptions,
-) -> PacketMonitor {
-    let dev = pcap::Capture::from_device(interface)
-        .expect("Failed to open capture handle")
-        .immediate_mode(true)
-        .open()
-        .expect("Failed to activate capture");
-
-    if let Some(direction) = monitor_options.direction {
-        dev.direction(direction).unwrap();
-    }
+) -> Result<PacketMonitor, Error> {
+    let mut packet_streams = vec![];
+
+    for interface in interfaces {
+        let start_capture_error =
+            |error: pcap::Error| Error::StartCapture(interface.to_string(), error);
+
+        let mut dev = pcap::Capture::from_device(*interface)
+            .map_err(start_capture_error)?
+            .immediate_mode(true)
+            .open()
+            .map_err(start_capture_error)?;
 
-    let dev = dev.setnonblock().unwrap();
+        if let Some(direction) = monitor_options.direction {
+            dev.direction(direction).map_err(start_capture_error)?;
+        }
+
+        if let Some(bpf_filter) = monitor_options.bpf_filter.as_deref() {
+            dev.filter(bpf_filter, true).map_err(|error| {
+                Error::InvalidBpfFilter(bpf_filter.to_owned(), interface.to_string(), error)
+            })?;
+        }
+
+        let dev = dev.setnonblock().map_err(start_capture_error)?;
+
+        packet_streams.push(
+            dev.stream(Codec {
+                no_frame: monitor_options.no_frame || *interface == CUSTOM_TUN_INTERFACE_NAME,
+                interface: interface.to_string(),
+            })
+            .map_err(start_capture_error)?,
+        );
+    }
 
     let (is_receiving_tx, is_receiving_rx) = oneshot::channel();
 
-    let packet_stream = dev
-        .stream(Codec {
-            no_frame: monitor_options.no_frame,
-        })
-        .unwrap();
+    // Polling the merged stream polls every capture
+    let packet_stream = futures::stream::select_all(packet_streams);
     let (stop_tx, stop_rx) = oneshot::channel();
 
-    let interface = interface.to_owned();
-
     let handle = tokio::spawn(async move {
         let mut monitor_result = MonitorResult {
             packets: vec![],
             discarded_packets: 0,
+            byte_count: BTreeMap::new(),
         };
         let mut packet_stream = packet_stream.fuse();
 
@@ -274,19 +448,22 @@ async fn start_packet_monitor_for_interface(
 
             match select(select(next_packet, &mut stop_rx), &mut timeout).await {
                 Either::Left((Either::Left((Some(Ok(packet)), _)), _)) => {
-                    if let Some(packet) = packet {
+                    if let Some((packet, length)) = packet {
                         if !filter_fn(&packet) {
                             log::debug!(
-                                "{interface} \"{packet:?}\" does not match closure conditions"
+                                "{} \"{packet:?}\" does not match closure conditions",
+                                packet.interface
                             );
                             monitor_result.discarded_packets =
                                 monitor_result.discarded_packets.saturating_add(1);
                         } else {
-                            log::debug!("{interface} \"{packet:?}\" matches closure conditions");
+                            log::debug!(
+                                "{} \"{packet:?}\" matches closure conditions",
+                                packet.interface
+                            );
 
                             let should_continue = should_continue_fn(&packet);
-
-                            monitor_result.packets.push(packet);
+                            monitor_result.add_packet(packet, length);
 
                             if !should_continue {
                                 break Ok(monitor_result);
@@ -313,7 +490,7 @@ async fn start_packet_monitor_for_interface(
     // Wait for the loop to start receiving its first packet
     let _ = is_receiving_rx.await;
 
-    PacketMonitor { stop_tx, handle }
+    Ok(PacketMonitor { stop_tx, handle })
 }
 
 /// Poll the future once and notify `tx` that it has been polled. Then return
@@ -329,3 +506,142 @@ fn poll_and_notify<F: std::future::Future<Output = O> + Unpin, O>(
     }
     result
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Query for `example.com` of type A, with ID 0xabcd
+    const EXAMPLE_QUERY: &[u8] = &[
+        0xab, 0xcd, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 7, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    fn example_question() -> DnsQuestion {
+        DnsQuestion {
+            name: "example.com".to_owned(),
+            record_type: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_question() {
+        assert_eq!(DnsQuestion::parse(EXAMPLE_QUERY), Some(example_question()));
+    }
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(example_question().to_query(0xabcd), EXAMPLE_QUERY);
+
+        let question = DnsQuestion {
+            name: "test.mullvad.net".to_owned(),
+            record_type: 28,
+        };
+        assert_eq!(DnsQuestion::parse(&question.to_query(1)), Some(question));
+    }
+
+    #[test]
+    fn test_parse_invalid_message() {
+        // Truncated header
+        assert_eq!(DnsQuestion::parse(&EXAMPLE_QUERY[..5]), None);
+        // Truncated record type
+        assert_eq!(DnsQuestion::parse(&EXAMPLE_QUERY[..26]), None);
+        // Truncated label
+        assert_eq!(DnsQuestion::parse(&EXAMPLE_QUERY[..16]), None);
+
+        // No questions
+        let mut message = EXAMPLE_QUERY.to_vec();
+        message[5] = 0;
+        assert_eq!(DnsQuestion::parse(&message), None);
+
+        // Compressed name
+        let mut message = EXAMPLE_QUERY[..DnsQuestion::HEADER_SIZE].to_vec();
+        message.extend([0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        assert_eq!(DnsQuestion::parse(&message), None);
+
+        // Name that is not valid UTF-8
+        let mut message = EXAMPLE_QUERY.to_vec();
+        message[13] = 0xff;
+        assert_eq!(DnsQuestion::parse(&message), None);
+    }
+
+    #[test]
+    fn test_parse_segment() {
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let resolver: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let other: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+
+        let parse = |payload: &[u8],
+                     protocol: IpNextHeaderProtocol,
+                     source: &SocketAddr,
+                     destination: &SocketAddr| {
+            DnsQuestion::parse_segment(payload, protocol, source, destination)
+        };
+
+        // Queries and responses over UDP
+        assert_eq!(
+            parse(EXAMPLE_QUERY, IpHeaderProtocols::Udp, &client, &resolver),
+            Some(example_question())
+        );
+        assert_eq!(
+            parse(EXAMPLE_QUERY, IpHeaderProtocols::Udp, &resolver, &client),
+            Some(example_question())
+        );
+
+        // Messages over TCP are prefixed with their length
+        let mut tcp_payload = u16::try_from(EXAMPLE_QUERY.len())
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+        tcp_payload.extend(EXAMPLE_QUERY);
+        assert_eq!(
+            parse(&tcp_payload, IpHeaderProtocols::Tcp, &client, &resolver),
+            Some(example_question())
+        );
+        assert_eq!(
+            parse(EXAMPLE_QUERY, IpHeaderProtocols::Tcp, &client, &resolver),
+            None
+        );
+
+        // Other ports and protocols are ignored
+        assert_eq!(
+            parse(EXAMPLE_QUERY, IpHeaderProtocols::Udp, &client, &other),
+            None
+        );
+        assert_eq!(
+            parse(EXAMPLE_QUERY, IpHeaderProtocols::Icmp, &client, &resolver),
+            None
+        );
+    }
+
+    fn packet(protocol: IpNextHeaderProtocol) -> ParsedPacket {
+        ParsedPacket {
+            source: "10.0.0.2:40000".parse().unwrap(),
+            destination: "1.1.1.1:1337".parse().unwrap(),
+            protocol,
+            interface: "eth0".to_owned(),
+            dns_question: None,
+        }
+    }
+
+    #[test]
+    fn test_total_bytes() {
+        let mut result = MonitorResult {
+            packets: vec![],
+            discarded_packets: 0,
+            byte_count: BTreeMap::new(),
+        };
+        assert_eq!(result.total_bytes(), 0);
+
+        result.add_packet(packet(IpHeaderProtocols::Udp), 100);
+        result.add_packet(packet(IpHeaderProtocols::Tcp), 60);
+        result.add_packet(packet(IpHeaderProtocols::Udp), 1500);
+        result.add_packet(packet(IpHeaderProtocols::Icmp), 84);
+
+        assert_eq!(result.packets.len(), 4);
+        assert_eq!(result.byte_count[&IpHeaderProtocols::Udp], 1600);
+        assert_eq!(result.byte_count[&IpHeaderProtocols::Tcp], 60);
+        assert_eq!(result.byte_count[&IpHeaderProtocols::Icmp], 84);
+        assert_eq!(result.total_bytes(), 1744);
+    }
+}
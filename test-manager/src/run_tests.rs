@@ -1,32 +1,59 @@
-use crate::summary::{self, maybe_log_test_result};
+use crate::summary::{self, maybe_log_skipped_test, maybe_log_test_result};
 use crate::tests::TestContext;
-use crate::{logging::run_test, mullvad_daemon, tests, vm};
+use crate::timing::{TestTiming, TimingLogger};
+use crate::{
+    logging::{run_test, TestOutput},
+    mullvad_daemon, tests, vm,
+};
 use anyhow::{Context, Result};
 use mullvad_management_interface::ManagementServiceClient;
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use test_rpc::{mullvad_daemon::MullvadClientVersion, ServiceClient};
+use tokio::sync::Notify;
 
 const BAUD: u32 = 115200;
 
+/// Returned by `run` if the tests were run, but one or more of them did not pass
+#[derive(err_derive::Error, Debug)]
+#[error(display = "One or more tests did not pass")]
+pub struct TestsFailed;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     config: tests::config::TestConfig,
-    instance: &dyn vm::VmInstance,
+    instance: &mut dyn vm::VmInstance,
     test_filters: &[String],
     skip_wait: bool,
+    mut rollback_per_test: bool,
+    fail_fast: bool,
     print_failed_tests_only: bool,
+    strict_cleanup: bool,
+    max_runtime: Option<Duration>,
+    failure_log_dir: &Path,
     mut summary_logger: Option<summary::SummaryLogger>,
+    mut timing_logger: Option<TimingLogger>,
 ) -> Result<()> {
     log::trace!("Setting test constants");
     tests::config::TEST_CONFIG.init(config);
 
-    let pty_path = instance.get_pty();
+    let pty_path = instance.get_pty().to_owned();
 
     log::info!("Connecting to {pty_path}");
 
     let serial_stream =
-        tokio_serial::SerialStream::open(&tokio_serial::new(pty_path, BAUD)).unwrap();
-    let (runner_transport, mullvad_daemon_transport, mut connection_handle, completion_handle) =
-        test_rpc::transport::create_client_transports(serial_stream).await?;
+        tokio_serial::SerialStream::open(&tokio_serial::new(&pty_path, BAUD)).unwrap();
+    let (
+        runner_transport,
+        mullvad_daemon_transport,
+        file_transport,
+        mut connection_handle,
+        completion_handle,
+    ) = test_rpc::transport::create_client_transports(serial_stream).await?;
 
     if !skip_wait {
         connection_handle.wait_for_server().await?;
@@ -34,12 +61,17 @@ pub async fn run(
 
     log::info!("Running client");
 
-    let client = ServiceClient::new(connection_handle.clone(), runner_transport);
+    let client = ServiceClient::new(connection_handle.clone(), runner_transport, file_transport);
     let mullvad_client =
         mullvad_daemon::new_rpc_client(connection_handle, mullvad_daemon_transport).await;
 
+    let guest_os = client.get_os().await.context("Failed to get guest OS")?;
+    let targets_guest_os =
+        |test: &tests::TestMetadata| test.targets.is_empty() || test.targets.contains(&guest_os);
+
     let mut tests: Vec<_> = inventory::iter::<tests::TestMetadata>().collect();
     tests.sort_by_key(|test| test.priority.unwrap_or(0));
+    let mut tests = sort_by_dependencies(tests)?;
 
     if !test_filters.is_empty() {
         tests.retain(|test| {
@@ -56,6 +88,37 @@ pub async fn run(
         });
     }
 
+    let mut skipped_tests = vec![];
+
+    tests.retain(|test| {
+        if targets_guest_os(test) {
+            return true;
+        }
+        log::info!(
+            "Skipping {}, since it does not target {guest_os}",
+            test.name
+        );
+        skipped_tests.push(test.name);
+        false
+    });
+    for test in &skipped_tests {
+        maybe_log_skipped_test(
+            summary_logger.as_mut(),
+            test,
+            &format!("Does not target {guest_os}"),
+        )
+        .await
+        .context("Failed to log test result")?;
+    }
+
+    // Security-critical tests that are selected for this run must pass. Skipping them is not
+    // enough
+    let security_critical_tests: Vec<_> = tests
+        .iter()
+        .filter(|test| test.security_critical)
+        .map(|test| test.name)
+        .collect();
+
     let mut final_result = Ok(());
 
     let test_context = TestContext {
@@ -64,95 +127,326 @@ pub async fn run(
 
     let mut successful_tests = vec![];
     let mut failed_tests = vec![];
+    let mut expected_failures = vec![];
+    let mut unexpected_passes = vec![];
 
     let logger = super::logging::Logger::get_or_init();
 
-    for test in tests {
-        let mut mclient = test_context
-            .rpc_provider
-            .as_type(test.mullvad_client_version)
-            .await;
-
-        if let Some(client) = mclient.downcast_mut::<ManagementServiceClient>() {
-            crate::tests::init_default_settings(client).await;
-        }
-
-        log::info!("Running {}", test.name);
+    let abort_test = abort_test_on_signal();
 
-        if print_failed_tests_only {
-            // Stop live record
-            logger.store_records(true);
-        }
+    let mut tests = tests.into_iter();
+    let mut current_test = None;
 
-        let test_result = run_test(
-            client.clone(),
-            mclient,
-            &test.func,
-            test.name,
-            test_context.clone(),
-        )
-        .await
-        .context("Failed to run test")?;
-
-        if test.mullvad_client_version == MullvadClientVersion::New {
-            // Try to reset the daemon state if the test failed OR if the test doesn't explicitly
-            // disabled cleanup.
-            if test.cleanup || matches!(test_result.result, Err(_) | Ok(Err(_))) {
-                let mut client = test_context.rpc_provider.new_client().await;
-                crate::tests::cleanup_after_test(&mut client).await?;
+    let test_loop = async {
+        for test in tests.by_ref() {
+            let failed_dependency = test.depends_on.iter().find(|dependency| {
+                failed_tests.contains(*dependency)
+                    || expected_failures.contains(*dependency)
+                    || skipped_tests.contains(*dependency)
+            });
+            if let Some(dependency) = failed_dependency {
+                log::warn!("Skipping {}, since {dependency} did not pass", test.name);
+                skipped_tests.push(test.name);
+                maybe_log_skipped_test(
+                    summary_logger.as_mut(),
+                    test.name,
+                    &format!("{dependency} did not pass"),
+                )
+                .await
+                .context("Failed to log test result")?;
+                continue;
             }
-        }
 
-        if print_failed_tests_only {
-            // Print results of failed test
-            if matches!(test_result.result, Err(_) | Ok(Err(_))) {
-                logger.print_stored_records();
+            let started = Instant::now();
+            current_test = Some((test.name, started));
+
+            let expected_failure = test.xfail_on.contains(&guest_os);
+            // Expected failures are not retried
+            let max_attempts = if expected_failure {
+                1
             } else {
-                logger.flush_records();
-            }
-            logger.store_records(false);
-        }
+                test.retries + 1
+            };
 
-        test_result.print();
+            let mut attempts: usize = 0;
+            let (test_result, cleanup_succeeded) = loop {
+                attempts += 1;
 
-        let test_succeeded = matches!(test_result.result, Ok(Ok(_)));
+                let mut timing = TestTiming::default();
+                let setup_start = Instant::now();
 
-        maybe_log_test_result(
-            summary_logger.as_mut(),
-            test.name,
-            if test_succeeded {
-                summary::TestResult::Pass
-            } else {
-                summary::TestResult::Fail
-            },
-        )
-        .await
-        .context("Failed to log test result")?;
+                let snapshot = if rollback_per_test {
+                    match instance.snapshot().await {
+                        Ok(snapshot) => Some(snapshot),
+                        Err(error) => {
+                            log::warn!("Failed to snapshot VM, disabling rollback: {error:#}");
+                            rollback_per_test = false;
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let mut mclient = test_context
+                    .rpc_provider
+                    .as_type(test.mullvad_client_version)
+                    .await;
+
+                if let Some(client) = mclient.downcast_mut::<ManagementServiceClient>() {
+                    crate::tests::init_default_settings(client).await;
+                }
+
+                timing.setup = setup_start.elapsed();
+
+                log::info!("Running {}", test.name);
 
-        match test_result.result {
-            Err(panic) => {
-                failed_tests.push(test.name);
-                final_result = Err(panic).context("test panicked");
-                if test.must_succeed {
-                    break;
+                if print_failed_tests_only {
+                    // Stop live record
+                    logger.store_records(true);
                 }
+
+                let test_start = Instant::now();
+
+                let test_result = run_test(
+                    client.clone(),
+                    mclient,
+                    &test.func,
+                    test.name,
+                    test_context.clone(),
+                    test.timeout,
+                    abort_test.notified(),
+                )
+                .await
+                .context("Failed to run test")?;
+
+                timing.test = test_start.elapsed();
+                let cleanup_start = Instant::now();
+
+                let mut cleanup_succeeded = true;
+                let test_skipped = matches!(test_result.result, Ok(Err(tests::Error::Skipped(_))));
+                let test_failed =
+                    !test_skipped && matches!(test_result.result, Err(_) | Ok(Err(_)));
+
+                if test_failed {
+                    save_failure_log(
+                        failure_log_dir,
+                        test.name,
+                        attempts,
+                        &test_result,
+                        &test_context.rpc_provider,
+                    )
+                    .await;
+                }
+
+                let mut rolled_back = false;
+                if let Some(snapshot) = snapshot {
+                    if test_failed {
+                        log::info!("Rolling back VM after {}", test.name);
+                        rollback(instance, &client, snapshot.clone(), &pty_path).await?;
+                        rolled_back = true;
+                    }
+                    instance
+                        .delete_snapshot(snapshot)
+                        .await
+                        .context("Failed to delete VM snapshot")?;
+                }
+
+                if test.mullvad_client_version == MullvadClientVersion::New && !rolled_back {
+                    // Try to reset the daemon state if the test failed OR if the test doesn't
+                    // explicitly disabled cleanup. This also resets the settings before the test is
+                    // retried. This is not needed if the VM was rolled back.
+                    if test.cleanup || test_failed {
+                        let mut client =
+                            crate::tests::cleanup_after_test(&client, &test_context.rpc_provider)
+                                .await?;
+
+                        let settings_diff =
+                            crate::tests::diff_default_settings(&mut client).await?;
+                        if !settings_diff.is_empty() {
+                            log::warn!(
+                                "Settings were not restored to the defaults after {}:\n{}",
+                                test.name,
+                                settings_diff.join("\n")
+                            );
+                            cleanup_succeeded = !strict_cleanup;
+                        }
+                    }
+                }
+
+                timing.cleanup = cleanup_start.elapsed();
+
+                if let Some(timing_logger) = timing_logger.as_mut() {
+                    timing_logger
+                        .log_test_timing(test.name, timing)
+                        .await
+                        .context("Failed to log test timing")?;
+                }
+
+                if print_failed_tests_only {
+                    // Print results of failed test
+                    if test_failed {
+                        logger.print_stored_records();
+                    } else {
+                        logger.flush_records();
+                    }
+                    logger.store_records(false);
+                }
+
+                test_result.print();
+
+                let test_succeeded = matches!(test_result.result, Ok(Ok(_))) && cleanup_succeeded;
+                let aborted = matches!(test_result.result, Ok(Err(tests::Error::Aborted)));
+                if test_succeeded || test_skipped || aborted || attempts >= max_attempts {
+                    break (test_result, cleanup_succeeded);
+                }
+
+                log::warn!(
+                    "{} failed. Retrying (attempt {} of {max_attempts})",
+                    test.name,
+                    attempts + 1
+                );
+            };
+
+            if let Ok(Err(tests::Error::Skipped(reason))) = &test_result.result {
+                log::info!("Skipped {}: {reason}", test.name);
+                skipped_tests.push(test.name);
+                maybe_log_skipped_test(summary_logger.as_mut(), test.name, reason)
+                    .await
+                    .context("Failed to log test result")?;
+                current_test = None;
+                continue;
             }
-            Ok(Err(failure)) => {
-                failed_tests.push(test.name);
-                final_result = Err(failure).context("test failed");
-                if test.must_succeed {
-                    break;
+
+            let test_succeeded = matches!(test_result.result, Ok(Ok(_))) && cleanup_succeeded;
+
+            let summary_result = match (test_succeeded, expected_failure) {
+                (true, true) => summary::TestResult::UnexpectedPass,
+                (false, true) => summary::TestResult::ExpectedFail,
+                (test_succeeded, false) => {
+                    let attempts = u32::try_from(attempts).unwrap_or(u32::MAX);
+                    summary::TestResult::from_attempts(test_succeeded, attempts)
+                }
+            };
+            maybe_log_test_result(
+                summary_logger.as_mut(),
+                test.name,
+                summary_result,
+                started.elapsed(),
+            )
+            .await
+            .context("Failed to log test result")?;
+
+            current_test = None;
+
+            let result = match test_result.result {
+                Err(panic) => Err(panic).context("test panicked"),
+                Ok(Err(failure)) => Err(failure).context("test failed"),
+                Ok(Ok(_)) if !cleanup_succeeded => {
+                    Err(anyhow::anyhow!("cleanup did not restore default settings"))
+                        .context("test failed")
+                }
+                Ok(Ok(result)) => Ok(result),
+            };
+
+            match result {
+                Ok(result) => {
+                    if expected_failure {
+                        log::warn!(
+                            "{} passed, but is expected to fail on {guest_os}",
+                            test.name
+                        );
+                        unexpected_passes.push(test.name);
+                    }
+                    successful_tests.push(test.name);
+                    final_result = final_result.and(Ok(result));
+                }
+                Err(error) => {
+                    if expected_failure {
+                        log::warn!("{} failed, as expected on {guest_os}", test.name);
+                        expected_failures.push(test.name);
+                    } else {
+                        failed_tests.push(test.name);
+                        final_result = Err(error);
+                        if fail_fast {
+                            log::warn!("Stopping, since {} failed", test.name);
+                            break;
+                        }
+                    }
+                    if test.must_succeed {
+                        break;
+                    }
                 }
             }
-            Ok(Ok(result)) => {
-                successful_tests.push(test.name);
-                final_result = final_result.and(Ok(result));
+        }
+
+        Ok::<(), anyhow::Error>(())
+    };
+
+    let timed_out = match max_runtime {
+        Some(max_runtime) => match tokio::time::timeout(max_runtime, test_loop).await {
+            Ok(result) => {
+                result?;
+                false
+            }
+            Err(_elapsed) => {
+                log::error!(
+                    "Test run exceeded the maximum runtime of {} minutes",
+                    max_runtime.as_secs() / 60
+                );
+                true
+            }
+        },
+        None => {
+            test_loop.await?;
+            false
+        }
+    };
+
+    if timed_out {
+        if let Some((interrupted_test, started)) = current_test {
+            log::error!("Interrupted {interrupted_test}");
+
+            if print_failed_tests_only {
+                logger.print_stored_records();
+                logger.store_records(false);
             }
+
+            failed_tests.push(interrupted_test);
+            maybe_log_test_result(
+                summary_logger.as_mut(),
+                interrupted_test,
+                summary::TestResult::Fail,
+                started.elapsed(),
+            )
+            .await
+            .context("Failed to log test result")?;
+        }
+
+        for test in tests {
+            skipped_tests.push(test.name);
+            maybe_log_skipped_test(
+                summary_logger.as_mut(),
+                test.name,
+                "Maximum runtime exceeded",
+            )
+            .await
+            .context("Failed to log test result")?;
+        }
+
+        final_result = Err(anyhow::anyhow!("maximum runtime exceeded"));
+    } else if fail_fast {
+        // Remaining tests were not run because a test failed
+        for test in tests {
+            skipped_tests.push(test.name);
+            maybe_log_skipped_test(summary_logger.as_mut(), test.name, "A previous test failed")
+                .await
+                .context("Failed to log test result")?;
         }
     }
 
     log::info!("TESTS THAT SUCCEEDED:");
-    for test in successful_tests {
+    for test in &successful_tests {
         log::info!("{test}");
     }
 
@@ -161,9 +455,185 @@ pub async fn run(
         log::info!("{test}");
     }
 
+    if !expected_failures.is_empty() {
+        log::info!("TESTS THAT FAILED AS EXPECTED:");
+        for test in expected_failures {
+            log::info!("{test}");
+        }
+    }
+
+    if !unexpected_passes.is_empty() {
+        log::warn!("TESTS THAT PASSED UNEXPECTEDLY:");
+        for test in unexpected_passes {
+            log::warn!("{test}");
+        }
+    }
+
+    if !skipped_tests.is_empty() {
+        log::info!("TESTS THAT WERE SKIPPED:");
+        for test in skipped_tests {
+            log::info!("{test}");
+        }
+    }
+
+    let unpassed_security_critical_tests: Vec<_> = security_critical_tests
+        .into_iter()
+        .filter(|test| !successful_tests.contains(test))
+        .collect();
+
+    if !unpassed_security_critical_tests.is_empty() {
+        log::error!("SECURITY-CRITICAL TESTS THAT FAILED OR WERE SKIPPED:");
+        for test in &unpassed_security_critical_tests {
+            log::error!("{test}");
+        }
+
+        final_result = final_result.and(Err(anyhow::anyhow!(
+            "{} security-critical test(s) did not pass",
+            unpassed_security_critical_tests.len()
+        )));
+    }
+
     // wait for cleanup
     drop(test_context);
     let _ = tokio::time::timeout(Duration::from_secs(5), completion_handle).await;
 
-    final_result
+    final_result.context(TestsFailed)
+}
+
+/// Sort `tests` so that every test runs after the tests that it depends on. Otherwise, the
+/// original order is preserved. This fails if a dependency does not exist, or if there are
+/// cyclic dependencies.
+fn sort_by_dependencies(mut tests: Vec<&tests::TestMetadata>) -> Result<Vec<&tests::TestMetadata>> {
+    let names: HashSet<_> = tests.iter().map(|test| test.name).collect();
+    for test in &tests {
+        if let Some(dependency) = test
+            .depends_on
+            .iter()
+            .find(|dependency| !names.contains(*dependency))
+        {
+            anyhow::bail!("{} depends on unknown test {dependency}", test.name);
+        }
+    }
+
+    let mut sorted = Vec::with_capacity(tests.len());
+    let mut sorted_names = HashSet::new();
+    while !tests.is_empty() {
+        let next = tests.iter().position(|test| {
+            test.depends_on
+                .iter()
+                .all(|dependency| sorted_names.contains(dependency))
+        });
+        let test = match next {
+            Some(next) => tests.remove(next),
+            None => {
+                let remaining: Vec<_> = tests.iter().map(|test| test.name).collect();
+                anyhow::bail!(
+                    "Cyclic dependencies between tests: {}",
+                    remaining.join(", ")
+                );
+            }
+        };
+        sorted_names.insert(test.name);
+        sorted.push(test);
+    }
+
+    Ok(sorted)
+}
+
+/// Restore the VM to `snapshot` after a failed test. The existing serial connection is reused,
+/// so the VM must keep its serial device.
+///
+/// Loading a snapshot also restores the memory of the guest, including the state of the test
+/// runner and of any daemon RPC session, which no longer matches the state of the test manager.
+/// The guest is therefore rebooted afterwards, so that only the disk state is kept and the
+/// transport is reconnected.
+async fn rollback(
+    instance: &mut dyn vm::VmInstance,
+    client: &ServiceClient,
+    snapshot: vm::SnapshotId,
+    pty_path: &str,
+) -> Result<()> {
+    instance
+        .restore(snapshot)
+        .await
+        .context("Failed to roll back VM")?;
+    if instance.get_pty() != pty_path {
+        anyhow::bail!("The serial device of the VM changed during rollback");
+    }
+    client
+        .clone()
+        .reboot()
+        .await
+        .context("Failed to reboot VM after rollback")?;
+    // The guest clock is restored along with the VM
+    client
+        .set_system_time(std::time::SystemTime::now())
+        .await
+        .context("Failed to set guest time after rollback")?;
+    Ok(())
+}
+
+/// Write the logs of a failed attempt of a test and the current tunnel state to
+/// `<dir>/<test_name>-<attempt>.log` on the host. The attempt is included so that retries do not
+/// overwrite each other. This must be called before the daemon is cleaned up. Failures are only
+/// logged.
+async fn save_failure_log(
+    dir: &Path,
+    test_name: &str,
+    attempt: usize,
+    test_output: &TestOutput,
+    rpc_provider: &mullvad_daemon::RpcClientProvider,
+) {
+    let tunnel_state = match rpc_provider.new_client().await.get_tunnel_state(()).await {
+        Ok(state) => {
+            let state = state.into_inner();
+            match mullvad_types::states::TunnelState::try_from(state.clone()) {
+                Ok(state) => format!("{state:?}"),
+                Err(_) => format!("{state:?}"),
+            }
+        }
+        Err(error) => format!("Failed to get tunnel state: {error}"),
+    };
+
+    if let Err(error) = tokio::fs::create_dir_all(dir).await {
+        log::error!("Failed to create {}: {error}", dir.display());
+        return;
+    }
+
+    let path = dir.join(format!("{test_name}-{attempt}.log"));
+    match test_output.save(&path, &tunnel_state).await {
+        Ok(()) => log::info!("Wrote logs of {test_name} to {}", path.display()),
+        Err(error) => log::error!("Failed to write {}: {error}", path.display()),
+    }
+}
+
+/// Return a `Notify` that wakes all waiters when the test manager receives `SIGUSR1`. This is used
+/// to abort the currently running test and move on to the next one.
+///
+/// Aborting tests is only supported on Unix. On other platforms, the returned `Notify` is never
+/// notified.
+fn abort_test_on_signal() -> Arc<Notify> {
+    let notify = Arc::new(Notify::new());
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined1()) {
+            Ok(mut stream) => {
+                let notify = notify.clone();
+                tokio::spawn(async move {
+                    while stream.recv().await.is_some() {
+                        log::warn!("Received SIGUSR1");
+                        // Only wakes a test that is currently running. Signals received between
+                        // tests are ignored.
+                        notify.notify_waiters();
+                    }
+                });
+            }
+            Err(error) => log::error!("Failed to register SIGUSR1 handler: {error}"),
+        }
+    }
+
+    notify
 }
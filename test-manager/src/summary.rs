@@ -1,4 +1,5 @@
-use std::{collections::BTreeMap, io, path::Path};
+use serde::Serialize;
+use std::{collections::BTreeMap, io, path::Path, time::Duration};
 use tokio::{
     fs,
     io::{AsyncBufReadExt, AsyncWriteExt},
@@ -15,19 +16,88 @@ pub enum Error {
     Read(#[error(source)] io::Error),
     #[error(display = "Failed to parse log file")]
     Parse,
+    #[error(display = "Failed to serialize summary")]
+    Serialize(#[error(source)] serde_json::Error),
 }
 
+/// Output format of `FormatTestReports`
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// HTML table
+    #[default]
+    Html,
+    /// JSON array containing the results of each report
+    Json,
+}
+
+/// Serialized as a flat status string, such as `"pass"`. See `TestResult::status`.
 #[derive(Clone, Copy)]
 pub enum TestResult {
     Pass,
+    /// The test passed, but only after being run more than once
+    Flaky {
+        attempts: u32,
+    },
     Fail,
+    /// The test failed on an OS that it is known to fail on
+    ExpectedFail,
+    /// The test passed on an OS that it is known to fail on
+    UnexpectedPass,
+    /// The test was not run, because it does not target the OS, or because an earlier test
+    /// failed
+    Skip,
     Unknown,
 }
 
 impl TestResult {
     const PASS_STR: &str = "✅";
+    const FLAKY_STR: &str = "⚠️";
     const FAIL_STR: &str = "❌";
+    const EXPECTED_FAIL_STR: &str = "🔶";
+    const UNEXPECTED_PASS_STR: &str = "❗";
+    const SKIP_STR: &str = "⏭️";
     const UNKNOWN_STR: &str = " ";
+
+    /// Return the result of a test that was run `attempts` times, where the last attempt either
+    /// succeeded or failed.
+    pub fn from_attempts(succeeded: bool, attempts: u32) -> Self {
+        match (succeeded, attempts) {
+            (false, _) => TestResult::Fail,
+            (true, 0 | 1) => TestResult::Pass,
+            (true, attempts) => TestResult::Flaky { attempts },
+        }
+    }
+
+    /// Return the status of the test as written to JSON reports. Flaky tests are reported as
+    /// passed, and their attempts are reported separately. See `Summary::attempts`.
+    fn status(&self) -> &'static str {
+        match self {
+            TestResult::Pass | TestResult::Flaky { .. } => "pass",
+            TestResult::Fail => "fail",
+            TestResult::ExpectedFail => "expected_fail",
+            TestResult::UnexpectedPass => "unexpected_pass",
+            TestResult::Skip => "skip",
+            TestResult::Unknown => "unknown",
+        }
+    }
+
+    /// Whether the test passed, possibly after several attempts. Expected failures are not
+    /// counted as failures.
+    fn passed(&self) -> bool {
+        matches!(
+            self,
+            TestResult::Pass
+                | TestResult::Flaky { .. }
+                | TestResult::ExpectedFail
+                | TestResult::UnexpectedPass
+        )
+    }
+}
+
+impl Serialize for TestResult {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.status())
+    }
 }
 
 impl std::str::FromStr for TestResult {
@@ -37,7 +107,15 @@ impl std::str::FromStr for TestResult {
         match s {
             TestResult::PASS_STR => Ok(TestResult::Pass),
             TestResult::FAIL_STR => Ok(TestResult::Fail),
-            _ => Ok(TestResult::Unknown),
+            TestResult::EXPECTED_FAIL_STR => Ok(TestResult::ExpectedFail),
+            TestResult::UNEXPECTED_PASS_STR => Ok(TestResult::UnexpectedPass),
+            TestResult::SKIP_STR => Ok(TestResult::Skip),
+            _ => match s.strip_prefix(TestResult::FLAKY_STR) {
+                Some(attempts) => Ok(TestResult::Flaky {
+                    attempts: attempts.parse().map_err(|_| Error::Parse)?,
+                }),
+                None => Ok(TestResult::Unknown),
+            },
         }
     }
 }
@@ -46,7 +124,12 @@ impl std::fmt::Display for TestResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TestResult::Pass => f.write_str(TestResult::PASS_STR),
+            // This must not contain whitespace, since it is also written to the summary log
+            TestResult::Flaky { attempts } => write!(f, "{}{attempts}", TestResult::FLAKY_STR),
             TestResult::Fail => f.write_str(TestResult::FAIL_STR),
+            TestResult::ExpectedFail => f.write_str(TestResult::EXPECTED_FAIL_STR),
+            TestResult::UnexpectedPass => f.write_str(TestResult::UNEXPECTED_PASS_STR),
+            TestResult::Skip => f.write_str(TestResult::SKIP_STR),
             TestResult::Unknown => f.write_str(TestResult::UNKNOWN_STR),
         }
     }
@@ -78,10 +161,30 @@ impl SummaryLogger {
         Ok(SummaryLogger { file })
     }
 
+    /// Log the result of a test, and the time it took to run. Use `log_skipped_test` for tests
+    /// that were not run.
     pub async fn log_test_result(
         &mut self,
         test_name: &str,
         test_result: TestResult,
+        duration: Duration,
+    ) -> Result<(), Error> {
+        self.write_result(test_name, test_result, duration, None)
+            .await
+    }
+
+    /// Log that a test was not run, and why.
+    pub async fn log_skipped_test(&mut self, test_name: &str, reason: &str) -> Result<(), Error> {
+        self.write_result(test_name, TestResult::Skip, Duration::ZERO, Some(reason))
+            .await
+    }
+
+    async fn write_result(
+        &mut self,
+        test_name: &str,
+        test_result: TestResult,
+        duration: Duration,
+        reason: Option<&str>,
     ) -> Result<(), Error> {
         self.file
             .write_all(test_name.as_bytes())
@@ -92,6 +195,19 @@ impl SummaryLogger {
             .write_all(test_result.to_string().as_bytes())
             .await
             .map_err(Error::Write)?;
+        self.file.write_u8(b' ').await.map_err(Error::Write)?;
+        self.file
+            .write_all(format!("{:.3}", duration.as_secs_f64()).as_bytes())
+            .await
+            .map_err(Error::Write)?;
+        if let Some(reason) = reason {
+            // The reason is the remainder of the line
+            self.file.write_u8(b' ').await.map_err(Error::Write)?;
+            self.file
+                .write_all(reason.replace('\n', " ").as_bytes())
+                .await
+                .map_err(Error::Write)?;
+        }
         self.file.write_u8(b'\n').await.map_err(Error::Write)?;
 
         Ok(())
@@ -104,19 +220,46 @@ pub async fn maybe_log_test_result(
     summary_logger: Option<&mut SummaryLogger>,
     test_name: &str,
     test_result: TestResult,
+    duration: Duration,
+) -> Result<(), Error> {
+    match summary_logger {
+        Some(logger) => {
+            logger
+                .log_test_result(test_name, test_result, duration)
+                .await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Convenience function that logs a skipped test when there's a value, and is a no-op otherwise.
+pub async fn maybe_log_skipped_test(
+    summary_logger: Option<&mut SummaryLogger>,
+    test_name: &str,
+    reason: &str,
 ) -> Result<(), Error> {
     match summary_logger {
-        Some(logger) => logger.log_test_result(test_name, test_result).await,
+        Some(logger) => logger.log_skipped_test(test_name, reason).await,
         None => Ok(()),
     }
 }
 
 /// Parsed summary results
+#[derive(Serialize)]
 pub struct Summary {
     /// Summary name
     name: String,
     /// Pairs of test names mapped to test results
     results: BTreeMap<String, TestResult>,
+    /// Number of times that each flaky test was run before it passed
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attempts: BTreeMap<String, u32>,
+    /// Time taken by each test. This is missing for logs written by older versions
+    #[serde(skip)]
+    durations: BTreeMap<String, Duration>,
+    /// Reasons why tests were skipped
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    skip_reasons: BTreeMap<String, String>,
 }
 
 impl Summary {
@@ -137,34 +280,86 @@ impl Summary {
             .ok_or(Error::Parse)?;
 
         let mut results = BTreeMap::new();
+        let mut attempts = BTreeMap::new();
+        let mut durations = BTreeMap::new();
+        let mut skip_reasons = BTreeMap::new();
 
         while let Some(line) = lines.next_line().await.map_err(Error::Read)? {
-            let mut cols = line.split_whitespace();
+            let mut cols = line.splitn(4, ' ').filter(|col| !col.is_empty());
 
             let test_name = cols.next().ok_or(Error::Parse)?;
             let test_result = cols.next().ok_or(Error::Parse)?.parse()?;
 
+            // The duration column is missing in logs written by older versions
+            if let Some(duration) = cols.next() {
+                let secs: f64 = duration.parse().map_err(|_| Error::Parse)?;
+                let duration = Duration::try_from_secs_f64(secs).map_err(|_| Error::Parse)?;
+                durations.insert(test_name.to_owned(), duration);
+            }
+            if let Some(reason) = cols.next() {
+                skip_reasons.insert(test_name.to_owned(), reason.trim().to_owned());
+            }
+
+            if let TestResult::Flaky {
+                attempts: test_attempts,
+            } = test_result
+            {
+                attempts.insert(test_name.to_owned(), test_attempts);
+            }
             results.insert(test_name.to_owned(), test_result);
         }
 
-        Ok(Summary { name, results })
+        Ok(Summary {
+            name,
+            results,
+            attempts,
+            durations,
+            skip_reasons,
+        })
     }
 
     // Return all tests which passed.
     fn passed(&self) -> Vec<&TestResult> {
+        self.results.values().filter(|x| x.passed()).collect()
+    }
+
+    /// Return the number of tests which were skipped.
+    fn skipped(&self) -> usize {
         self.results
             .values()
-            .filter(|x| matches!(x, TestResult::Pass))
-            .collect()
+            .filter(|result| matches!(result, TestResult::Skip))
+            .count()
+    }
+
+    /// Whether all of `total_tests` tests passed, not counting skipped tests.
+    fn all_passed(&self, total_tests: usize) -> bool {
+        self.passed().len() == total_tests.saturating_sub(self.skipped())
     }
 }
 
-/// Outputs an HTML table, to stdout, containing the results of the given log files.
-pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<(), Error> {
+/// Marker appended to the names of security-critical tests in the summary table
+const SECURITY_CRITICAL_STR: &str = " 🔒";
+
+/// Read the summaries in all of the given log files.
+async fn parse_logs<P: AsRef<Path>>(summary_files: &[P]) -> Result<Vec<Summary>, Error> {
     let mut summaries = vec![];
     for sumfile in summary_files {
         summaries.push(Summary::parse_log(sumfile.as_ref()).await?);
     }
+    Ok(summaries)
+}
+
+/// Outputs the results of the given log files, to stdout, as a JSON array.
+pub async fn print_summary_json<P: AsRef<Path>>(summary_files: &[P]) -> Result<(), Error> {
+    let summaries = parse_logs(summary_files).await?;
+    let json = serde_json::to_string_pretty(&summaries).map_err(Error::Serialize)?;
+    println!("{json}");
+    Ok(())
+}
+
+/// Outputs an HTML table, to stdout, containing the results of the given log files.
+pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<(), Error> {
+    let summaries = parse_logs(summary_files).await?;
 
     // Collect test details
     let tests: Vec<_> = inventory::iter::<crate::tests::TestMetadata>().collect();
@@ -180,7 +375,8 @@ pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<
     println!("<td style='text-align: center;'>Test ⬇️ / Platform ➡️ </td>");
 
     for summary in &summaries {
-        let total_tests = tests.len();
+        // Skipped tests are not counted
+        let total_tests = tests.len().saturating_sub(summary.skipped());
         let total_passed = summary.passed().len();
         let counter_text = if total_passed == total_tests {
             String::from(TestResult::PASS_STR)
@@ -198,7 +394,7 @@ pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<
     println!("{}", {
         let oses_passed: Vec<_> = summaries
             .iter()
-            .filter(|summary| summary.passed().len() == tests.len())
+            .filter(|summary| summary.all_passed(tests.len()))
             .collect();
         if oses_passed.len() == summaries.len() {
             "🎉 All Platforms passed 🎉".to_string()
@@ -206,7 +402,7 @@ pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<
             let failed: usize = summaries
                 .iter()
                 .map(|summary| {
-                    if summary.passed().len() == tests.len() {
+                    if summary.all_passed(tests.len()) {
                         0
                     } else {
                         1
@@ -228,9 +424,14 @@ pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<
         println!("<tr>");
 
         println!(
-            "<td>{}{}</td>",
+            "<td>{}{}{}</td>",
             test.name,
-            if test.must_succeed { " *" } else { "" }
+            if test.must_succeed { " *" } else { "" },
+            if test.security_critical {
+                SECURITY_CRITICAL_STR
+            } else {
+                ""
+            }
         );
 
         let mut failed_platforms = vec![];
@@ -243,9 +444,26 @@ pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<
                 TestResult::Fail | TestResult::Unknown => {
                     failed_platforms.push(summary.name.clone())
                 }
-                TestResult::Pass => (),
+                TestResult::Skip
+                | TestResult::Pass
+                | TestResult::Flaky { .. }
+                | TestResult::ExpectedFail
+                | TestResult::UnexpectedPass => (),
+            }
+            // Show the reason for skipping the test when hovering over it
+            let title = summary
+                .skip_reasons
+                .get(test.name)
+                .map(|reason| format!(" title='{}'", escape_html(reason)))
+                .unwrap_or_default();
+            match summary.durations.get(test.name) {
+                Some(duration) if !duration.is_zero() => println!(
+                    "<td style='text-align: center;'{title}>{} {}</td>",
+                    result,
+                    format_duration(*duration)
+                ),
+                _ => println!("<td style='text-align: center;'{title}>{}</td>", result),
             }
-            println!("<td style='text-align: center;'>{}</td>", result);
         }
         // Print a summary of all OSes at the end of the table
         // For each test, collect the result for each platform.
@@ -273,7 +491,135 @@ pub async fn print_summary_table<P: AsRef<Path>>(summary_files: &[P]) -> Result<
 
     // Print explanation of test result
     println!("<p>{} = Test passed</p>", TestResult::PASS_STR);
+    println!(
+        "<p>{}N = Test passed after N attempts</p>",
+        TestResult::FLAKY_STR
+    );
     println!("<p>{} = Test failed</p>", TestResult::FAIL_STR);
+    println!(
+        "<p>{} = Test failed, as expected on this platform</p>",
+        TestResult::EXPECTED_FAIL_STR
+    );
+    println!(
+        "<p>{} = Test passed, but is expected to fail on this platform</p>",
+        TestResult::UNEXPECTED_PASS_STR
+    );
+    println!(
+        "<p>{} = Test was skipped. Hover over it to see why</p>",
+        TestResult::SKIP_STR
+    );
+    println!(
+        "<p>{} = Security-critical test. The test run fails unless it passes</p>",
+        SECURITY_CRITICAL_STR.trim()
+    );
 
     Ok(())
 }
+
+/// Format `duration` as minutes and seconds, e.g. `2m 5s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m {}s", secs / 60, secs % 60)
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Summary log containing lines written by older versions, which lack the duration column,
+    /// along with lines written by newer versions
+    const MIXED_LOG: &str = "linux
+test_a ✅
+test_b ❌ 1.500
+test_c ⚠️3 2.250
+test_d ⏭️ 0.000 Does not target linux
+test_e ⏭️
+";
+
+    async fn parse(log: &str) -> Result<Summary, Error> {
+        let mut file = async_tempfile::TempFile::new().await.unwrap();
+        file.write_all(log.as_bytes()).await.unwrap();
+        file.flush().await.unwrap();
+        Summary::parse_log(file.file_path()).await
+    }
+
+    #[tokio::test]
+    async fn test_parse_mixed_log() {
+        let summary = parse(MIXED_LOG).await.unwrap();
+
+        assert_eq!(summary.name, "linux");
+        let results: Vec<_> = summary
+            .results
+            .iter()
+            .map(|(name, result)| (name.as_str(), result.to_string()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("test_a", TestResult::PASS_STR.to_owned()),
+                ("test_b", TestResult::FAIL_STR.to_owned()),
+                ("test_c", format!("{}3", TestResult::FLAKY_STR)),
+                ("test_d", TestResult::SKIP_STR.to_owned()),
+                ("test_e", TestResult::SKIP_STR.to_owned()),
+            ]
+        );
+        assert_eq!(
+            summary.durations,
+            BTreeMap::from([
+                ("test_b".to_owned(), Duration::from_millis(1500)),
+                ("test_c".to_owned(), Duration::from_millis(2250)),
+                ("test_d".to_owned(), Duration::ZERO),
+            ])
+        );
+        assert_eq!(
+            summary.skip_reasons,
+            BTreeMap::from([("test_d".to_owned(), "Does not target linux".to_owned())])
+        );
+        assert_eq!(summary.attempts, BTreeMap::from([("test_c".to_owned(), 3)]));
+    }
+
+    #[tokio::test]
+    async fn test_serialize_summary() {
+        let summary = parse(MIXED_LOG).await.unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "name": "linux",
+                "results": {
+                    "test_a": "pass",
+                    "test_b": "fail",
+                    "test_c": "pass",
+                    "test_d": "skip",
+                    "test_e": "skip",
+                },
+                "attempts": { "test_c": 3 },
+                "skip_reasons": { "test_d": "Does not target linux" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_invalid_log() {
+        // Missing summary name
+        assert!(matches!(parse("").await, Err(Error::Parse)));
+        // Missing result column
+        assert!(matches!(parse("linux\ntest_a\n").await, Err(Error::Parse)));
+        // Invalid duration
+        assert!(matches!(
+            parse("linux\ntest_a ✅ soon\n").await,
+            Err(Error::Parse)
+        ));
+    }
+}
@@ -1,19 +1,25 @@
 use super::config::TEST_CONFIG;
-use super::{helpers, ui, Error, TestContext};
+use super::helpers::{self, send_guest_probes};
+use super::{ui, Error, TestContext};
 use mullvad_api::DevicesProxy;
 use mullvad_management_interface::{types, Code, ManagementServiceClient};
 use mullvad_types::device::Device;
 use mullvad_types::states::TunnelState;
-use std::net::ToSocketAddrs;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use talpid_types::net::wireguard;
 use test_macro::test_function;
-use test_rpc::ServiceClient;
+use test_rpc::{Interface, ServiceClient};
 
 const THROTTLE_RETRY_DELAY: Duration = Duration::from_secs(120);
 
 /// Log in and create a new device for the account.
-#[test_function(always_run = true, must_succeed = true, priority = -100)]
+#[test_function(
+    always_run = true,
+    must_succeed = true,
+    priority = -100,
+    depends_on = "test_install_new_app"
+)]
 pub async fn test_login(
     _: TestContext,
     _rpc: ServiceClient,
@@ -201,6 +207,138 @@ pub async fn test_revoked_device(
     Ok(())
 }
 
+/// Log in to an account without time left while connected, and verify that the daemon keeps
+/// blocking traffic rather than leaking. This approximates the account running out of time during
+/// a session. The expired account is specified using `--expired-account`. This test fails if:
+///
+/// * Outgoing non-tunnel traffic is observed after switching accounts.
+/// * The daemon ends up in the disconnected state or a non-blocking error state.
+/// * The daemon does not report the account as expired.
+///
+/// The test account is logged in again afterwards.
+///
+/// # Limitations
+///
+/// The account does not actually run out of time during the session. This test is skipped if no
+/// expired account is specified.
+#[test_function(priority = -149)]
+pub async fn test_account_expires_while_connected(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let expired_account = TEST_CONFIG
+        .expired_account_number
+        .clone()
+        .ok_or_else(|| Error::Skipped("no expired account specified".to_string()))?;
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
+
+    log::info!("Logging in/generating device");
+    login_with_retries(&mut mullvad_client)
+        .await
+        .expect("login failed");
+
+    helpers::connect_and_wait(&mut mullvad_client).await?;
+
+    let result = async {
+        // Begin listening to tunnel state changes first, so that we catch changes due to the
+        // new account.
+        let events = mullvad_client
+            .events_listen(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to listen to events: {error}")))?
+            .into_inner();
+        let next_state = helpers::find_next_tunnel_state(events, |state| {
+            matches!(
+                state,
+                TunnelState::Error(..) | TunnelState::Disconnected { .. }
+            )
+        });
+
+        log::info!("Switching to expired account");
+
+        mullvad_client
+            .logout_account(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to log out: {error}")))?;
+        mullvad_client
+            .login_account(expired_account.clone())
+            .await
+            .map_err(|error| {
+                Error::DaemonError(format!("Failed to log in to expired account: {error}"))
+            })?;
+        mullvad_client
+            .connect_tunnel(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to connect: {error}")))?;
+
+        log::info!("Test whether outgoing non-tunnel traffic is blocked");
+
+        let detected_probes =
+            send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
+        if detected_probes.any() {
+            return Err(Error::Other(format!(
+                "Observed outgoing packets with expired account: {detected_probes:?}"
+            )));
+        }
+
+        // The daemon may also keep retrying in the connecting state, which is blocking as well
+        match next_state.await {
+            Ok(TunnelState::Error(error_state)) if error_state.is_blocking() => (),
+            Ok(state) => {
+                return Err(Error::Other(format!(
+                    "Unexpected state with expired account: {state:?}"
+                )))
+            }
+            Err(_timeout) => {
+                let state = helpers::get_tunnel_state(&mut mullvad_client).await;
+                if !matches!(state, TunnelState::Connecting { .. }) {
+                    return Err(Error::Other(format!(
+                        "Unexpected state with expired account: {state:?}"
+                    )));
+                }
+            }
+        }
+
+        let expiry = mullvad_client
+            .get_account_data(expired_account)
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to get account data: {error}")))?
+            .into_inner()
+            .expiry
+            .ok_or_else(|| Error::DaemonError("Missing account expiry".to_string()))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if expiry.seconds > i64::try_from(now).unwrap() {
+            return Err(Error::Other(format!(
+                "Account is not expired. Expiry: {} s",
+                expiry.seconds
+            )));
+        }
+
+        Ok(())
+    }
+    .await;
+
+    log::info!("Logging in to the test account again");
+
+    mullvad_client
+        .disconnect_tunnel(())
+        .await
+        .expect("failed to disconnect");
+    mullvad_client
+        .logout_account(())
+        .await
+        .expect("logout failed");
+    login_with_retries(&mut mullvad_client)
+        .await
+        .expect("login failed");
+
+    result
+}
+
 /// Remove all devices on the current account
 pub async fn clear_devices(device_client: &DevicesProxy) -> Result<(), mullvad_api::rest::Error> {
     log::info!("Removing all devices for account");
@@ -219,12 +357,7 @@ pub async fn clear_devices(device_client: &DevicesProxy) -> Result<(), mullvad_a
 pub async fn new_device_client() -> DevicesProxy {
     let api_endpoint = mullvad_api::ApiEndpoint::from_env_vars();
 
-    let api_host = format!("api.{}", TEST_CONFIG.mullvad_host);
-    let api_addr = format!("{api_host}:443")
-        .to_socket_addrs()
-        .expect("failed to resolve API host")
-        .next()
-        .unwrap();
+    let (api_host, api_addr) = TEST_CONFIG.api_endpoint();
 
     // Override the API endpoint to use the one specified in the test config
     let _ = mullvad_api::API.override_init(mullvad_api::ApiEndpoint {
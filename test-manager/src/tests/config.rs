@@ -1,25 +1,82 @@
 use once_cell::sync::OnceCell;
-use std::ops::Deref;
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    ops::Deref,
+    path::PathBuf,
+};
 
 // Default `mullvad_host`. This should match the production env.
 pub const DEFAULT_MULLVAD_HOST: &str = "mullvad.net";
 
+// Default `max_install_footprint`, in bytes.
+pub const DEFAULT_MAX_INSTALL_FOOTPRINT: u64 = 1024 * 1024 * 1024;
+
+// Default `connect_churn_cycles`.
+pub const DEFAULT_CONNECT_CHURN_CYCLES: usize = 20;
+
 /// Constants that are accessible from each test via `TEST_CONFIG`.
 /// The constants must be initialized before running any tests using `TEST_CONFIG.init()`.
 #[derive(Debug, Clone)]
 pub struct TestConfig {
     pub account_number: String,
+    /// Account without time left. See `test_account_expires_while_connected`.
+    pub expired_account_number: Option<String>,
 
     pub artifacts_dir: String,
     pub current_app_filename: String,
     pub previous_app_filename: String,
     pub ui_e2e_tests_filename: String,
 
+    /// Paths of the app packages on the host.
+    pub current_app_path: PathBuf,
+    pub previous_app_path: PathBuf,
+    /// Send the app packages from the host when they are installed, instead of installing the
+    /// copies in `artifacts_dir`. See `helpers::install_app`.
+    pub stream_packages: bool,
+
     /// Used to override MULLVAD_API_*, for conncheck,
     /// and for resolving relay IPs.
     pub mullvad_host: String,
 
+    /// API host to use instead of `api.<mullvad_host>`. See `api_endpoint`.
+    pub api_host: Option<String>,
+    /// API address to use instead of resolving the API host.
+    pub api_address: Option<SocketAddr>,
+
     pub host_bridge_name: String,
+
+    /// Upper bound on the size of the installed app, in bytes.
+    pub max_install_footprint: u64,
+
+    /// Number of connect/disconnect cycles in `test_connect_disconnect_churn`.
+    pub connect_churn_cycles: usize,
+}
+
+impl TestConfig {
+    /// Return the host and address of the API used by the tests.
+    ///
+    /// The host is `api_host` if set, or else `api.<mullvad_host>`, and the address is
+    /// `api_address` if set, or else the resolved host. The result is passed to the daemon as
+    /// `MULLVAD_API_HOST` and `MULLVAD_API_ADDR`, so it takes precedence over any such variables
+    /// in the environment of the test manager, which only affect the remaining API settings.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the API host cannot be resolved.
+    pub fn api_endpoint(&self) -> (String, SocketAddr) {
+        let api_host = self
+            .api_host
+            .clone()
+            .unwrap_or_else(|| format!("api.{}", self.mullvad_host));
+        let api_addr = self.api_address.unwrap_or_else(|| {
+            format!("{api_host}:443")
+                .to_socket_addrs()
+                .expect("failed to resolve API host")
+                .next()
+                .unwrap()
+        });
+        (api_host, api_addr)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,33 +1,35 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::atomic::{AtomicUsize, Ordering},
     time::Duration,
 };
 
 use itertools::Itertools;
-use mullvad_management_interface::{types, ManagementServiceClient};
+use mullvad_management_interface::{types, Code, ManagementServiceClient};
 use mullvad_types::{
     relay_constraints::RelaySettingsUpdate, ConnectionConfig, CustomTunnelEndpoint,
 };
-use talpid_types::net::wireguard;
 use test_macro::test_function;
-use test_rpc::{Interface, ServiceClient};
+use test_rpc::{AddressFamily, Interface, ServiceClient};
 
 use super::{helpers::connect_and_wait, Error, TestContext};
 use crate::network_monitor::{
-    start_packet_monitor_until, start_tunnel_packet_monitor_until, Direction, IpHeaderProtocols,
-    MonitorOptions,
+    start_packet_monitor_until, start_tunnel_packet_monitor_until, Direction, DnsQuestion,
+    IpHeaderProtocols, MonitorOptions,
 };
 use crate::vm::network::{
-    CUSTOM_TUN_GATEWAY, CUSTOM_TUN_LOCAL_PRIVKEY, CUSTOM_TUN_LOCAL_TUN_ADDR,
-    CUSTOM_TUN_REMOTE_PUBKEY, CUSTOM_TUN_REMOTE_REAL_ADDR, CUSTOM_TUN_REMOTE_REAL_PORT,
-    CUSTOM_TUN_REMOTE_TUN_ADDR, NON_TUN_GATEWAY,
+    CUSTOM_TUN_REMOTE_REAL_ADDR, CUSTOM_TUN_REMOTE_TUN_ADDR, NON_TUN_GATEWAY,
 };
 
-use super::helpers::update_relay_settings;
+use super::helpers::{address_family, local_wg_relay_config, update_relay_settings};
 
 /// How long to wait for expected "DNS queries" to appear
 const MONITOR_TIMEOUT: Duration = Duration::from_secs(5);
+/// Name that is queried by the spoofed UDP DNS packets of `leak_test_dns`
+const LEAK_TEST_HOSTNAME: &str = "dns-leak-test.mullvad.net";
+
+// TODO: Test that no plaintext DNS is sent when encrypted DNS (DoH/DoT) is enabled. The daemon's
+// `DnsOptions` currently have no such setting, so this cannot be tested yet.
 
 /// Test whether DNS leaks can be produced when using the default resolver. It does this by
 /// connecting to a custom WireGuard relay on localhost and monitoring outbound DNS traffic in (and
@@ -40,7 +42,7 @@ const MONITOR_TIMEOUT: Duration = Duration::from_secs(5);
 /// # Limitations
 ///
 /// This test only detects outbound DNS leaks in the connected state.
-#[test_function]
+#[test_function(security_critical = true)]
 pub async fn test_dns_leak_default(
     _: TestContext,
     rpc: ServiceClient,
@@ -63,7 +65,7 @@ pub async fn test_dns_leak_default(
 /// # Limitations
 ///
 /// This test only detects outbound DNS leaks in the connected state.
-#[test_function]
+#[test_function(security_critical = true)]
 pub async fn test_dns_leak_custom_public_ip(
     _: TestContext,
     rpc: ServiceClient,
@@ -87,6 +89,63 @@ pub async fn test_dns_leak_custom_public_ip(
     leak_test_dns(&rpc, &mut mullvad_client, Interface::Tunnel, CONFIG_IP).await
 }
 
+/// Test whether DNS leaks can be produced when using a custom public IPv6 resolver. This test
+/// succeeds if and only if outgoing packets are only observed on the tunnel interface to the
+/// expected IP.
+///
+/// See `test_dns_leak_default` for more details.
+///
+/// # Limitations
+///
+/// This test only detects outbound DNS leaks in the connected state. Queries outside the tunnel
+/// are sent from the IPv6 address of the non-tunnel interface, so the test is skipped if the guest
+/// has no routable IPv6 address on its LAN.
+#[test_function(security_critical = true)]
+pub async fn test_dns_leak_custom_public_ipv6(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const CONFIG_IP: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x1337));
+
+    if rpc
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv6)
+        .await
+        .is_err()
+    {
+        return Err(Error::Skipped(
+            "the guest has no IPv6 address outside the tunnel".to_owned(),
+        ));
+    }
+
+    mullvad_client
+        .set_enable_ipv6(true)
+        .await
+        .expect("failed to enable IPv6");
+
+    log::debug!("Setting custom DNS resolver to {CONFIG_IP}");
+
+    mullvad_client
+        .set_dns_options(types::DnsOptions {
+            default_options: Some(types::DefaultDnsOptions::default()),
+            custom_options: Some(types::CustomDnsOptions {
+                addresses: vec![CONFIG_IP.to_string()],
+            }),
+            state: i32::from(types::dns_options::DnsState::Custom),
+        })
+        .await
+        .expect("failed to configure DNS server");
+
+    let result = leak_test_dns(&rpc, &mut mullvad_client, Interface::Tunnel, CONFIG_IP).await;
+
+    mullvad_client
+        .set_enable_ipv6(false)
+        .await
+        .expect("failed to disable IPv6");
+
+    result
+}
+
 /// Test whether DNS leaks can be produced when using a custom private IP. This test succeeds if and
 /// only if outgoing packets are only observed on the non-tunnel interface to the expected IP.
 ///
@@ -95,7 +154,7 @@ pub async fn test_dns_leak_custom_public_ip(
 /// # Limitations
 ///
 /// This test only detects outbound DNS leaks in the connected state.
-#[test_function]
+#[test_function(security_critical = true)]
 pub async fn test_dns_leak_custom_private_ip(
     _: TestContext,
     rpc: ServiceClient,
@@ -139,12 +198,13 @@ async fn leak_test_dns(
         .await
         .expect("failed to connect to custom wg relay");
 
+    let family = address_family(whitelisted_dest);
     let guest_ip = rpc
-        .get_interface_ip(Interface::NonTunnel)
+        .get_interface_ip(Interface::NonTunnel, family)
         .await
         .expect("failed to obtain guest IP");
     let tunnel_ip = rpc
-        .get_interface_ip(Interface::Tunnel)
+        .get_interface_ip(Interface::Tunnel, family)
         .await
         .expect("failed to obtain tunnel IP");
 
@@ -157,13 +217,22 @@ async fn leak_test_dns(
 
     let tun_bind_addr = SocketAddr::new(tunnel_ip, 0);
     let guest_bind_addr = SocketAddr::new(guest_ip, 0);
+    let (blocked_dest_local, blocked_dest_public) = if whitelisted_dest.is_ipv4() {
+        (
+            "10.64.100.100:53".parse().unwrap(),
+            "1.1.1.1:53".parse().unwrap(),
+        )
+    } else {
+        (
+            "[fd00::64:100:100]:53".parse().unwrap(),
+            "[2606:4700:4700::1111]:53".parse().unwrap(),
+        )
+    };
 
     let whitelisted_dest = SocketAddr::new(whitelisted_dest, 53);
-    let blocked_dest_local = "10.64.100.100:53".parse().unwrap();
-    let blocked_dest_public = "1.1.1.1:53".parse().unwrap();
 
     // Capture all outgoing DNS
-    let mut pkt_counter = DnsPacketsFound::new(1, 1);
+    let mut pkt_counter = DnsPacketsFound::new(LEAK_TEST_HOSTNAME, 1, 1);
 
     let (tunnel_monitor, non_tunnel_monitor) = if use_tun {
         let tunnel_monitor = start_tunnel_packet_monitor_until(
@@ -171,20 +240,22 @@ async fn leak_test_dns(
             move |packet| pkt_counter.handle_packet(packet),
             MonitorOptions {
                 direction: Some(Direction::In),
+                bpf_filter: Some("dst port 53".to_owned()),
                 timeout: Some(MONITOR_TIMEOUT),
                 ..Default::default()
             },
         )
-        .await;
+        .await?;
         let non_tunnel_monitor = start_packet_monitor_until(
             move |packet| packet.destination.port() == 53,
             |_packet| false,
             MonitorOptions {
                 direction: Some(Direction::In),
+                bpf_filter: Some("dst port 53".to_owned()),
                 ..Default::default()
             },
         )
-        .await;
+        .await?;
         (tunnel_monitor, non_tunnel_monitor)
     } else {
         let tunnel_monitor = start_tunnel_packet_monitor_until(
@@ -192,20 +263,22 @@ async fn leak_test_dns(
             |_packet| false,
             MonitorOptions {
                 direction: Some(Direction::In),
+                bpf_filter: Some("dst port 53".to_owned()),
                 ..Default::default()
             },
         )
-        .await;
+        .await?;
         let non_tunnel_monitor = start_packet_monitor_until(
             move |packet| packet.destination.port() == 53,
             move |packet| pkt_counter.handle_packet(packet),
             MonitorOptions {
                 direction: Some(Direction::In),
+                bpf_filter: Some("dst port 53".to_owned()),
                 timeout: Some(MONITOR_TIMEOUT),
                 ..Default::default()
             },
         )
-        .await;
+        .await?;
         (tunnel_monitor, non_tunnel_monitor)
     };
 
@@ -359,6 +432,49 @@ pub async fn test_dns_config_custom_private(
     run_dns_config_non_tunnel_test(&rpc, &mut mullvad_client, IpAddr::V4(NON_TUN_GATEWAY)).await
 }
 
+/// Test whether a custom DNS resolver on the LAN is used regardless of whether LAN sharing is
+/// enabled. The daemon always permits traffic to custom private resolvers, so the test succeeds if
+/// and only if names resolved through the daemon are looked up using the LAN resolver on the
+/// non-tunnel interface, both with `allow_lan` on and off.
+///
+/// # Limitations
+///
+/// This test only examines outbound packets in the connected state.
+#[test_function]
+pub async fn test_dns_custom_private_allow_lan(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let lan_resolver = IpAddr::V4(NON_TUN_GATEWAY);
+
+    log::debug!("Setting custom DNS resolver to {lan_resolver}");
+
+    mullvad_client
+        .set_dns_options(types::DnsOptions {
+            default_options: Some(types::DefaultDnsOptions::default()),
+            custom_options: Some(types::CustomDnsOptions {
+                addresses: vec![lan_resolver.to_string()],
+            }),
+            state: i32::from(types::dns_options::DnsState::Custom),
+        })
+        .await
+        .expect("failed to configure DNS server");
+
+    for allow_lan in [true, false] {
+        log::info!("Verify that the LAN resolver is used with allow_lan = {allow_lan}");
+
+        mullvad_client
+            .set_allow_lan(allow_lan)
+            .await
+            .expect("failed to set LAN sharing");
+
+        run_dns_config_non_tunnel_test(&rpc, &mut mullvad_client, lan_resolver).await?;
+    }
+
+    Ok(())
+}
+
 /// Test whether the expected custom DNS works for public IPs.
 ///
 /// # Limitations
@@ -495,6 +611,122 @@ pub async fn test_content_blockers(
     Ok(())
 }
 
+/// Test whether content blocker settings are preserved when the daemon is restarted. This enables
+/// ad and tracker blocking, restarts the daemon, and verifies that the blocking resolver is still
+/// used after reconnecting.
+#[test_function]
+pub async fn test_dns_blockers_persist_restart(
+    ctx: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    // Resolver used when both ad and tracker blocking are enabled
+    const BLOCKING_RESOLVER: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 3);
+
+    let dns_options = types::DnsOptions {
+        default_options: Some(types::DefaultDnsOptions {
+            block_ads: true,
+            block_trackers: true,
+            ..Default::default()
+        }),
+        custom_options: Some(types::CustomDnsOptions::default()),
+        state: i32::from(types::dns_options::DnsState::Default),
+    };
+
+    mullvad_client
+        .set_dns_options(dns_options.clone())
+        .await
+        .expect("failed to configure DNS server");
+
+    run_dns_config_tunnel_test(&rpc, &mut mullvad_client, IpAddr::V4(BLOCKING_RESOLVER)).await?;
+
+    log::info!("Restarting daemon");
+
+    rpc.set_mullvad_daemon_service_state(false).await?;
+    rpc.set_mullvad_daemon_service_state(true).await?;
+
+    // NOTE: Need to create a new `mullvad_client` here after the restart otherwise we can't
+    // communicate with the daemon
+    drop(mullvad_client);
+    let mut mullvad_client = ctx.rpc_provider.new_client().await;
+
+    let settings = mullvad_client
+        .get_settings(())
+        .await
+        .expect("failed to obtain settings")
+        .into_inner();
+    assert_eq!(
+        settings
+            .tunnel_options
+            .and_then(|tunnel_options| tunnel_options.dns_options),
+        Some(dns_options),
+        "DNS options were not preserved after restart",
+    );
+
+    run_dns_config_tunnel_test(&rpc, &mut mullvad_client, IpAddr::V4(BLOCKING_RESOLVER)).await
+}
+
+/// Test whether the daemon rejects malformed custom DNS addresses. This test fails if the daemon
+/// does not respond with an "invalid argument" error, or if the DNS settings are changed.
+#[test_function]
+pub async fn test_invalid_custom_dns(
+    _: TestContext,
+    _rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const INVALID_ADDRESSES: [&str; 4] = ["not.an.ip", "", "10.0.0.256", "fd00::g"];
+
+    let initial_dns_options = get_dns_options(&mut mullvad_client).await;
+
+    for address in INVALID_ADDRESSES {
+        log::info!("Setting custom DNS resolver to {address:?}");
+
+        let result = mullvad_client
+            .set_dns_options(types::DnsOptions {
+                default_options: Some(types::DefaultDnsOptions::default()),
+                custom_options: Some(types::CustomDnsOptions {
+                    addresses: vec![address.to_string()],
+                }),
+                state: i32::from(types::dns_options::DnsState::Custom),
+            })
+            .await;
+
+        match result {
+            Err(status) if status.code() == Code::InvalidArgument => (),
+            Err(status) => {
+                return Err(Error::DaemonError(format!(
+                    "Unexpected error for address {address:?}: {status}"
+                )))
+            }
+            Ok(_) => {
+                return Err(Error::DaemonError(format!(
+                    "Daemon accepted invalid address {address:?}"
+                )))
+            }
+        }
+    }
+
+    assert_eq!(
+        get_dns_options(&mut mullvad_client).await,
+        initial_dns_options,
+        "DNS options were changed by invalid addresses",
+    );
+
+    Ok(())
+}
+
+async fn get_dns_options(
+    mullvad_client: &mut ManagementServiceClient,
+) -> Option<types::DnsOptions> {
+    mullvad_client
+        .get_settings(())
+        .await
+        .expect("failed to obtain settings")
+        .into_inner()
+        .tunnel_options
+        .and_then(|tunnel_options| tunnel_options.dns_options)
+}
+
 async fn run_dns_config_tunnel_test(
     rpc: &ServiceClient,
     mullvad_client: &mut ManagementServiceClient,
@@ -544,7 +776,9 @@ async fn run_dns_config_non_tunnel_test(
 }
 
 async fn run_dns_config_test<
-    F: std::future::Future<Output = crate::network_monitor::PacketMonitor>,
+    F: std::future::Future<
+        Output = Result<crate::network_monitor::PacketMonitor, crate::network_monitor::Error>,
+    >,
 >(
     rpc: &ServiceClient,
     create_monitor: impl FnOnce() -> F,
@@ -568,18 +802,18 @@ async fn run_dns_config_test<
     }
 
     let guest_ip = rpc
-        .get_interface_ip(Interface::NonTunnel)
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
         .await
         .expect("failed to obtain guest IP");
     let tunnel_ip = rpc
-        .get_interface_ip(Interface::Tunnel)
+        .get_interface_ip(Interface::Tunnel, AddressFamily::Ipv4)
         .await
         .expect("failed to obtain tunnel IP");
 
     log::debug!("Tunnel (guest) IP: {tunnel_ip}");
     log::debug!("Non-tunnel (guest) IP: {guest_ip}");
 
-    let monitor = create_monitor().await;
+    let monitor = create_monitor().await?;
 
     let next_nonce = {
         static NONCE: AtomicUsize = AtomicUsize::new(0);
@@ -612,30 +846,9 @@ async fn run_dns_config_test<
 /// Connect to the WireGuard relay that is set up in scripts/setup-network.sh
 /// See that script for details.
 async fn connect_local_wg_relay(mullvad_client: &mut ManagementServiceClient) -> Result<(), Error> {
-    let peer_addr: SocketAddr = SocketAddr::new(
-        IpAddr::V4(CUSTOM_TUN_REMOTE_REAL_ADDR),
-        CUSTOM_TUN_REMOTE_REAL_PORT,
-    );
-
     let relay_settings = RelaySettingsUpdate::CustomTunnelEndpoint(CustomTunnelEndpoint {
-        host: peer_addr.ip().to_string(),
-        config: ConnectionConfig::Wireguard(wireguard::ConnectionConfig {
-            tunnel: wireguard::TunnelConfig {
-                addresses: vec![IpAddr::V4(CUSTOM_TUN_LOCAL_TUN_ADDR)],
-                private_key: wireguard::PrivateKey::from(CUSTOM_TUN_LOCAL_PRIVKEY),
-            },
-            peer: wireguard::PeerConfig {
-                public_key: wireguard::PublicKey::from(CUSTOM_TUN_REMOTE_PUBKEY),
-                allowed_ips: vec!["0.0.0.0/0".parse().unwrap()],
-                endpoint: peer_addr,
-                psk: None,
-            },
-            ipv4_gateway: CUSTOM_TUN_GATEWAY,
-            exit_peer: None,
-            #[cfg(target_os = "linux")]
-            fwmark: None,
-            ipv6_gateway: None,
-        }),
+        host: CUSTOM_TUN_REMOTE_REAL_ADDR.to_string(),
+        config: ConnectionConfig::Wireguard(local_wg_relay_config()),
     });
 
     update_relay_settings(mullvad_client, relay_settings)
@@ -661,13 +874,24 @@ fn spoof_packets(
     });
     tokio::spawn(async move {
         log::debug!("sending to {}/udp from {}", dest, bind_addr);
-        let _ = rpc2.send_udp(interface, bind_addr, dest).await;
+        let query = DnsQuestion {
+            name: LEAK_TEST_HOSTNAME.to_owned(),
+            record_type: 1,
+        }
+        .to_query(0);
+        let _ = rpc2
+            .send_udp_payload(interface, bind_addr, dest, query)
+            .await;
     });
 }
 
 type ShouldContinue = bool;
 
+/// Counts the spoofed DNS packets sent by `spoof_packets`. UDP packets are only counted if they
+/// query `hostname`. The TCP probes are never answered, so no query is sent over TCP, and TCP
+/// segments are counted by their port only.
 struct DnsPacketsFound {
+    hostname: String,
     tcp_count: usize,
     udp_count: usize,
     min_tcp_count: usize,
@@ -675,8 +899,9 @@ struct DnsPacketsFound {
 }
 
 impl DnsPacketsFound {
-    fn new(min_udp_count: usize, min_tcp_count: usize) -> Self {
+    fn new(hostname: &str, min_udp_count: usize, min_tcp_count: usize) -> Self {
         Self {
+            hostname: hostname.to_owned(),
             tcp_count: 0,
             udp_count: 0,
             min_tcp_count,
@@ -688,8 +913,24 @@ impl DnsPacketsFound {
         if pkt.destination.port() != 53 && pkt.source.port() != 53 {
             return true;
         }
+        if let Some(question) = &pkt.dns_question {
+            log::debug!(
+                "Observed DNS query for {} (type {})",
+                question.name,
+                question.record_type
+            );
+        }
         match pkt.protocol {
-            IpHeaderProtocols::Udp => self.udp_count += 1,
+            IpHeaderProtocols::Udp => {
+                let queries_hostname = matches!(
+                    &pkt.dns_question,
+                    Some(question) if question.name == self.hostname
+                );
+                if !queries_hostname {
+                    return true;
+                }
+                self.udp_count += 1;
+            }
             IpHeaderProtocols::Tcp => self.tcp_count += 1,
             _ => return true,
         }
@@ -1,22 +1,41 @@
 use super::{config::TEST_CONFIG, Error, PING_TIMEOUT, WAIT_FOR_TUNNEL_STATE_TIMEOUT};
+use crate::mullvad_daemon::RpcClientProvider;
 use crate::network_monitor::{start_packet_monitor, MonitorOptions};
+use crate::vm::network::{
+    CUSTOM_TUN_GATEWAY, CUSTOM_TUN_LOCAL_PRIVKEY, CUSTOM_TUN_LOCAL_TUN_ADDR,
+    CUSTOM_TUN_LOCAL_TUN_ADDR_V6, CUSTOM_TUN_REMOTE_PUBKEY, CUSTOM_TUN_REMOTE_REAL_ADDR,
+    CUSTOM_TUN_REMOTE_REAL_PORT, CUSTOM_TUN_REMOTE_TUN_ADDR_V6,
+};
 use futures::StreamExt;
 use mullvad_management_interface::{types, ManagementServiceClient};
 use mullvad_types::{
     relay_constraints::{
         Constraint, GeographicLocationConstraint, LocationConstraint, OpenVpnConstraints,
-        RelayConstraintsUpdate, RelaySettingsUpdate, WireguardConstraints,
+        RelayConstraintsUpdate, RelaySettingsUpdate, TransportPort, WireguardConstraints,
     },
     states::TunnelState,
 };
+use once_cell::sync::Lazy;
 use pnet_packet::ip::IpNextHeaderProtocols;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use std::{
+    collections::HashSet,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::Path,
+    sync::Mutex,
     time::Duration,
 };
-use talpid_types::net::wireguard::{PeerConfig, PrivateKey, TunnelConfig};
-use test_rpc::{package::Package, AmIMullvad, Interface, ServiceClient};
+use talpid_types::{
+    net::{
+        wireguard::{PeerConfig, PrivateKey, PublicKey, TunnelConfig},
+        TunnelType,
+    },
+    tunnel::ErrorStateCause,
+};
+use test_rpc::{
+    logging::LogLevel, package::Package, AddressFamily, AmIMullvad, GeoIpOptions, Interface,
+    PingOptions, PingSummary, ServiceClient,
+};
 use tokio::time::timeout;
 
 #[macro_export]
@@ -27,75 +46,51 @@ macro_rules! assert_tunnel_state {
     }};
 }
 
-/// Return all possible API endpoints. Note that this includes all bridge IPs. Ideally,
-/// we'd keep track of the current API IP, not exonerate all bridges from being considered
-/// leaky.
-#[macro_export]
-macro_rules! get_possible_api_endpoints {
-    ($mullvad_client:expr) => {{
-        use std::net::ToSocketAddrs;
-
-        let mut api_endpoints = vec![
-            "api.devmole.eu:0"
-                .to_socket_addrs()
-                .expect("failed to resolve api.devmole.eu")
-                .next()
-                .unwrap()
-                .ip(),
-            "api.stagemole.eu:0"
-                .to_socket_addrs()
-                .expect("failed to resolve api.stagemole.eu")
-                .next()
-                .unwrap()
-                .ip(),
-            "api.mullvad.net:0"
-                .to_socket_addrs()
-                .expect("failed to resolve api.mullvad.net")
-                .next()
-                .unwrap()
-                .ip(),
-            IpAddr::V4(Ipv4Addr::new(45, 83, 223, 196)),
-        ];
-
-        let relay_list = $mullvad_client
-            .get_relay_locations(())
-            .await
-            .map_err(|error| Error::DaemonError(format!("Failed to obtain relay list: {}", error)))?
-            .into_inner();
-
-        api_endpoints.extend(
-            relay_list
-                .countries
-                .into_iter()
-                .flat_map(|country| country.cities)
-                .filter_map(|mut city| {
-                    city.relays.retain(|relay| {
-                        relay.active
-                            && relay.endpoint_type == (types::relay::RelayType::Bridge as i32)
-                    });
-                    if !city.relays.is_empty() {
-                        Some(city)
-                    } else {
-                        None
-                    }
-                })
-                .flat_map(|city| {
-                    city.relays
-                        .into_iter()
-                        .map(|relay| IpAddr::V4(relay.ipv4_addr_in.parse().expect("invalid IP")))
-                }),
-        );
-
-        Ok::<Vec<IpAddr>, Error>(api_endpoints)
-    }};
-}
-
 pub fn get_package_desc(name: &str) -> Result<Package, Error> {
     Ok(Package {
         path: Path::new(&TEST_CONFIG.artifacts_dir).join(name),
+        streamed: false,
     })
 }
 
+/// Install the app package at `host_path`, such as `TEST_CONFIG.current_app_path`. The package is
+/// sent from the host if `TEST_CONFIG.stream_packages` is set. Otherwise, the copy with the same
+/// name in `artifacts_dir` is installed.
+pub async fn install_app(rpc: &ServiceClient, host_path: &Path) -> Result<(), Error> {
+    if TEST_CONFIG.stream_packages {
+        rpc.install_app_from_host(host_path).await?;
+        return Ok(());
+    }
+    let name = host_path
+        .file_name()
+        .ok_or_else(|| Error::Other(format!("Invalid package path: {}", host_path.display())))?
+        .to_string_lossy();
+    rpc.install_app(get_package_desc(&name)?).await?;
+    Ok(())
+}
+
+/// Return the app version embedded in a package filename, such as `2023.4-beta1` for
+/// `MullvadVPN-2023.4~beta1_amd64.deb`.
+pub fn get_package_version(name: &str) -> Option<String> {
+    let version = name.strip_prefix("MullvadVPN-")?;
+    let version = version.split('_').next()?;
+    let version = [".exe", ".pkg", ".deb", ".rpm"]
+        .iter()
+        .fold(version, |version, extension| {
+            version.strip_suffix(extension).unwrap_or(version)
+        });
+    // Linux packages use '~' to separate the suffix
+    Some(version.replace('~', "-"))
+}
+
+/// Return the address family of `ip`.
+pub fn address_family(ip: IpAddr) -> AddressFamily {
+    match ip {
+        IpAddr::V4(_) => AddressFamily::Ipv4,
+        IpAddr::V6(_) => AddressFamily::Ipv6,
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ProbeResult {
     tcp: usize,
@@ -123,19 +118,25 @@ pub async fn send_guest_probes(
     interface: Option<Interface>,
     destination: SocketAddr,
 ) -> Result<ProbeResult, Error> {
+    // ICMP has no ports, so only the IP is compared for it
     let pktmon = start_packet_monitor(
-        move |packet| packet.destination.ip() == destination.ip(),
+        move |packet| {
+            packet.destination.ip() == destination.ip()
+                && (packet.destination.port() == destination.port()
+                    || packet.destination.port() == 0)
+        },
         MonitorOptions {
             direction: Some(crate::network_monitor::Direction::In),
             timeout: Some(Duration::from_secs(3)),
+            bpf_filter: Some(format!("dst host {}", destination.ip())),
             ..Default::default()
         },
     )
-    .await;
+    .await?;
 
     let bind_addr = if let Some(interface) = interface {
         SocketAddr::new(
-            rpc.get_interface_ip(interface)
+            rpc.get_interface_ip(interface, address_family(destination.ip()))
                 .await
                 .expect("failed to obtain interface IP"),
             0,
@@ -171,7 +172,7 @@ pub async fn send_guest_probes(
             IpNextHeaderProtocols::Udp => {
                 result.udp = result.udp.saturating_add(1);
             }
-            IpNextHeaderProtocols::Icmp => {
+            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
                 result.icmp = result.icmp.saturating_add(1);
             }
             _ => (),
@@ -181,18 +182,265 @@ pub async fn send_guest_probes(
     Ok(result)
 }
 
+/// Sends probes to several destinations concurrently, and compares the observed packets to
+/// whether each destination is expected to be reachable. Reachable destinations must receive
+/// TCP, UDP, and ICMP probes, while blocked destinations must not receive any.
+///
+/// Packets are matched to a destination by IP address, since ICMP has no port. Thus, every
+/// destination must have a distinct IP address.
+///
+/// ```ignore
+/// LeakTest::new(Some(Interface::NonTunnel))
+///     .blocked("1.1.1.1:1337".parse().unwrap())
+///     .reachable(lan_destination)
+///     .run(&rpc)
+///     .await?
+///     .into_result()?;
+/// ```
+pub struct LeakTest {
+    interface: Option<Interface>,
+    destinations: Vec<(SocketAddr, bool)>,
+}
+
+impl LeakTest {
+    /// Create a leak test that sends probes from `interface`, or from any interface if it is
+    /// `None`.
+    pub fn new(interface: Option<Interface>) -> Self {
+        Self {
+            interface,
+            destinations: vec![],
+        }
+    }
+
+    /// Expect all probes to `destination` to be observed.
+    pub fn reachable(mut self, destination: SocketAddr) -> Self {
+        self.destinations.push((destination, true));
+        self
+    }
+
+    /// Expect no probes to `destination` to be observed.
+    pub fn blocked(mut self, destination: SocketAddr) -> Self {
+        self.destinations.push((destination, false));
+        self
+    }
+
+    /// Send the probes and return the destinations and protocols that did not behave as
+    /// expected.
+    pub async fn run(self, rpc: &ServiceClient) -> Result<LeakReport, Error> {
+        let mut addresses = HashSet::new();
+        for (destination, _) in &self.destinations {
+            if !addresses.insert(destination.ip()) {
+                return Err(Error::Other(format!(
+                    "leak test destinations must have distinct IPs: {}",
+                    destination.ip()
+                )));
+            }
+        }
+
+        let interface = self.interface;
+        let results = futures::future::try_join_all(
+            self.destinations
+                .iter()
+                .map(|(destination, _)| send_guest_probes(rpc.clone(), interface, *destination)),
+        )
+        .await?;
+
+        let mut failures = vec![];
+        for ((destination, reachable), result) in self.destinations.into_iter().zip(results) {
+            for (protocol, count) in [
+                ("TCP", result.tcp),
+                ("UDP", result.udp),
+                ("ICMP", result.icmp),
+            ] {
+                if reachable == (count == 0) {
+                    failures.push(ProbeFailure {
+                        destination,
+                        protocol,
+                        reachable,
+                        count,
+                    });
+                }
+            }
+        }
+
+        Ok(LeakReport { failures })
+    }
+}
+
+/// Probes that did not behave as expected during a [`LeakTest`].
+#[derive(Debug)]
+pub struct LeakReport {
+    pub failures: Vec<ProbeFailure>,
+}
+
+#[derive(Debug)]
+pub struct ProbeFailure {
+    pub destination: SocketAddr,
+    pub protocol: &'static str,
+    /// Whether the destination was expected to be reachable
+    pub reachable: bool,
+    /// Number of observed packets
+    pub count: usize,
+}
+
+impl LeakReport {
+    /// Return an error describing all failed probes, if there are any.
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.failures.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Other(format!("leak test failed:\n{self}")))
+    }
+}
+
+impl std::fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for failure in &self.failures {
+            if failure.reachable {
+                writeln!(
+                    f,
+                    "{} to {} was blocked, but should be reachable",
+                    failure.protocol, failure.destination
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "{} to {} leaked ({} packets), but should be blocked",
+                    failure.protocol, failure.destination, failure.count
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub async fn ping_with_timeout(
     rpc: &ServiceClient,
     dest: IpAddr,
     interface: Option<Interface>,
 ) -> Result<(), Error> {
-    timeout(PING_TIMEOUT, rpc.send_ping(interface, dest))
+    ping_sized_with_timeout(rpc, dest, interface, None).await
+}
+
+/// Like `ping_with_timeout`, but send an echo request with a payload of `size` bytes.
+pub async fn ping_sized_with_timeout(
+    rpc: &ServiceClient,
+    dest: IpAddr,
+    interface: Option<Interface>,
+    size: Option<usize>,
+) -> Result<(), Error> {
+    let options = PingOptions {
+        size,
+        ..Default::default()
+    };
+    let summary = ping_with_options(rpc, dest, interface, options).await?;
+    if summary.received == 0 {
+        return Err(Error::Rpc(test_rpc::Error::Ping));
+    }
+    Ok(())
+}
+
+/// Send echo requests as specified by `options`, and return the statistics. This does not fail
+/// due to packet loss.
+pub async fn ping_with_options(
+    rpc: &ServiceClient,
+    dest: IpAddr,
+    interface: Option<Interface>,
+    options: PingOptions,
+) -> Result<PingSummary, Error> {
+    let max_duration = PING_TIMEOUT + options.interval * options.count.saturating_sub(1);
+    timeout(max_duration, rpc.send_ping(interface, dest, options))
         .await
         .map_err(|_| Error::PingTimeout)?
         .map_err(Error::Rpc)
 }
 
+/// Fail if any errors are found in the current daemon log, `daemon.log`. Warnings are logged but
+/// do not cause a failure. The log is rotated whenever the daemon restarts, e.g. when it is
+/// cleaned up after a test, so this mostly covers messages logged by the current test.
+pub async fn assert_no_daemon_errors(rpc: &ServiceClient) -> Result<(), Error> {
+    let log_files = rpc
+        .get_mullvad_app_logs(Some(LogLevel::Warn))
+        .await?
+        .log_files
+        .map_err(|error| Error::Other(format!("Failed to get daemon logs: {error}")))?;
+
+    let mut errors = vec![];
+    for log_file in log_files {
+        let log_file =
+            log_file.map_err(|error| Error::Other(format!("Failed to get daemon log: {error}")))?;
+        if log_file.name.file_name() != Some(std::ffi::OsStr::new("daemon.log")) {
+            continue;
+        }
+        for line in log_file.lines() {
+            match line.level {
+                LogLevel::Error => errors.push(line.to_string()),
+                _ => log::warn!("Daemon logged a warning: {line}"),
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "The daemon logged errors:\n{}",
+            errors.join("\n")
+        )))
+    }
+}
+
+/// Public IP that is pinged through the tunnel by `assert_tunnel_functional`.
+const TUNNEL_PING_DEST: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+
+/// Log the path that traffic to a public IP takes through the tunnel. This is meant for debugging
+/// failed tests, so errors are only logged.
+pub async fn log_tunnel_path(rpc: &ServiceClient) {
+    match rpc
+        .traceroute(Some(Interface::Tunnel), TUNNEL_PING_DEST)
+        .await
+    {
+        Ok(hops) => {
+            log::info!("Path to {TUNNEL_PING_DEST}:");
+            for hop in hops {
+                log::info!("{hop}");
+            }
+        }
+        Err(error) => log::warn!("Failed to trace path to {TUNNEL_PING_DEST}: {error}"),
+    }
+}
+
+/// Verify that the tunnel actually carries traffic, as opposed to the daemon merely reporting that
+/// it is connected. This pings a public IP on the tunnel interface and checks that the exit IP
+/// belongs to Mullvad.
+pub async fn assert_tunnel_functional(rpc: &ServiceClient) -> Result<(), Error> {
+    ping_with_timeout(rpc, TUNNEL_PING_DEST, Some(Interface::Tunnel))
+        .await
+        .map_err(|error| {
+            Error::TunnelNotFunctional(format!("failed to ping {TUNNEL_PING_DEST}: {error}"))
+        })?;
+
+    let geoip_lookup = geoip_lookup_with_retries(rpc)
+        .await
+        .map_err(|error| Error::TunnelNotFunctional(format!("geoip lookup failed: {error}")))?;
+    if !geoip_lookup.mullvad_exit_ip {
+        return Err(Error::TunnelNotFunctional(
+            "exit IP is not from Mullvad".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 pub async fn connect_and_wait(mullvad_client: &mut ManagementServiceClient) -> Result<(), Error> {
+    connect_and_wait_with_timeout(mullvad_client, WAIT_FOR_TUNNEL_STATE_TIMEOUT).await
+}
+
+/// Like `connect_and_wait`, but wait for at most `timeout` for the tunnel to connect.
+pub async fn connect_and_wait_with_timeout(
+    mullvad_client: &mut ManagementServiceClient,
+    timeout: Duration,
+) -> Result<(), Error> {
     log::info!("Connecting");
 
     mullvad_client
@@ -200,7 +448,7 @@ pub async fn connect_and_wait(mullvad_client: &mut ManagementServiceClient) -> R
         .await
         .map_err(|error| Error::DaemonError(format!("failed to begin connecting: {}", error)))?;
 
-    let new_state = wait_for_tunnel_state(mullvad_client.clone(), |state| {
+    let new_state = wait_for_tunnel_state_with_timeout(mullvad_client.clone(), timeout, |state| {
         matches!(
             state,
             TunnelState::Connected { .. } | TunnelState::Error(..)
@@ -208,8 +456,8 @@ pub async fn connect_and_wait(mullvad_client: &mut ManagementServiceClient) -> R
     })
     .await?;
 
-    if matches!(new_state, TunnelState::Error(..)) {
-        return Err(Error::DaemonError("daemon entered error state".to_string()));
+    if let TunnelState::Error(error_state) = new_state {
+        return Err(Error::ErrorState(error_state.cause().clone()));
     }
 
     log::info!("Connected");
@@ -236,8 +484,78 @@ pub async fn disconnect_and_wait(
     Ok(())
 }
 
+/// Wait until the daemon responds to requests on the management interface, and return a client
+/// connected to it. The daemon socket may exist before the daemon serves requests, e.g. right
+/// after it has been installed or restarted, so checking the service status is not sufficient.
+pub async fn wait_for_daemon_ready(
+    rpc_provider: &RpcClientProvider,
+) -> Result<ManagementServiceClient, Error> {
+    wait_for_management_interface(|| async move {
+        let mut mullvad_client = rpc_provider.new_client().await;
+        mullvad_client
+            .get_tunnel_state(())
+            .await
+            .map(|_| mullvad_client)
+    })
+    .await
+}
+
+/// Like `wait_for_daemon_ready`, but using the previous management interface. This must be used
+/// while the previous app version is installed, since its daemon does not necessarily understand
+/// the new interface.
+pub async fn wait_for_previous_daemon_ready(
+    rpc_provider: &RpcClientProvider,
+) -> Result<old_mullvad_management_interface::ManagementServiceClient, Error> {
+    wait_for_management_interface(|| async move {
+        let mut mullvad_client = rpc_provider.old_client().await;
+        mullvad_client
+            .get_tunnel_state(())
+            .await
+            .map(|_| mullvad_client)
+    })
+    .await
+}
+
+/// Call `try_request` until it succeeds, and return the client that it produced.
+async fn wait_for_management_interface<Client, E, F, Fut>(
+    mut try_request: F,
+) -> Result<Client, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<Client, E>>,
+    E: std::fmt::Display,
+{
+    const DAEMON_READY_TIMEOUT: Duration = Duration::from_secs(30);
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    let wait_for_ready = async {
+        loop {
+            match try_request().await {
+                Ok(mullvad_client) => return mullvad_client,
+                Err(error) => log::debug!("Daemon is not ready yet: {error}"),
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    };
+
+    timeout(DAEMON_READY_TIMEOUT, wait_for_ready)
+        .await
+        .map_err(|_| {
+            Error::DaemonError("Timed out waiting for the management interface".to_owned())
+        })
+}
+
 pub async fn wait_for_tunnel_state(
+    rpc: mullvad_management_interface::ManagementServiceClient,
+    accept_state_fn: impl Fn(&mullvad_types::states::TunnelState) -> bool,
+) -> Result<mullvad_types::states::TunnelState, Error> {
+    wait_for_tunnel_state_with_timeout(rpc, WAIT_FOR_TUNNEL_STATE_TIMEOUT, accept_state_fn).await
+}
+
+/// Like `wait_for_tunnel_state`, but wait for at most `timeout` for a new tunnel state.
+pub async fn wait_for_tunnel_state_with_timeout(
     mut rpc: mullvad_management_interface::ManagementServiceClient,
+    timeout: Duration,
     accept_state_fn: impl Fn(&mullvad_types::states::TunnelState) -> bool,
 ) -> Result<mullvad_types::states::TunnelState, Error> {
     let events = rpc
@@ -258,15 +576,49 @@ pub async fn wait_for_tunnel_state(
         return Ok(state);
     }
 
-    find_next_tunnel_state(events.into_inner(), accept_state_fn).await
+    find_next_tunnel_state_with_timeout(events.into_inner(), timeout, accept_state_fn).await
+}
+
+/// Wait for the daemon to enter the error state with a cause that matches `cause_matcher`, and
+/// return the cause. If this does not happen in time, the last observed cause, if any, is
+/// returned as `Error::UnexpectedErrorState`.
+pub async fn wait_for_error_state(
+    rpc: mullvad_management_interface::ManagementServiceClient,
+    cause_matcher: impl Fn(&ErrorStateCause) -> bool,
+) -> Result<ErrorStateCause, Error> {
+    let last_cause = Mutex::new(None);
+    let result = wait_for_tunnel_state(rpc, |state| match state {
+        TunnelState::Error(error_state) => {
+            *last_cause.lock().unwrap() = Some(error_state.cause().clone());
+            cause_matcher(error_state.cause())
+        }
+        _ => false,
+    })
+    .await;
+
+    match (result, last_cause.into_inner().unwrap()) {
+        (Ok(TunnelState::Error(error_state)), _) => Ok(error_state.cause().clone()),
+        (Ok(_), _) => unreachable!("only the error state is accepted"),
+        (Err(_error), Some(cause)) => Err(Error::UnexpectedErrorState(cause)),
+        (Err(error), None) => Err(error),
+    }
 }
 
 pub async fn find_next_tunnel_state(
     stream: impl futures::Stream<Item = Result<types::DaemonEvent, tonic::Status>> + Unpin,
     accept_state_fn: impl Fn(&mullvad_types::states::TunnelState) -> bool,
+) -> Result<mullvad_types::states::TunnelState, Error> {
+    find_next_tunnel_state_with_timeout(stream, WAIT_FOR_TUNNEL_STATE_TIMEOUT, accept_state_fn)
+        .await
+}
+
+async fn find_next_tunnel_state_with_timeout(
+    stream: impl futures::Stream<Item = Result<types::DaemonEvent, tonic::Status>> + Unpin,
+    timeout: Duration,
+    accept_state_fn: impl Fn(&mullvad_types::states::TunnelState) -> bool,
 ) -> Result<mullvad_types::states::TunnelState, Error> {
     tokio::time::timeout(
-        WAIT_FOR_TUNNEL_STATE_TIMEOUT,
+        timeout,
         find_next_tunnel_state_inner(stream, accept_state_fn),
     )
     .await
@@ -304,6 +656,15 @@ async fn find_next_tunnel_state_inner(
 }
 
 pub async fn geoip_lookup_with_retries(rpc: &ServiceClient) -> Result<AmIMullvad, Error> {
+    geoip_lookup_with_options(rpc, GeoIpOptions::default()).await
+}
+
+/// Like `geoip_lookup_with_retries`, but look up the location using the given host and/or
+/// address family.
+pub async fn geoip_lookup_with_options(
+    rpc: &ServiceClient,
+    options: GeoIpOptions,
+) -> Result<AmIMullvad, Error> {
     const MAX_ATTEMPTS: usize = 5;
     const BEFORE_RETRY_DELAY: Duration = Duration::from_secs(2);
 
@@ -311,7 +672,7 @@ pub async fn geoip_lookup_with_retries(rpc: &ServiceClient) -> Result<AmIMullvad
 
     loop {
         let result = rpc
-            .geoip_lookup(TEST_CONFIG.mullvad_host.to_owned())
+            .geoip_lookup(TEST_CONFIG.mullvad_host.to_owned(), options.clone())
             .await
             .map_err(Error::GeoipError);
 
@@ -324,6 +685,50 @@ pub async fn geoip_lookup_with_retries(rpc: &ServiceClient) -> Result<AmIMullvad
     }
 }
 
+/// Return all IPs that the daemon may contact while it is being upgraded: the API for
+/// `mullvad_host`, which the daemon is configured to use, the production API, which is the default
+/// before the configuration has been applied, and all bridges.
+///
+/// Ideally, we'd know the current API IP rather than exonerating all bridges from being considered
+/// leaky.
+pub async fn allowed_endpoints_during_upgrade(
+    mullvad_client: &mut old_mullvad_management_interface::ManagementServiceClient,
+) -> Result<Vec<IpAddr>, Error> {
+    use old_mullvad_management_interface::types::relay::RelayType;
+
+    const DEFAULT_API_HOST: &str = "api.mullvad.net";
+
+    let mut endpoints = vec![];
+
+    for api_host in [
+        format!("api.{}", TEST_CONFIG.mullvad_host),
+        DEFAULT_API_HOST.to_owned(),
+    ] {
+        let addrs = tokio::net::lookup_host((api_host.as_str(), 0))
+            .await
+            .map_err(|error| Error::Other(format!("Failed to resolve {api_host}: {error}")))?;
+        endpoints.extend(addrs.map(|addr| addr.ip()));
+    }
+
+    let relay_list = mullvad_client
+        .get_relay_locations(())
+        .await
+        .map_err(|error| Error::DaemonError(format!("Failed to obtain relay list: {}", error)))?
+        .into_inner();
+
+    endpoints.extend(
+        relay_list
+            .countries
+            .into_iter()
+            .flat_map(|country| country.cities)
+            .flat_map(|city| city.relays)
+            .filter(|relay| relay.active && relay.endpoint_type == RelayType::Bridge as i32)
+            .map(|relay| IpAddr::V4(relay.ipv4_addr_in.parse().expect("invalid IP"))),
+    );
+
+    Ok(endpoints)
+}
+
 pub struct AbortOnDrop<T>(pub tokio::task::JoinHandle<T>);
 
 impl<T> Drop for AbortOnDrop<T> {
@@ -384,6 +789,116 @@ pub async fn update_relay_settings(
     Ok(())
 }
 
+/// Protocols and features to connect with. See `set_connection_profile`.
+#[derive(Debug, Clone)]
+pub struct ConnectionProfile {
+    pub tunnel_type: TunnelType,
+    /// Relay port to use. The transport protocol is only used for OpenVPN, since WireGuard is
+    /// always UDP.
+    pub port: Constraint<TransportPort>,
+    /// Use udp2tcp obfuscation. Only applies to WireGuard.
+    pub udp2tcp: bool,
+    /// Use WireGuard multihop.
+    pub multihop: bool,
+    /// Use a quantum-resistant tunnel. Only applies to WireGuard.
+    pub quantum_resistant: bool,
+}
+
+impl ConnectionProfile {
+    /// Any WireGuard relay, without any additional features enabled.
+    pub const fn wireguard() -> Self {
+        Self {
+            tunnel_type: TunnelType::Wireguard,
+            port: Constraint::Any,
+            udp2tcp: false,
+            multihop: false,
+            quantum_resistant: false,
+        }
+    }
+
+    /// Any OpenVPN relay.
+    pub const fn openvpn() -> Self {
+        Self {
+            tunnel_type: TunnelType::OpenVpn,
+            ..Self::wireguard()
+        }
+    }
+}
+
+/// Update the relay, obfuscation, and quantum-resistant tunnel settings to match `profile`.
+/// Relays in Sweden are used.
+pub async fn set_connection_profile(
+    mullvad_client: &mut ManagementServiceClient,
+    profile: ConnectionProfile,
+) -> Result<(), Error> {
+    let sweden = || {
+        Constraint::Only(LocationConstraint::Location(
+            GeographicLocationConstraint::Country("se".to_string()),
+        ))
+    };
+
+    let (openvpn_constraints, wireguard_constraints) = match profile.tunnel_type {
+        TunnelType::OpenVpn => (
+            OpenVpnConstraints { port: profile.port },
+            WireguardConstraints::default(),
+        ),
+        TunnelType::Wireguard => (
+            OpenVpnConstraints::default(),
+            WireguardConstraints {
+                port: match profile.port {
+                    Constraint::Only(transport_port) => transport_port.port,
+                    Constraint::Any => Constraint::Any,
+                },
+                use_multihop: profile.multihop,
+                entry_location: if profile.multihop {
+                    sweden()
+                } else {
+                    Constraint::Any
+                },
+                ..Default::default()
+            },
+        ),
+    };
+
+    let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
+        location: Some(sweden()),
+        tunnel_protocol: Some(Constraint::Only(profile.tunnel_type)),
+        openvpn_constraints: Some(openvpn_constraints),
+        wireguard_constraints: Some(wireguard_constraints),
+        ..Default::default()
+    });
+
+    update_relay_settings(mullvad_client, relay_settings).await?;
+
+    let selected_obfuscation = if profile.udp2tcp {
+        types::obfuscation_settings::SelectedObfuscation::Udp2tcp
+    } else {
+        types::obfuscation_settings::SelectedObfuscation::Off
+    };
+    mullvad_client
+        .set_obfuscation_settings(types::ObfuscationSettings {
+            selected_obfuscation: i32::from(selected_obfuscation),
+            udp2tcp: Some(types::Udp2TcpObfuscationSettings { port: 0 }),
+        })
+        .await
+        .map_err(|error| Error::DaemonError(format!("Failed to set obfuscation: {}", error)))?;
+
+    let quantum_resistant = if profile.quantum_resistant {
+        types::quantum_resistant_state::State::On
+    } else {
+        types::quantum_resistant_state::State::Off
+    };
+    mullvad_client
+        .set_quantum_resistant_tunnel(types::QuantumResistantState {
+            state: i32::from(quantum_resistant),
+        })
+        .await
+        .map(|_| ())
+        .map_err(|error| {
+            Error::DaemonError(format!("Failed to set quantum-resistant tunnel: {}", error))
+        })
+}
+
 pub async fn get_tunnel_state(mullvad_client: &mut ManagementServiceClient) -> TunnelState {
     let state = mullvad_client
         .get_tunnel_state(())
@@ -415,6 +930,9 @@ pub async fn ensure_updated_relay_list(mullvad_client: &mut ManagementServiceCli
         }
     };
     let _ = tokio::time::timeout(std::time::Duration::from_secs(3), wait_for_relay_update).await;
+    // Invalidate only once the new list is available, or a concurrent lookup could cache the old
+    // one again
+    RELAY_LIST_CACHE.invalidate();
 }
 
 pub fn unreachable_wireguard_tunnel() -> talpid_types::net::wireguard::ConnectionConfig {
@@ -440,6 +958,36 @@ pub fn unreachable_wireguard_tunnel() -> talpid_types::net::wireguard::Connectio
     }
 }
 
+/// Return a WireGuard config for the relay that is set up in scripts/setup-network.sh
+/// See that script for details.
+pub fn local_wg_relay_config() -> talpid_types::net::wireguard::ConnectionConfig {
+    let peer_addr: SocketAddr = SocketAddr::new(
+        IpAddr::V4(CUSTOM_TUN_REMOTE_REAL_ADDR),
+        CUSTOM_TUN_REMOTE_REAL_PORT,
+    );
+
+    talpid_types::net::wireguard::ConnectionConfig {
+        tunnel: TunnelConfig {
+            addresses: vec![
+                IpAddr::V4(CUSTOM_TUN_LOCAL_TUN_ADDR),
+                IpAddr::V6(CUSTOM_TUN_LOCAL_TUN_ADDR_V6),
+            ],
+            private_key: PrivateKey::from(CUSTOM_TUN_LOCAL_PRIVKEY),
+        },
+        peer: PeerConfig {
+            public_key: PublicKey::from(CUSTOM_TUN_REMOTE_PUBKEY),
+            allowed_ips: vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()],
+            endpoint: peer_addr,
+            psk: None,
+        },
+        ipv4_gateway: CUSTOM_TUN_GATEWAY,
+        exit_peer: None,
+        #[cfg(target_os = "linux")]
+        fwmark: None,
+        ipv6_gateway: Some(CUSTOM_TUN_REMOTE_TUN_ADDR_V6),
+    }
+}
+
 /// Randomly select an entry and exit node from the daemon's relay list.
 /// The exit node is distinct from the entry node.
 ///
@@ -453,15 +1001,64 @@ where
     Filter: Fn(&types::Relay) -> bool,
 {
     use itertools::Itertools;
-    // Pluck the first 2 relays and return them as a tuple.
+    // Pluck 2 random relays and return them as a tuple.
     // This will fail if there are less than 2 relays in the relay list.
-    filter_relays(mullvad_client, criteria)
-        .await?
-        .into_iter()
-        .next_tuple()
+    let relays = filter_relays(mullvad_client, criteria).await?;
+    let selected: Vec<_> = relays
+        .choose_multiple(&mut *relay_rng(), 2)
+        .cloned()
+        .collect();
+    selected.into_iter().next_tuple().ok_or(Error::Other(
+        "failed to randomly select two relays from daemon's relay list".to_string(),
+    ))
+}
+
+/// Randomly select an active relay from the daemon's relay list, and return it along with its
+/// IPv4 address.
+///
+/// * `mullvad_client` - An interface to the Mullvad daemon.
+/// * `critera` - A function used to determine which relays to include in random selection, e.g.
+///   by tunnel type or location.
+pub async fn select_relay<Filter>(
+    mullvad_client: &mut ManagementServiceClient,
+    criteria: Filter,
+) -> Result<(types::Relay, Ipv4Addr), Error>
+where
+    Filter: Fn(&types::Relay) -> bool,
+{
+    let relays = filter_relays(mullvad_client, |relay| relay.active && criteria(relay)).await?;
+    let relay = relays
+        .choose(&mut *relay_rng())
+        .cloned()
         .ok_or(Error::Other(
-            "failed to randomly select two relays from daemon's relay list".to_string(),
+            "found no matching relays in the daemon's relay list".to_string(),
+        ))?;
+    let ipv4_addr = relay.ipv4_addr_in.parse().map_err(|_error| {
+        Error::Other(format!(
+            "Invalid IP address for relay {}: {}",
+            relay.hostname, relay.ipv4_addr_in
         ))
+    })?;
+    Ok((relay, ipv4_addr))
+}
+
+/// Environment variable containing the seed used for random relay selection. If it is not set, a
+/// random seed is used. The seed is logged either way, so that a selection can be reproduced.
+const RELAY_SEED_ENV_VAR: &str = "TEST_RELAY_SEED";
+
+/// Return the random number generator used for selecting relays.
+fn relay_rng() -> std::sync::MutexGuard<'static, StdRng> {
+    static RELAY_RNG: Lazy<Mutex<StdRng>> = Lazy::new(|| {
+        let seed = match std::env::var(RELAY_SEED_ENV_VAR) {
+            Ok(seed) => seed
+                .parse()
+                .unwrap_or_else(|_| panic!("{RELAY_SEED_ENV_VAR} must be an integer")),
+            Err(_) => rand::random(),
+        };
+        log::info!("Relay selection seed: {seed} (set {RELAY_SEED_ENV_VAR} to reproduce)");
+        Mutex::new(StdRng::seed_from_u64(seed))
+    });
+    RELAY_RNG.lock().unwrap()
 }
 
 /// Return a filtered version of the daemon's relay list.
@@ -475,11 +1072,7 @@ pub async fn filter_relays<Filter>(
 where
     Filter: Fn(&types::Relay) -> bool,
 {
-    let relaylist = mullvad_client
-        .get_relay_locations(())
-        .await
-        .map_err(|error| Error::DaemonError(format!("Failed to obtain relay list: {}", error)))?
-        .into_inner();
+    let relaylist = RELAY_LIST_CACHE.get(mullvad_client).await?;
 
     Ok(flatten_relaylist(relaylist)
         .into_iter()
@@ -487,6 +1080,42 @@ where
         .collect())
 }
 
+/// Cache of the daemon's relay list, shared by all tests. Fetching the relay list over the
+/// management interface is slow, so it is only done when the cache is empty.
+///
+/// The cache must be invalidated whenever the daemon's relay list may have changed, e.g. after
+/// updating the relay list or (re)installing the app.
+pub static RELAY_LIST_CACHE: RelayListCache = RelayListCache(Mutex::new(None));
+
+pub struct RelayListCache(Mutex<Option<types::RelayList>>);
+
+impl RelayListCache {
+    /// Return the cached relay list, or fetch it from the daemon if the cache is empty.
+    pub async fn get(
+        &self,
+        mullvad_client: &mut ManagementServiceClient,
+    ) -> Result<types::RelayList, Error> {
+        if let Some(relay_list) = self.0.lock().unwrap().as_ref() {
+            return Ok(relay_list.clone());
+        }
+
+        let relay_list = mullvad_client
+            .get_relay_locations(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to obtain relay list: {}", error)))?
+            .into_inner();
+
+        *self.0.lock().unwrap() = Some(relay_list.clone());
+
+        Ok(relay_list)
+    }
+
+    /// Clear the cache, forcing the next call to `get` to fetch the relay list from the daemon.
+    pub fn invalidate(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
 /// Dig out the [`Relay`]s contained in a [`RelayList`].
 pub fn flatten_relaylist(relays: types::RelayList) -> Vec<types::Relay> {
     relays
@@ -1,23 +1,24 @@
-use super::helpers::{get_package_desc, ping_with_timeout, AbortOnDrop};
+use super::helpers::{
+    allowed_endpoints_during_upgrade, get_package_version, install_app, ping_with_timeout,
+    wait_for_daemon_ready, wait_for_previous_daemon_ready, AbortOnDrop, RELAY_LIST_CACHE,
+};
 use super::{Error, TestContext};
-use crate::get_possible_api_endpoints;
 
 use super::config::TEST_CONFIG;
 use crate::network_monitor::{start_packet_monitor, MonitorOptions};
-use mullvad_management_interface::types;
+use mullvad_management_interface::{types, ManagementServiceClient};
 use std::collections::HashMap;
-use std::net::ToSocketAddrs;
-use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    time::Duration,
-};
+use std::{net::SocketAddr, time::Duration};
 use test_macro::test_function;
 use test_rpc::meta::Os;
-use test_rpc::{mullvad_daemon::ServiceStatus, Interface, ServiceClient};
+use test_rpc::{
+    mullvad_daemon::{ServiceStatus, SocketPermissions},
+    AddressFamily, Interface, ServiceClient,
+};
 
 /// Install the last stable version of the app and verify that it is running.
 #[test_function(priority = -200)]
-pub async fn test_install_previous_app(_: TestContext, rpc: ServiceClient) -> Result<(), Error> {
+pub async fn test_install_previous_app(ctx: TestContext, rpc: ServiceClient) -> Result<(), Error> {
     // verify that daemon is not already running
     if rpc.mullvad_daemon_get_status().await? != ServiceStatus::NotRunning {
         return Err(Error::DaemonRunning);
@@ -25,8 +26,7 @@ pub async fn test_install_previous_app(_: TestContext, rpc: ServiceClient) -> Re
 
     // install package
     log::debug!("Installing old app");
-    rpc.install_app(get_package_desc(&TEST_CONFIG.previous_app_filename)?)
-        .await?;
+    install_app(&rpc, &TEST_CONFIG.previous_app_path).await?;
 
     // verify that daemon is running
     if rpc.mullvad_daemon_get_status().await? != ServiceStatus::Running {
@@ -38,6 +38,10 @@ pub async fn test_install_previous_app(_: TestContext, rpc: ServiceClient) -> Re
     // Override env vars
     rpc.set_daemon_environment(get_app_env()).await?;
 
+    // Setting the environment restarts the daemon
+    wait_for_previous_daemon_ready(&ctx.rpc_provider).await?;
+    RELAY_LIST_CACHE.invalidate();
+
     Ok(())
 }
 
@@ -134,12 +138,12 @@ pub async fn test_upgrade_app(
     //
 
     let guest_ip = rpc
-        .get_interface_ip(Interface::NonTunnel)
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
         .await
         .expect("failed to obtain tunnel IP");
     log::debug!("Guest IP: {guest_ip}");
 
-    let api_endpoints = get_possible_api_endpoints!(&mut mullvad_client)?;
+    let api_endpoints = allowed_endpoints_during_upgrade(&mut mullvad_client).await?;
 
     log::debug!("Monitoring outgoing traffic");
 
@@ -147,9 +151,12 @@ pub async fn test_upgrade_app(
         move |packet| {
             packet.source.ip() == guest_ip && !api_endpoints.contains(&packet.destination.ip())
         },
-        MonitorOptions::default(),
+        MonitorOptions {
+            bpf_filter: Some(format!("src host {guest_ip}")),
+            ..Default::default()
+        },
     )
-    .await;
+    .await?;
 
     let ping_rpc = rpc.clone();
     let abort_on_drop = AbortOnDrop(tokio::spawn(async move {
@@ -163,17 +170,34 @@ pub async fn test_upgrade_app(
 
     // install new package
     log::debug!("Installing new app");
-    rpc.install_app(get_package_desc(&TEST_CONFIG.current_app_filename)?)
-        .await?;
-
-    // Give it some time to start
-    tokio::time::sleep(Duration::from_secs(3)).await;
+    install_app(&rpc, &TEST_CONFIG.current_app_path).await?;
 
     // verify that daemon is running
     if rpc.mullvad_daemon_get_status().await? != ServiceStatus::Running {
         return Err(Error::DaemonNotRunning);
     }
 
+    // verify that the expected version was installed
+    match get_package_version(&TEST_CONFIG.current_app_filename) {
+        Some(expected_version) => {
+            let version = rpc.mullvad_daemon_get_version().await?;
+            if version != expected_version {
+                return Err(Error::Other(format!(
+                    "Installed version {version} does not match expected version {expected_version}"
+                )));
+            }
+        }
+        None => log::warn!(
+            "Not checking installed version: no version in {}",
+            TEST_CONFIG.current_app_filename
+        ),
+    }
+
+    drop(mullvad_client);
+    let mut mullvad_client = wait_for_daemon_ready(&ctx.rpc_provider).await?;
+    // The new daemon may use a different relay list
+    RELAY_LIST_CACHE.invalidate();
+
     //
     // Check if any traffic was observed
     //
@@ -185,9 +209,6 @@ pub async fn test_upgrade_app(
         "observed unexpected packets from {guest_ip}"
     );
 
-    drop(mullvad_client);
-    let mut mullvad_client = ctx.rpc_provider.new_client().await;
-
     // check if settings were (partially) preserved
     log::info!("Sanity checking settings");
 
@@ -316,7 +337,7 @@ pub async fn test_uninstall_app(
 /// Install the app cleanly, failing if the installer doesn't succeed
 /// or if the VPN service is not running afterwards.
 #[test_function(always_run = true, must_succeed = true, priority = -160)]
-pub async fn test_install_new_app(_: TestContext, rpc: ServiceClient) -> Result<(), Error> {
+pub async fn test_install_new_app(ctx: TestContext, rpc: ServiceClient) -> Result<(), Error> {
     // verify that daemon is not already running
     if rpc.mullvad_daemon_get_status().await? != ServiceStatus::NotRunning {
         return Err(Error::DaemonRunning);
@@ -324,8 +345,7 @@ pub async fn test_install_new_app(_: TestContext, rpc: ServiceClient) -> Result<
 
     // install package
     log::debug!("Installing new app");
-    rpc.install_app(get_package_desc(&TEST_CONFIG.current_app_filename)?)
-        .await?;
+    install_app(&rpc, &TEST_CONFIG.current_app_path).await?;
 
     // verify that daemon is running
     if rpc.mullvad_daemon_get_status().await? != ServiceStatus::Running {
@@ -341,18 +361,173 @@ pub async fn test_install_new_app(_: TestContext, rpc: ServiceClient) -> Result<
     // Override env vars
     rpc.set_daemon_environment(get_app_env()).await?;
 
+    // Setting the environment restarts the daemon
+    wait_for_daemon_ready(&ctx.rpc_provider).await?;
+    RELAY_LIST_CACHE.invalidate();
+
+    Ok(())
+}
+
+/// Verify that the combined size of the app install and data directories does not exceed
+/// `max_install_footprint`. This is meant to catch accidental bloat between versions.
+#[test_function]
+pub async fn test_install_footprint(_: TestContext, rpc: ServiceClient) -> Result<(), Error> {
+    let disk_usage = rpc.get_disk_usage().await?;
+
+    for (path, size) in &disk_usage {
+        log::info!("{}: {size} bytes", path.display());
+    }
+
+    let total_size: u64 = disk_usage.values().sum();
+    log::info!("Install footprint: {total_size} bytes");
+
+    assert!(
+        total_size <= TEST_CONFIG.max_install_footprint,
+        "install footprint of {total_size} bytes exceeds limit of {} bytes",
+        TEST_CONFIG.max_install_footprint,
+    );
+
+    Ok(())
+}
+
+/// Verify that a second daemon instance refuses to start while the daemon is already running, and
+/// that the running daemon keeps working afterwards.
+///
+/// # Limitations
+///
+/// This is currently only tested on Linux.
+#[test_function(os = "linux")]
+pub async fn test_single_instance(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    /// How long to wait for the second instance to give up
+    const SECOND_INSTANCE_TIMEOUT: Duration = Duration::from_secs(30);
+    const DAEMON_PATH: &str = "/usr/bin/mullvad-daemon";
+
+    if rpc.mullvad_daemon_get_status().await? != ServiceStatus::Running {
+        return Err(Error::DaemonNotRunning);
+    }
+
+    log::info!("Launching a second daemon instance");
+
+    let result = rpc
+        .exec(
+            DAEMON_PATH,
+            std::iter::empty::<&str>(),
+            Some(SECOND_INSTANCE_TIMEOUT),
+        )
+        .await
+        .map_err(|error| match error {
+            test_rpc::Error::TestRunnerTimeout => {
+                Error::Other("second daemon instance did not exit".to_string())
+            }
+            error => Error::Rpc(error),
+        })?;
+
+    log::debug!(
+        "Second instance stderr: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    assert!(
+        !result.success(),
+        "second daemon instance exited successfully"
+    );
+
+    log::info!("Verify that the original daemon still works");
+
+    if rpc.mullvad_daemon_get_status().await? != ServiceStatus::Running {
+        return Err(Error::DaemonNotRunning);
+    }
+
+    mullvad_client
+        .get_settings(())
+        .await
+        .map_err(|error| Error::DaemonError(format!("failed to get settings: {error}")))?;
+
     Ok(())
 }
 
+/// Verify that the daemon works with custom settings and log directories, set using the
+/// `MULLVAD_SETTINGS_DIR` and `MULLVAD_LOG_DIR` environment variables. This test fails if the
+/// settings or the daemon log are not written to the custom directories, or if the daemon does
+/// not respond when using them.
+///
+/// The original daemon environment is restored, and the custom directories are removed, when
+/// the test finishes.
+///
+/// # Limitations
+///
+/// This is currently only tested on Linux.
+#[test_function(os = "linux")]
+pub async fn test_custom_data_dir(
+    ctx: TestContext,
+    rpc: ServiceClient,
+    mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const CUSTOM_DATA_DIR: &str = "/tmp/mullvad-custom-data-dir";
+
+    let settings_dir = format!("{CUSTOM_DATA_DIR}/settings");
+    let log_dir = format!("{CUSTOM_DATA_DIR}/log");
+
+    let mut env = get_app_env();
+    env.insert("MULLVAD_SETTINGS_DIR".to_string(), settings_dir.clone());
+    env.insert("MULLVAD_LOG_DIR".to_string(), log_dir.clone());
+
+    log::info!("Restarting daemon with data in {CUSTOM_DATA_DIR}");
+
+    drop(mullvad_client);
+    rpc.set_daemon_environment(env).await?;
+
+    let result = async {
+        let mut mullvad_client = wait_for_daemon_ready(&ctx.rpc_provider).await?;
+
+        // Change a setting to make sure that the settings are written
+        let allow_lan = mullvad_client
+            .get_settings(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to get settings: {error}")))?
+            .into_inner()
+            .allow_lan;
+        for allow_lan in [!allow_lan, allow_lan] {
+            mullvad_client
+                .set_allow_lan(allow_lan)
+                .await
+                .map_err(|error| Error::DaemonError(format!("Failed to set allow LAN: {error}")))?;
+        }
+
+        for path in [
+            format!("{settings_dir}/settings.json"),
+            format!("{log_dir}/daemon.log"),
+        ] {
+            let result = rpc
+                .exec("test", ["-e", path.as_str()].into_iter(), None)
+                .await?;
+            if !result.success() {
+                return Err(Error::Other(format!("{path} was not created")));
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    log::info!("Restoring daemon environment");
+
+    rpc.set_daemon_environment(get_app_env()).await?;
+    wait_for_daemon_ready(&ctx.rpc_provider).await?;
+    rpc.exec("rm", ["-rf", CUSTOM_DATA_DIR].into_iter(), None)
+        .await?;
+
+    result
+}
+
 fn get_app_env() -> HashMap<String, String> {
     let mut map = HashMap::new();
 
-    let api_host = format!("api.{}", TEST_CONFIG.mullvad_host);
-    let api_addr = format!("{api_host}:443")
-        .to_socket_addrs()
-        .expect("failed to resolve API host")
-        .next()
-        .unwrap();
+    let (api_host, api_addr) = TEST_CONFIG.api_endpoint();
 
     map.insert("MULLVAD_API_HOST".to_string(), api_host);
     map.insert("MULLVAD_API_ADDR".to_string(), api_addr.to_string());
@@ -387,3 +562,108 @@ async fn replace_openvpn_cert(rpc: &ServiceClient) -> Result<(), Error> {
     .await
     .map_err(Error::Rpc)
 }
+
+/// Verify that unprivileged users cannot take over the management interface socket. This test
+/// fails if the socket is not owned by root (or SYSTEM or Administrators on Windows), if it is
+/// not a socket, e.g. a symlink, or if unprivileged users are allowed to change its owner or
+/// permissions.
+///
+/// # Limitations
+///
+/// Unprivileged users are deliberately allowed to connect to the socket, since the CLI and GUI
+/// run as the logged in user, so that is not checked.
+#[test_function(security_critical = true)]
+pub async fn test_socket_permissions(_: TestContext, rpc: ServiceClient) -> Result<(), Error> {
+    if rpc.mullvad_daemon_get_status().await? != ServiceStatus::Running {
+        return Err(Error::DaemonNotRunning);
+    }
+
+    let socket_info = rpc.get_daemon_socket_info().await?;
+    log::debug!("Management interface socket: {socket_info:?}");
+
+    match socket_info.permissions {
+        SocketPermissions::Unix { mode, uid, .. } => {
+            const FILE_TYPE_MASK: u32 = 0o170000;
+            const SOCKET_FILE_TYPE: u32 = 0o140000;
+
+            if mode & FILE_TYPE_MASK != SOCKET_FILE_TYPE {
+                return Err(Error::Other(format!(
+                    "{} is not a socket (mode {mode:o})",
+                    socket_info.path
+                )));
+            }
+            if uid != 0 {
+                return Err(Error::Other(format!(
+                    "{} is owned by uid {uid}, not root",
+                    socket_info.path
+                )));
+            }
+        }
+        SocketPermissions::Windows {
+            security_descriptor,
+        } => check_pipe_security_descriptor(&socket_info.path, &security_descriptor)?,
+    }
+
+    Ok(())
+}
+
+/// Check that the named pipe described by `sddl` is owned by SYSTEM or Administrators and that
+/// no ACE allows unprivileged users to change its DACL or owner.
+fn check_pipe_security_descriptor(path: &str, sddl: &str) -> Result<(), Error> {
+    /// Accounts and groups that include unprivileged users
+    const UNPRIVILEGED_SIDS: [&str; 12] = [
+        "WD",
+        "AU",
+        "BU",
+        "IU",
+        "BG",
+        "AN",
+        "S-1-1-0",
+        "S-1-5-11",
+        "S-1-5-32-545",
+        "S-1-5-4",
+        "S-1-5-32-546",
+        "S-1-5-7",
+    ];
+    /// Rights that allow taking over the pipe: generic all, file all, write DAC, write owner
+    const DANGEROUS_RIGHTS: [&str; 4] = ["GA", "FA", "WD", "WO"];
+    const DANGEROUS_RIGHTS_MASK: u32 = 0x1000_0000 | 0x0004_0000 | 0x0008_0000;
+
+    let (owner, dacl) = sddl
+        .strip_prefix("O:")
+        .and_then(|sddl| sddl.split_once("D:"))
+        .ok_or_else(|| Error::Other(format!("unexpected security descriptor: {sddl}")))?;
+
+    if !["SY", "BA", "S-1-5-18", "S-1-5-32-544"].contains(&owner) {
+        return Err(Error::Other(format!("{path} is owned by {owner}")));
+    }
+
+    // ACEs have the form `(type;flags;rights;object_guid;inherit_object_guid;sid)`
+    for ace in dacl.split(['(', ')']).filter(|ace| ace.contains(';')) {
+        let fields: Vec<_> = ace.split(';').collect();
+        let (ace_type, rights, sid) = match fields.as_slice() {
+            [ace_type, _, rights, _, _, sid, ..] => (*ace_type, *rights, *sid),
+            _ => return Err(Error::Other(format!("unexpected ACE: {ace}"))),
+        };
+        if ace_type != "A" || !UNPRIVILEGED_SIDS.contains(&sid) {
+            continue;
+        }
+
+        let dangerous = match rights.strip_prefix("0x") {
+            Some(mask) => u32::from_str_radix(mask, 16)
+                .map(|mask| mask & DANGEROUS_RIGHTS_MASK != 0)
+                .map_err(|_| Error::Other(format!("unexpected access mask: {rights}")))?,
+            None => rights
+                .as_bytes()
+                .chunks(2)
+                .any(|right| DANGEROUS_RIGHTS.iter().any(|r| r.as_bytes() == right)),
+        };
+        if dangerous {
+            return Err(Error::Other(format!(
+                "{path} grants {rights} to unprivileged users ({sid})"
+            )));
+        }
+    }
+
+    Ok(())
+}
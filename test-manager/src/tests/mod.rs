@@ -19,7 +19,11 @@ use futures::future::BoxFuture;
 
 use mullvad_management_interface::{types::Settings, ManagementServiceClient};
 use once_cell::sync::OnceCell;
-use std::time::Duration;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
 
 const PING_TIMEOUT: Duration = Duration::from_secs(3);
 const WAIT_FOR_TUNNEL_STATE_TIMEOUT: Duration = Duration::from_secs(40);
@@ -57,8 +61,31 @@ pub enum Error {
     #[error(display = "The daemon returned an error: {}", _0)]
     DaemonError(String),
 
+    #[error(display = "The daemon entered the error state: {}", _0)]
+    ErrorState(talpid_types::tunnel::ErrorStateCause),
+
+    #[error(display = "The daemon entered an unexpected error state: {}", _0)]
+    UnexpectedErrorState(talpid_types::tunnel::ErrorStateCause),
+
+    #[error(display = "The tunnel is up but not passing traffic: {}", _0)]
+    TunnelNotFunctional(String),
+
+    #[error(display = "Failed to start packet monitor")]
+    PacketMonitor(#[source] crate::network_monitor::Error),
+
     #[error(display = "An error occurred: {}", _0)]
     Other(String),
+
+    #[error(display = "The test was aborted")]
+    Aborted,
+
+    #[error(display = "The test timed out after {} s", _0)]
+    TimedOut(u64),
+
+    /// The test could not be run in this environment. It is reported as skipped rather than
+    /// passed.
+    #[error(display = "The test was skipped: {}", _0)]
+    Skipped(String),
 }
 
 static DEFAULT_SETTINGS: OnceCell<Settings> = OnceCell::new();
@@ -75,88 +102,115 @@ pub async fn init_default_settings(mullvad_client: &mut ManagementServiceClient)
     }
 }
 
-/// Restore settings to `DEFAULT_SETTINGS`.
+/// Restore the daemon to its default settings by removing its settings and cache, and then
+/// restarting it. The account remains logged in. This returns a new client, since the daemon is
+/// restarted.
+pub async fn cleanup_after_test(
+    rpc: &ServiceClient,
+    rpc_provider: &RpcClientProvider,
+) -> anyhow::Result<ManagementServiceClient> {
+    log::debug!("Cleaning up daemon in test cleanup");
+
+    let mut mullvad_client = rpc_provider.new_client().await;
+    helpers::disconnect_and_wait(&mut mullvad_client)
+        .await
+        .context("Could not disconnect in cleanup")?;
+
+    let removed = rpc
+        .reset_settings()
+        .await
+        .context("Could not reset daemon settings in cleanup")?;
+    log::debug!("Removed daemon files: {removed:?}");
+
+    let mut mullvad_client = helpers::wait_for_daemon_ready(rpc_provider)
+        .await
+        .context("Daemon did not start after resetting settings")?;
+
+    // The cached relay list was removed
+    helpers::ensure_updated_relay_list(&mut mullvad_client).await;
+
+    reset_relay_settings(&mut mullvad_client).await?;
+
+    Ok(mullvad_client)
+}
+
+/// Compare the current settings to `DEFAULT_SETTINGS`. This returns the lines in the
+/// pretty-printed settings that differ, or nothing if the settings are identical.
 ///
 /// # Panics
 ///
-/// `DEFAULT_SETTINGS` must be initialized using `init_default_settings` before any settings are
-/// modified, or this function panics.
-pub async fn cleanup_after_test(
+/// `DEFAULT_SETTINGS` must be initialized using `init_default_settings` first, or this function
+/// panics.
+pub async fn diff_default_settings(
     mullvad_client: &mut ManagementServiceClient,
-) -> anyhow::Result<()> {
-    log::debug!("Cleaning up daemon in test cleanup");
-
+) -> anyhow::Result<Vec<String>> {
     let default_settings = DEFAULT_SETTINGS
         .get()
         .expect("default settings were not initialized");
 
-    reset_relay_settings(mullvad_client).await?;
-
-    mullvad_client
-        .set_auto_connect(default_settings.auto_connect)
-        .await
-        .context("Could not set auto connect in cleanup")?;
-    mullvad_client
-        .set_allow_lan(default_settings.allow_lan)
-        .await
-        .context("Could not set allow lan in cleanup")?;
-    mullvad_client
-        .set_show_beta_releases(default_settings.show_beta_releases)
-        .await
-        .context("Could not set show beta releases in cleanup")?;
-    mullvad_client
-        .set_bridge_state(default_settings.bridge_state.clone().unwrap())
-        .await
-        .context("Could not set bridge state in cleanup")?;
-    mullvad_client
-        .set_bridge_settings(default_settings.bridge_settings.clone().unwrap())
-        .await
-        .context("Could not set bridge settings in cleanup")?;
-    mullvad_client
-        .set_obfuscation_settings(default_settings.obfuscation_settings.clone().unwrap())
+    let settings: Settings = mullvad_client
+        .get_settings(())
         .await
-        .context("Could set obfuscation settings in cleanup")?;
-    mullvad_client
-        .set_block_when_disconnected(default_settings.block_when_disconnected)
-        .await
-        .context("Could not set block when disconnected setting in cleanup")?;
-    mullvad_client
-        .clear_split_tunnel_apps(())
-        .await
-        .context("Could not clear split tunnel apps in cleanup")?;
-    mullvad_client
-        .clear_split_tunnel_processes(())
-        .await
-        .context("Could not clear split tunnel processes in cleanup")?;
-    mullvad_client
-        .set_dns_options(
-            default_settings
-                .tunnel_options
-                .as_ref()
-                .unwrap()
-                .dns_options
-                .as_ref()
-                .unwrap()
-                .clone(),
-        )
-        .await
-        .context("Could not clear dns options in cleanup")?;
-    mullvad_client
-        .set_quantum_resistant_tunnel(
-            default_settings
-                .tunnel_options
-                .as_ref()
-                .unwrap()
-                .wireguard
-                .as_ref()
-                .unwrap()
-                .quantum_resistant
-                .as_ref()
-                .unwrap()
-                .clone(),
-        )
-        .await
-        .context("Could not clear PQ options in cleanup")?;
+        .context("Could not get settings")?
+        .into_inner();
 
-    Ok(())
+    Ok(diff_settings(default_settings, &settings))
+}
+
+/// Compare `settings` to `expected`. This returns the lines in the pretty-printed settings that
+/// differ, or nothing if the settings are identical.
+pub fn diff_settings(expected: &Settings, settings: &Settings) -> Vec<String> {
+    let expected_checksum = settings_checksum(expected);
+    let checksum = settings_checksum(settings);
+    log::debug!("Settings checksum: {checksum:016x}, expected: {expected_checksum:016x}");
+
+    if checksum == expected_checksum {
+        return vec![];
+    }
+
+    diff_lines(&format!("{expected:#?}"), &format!("{settings:#?}"))
+}
+
+/// Hash the debug representation of `settings`. The checksum is only stable within a single run.
+fn settings_checksum(settings: &Settings) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    format!("{settings:?}").hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Return a line-based diff of `old` and `new`, containing only the lines that were removed
+/// (prefixed with `-`) or added (prefixed with `+`).
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // Length of the longest common subsequence of `old[i..]` and `new[j..]`
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| format!("-{line}")));
+    diff.extend(new[j..].iter().map(|line| format!("+{line}")));
+    diff
 }
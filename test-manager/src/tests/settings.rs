@@ -1,16 +1,19 @@
 use super::helpers::{
     connect_and_wait, disconnect_and_wait, geoip_lookup_with_retries, get_tunnel_state,
-    send_guest_probes,
+    send_guest_probes, AbortOnDrop, LeakTest,
 };
 use super::{Error, TestContext};
 use crate::assert_tunnel_state;
 use crate::vm::network::DUMMY_LAN_INTERFACE_IP;
 
-use mullvad_management_interface::ManagementServiceClient;
+use mullvad_management_interface::{types, ManagementServiceClient};
 use mullvad_types::states::TunnelState;
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 use test_macro::test_function;
-use test_rpc::{Interface, ServiceClient};
+use test_rpc::{meta::Os, Interface, ServiceClient};
 
 /// Verify that traffic to private IPs is blocked when
 /// "local network sharing" is disabled, but not blocked
@@ -100,7 +103,7 @@ pub async fn test_lan(
 /// and one private IP address. They detect basic leaks but
 /// do not guarantee close conformity with the security
 /// document.
-#[test_function]
+#[test_function(security_critical = true)]
 pub async fn test_lockdown(
     _: TestContext,
     rpc: ServiceClient,
@@ -135,16 +138,12 @@ pub async fn test_lockdown(
     // Ensure all destinations are unreachable
     //
 
-    let detected_probes =
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), lan_destination).await?;
-    assert!(detected_probes.none(), "observed outgoing packets to LAN");
-
-    let detected_probes =
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
-    assert!(
-        detected_probes.none(),
-        "observed outgoing packets to internet"
-    );
+    LeakTest::new(Some(Interface::NonTunnel))
+        .blocked(lan_destination)
+        .blocked(inet_destination)
+        .run(&rpc)
+        .await?
+        .into_result()?;
 
     //
     // Enable LAN sharing
@@ -161,19 +160,12 @@ pub async fn test_lockdown(
     // Ensure private IPs are reachable, but not others
     //
 
-    let detected_probes =
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), lan_destination).await?;
-    assert!(
-        detected_probes.all(),
-        "did not observe some outgoing packets"
-    );
-
-    let detected_probes =
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
-    assert!(
-        detected_probes.none(),
-        "observed outgoing packets to internet"
-    );
+    LeakTest::new(Some(Interface::NonTunnel))
+        .reachable(lan_destination)
+        .blocked(inet_destination)
+        .run(&rpc)
+        .await?
+        .into_result()?;
 
     //
     // Connect
@@ -193,12 +185,11 @@ pub async fn test_lockdown(
 
     // Send traffic outside the tunnel to sanity check that the internet is *not* reachable via non-
     // tunnel interfaces.
-    let detected_probes =
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
-    assert!(
-        detected_probes.none(),
-        "observed outgoing packets to internet"
-    );
+    LeakTest::new(Some(Interface::NonTunnel))
+        .blocked(inet_destination)
+        .run(&rpc)
+        .await?
+        .into_result()?;
 
     //
     // Disable lockdown mode
@@ -212,3 +203,186 @@ pub async fn test_lockdown(
 
     Ok(())
 }
+
+/// Verify that settings can be exported to a file using the CLI, and later be restored by
+/// importing that file. This test fails if the settings after the import differ from the
+/// exported ones.
+#[test_function]
+pub async fn test_settings_export_import(
+    ctx: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let os = rpc.get_os().await?;
+    let cli_path = cli_path(&os);
+    let settings_path = match os {
+        Os::Linux | Os::Macos => "/tmp/mullvad-settings.json",
+        Os::Windows => r"C:\Windows\Temp\mullvad-settings.json",
+    };
+
+    //
+    // Configure some non-default settings
+    //
+
+    mullvad_client
+        .set_allow_lan(true)
+        .await
+        .expect("failed to enable LAN sharing");
+    mullvad_client
+        .set_block_when_disconnected(true)
+        .await
+        .expect("failed to enable lockdown mode");
+    mullvad_client
+        .set_show_beta_releases(true)
+        .await
+        .expect("failed to enable beta releases");
+
+    let exported_settings = mullvad_client
+        .get_settings(())
+        .await
+        .expect("failed to obtain settings")
+        .into_inner();
+
+    //
+    // Export, reset, and import the settings
+    //
+
+    log::info!("Exporting settings to {settings_path}");
+    run_cli(&rpc, cli_path, ["export-settings", settings_path]).await?;
+
+    let mut mullvad_client = super::cleanup_after_test(&rpc, &ctx.rpc_provider)
+        .await
+        .map_err(|error| Error::Other(format!("Failed to reset settings: {error}")))?;
+
+    log::info!("Importing settings from {settings_path}");
+    run_cli(&rpc, cli_path, ["import-settings", settings_path]).await?;
+
+    let imported_settings = mullvad_client
+        .get_settings(())
+        .await
+        .expect("failed to obtain settings")
+        .into_inner();
+
+    let settings_diff = super::diff_settings(&exported_settings, &imported_settings);
+    assert!(
+        settings_diff.is_empty(),
+        "imported settings differ from the exported ones:\n{}",
+        settings_diff.join("\n")
+    );
+
+    Ok(())
+}
+
+/// Verify that the daemon serves several management interface clients at once. The CLI on the
+/// guest changes a setting repeatedly while this client listens for events and queries the
+/// daemon. This test fails if the listener does not observe every change, in order, or if any
+/// request fails.
+///
+/// The test manager only forwards a single gRPC session to the daemon at a time, so the listener
+/// and the queries share that session, and the CLI is the second client.
+#[test_function]
+pub async fn test_concurrent_mgmt_clients(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const SETTING_CHANGES: usize = 6;
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let initial_allow_lan = mullvad_client
+        .get_settings(())
+        .await
+        .map_err(|error| Error::DaemonError(format!("Failed to get settings: {error}")))?
+        .into_inner()
+        .allow_lan;
+    let expected_allow_lan: Vec<bool> = (1..=SETTING_CHANGES)
+        .map(|change| (change % 2 == 1) != initial_allow_lan)
+        .collect();
+
+    let cli_path = cli_path(&rpc.get_os().await?);
+
+    let mut events = mullvad_client
+        .clone()
+        .events_listen(())
+        .await
+        .map_err(|error| Error::DaemonError(format!("Failed to get event stream: {error}")))?
+        .into_inner();
+
+    let mut listener = AbortOnDrop(tokio::spawn(async move {
+        let mut observed_allow_lan = vec![];
+        while observed_allow_lan.len() < SETTING_CHANGES {
+            match events.message().await {
+                Ok(Some(types::DaemonEvent {
+                    event: Some(types::daemon_event::Event::Settings(settings)),
+                })) => {
+                    // Ignore settings events caused by other changes
+                    if observed_allow_lan.last() != Some(&settings.allow_lan) {
+                        observed_allow_lan.push(settings.allow_lan);
+                    }
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => break,
+            }
+        }
+        observed_allow_lan
+    }));
+
+    log::info!("Changing settings using the CLI while listening for events");
+
+    let change_settings = async {
+        for allow_lan in &expected_allow_lan {
+            let allow_lan = if *allow_lan { "allow" } else { "block" };
+            run_cli(&rpc, cli_path, ["lan", "set", allow_lan]).await?;
+        }
+        Ok::<_, Error>(())
+    };
+    let query_daemon = async {
+        for _ in 0..SETTING_CHANGES {
+            mullvad_client.get_tunnel_state(()).await.map_err(|error| {
+                Error::DaemonError(format!("Failed to get tunnel state: {error}"))
+            })?;
+        }
+        Ok::<_, Error>(())
+    };
+    let (change_result, query_result) = tokio::join!(change_settings, query_daemon);
+    change_result?;
+    query_result?;
+
+    let observed_allow_lan = tokio::time::timeout(EVENT_TIMEOUT, &mut listener.0)
+        .await
+        .map_err(|_| Error::Other("Timed out waiting for settings events".to_owned()))?
+        .expect("event listener panicked");
+
+    assert_eq!(
+        observed_allow_lan, expected_allow_lan,
+        "listener did not observe all settings changes"
+    );
+
+    Ok(())
+}
+
+/// Path to the CLI on the guest
+fn cli_path(os: &Os) -> &'static str {
+    match os {
+        Os::Linux => "/usr/bin/mullvad",
+        Os::Macos => "/usr/local/bin/mullvad",
+        Os::Windows => r"C:\Program Files\Mullvad VPN\resources\mullvad.exe",
+    }
+}
+
+/// Run the CLI with the given arguments and fail if it does not succeed.
+async fn run_cli<const N: usize>(
+    rpc: &ServiceClient,
+    cli_path: &str,
+    args: [&str; N],
+) -> Result<(), Error> {
+    let result = rpc.exec(cli_path, args.into_iter(), None).await?;
+    if !result.success() {
+        return Err(Error::DaemonError(format!(
+            "mullvad {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&result.stderr)
+        )));
+    }
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use super::TestWrapperFunction;
-use test_rpc::mullvad_daemon::MullvadClientVersion;
+use std::time::Duration;
+use test_rpc::{meta::Os, mullvad_daemon::MullvadClientVersion};
 
 pub struct TestMetadata {
     pub name: &'static str,
@@ -9,8 +10,72 @@ pub struct TestMetadata {
     pub priority: Option<i32>,
     pub always_run: bool,
     pub must_succeed: bool,
+    pub security_critical: bool,
+    pub xfail_on: &'static [Os],
+    /// Operating systems that the test is run on. It is run on all of them if this is empty
+    pub targets: &'static [Os],
+    /// Names of tests that must pass before this test is run
+    pub depends_on: &'static [&'static str],
     pub cleanup: bool,
+    /// Maximum time that the test may run. There is no limit if this is `None`
+    pub timeout: Option<Duration>,
+    /// Number of times to rerun the test if it fails
+    pub retries: usize,
 }
 
 // Register our test metadata struct with inventory to allow submitting tests of this type.
 inventory::collect!(TestMetadata);
+
+#[cfg(test)]
+mod test {
+    use super::TestMetadata;
+    use crate::tests::{Error, TestContext};
+    use test_macro::test_function;
+    use test_rpc::ServiceClient;
+
+    // These tests are only registered in unit test builds, and are never run. They fail to
+    // compile if `TEST_NAME` is not declared correctly.
+
+    #[test_function]
+    async fn test_declares_test_name(_: TestContext, _rpc: ServiceClient) -> Result<(), Error> {
+        let name: &'static str = TEST_NAME;
+        assert_eq!(name, "test_declares_test_name");
+        Ok(())
+    }
+
+    #[test_function]
+    async fn test_shadows_test_name(_: TestContext, _rpc: ServiceClient) -> Result<(), Error> {
+        const TEST_NAME: u32 = 1;
+        let _: u32 = TEST_NAME;
+        Ok(())
+    }
+
+    #[test_function(params = "1, 2")]
+    async fn test_declares_test_name_with_params(
+        _: TestContext,
+        _rpc: ServiceClient,
+        value: u32,
+    ) -> Result<(), Error> {
+        // The name of the function, without the parameter
+        let name: &'static str = TEST_NAME;
+        assert_eq!(name, "test_declares_test_name_with_params");
+        assert!(value > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_test_name_tests_are_registered() {
+        let names: Vec<_> = inventory::iter::<TestMetadata>()
+            .map(|test| test.name)
+            .filter(|name| name.starts_with("test_declares_") || name.starts_with("test_shadows_"))
+            .collect();
+        for name in [
+            "test_declares_test_name",
+            "test_shadows_test_name",
+            "test_declares_test_name_with_params[value=1]",
+            "test_declares_test_name_with_params[value=2]",
+        ] {
+            assert!(names.contains(&name), "{name} is not registered");
+        }
+    }
+}
@@ -1,22 +1,37 @@
+use super::config::TEST_CONFIG;
 use super::helpers::{
-    self, connect_and_wait, disconnect_and_wait, geoip_lookup_with_retries, update_relay_settings,
+    self, connect_and_wait, disconnect_and_wait, geoip_lookup_with_retries, set_connection_profile,
+    update_relay_settings, ConnectionProfile,
 };
 use super::{Error, TestContext};
-use std::net::IpAddr;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 use crate::network_monitor::{start_packet_monitor, MonitorOptions};
-use mullvad_management_interface::{types, ManagementServiceClient};
+use crate::vm::network::{CUSTOM_TUN_REMOTE_REAL_ADDR, CUSTOM_TUN_REMOTE_TUN_ADDR};
+use mullvad_management_interface::{types, Code, ManagementServiceClient};
 use mullvad_types::relay_constraints::{
     Constraint, LocationConstraint, OpenVpnConstraints, RelayConstraintsUpdate,
     RelaySettingsUpdate, WireguardConstraints,
 };
 use mullvad_types::relay_constraints::{GeographicLocationConstraint, TransportPort};
+use mullvad_types::relay_list::{RelayEndpointData, RelayList};
+use mullvad_types::{states::TunnelState, ConnectionConfig, CustomTunnelEndpoint};
 use pnet_packet::ip::IpNextHeaderProtocols;
-use talpid_types::net::{TransportProtocol, TunnelType};
+use talpid_types::net::{
+    proxy::ProxyType, wireguard::PrivateKey, IpVersion, TransportProtocol, TunnelType,
+};
 use test_macro::test_function;
 use test_rpc::meta::Os;
 use test_rpc::mullvad_daemon::ServiceStatus;
-use test_rpc::{Interface, ServiceClient};
+use test_rpc::{client::DEFAULT_HTTP_TIMEOUT, AddressFamily, Interface, ServiceClient};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, UdpSocket},
+    time::timeout,
+};
 
 /// Set up an OpenVPN tunnel, UDP as well as TCP.
 /// This test fails if a working tunnel cannot be set up.
@@ -66,8 +81,7 @@ pub async fn test_openvpn_tunnel(
 
         // Send traffic through the tunnel to sanity check that the internet is reachable.
         log::info!("Test whether tunnel traffic works");
-        let geoip_lookup = geoip_lookup_with_retries(&rpc).await.unwrap();
-        assert!(geoip_lookup.mullvad_exit_ip, "Exit ip is not from Mullvad");
+        helpers::assert_tunnel_functional(&rpc).await?;
 
         disconnect_and_wait(&mut mullvad_client).await?;
     }
@@ -75,56 +89,53 @@ pub async fn test_openvpn_tunnel(
     Ok(())
 }
 
-/// Set up a WireGuard tunnel.
-/// This test fails if a working tunnel cannot be set up.
+/// Set up a WireGuard tunnel on a given port. Connecting should succeed for the ports that
+/// relays listen on, and fail for other ports.
+/// This test fails if a working tunnel cannot be set up, or if connecting unexpectedly succeeds.
 /// WARNING: This test will fail if host has something bound to port 53 such as a connected Mullvad
-#[test_function]
+#[test_function(params = "(53, true), (51820, true), (1, false)")]
 pub async fn test_wireguard_tunnel(
     _: TestContext,
     rpc: ServiceClient,
     mut mullvad_client: ManagementServiceClient,
+    (port, should_succeed): (u16, bool),
 ) -> Result<(), Error> {
     // TODO: observe UDP traffic on the expected destination/port (only)
     // TODO: IPv6
 
-    const PORTS: [(u16, bool); 3] = [(53, true), (51820, true), (1, false)];
-
-    for (port, should_succeed) in PORTS {
-        log::info!("Connect to WireGuard endpoint on port {port}");
+    log::info!("Connect to WireGuard endpoint on port {port}");
 
-        let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
-            location: Some(Constraint::Only(LocationConstraint::Location(
-                GeographicLocationConstraint::Country("se".to_string()),
-            ))),
-            tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
-            wireguard_constraints: Some(WireguardConstraints {
-                port: Constraint::Only(port),
-                ..Default::default()
-            }),
+    let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
+        location: Some(Constraint::Only(LocationConstraint::Location(
+            GeographicLocationConstraint::Country("se".to_string()),
+        ))),
+        tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
+        wireguard_constraints: Some(WireguardConstraints {
+            port: Constraint::Only(port),
             ..Default::default()
-        });
-
-        update_relay_settings(&mut mullvad_client, relay_settings)
-            .await
-            .expect("failed to update relay settings");
+        }),
+        ..Default::default()
+    });
 
-        let connection_result = connect_and_wait(&mut mullvad_client).await;
-        assert_eq!(
-            connection_result.is_ok(),
-            should_succeed,
-            "unexpected result for port {port}: {connection_result:?}",
-        );
+    update_relay_settings(&mut mullvad_client, relay_settings)
+        .await
+        .expect("failed to update relay settings");
 
-        if should_succeed {
-            // Send traffic through the tunnel to sanity check that the internet is reachable.
-            log::info!("Test whether tunnel traffic works");
-            let geoip_lookup = geoip_lookup_with_retries(&rpc).await.unwrap();
-            assert!(geoip_lookup.mullvad_exit_ip, "Exit ip is not from Mullvad");
-        }
+    let connection_result = connect_and_wait(&mut mullvad_client).await;
+    assert_eq!(
+        connection_result.is_ok(),
+        should_succeed,
+        "unexpected result for port {port}: {connection_result:?}",
+    );
 
-        disconnect_and_wait(&mut mullvad_client).await?;
+    if should_succeed {
+        // Send traffic through the tunnel to sanity check that the internet is reachable.
+        log::info!("Test whether tunnel traffic works");
+        helpers::assert_tunnel_functional(&rpc).await?;
     }
 
+    disconnect_and_wait(&mut mullvad_client).await?;
+
     Ok(())
 }
 
@@ -140,28 +151,15 @@ pub async fn test_udp2tcp_tunnel(
     // TODO: check if src <-> target / tcp is observed (only)
     // TODO: ping a public IP on the fake network (not possible using real relay)
 
-    mullvad_client
-        .set_obfuscation_settings(types::ObfuscationSettings {
-            selected_obfuscation: i32::from(
-                types::obfuscation_settings::SelectedObfuscation::Udp2tcp,
-            ),
-            udp2tcp: Some(types::Udp2TcpObfuscationSettings { port: 0 }),
-        })
-        .await
-        .expect("failed to enable udp2tcp");
-
-    let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
-        location: Some(Constraint::Only(LocationConstraint::Location(
-            GeographicLocationConstraint::Country("se".to_string()),
-        ))),
-        tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
-        wireguard_constraints: Some(WireguardConstraints::default()),
-        ..Default::default()
-    });
-
-    update_relay_settings(&mut mullvad_client, relay_settings)
-        .await
-        .expect("failed to update relay settings");
+    set_connection_profile(
+        &mut mullvad_client,
+        ConnectionProfile {
+            udp2tcp: true,
+            ..ConnectionProfile::wireguard()
+        },
+    )
+    .await
+    .expect("failed to enable udp2tcp");
 
     log::info!("Connect to WireGuard via tcp2udp endpoint");
 
@@ -172,7 +170,7 @@ pub async fn test_udp2tcp_tunnel(
     //
 
     let guest_ip = rpc
-        .get_interface_ip(Interface::NonTunnel)
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
         .await
         .expect("failed to obtain inet interface IP");
 
@@ -182,7 +180,7 @@ pub async fn test_udp2tcp_tunnel(
         },
         MonitorOptions::default(),
     )
-    .await;
+    .await?;
 
     //
     // Verify that we can reach stuff
@@ -190,8 +188,7 @@ pub async fn test_udp2tcp_tunnel(
 
     // Send traffic through the tunnel to sanity check that the internet is reachable.
     log::info!("Test whether tunnel traffic works");
-    let geoip_lookup = geoip_lookup_with_retries(&rpc).await.unwrap();
-    assert!(geoip_lookup.mullvad_exit_ip, "Exit ip is not from Mullvad");
+    helpers::assert_tunnel_functional(&rpc).await?;
 
     let monitor_result = monitor.into_result().await.unwrap();
     assert_eq!(monitor_result.discarded_packets, 0);
@@ -201,11 +198,173 @@ pub async fn test_udp2tcp_tunnel(
     Ok(())
 }
 
+/// Obfuscate an OpenVPN tunnel using a Shadowsocks bridge. This test fails if:
+/// * The daemon does not connect via a Shadowsocks proxy on the selected bridge.
+/// * No outgoing TCP traffic to the bridge is observed on the expected port.
+/// * Any other outgoing traffic is observed from the SUT.
+///
+/// The test is skipped if the relay list contains no Shadowsocks bridges.
+#[test_function]
+pub async fn test_shadowsocks_obfuscation(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    // TODO: Test Shadowsocks obfuscation for WireGuard using `ObfuscationSettings` once the
+    //       management interface exposes it. For now, Shadowsocks is only used by bridges.
+
+    //
+    // Select bridge
+    //
+
+    log::info!("Select bridge");
+
+    let relay_list = helpers::RELAY_LIST_CACHE.get(&mut mullvad_client).await?;
+    let relay_list = RelayList::try_from(relay_list)
+        .map_err(|error| Error::Other(format!("Failed to convert relay list: {error:?}")))?;
+    let shadowsocks_ports: Vec<u16> = relay_list
+        .bridge
+        .shadowsocks
+        .iter()
+        .filter(|endpoint| endpoint.protocol == TransportProtocol::Tcp)
+        .map(|endpoint| endpoint.port)
+        .collect();
+
+    let bridge_filter = |relay: &types::Relay| {
+        relay.active && relay.endpoint_type == i32::from(types::relay::RelayType::Bridge)
+    };
+    if shadowsocks_ports.is_empty()
+        || helpers::filter_relays(&mut mullvad_client, bridge_filter)
+            .await?
+            .is_empty()
+    {
+        return Err(Error::Skipped(
+            "the relay list contains no Shadowsocks bridges".to_string(),
+        ));
+    }
+    let (bridge, bridge_ip) = helpers::select_relay(&mut mullvad_client, bridge_filter).await?;
+
+    //
+    // Enable bridge mode
+    //
+
+    log::info!("Updating bridge settings");
+
+    set_connection_profile(
+        &mut mullvad_client,
+        ConnectionProfile {
+            port: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Tcp,
+                port: Constraint::Any,
+            }),
+            ..ConnectionProfile::openvpn()
+        },
+    )
+    .await
+    .expect("failed to update relay settings");
+
+    mullvad_client
+        .set_bridge_state(types::BridgeState {
+            state: i32::from(types::bridge_state::State::On),
+        })
+        .await
+        .expect("failed to enable bridge mode");
+
+    mullvad_client
+        .set_bridge_settings(types::BridgeSettings {
+            r#type: Some(types::bridge_settings::Type::Normal(
+                types::bridge_settings::BridgeConstraints {
+                    location: helpers::into_locationconstraint(&bridge)
+                        .map(types::LocationConstraint::from),
+                    providers: vec![],
+                    ownership: i32::from(types::Ownership::Any),
+                },
+            )),
+        })
+        .await
+        .expect("failed to update bridge settings");
+
+    //
+    // Connect
+    //
+
+    log::info!("Connect to OpenVPN relay via Shadowsocks bridge");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    let proxy = match helpers::get_tunnel_state(&mut mullvad_client).await {
+        TunnelState::Connected { endpoint, .. } => endpoint.proxy,
+        actual => {
+            return Err(Error::Other(format!(
+                "expected connected state, got {actual:?}"
+            )))
+        }
+    }
+    .ok_or(Error::Other("the tunnel does not use a proxy".to_string()))?;
+
+    assert!(
+        matches!(proxy.proxy_type, ProxyType::Shadowsocks),
+        "unexpected proxy type: {:?}",
+        proxy.proxy_type
+    );
+    assert_eq!(proxy.endpoint.protocol, TransportProtocol::Tcp);
+    assert_eq!(proxy.endpoint.address.ip(), IpAddr::V4(bridge_ip));
+    assert!(
+        shadowsocks_ports.contains(&proxy.endpoint.address.port()),
+        "unexpected bridge port: {}",
+        proxy.endpoint.address.port()
+    );
+
+    //
+    // Set up packet monitor
+    //
+
+    let guest_ip = rpc
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
+        .await
+        .expect("failed to obtain inet interface IP");
+    let bridge_addr = proxy.endpoint.address;
+
+    let monitor = start_packet_monitor(
+        move |packet| {
+            packet.source.ip() != guest_ip
+                || (packet.protocol == IpNextHeaderProtocols::Tcp
+                    && packet.destination == bridge_addr)
+        },
+        MonitorOptions::default(),
+    )
+    .await?;
+
+    //
+    // Verify that traffic only goes to the bridge
+    //
+
+    log::info!("Test whether tunnel traffic works");
+    helpers::assert_tunnel_functional(&rpc).await?;
+
+    let monitor_result = monitor.into_result().await.unwrap();
+    assert_eq!(
+        monitor_result.discarded_packets, 0,
+        "observed traffic other than TCP to the bridge"
+    );
+    assert!(
+        monitor_result
+            .packets
+            .iter()
+            .any(|packet| packet.destination == bridge_addr),
+        "detected no traffic to the bridge",
+    );
+
+    disconnect_and_wait(&mut mullvad_client).await?;
+
+    Ok(())
+}
+
 /// Test whether bridge mode works. This fails if:
 /// * No outgoing traffic to the bridge/entry relay is
 ///   observed from the SUT.
 /// * The conncheck reports an unexpected exit relay.
-#[test_function]
+#[test_function(retries = 2)]
 pub async fn test_bridge(
     _: TestContext,
     rpc: ServiceClient,
@@ -218,14 +377,8 @@ pub async fn test_bridge(
     let ovpn_filter = |relay: &types::Relay| {
         relay.active && relay.endpoint_type == i32::from(types::relay::RelayType::Openvpn)
     };
-    let entry = helpers::filter_relays(&mut mullvad_client, bridge_filter)
-        .await?
-        .pop()
-        .unwrap();
-    let exit = helpers::filter_relays(&mut mullvad_client, ovpn_filter)
-        .await?
-        .pop()
-        .unwrap();
+    let (entry, entry_ip) = helpers::select_relay(&mut mullvad_client, bridge_filter).await?;
+    let (exit, _) = helpers::select_relay(&mut mullvad_client, ovpn_filter).await?;
 
     //
     // Enable bridge mode
@@ -271,10 +424,10 @@ pub async fn test_bridge(
     log::info!("Connect to OpenVPN relay via bridge");
 
     let monitor = start_packet_monitor(
-        move |packet| packet.destination.ip() == entry.ipv4_addr_in.parse::<IpAddr>().unwrap(),
+        move |packet| packet.destination.ip() == IpAddr::V4(entry_ip),
         MonitorOptions::default(),
     )
-    .await;
+    .await?;
 
     connect_and_wait(&mut mullvad_client)
         .await
@@ -287,6 +440,9 @@ pub async fn test_bridge(
     log::info!("Verifying entry server");
 
     let monitor_result = monitor.into_result().await.unwrap();
+    if monitor_result.packets.is_empty() {
+        helpers::log_tunnel_path(&rpc).await;
+    }
     assert!(
         !monitor_result.packets.is_empty(),
         "detected no traffic to entry server",
@@ -299,6 +455,9 @@ pub async fn test_bridge(
     log::info!("Verifying exit server");
 
     let geoip = geoip_lookup_with_retries(&rpc).await?;
+    if geoip.mullvad_exit_ip_hostname != exit.hostname {
+        helpers::log_tunnel_path(&rpc).await;
+    }
     assert_eq!(geoip.mullvad_exit_ip_hostname, exit.hostname);
 
     disconnect_and_wait(&mut mullvad_client).await?;
@@ -310,7 +469,7 @@ pub async fn test_bridge(
 /// * No outgoing traffic to the entry relay is
 ///   observed from the SUT.
 /// * The conncheck reports an unexpected exit relay.
-#[test_function]
+#[test_function(retries = 2)]
 pub async fn test_multihop(
     _: TestContext,
     rpc: ServiceClient,
@@ -354,7 +513,118 @@ pub async fn test_multihop(
         },
         MonitorOptions::default(),
     )
-    .await;
+    .await?;
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    //
+    // Verify entry IP
+    //
+
+    log::info!("Verifying entry server");
+
+    let monitor_result = monitor.into_result().await.unwrap();
+    if monitor_result.packets.is_empty() {
+        helpers::log_tunnel_path(&rpc).await;
+    }
+    assert!(!monitor_result.packets.is_empty(), "no matching packets",);
+
+    //
+    // Verify exit IP
+    //
+
+    log::info!("Verifying exit server");
+
+    let geoip = geoip_lookup_with_retries(&rpc).await?;
+    if geoip.mullvad_exit_ip_hostname != exit.hostname {
+        helpers::log_tunnel_path(&rpc).await;
+    }
+    assert_eq!(geoip.mullvad_exit_ip_hostname, exit.hostname);
+
+    disconnect_and_wait(&mut mullvad_client).await?;
+
+    Ok(())
+}
+
+/// Test whether WireGuard multihop honors an entry location constraint for a different country
+/// than the exit relay. This fails if:
+/// * The daemon connects via an entry relay outside of the requested country.
+/// * No outgoing traffic to a relay in the requested country is observed from the SUT.
+/// * The conncheck reports an unexpected exit relay.
+#[test_function]
+pub async fn test_multihop_entry_constraint(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    //
+    // Set relays to use
+    //
+
+    log::info!("Select relays");
+    let relay_filter = |relay: &types::Relay| {
+        relay.active && relay.endpoint_type == i32::from(types::relay::RelayType::Wireguard)
+    };
+    let relays = helpers::filter_relays(&mut mullvad_client, relay_filter).await?;
+    let country_code = |relay: &types::Relay| {
+        relay
+            .location
+            .as_ref()
+            .map(|location| location.country_code.clone())
+    };
+
+    let exit = relays
+        .first()
+        .cloned()
+        .ok_or(Error::Other("found no WireGuard relays".to_string()))?;
+    let exit_country = country_code(&exit);
+    let entry_country = relays
+        .iter()
+        .filter_map(country_code)
+        .find(|country| Some(country) != exit_country.as_ref())
+        .ok_or(Error::Other(
+            "found no WireGuard relays outside of the exit country".to_string(),
+        ))?;
+    let entry_ips: Vec<IpAddr> = relays
+        .iter()
+        .filter(|relay| country_code(relay).as_ref() == Some(&entry_country))
+        .map(|relay| relay.ipv4_addr_in.parse().unwrap())
+        .collect();
+
+    log::info!(
+        "Exit relay: {}, entry country: {entry_country}",
+        exit.hostname
+    );
+
+    let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
+        location: helpers::into_constraint(&exit),
+        wireguard_constraints: Some(WireguardConstraints {
+            use_multihop: true,
+            entry_location: Constraint::Only(LocationConstraint::Location(
+                GeographicLocationConstraint::Country(entry_country.clone()),
+            )),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+
+    update_relay_settings(&mut mullvad_client, relay_settings)
+        .await
+        .expect("failed to update relay settings");
+
+    //
+    // Connect
+    //
+
+    let monitor_ips = entry_ips.clone();
+    let monitor = start_packet_monitor(
+        move |packet| {
+            monitor_ips.contains(&packet.destination.ip())
+                && packet.protocol == IpNextHeaderProtocols::Udp
+        },
+        MonitorOptions::default(),
+    )
+    .await?;
 
     connect_and_wait(&mut mullvad_client).await?;
 
@@ -364,7 +634,24 @@ pub async fn test_multihop(
 
     log::info!("Verifying entry server");
 
+    let entry_endpoint = match helpers::get_tunnel_state(&mut mullvad_client).await {
+        TunnelState::Connected { endpoint, .. } => endpoint.entry_endpoint,
+        state => return Err(Error::Other(format!("unexpected tunnel state: {state:?}"))),
+    }
+    .ok_or(Error::Other(
+        "multihop entry endpoint is missing".to_string(),
+    ))?;
+
+    assert!(
+        entry_ips.contains(&entry_endpoint.address.ip()),
+        "entry endpoint {} is not a relay in {entry_country}",
+        entry_endpoint.address,
+    );
+
     let monitor_result = monitor.into_result().await.unwrap();
+    if monitor_result.packets.is_empty() {
+        helpers::log_tunnel_path(&rpc).await;
+    }
     assert!(!monitor_result.packets.is_empty(), "no matching packets",);
 
     //
@@ -374,6 +661,9 @@ pub async fn test_multihop(
     log::info!("Verifying exit server");
 
     let geoip = geoip_lookup_with_retries(&rpc).await?;
+    if geoip.mullvad_exit_ip_hostname != exit.hostname {
+        helpers::log_tunnel_path(&rpc).await;
+    }
     assert_eq!(geoip.mullvad_exit_ip_hostname, exit.hostname);
 
     disconnect_and_wait(&mut mullvad_client).await?;
@@ -382,11 +672,16 @@ pub async fn test_multihop(
 }
 
 /// Test whether the daemon automatically connects on reboot when using
-/// WireGuard.
+/// WireGuard. This test fails if:
+///
+/// * The daemon does not connect after the reboot.
+/// * Outgoing traffic to a public IP is observed between the reboot and the connected state.
 ///
 /// # Limitations
 ///
-/// This test does not guarantee that nothing leaks during boot or shutdown.
+/// This test does not guarantee that nothing leaks during boot or shutdown. Probes can only be
+/// sent once the test runner is reachable after the reboot, so leaks that occur earlier during
+/// boot are not detected.
 #[test_function]
 pub async fn test_wireguard_autoconnect(
     _: TestContext,
@@ -412,7 +707,30 @@ pub async fn test_wireguard_autoconnect(
         .await
         .expect("failed to enable auto-connect");
 
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
+    let bind_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+
+    log::debug!("Monitoring outgoing traffic to {inet_destination}");
+
+    let monitor = start_packet_monitor(
+        move |packet| packet.destination.ip() == inet_destination.ip(),
+        MonitorOptions::default(),
+    )
+    .await?;
+
     reboot(&mut rpc).await?;
+
+    // Send probes for as long as the daemon is not connected
+    let probe_rpc = rpc.clone();
+    let abort_on_drop = helpers::AbortOnDrop(tokio::spawn(async move {
+        loop {
+            let _ = probe_rpc.send_tcp(None, bind_addr, inet_destination).await;
+            let _ = probe_rpc.send_udp(None, bind_addr, inet_destination).await;
+            let _ = helpers::ping_with_timeout(&probe_rpc, inet_destination.ip(), None).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }));
+
     rpc.mullvad_daemon_wait_for_state(|state| state == ServiceStatus::Running)
         .await?;
 
@@ -423,6 +741,14 @@ pub async fn test_wireguard_autoconnect(
     })
     .await?;
 
+    drop(abort_on_drop);
+    let monitor_result = monitor.into_result().await.unwrap();
+    assert_eq!(
+        monitor_result.packets.len(),
+        0,
+        "observed unexpected packets to {inet_destination} before connecting"
+    );
+
     Ok(())
 }
 
@@ -542,21 +868,88 @@ pub async fn test_quantum_resistant_tunnel(
 
     // Send traffic through the tunnel to sanity check that the internet is reachable.
     log::info!("Test whether tunnel traffic works");
-    let geoip_lookup = geoip_lookup_with_retries(&rpc).await.unwrap();
-    assert!(geoip_lookup.mullvad_exit_ip, "Exit ip is not from Mullvad");
+    helpers::assert_tunnel_functional(&rpc).await?;
 
     Ok(())
 }
 
-async fn check_tunnel_psk(rpc: &ServiceClient, should_have_psk: bool) {
-    match rpc.get_os().await.expect("failed to get OS") {
-        Os::Linux => {
-            let name = rpc
-                .get_interface_name(Interface::Tunnel)
+/// Verify that the tunnel interface is only assigned an IPv6 address when IPv6 is enabled. This
+/// test fails if:
+///
+/// * The tunnel interface has any IPv6 address, other than link-local ones, while IPv6 is disabled.
+/// * The tunnel interface has no such address while IPv6 is enabled.
+#[test_function]
+pub async fn test_no_tunnel_ipv6_when_disabled(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    mullvad_client
+        .set_enable_ipv6(false)
+        .await
+        .expect("failed to disable IPv6");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    let tunnel_ips = get_tunnel_ipv6(&rpc).await?;
+    log::debug!("Tunnel IPv6 addresses with IPv6 disabled: {tunnel_ips:?}");
+    if !tunnel_ips.is_empty() {
+        return Err(Error::Other(format!(
+            "Tunnel interface has IPv6 addresses while IPv6 is disabled: {tunnel_ips:?}"
+        )));
+    }
+
+    disconnect_and_wait(&mut mullvad_client).await?;
+
+    log::info!("Enable IPv6");
+
+    mullvad_client
+        .set_enable_ipv6(true)
+        .await
+        .expect("failed to enable IPv6");
+
+    let result = async {
+        connect_and_wait(&mut mullvad_client).await?;
+
+        let tunnel_ips = get_tunnel_ipv6(&rpc).await?;
+        log::debug!("Tunnel IPv6 addresses with IPv6 enabled: {tunnel_ips:?}");
+        if tunnel_ips.is_empty() {
+            return Err(Error::Other(
+                "Tunnel interface has no IPv6 address while IPv6 is enabled".to_string(),
+            ));
+        }
+
+        disconnect_and_wait(&mut mullvad_client).await
+    }
+    .await;
+
+    mullvad_client
+        .set_enable_ipv6(false)
+        .await
+        .expect("failed to disable IPv6");
+
+    result
+}
+
+/// Return all IPv6 addresses of the tunnel interface, except link-local ones.
+async fn get_tunnel_ipv6(rpc: &ServiceClient) -> Result<Vec<Ipv6Addr>, Error> {
+    let tunnel_ips = rpc.get_interface_ipv6(Interface::Tunnel).await?;
+    Ok(tunnel_ips
+        .into_iter()
+        // fe80::/10
+        .filter(|ip| ip.segments()[0] & 0xffc0 != 0xfe80)
+        .collect())
+}
+
+async fn check_tunnel_psk(rpc: &ServiceClient, should_have_psk: bool) {
+    match rpc.get_os().await.expect("failed to get OS") {
+        Os::Linux => {
+            let name = rpc
+                .get_interface_name(Interface::Tunnel)
                 .await
                 .expect("failed to get tun name");
             let output = rpc
-                .exec("wg", vec!["show", &name].into_iter())
+                .exec("wg", vec!["show", &name].into_iter(), None)
                 .await
                 .expect("failed to run wg");
             let parsed_output = std::str::from_utf8(&output.stdout).expect("non-utf8 output");
@@ -583,47 +976,617 @@ pub async fn test_quantum_resistant_multihop_udp2tcp_tunnel(
     rpc: ServiceClient,
     mut mullvad_client: ManagementServiceClient,
 ) -> Result<(), Error> {
+    set_connection_profile(
+        &mut mullvad_client,
+        ConnectionProfile {
+            udp2tcp: true,
+            multihop: true,
+            quantum_resistant: true,
+            ..ConnectionProfile::wireguard()
+        },
+    )
+    .await
+    .expect("Failed to update connection settings");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    // Send traffic through the tunnel to sanity check that the internet is reachable.
+    log::info!("Test whether tunnel traffic works");
+    helpers::assert_tunnel_functional(&rpc).await?;
+
+    Ok(())
+}
+
+/// Connect using a number of combinations of tunnel protocols, ports, and features. This test fails
+/// if a working tunnel cannot be set up using any of them.
+///
+/// # Limitations
+///
+/// Like `test_quantum_resistant_multihop_udp2tcp_tunnel`, this only checks whether a working
+/// tunnel can be set up. It does not verify that the individual features are in use.
+#[test_function]
+pub async fn test_connection_matrix(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const WIREGUARD: ConnectionProfile = ConnectionProfile::wireguard();
+    const PROFILES: [ConnectionProfile; 8] = [
+        ConnectionProfile {
+            port: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Udp,
+                port: Constraint::Any,
+            }),
+            ..ConnectionProfile::openvpn()
+        },
+        ConnectionProfile {
+            port: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Tcp,
+                port: Constraint::Only(443),
+            }),
+            ..ConnectionProfile::openvpn()
+        },
+        WIREGUARD,
+        ConnectionProfile {
+            port: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Udp,
+                port: Constraint::Only(53),
+            }),
+            ..WIREGUARD
+        },
+        ConnectionProfile {
+            udp2tcp: true,
+            ..WIREGUARD
+        },
+        ConnectionProfile {
+            multihop: true,
+            ..WIREGUARD
+        },
+        ConnectionProfile {
+            quantum_resistant: true,
+            ..WIREGUARD
+        },
+        ConnectionProfile {
+            multihop: true,
+            quantum_resistant: true,
+            ..WIREGUARD
+        },
+    ];
+
+    for profile in PROFILES {
+        log::info!("Connect using {profile:?}");
+
+        set_connection_profile(&mut mullvad_client, profile).await?;
+        connect_and_wait(&mut mullvad_client).await?;
+
+        // Send traffic through the tunnel to sanity check that the internet is reachable.
+        log::info!("Test whether tunnel traffic works");
+        helpers::assert_tunnel_functional(&rpc).await?;
+
+        disconnect_and_wait(&mut mullvad_client).await?;
+    }
+
+    Ok(())
+}
+
+/// Try to connect to the local custom WireGuard relay using malformed configs. The daemon must
+/// either reject the relay settings or fail to connect. This test fails if a broken config ends up
+/// in the connected state.
+#[test_function]
+pub async fn test_custom_wireguard_malformed_config(
+    _: TestContext,
+    _rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let mut wrong_private_key = helpers::local_wg_relay_config();
+    wrong_private_key.tunnel.private_key = PrivateKey::new_from_random();
+
+    let mut unreachable_gateway = helpers::local_wg_relay_config();
+    unreachable_gateway.ipv4_gateway = Ipv4Addr::new(192, 0, 2, 1);
+
+    let mut no_allowed_ips = helpers::local_wg_relay_config();
+    no_allowed_ips.peer.allowed_ips = vec![];
+
+    let mut no_tunnel_addresses = helpers::local_wg_relay_config();
+    no_tunnel_addresses.tunnel.addresses = vec![];
+
+    let configs = [
+        ("wrong private key", wrong_private_key),
+        ("unreachable gateway", unreachable_gateway),
+        ("no allowed IPs", no_allowed_ips),
+        ("no tunnel addresses", no_tunnel_addresses),
+    ];
+
+    for (description, config) in configs {
+        log::info!("Connect using custom WireGuard config: {description}");
+
+        let relay_settings = RelaySettingsUpdate::CustomTunnelEndpoint(CustomTunnelEndpoint {
+            host: CUSTOM_TUN_REMOTE_REAL_ADDR.to_string(),
+            config: ConnectionConfig::Wireguard(config),
+        });
+
+        // The daemon fails to parse settings that it considers invalid
+        match mullvad_client
+            .update_relay_settings(types::RelaySettingsUpdate::from(relay_settings))
+            .await
+        {
+            Err(status) if status.code() == Code::InvalidArgument => {
+                log::info!("Relay settings were rejected: {status}");
+                continue;
+            }
+            Err(status) => {
+                return Err(Error::DaemonError(format!(
+                    "Unexpected error for {description}: {status}"
+                )))
+            }
+            Ok(_) => (),
+        }
+
+        match connect_and_wait(&mut mullvad_client).await {
+            Err(Error::ErrorState(cause)) => log::info!("Failed to connect: {cause:?}"),
+            // `connect_and_wait` timed out, so make sure that the daemon is still trying
+            Err(Error::DaemonError(error))
+                if matches!(
+                    helpers::get_tunnel_state(&mut mullvad_client).await,
+                    TunnelState::Connecting { .. }
+                ) =>
+            {
+                log::info!("Failed to connect: {error}")
+            }
+            result => {
+                return Err(Error::Other(format!(
+                    "unexpected result for {description}: {result:?}"
+                )))
+            }
+        }
+
+        disconnect_and_wait(&mut mullvad_client).await?;
+    }
+
+    Ok(())
+}
+
+/// Set a small custom MTU for the WireGuard tunnel, and verify that pings larger than the MTU
+/// still go through the tunnel, i.e. that they are fragmented and reassembled correctly. This test
+/// fails if the MTU cannot be set, or if the large pings fail.
+#[test_function]
+pub async fn test_wireguard_mtu(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    // Smallest MTU accepted by the daemon
+    const CUSTOM_MTU: u32 = 1280;
+    // Large enough for the ping to exceed `CUSTOM_MTU`
+    const PING_PAYLOAD_SIZE: usize = 1400;
+    const PING_DESTINATION: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+
+    log::info!("Setting WireGuard MTU to {CUSTOM_MTU}");
+
     mullvad_client
-        .set_quantum_resistant_tunnel(types::QuantumResistantState {
-            state: i32::from(types::quantum_resistant_state::State::On),
-        })
+        .set_wireguard_mtu(CUSTOM_MTU)
         .await
-        .expect("Failed to enable PQ tunnels");
+        .map_err(|error| Error::DaemonError(format!("Failed to set MTU: {error}")))?;
+
+    set_connection_profile(&mut mullvad_client, ConnectionProfile::wireguard())
+        .await
+        .expect("failed to update relay settings");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    log::info!("Sending {PING_PAYLOAD_SIZE} byte pings through the tunnel");
+
+    let result = helpers::ping_sized_with_timeout(
+        &rpc,
+        PING_DESTINATION,
+        Some(Interface::Tunnel),
+        Some(PING_PAYLOAD_SIZE),
+    )
+    .await;
+
+    disconnect_and_wait(&mut mullvad_client).await?;
 
+    log::info!("Restoring default WireGuard MTU");
+
+    // An MTU of 0 means that the default MTU is used
     mullvad_client
-        .set_obfuscation_settings(types::ObfuscationSettings {
-            selected_obfuscation: i32::from(
-                types::obfuscation_settings::SelectedObfuscation::Udp2tcp,
-            ),
-            udp2tcp: Some(types::Udp2TcpObfuscationSettings { port: 0 }),
-        })
+        .set_wireguard_mtu(0)
         .await
-        .expect("Failed to enable obfuscation");
+        .map_err(|error| Error::DaemonError(format!("Failed to reset MTU: {error}")))?;
+
+    result.map_err(|error| {
+        Error::TunnelNotFunctional(format!(
+            "failed to ping {PING_DESTINATION} with MTU {CUSTOM_MTU}: {error}"
+        ))
+    })
+}
+
+/// Send TCP and UDP payloads just below, at, and above the tunnel MTU through a tunnel to the
+/// local custom WireGuard relay, and receive them on the host. Oversized UDP datagrams must be
+/// fragmented and reassembled. This test fails if any payload is not received intact.
+#[test_function]
+pub async fn test_large_payload_through_tunnel(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    // Default MTU of the WireGuard tunnel interface
+    const TUNNEL_MTU: usize = 1380;
+    const IPV4_UDP_HEADER_SIZE: usize = 20 + 8;
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let udp_destination = SocketAddr::new(IpAddr::V4(CUSTOM_TUN_REMOTE_TUN_ADDR), 1337);
+    let tcp_destination = SocketAddr::new(IpAddr::V4(CUSTOM_TUN_REMOTE_TUN_ADDR), 1338);
+    let bind_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+
+    let max_udp_payload = TUNNEL_MTU - IPV4_UDP_HEADER_SIZE;
+    let payload_sizes = [
+        max_udp_payload - 1,
+        max_udp_payload,
+        max_udp_payload + 1,
+        4 * TUNNEL_MTU,
+    ];
+
+    let relay_settings = RelaySettingsUpdate::CustomTunnelEndpoint(CustomTunnelEndpoint {
+        host: CUSTOM_TUN_REMOTE_REAL_ADDR.to_string(),
+        config: ConnectionConfig::Wireguard(helpers::local_wg_relay_config()),
+    });
+
+    update_relay_settings(&mut mullvad_client, relay_settings)
+        .await
+        .expect("failed to update relay settings");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    let udp_socket = UdpSocket::bind(udp_destination)
+        .await
+        .map_err(|error| Error::Other(format!("Failed to bind UDP socket: {error}")))?;
+    let tcp_listener = TcpListener::bind(tcp_destination)
+        .await
+        .map_err(|error| Error::Other(format!("Failed to bind TCP listener: {error}")))?;
+
+    for size in payload_sizes {
+        let payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+
+        log::info!("Sending {size} byte UDP payload");
+
+        rpc.send_udp_payload(
+            Some(Interface::Tunnel),
+            bind_addr,
+            udp_destination,
+            payload.clone(),
+        )
+        .await?;
+
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let (len, _) = timeout(RECEIVE_TIMEOUT, udp_socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_error| Error::Other(format!("No {size} byte UDP payload received")))?
+            .map_err(|error| Error::Other(format!("Failed to receive UDP payload: {error}")))?;
+        assert_eq!(
+            &buffer[..len],
+            &payload[..],
+            "{size} byte UDP payload was not received intact"
+        );
+
+        log::info!("Sending {size} byte TCP payload");
+
+        let receive = async {
+            let (mut stream, _) = tcp_listener.accept().await?;
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await?;
+            Ok::<_, std::io::Error>(received)
+        };
+        let (send_result, receive_result) = tokio::join!(
+            rpc.send_tcp_payload(
+                Some(Interface::Tunnel),
+                bind_addr,
+                tcp_destination,
+                payload.clone()
+            ),
+            timeout(RECEIVE_TIMEOUT, receive),
+        );
+        send_result?;
+        let received = receive_result
+            .map_err(|_error| Error::Other(format!("No {size} byte TCP payload received")))?
+            .map_err(|error| Error::Other(format!("Failed to receive TCP payload: {error}")))?;
+        assert_eq!(
+            received, payload,
+            "{size} byte TCP payload was not received intact"
+        );
+    }
+
+    disconnect_and_wait(&mut mullvad_client).await?;
+
+    Ok(())
+}
+
+/// Verify that HTTPS requests succeed inside the tunnel, but not outside of it, in the connected
+/// state. This test fails if:
+///
+/// * An HTTPS request sent through the tunnel fails or returns a non-success status code.
+/// * An HTTPS request sent from the non-tunnel interface succeeds.
+#[test_function]
+pub async fn test_https_in_tunnel(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let url = format!("https://am.i.{}/json", TEST_CONFIG.mullvad_host);
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    log::info!("Sending HTTPS request inside the tunnel");
+
+    let response = rpc
+        .http_get(Some(Interface::Tunnel), &url, DEFAULT_HTTP_TIMEOUT)
+        .await?;
+    assert!(
+        response.success(),
+        "unexpected status code in tunnel: {}",
+        response.status
+    );
+
+    log::info!("Sending HTTPS request outside the tunnel");
+
+    let result = rpc
+        .http_get(Some(Interface::NonTunnel), &url, DEFAULT_HTTP_TIMEOUT)
+        .await;
+    // Only a failure to connect counts, since the request is bound to the non-tunnel interface
+    assert!(
+        matches!(
+            result,
+            Err(test_rpc::Error::HttpRequest(_) | test_rpc::Error::Timeout)
+        ),
+        "HTTPS request outside the tunnel did not fail to connect: {result:?}"
+    );
+
+    disconnect_and_wait(&mut mullvad_client).await?;
+
+    Ok(())
+}
+
+/// Verify that the daemon does not always pick the same relay when the location constraint is
+/// set to "any". This test connects and disconnects several times and fails if the same relay
+/// is used for every connection.
+///
+/// # Limitations
+///
+/// This test is probabilistic. If the relay selector picks uniformly among `N` relays, the
+/// probability of a false failure is `(1/N)^(RELAY_ROTATION_ATTEMPTS - 1)`, which is negligible
+/// for any realistic relay list, but the selector is weighted, so this is only approximate.
+#[test_function]
+pub async fn test_relay_rotation(
+    _: TestContext,
+    _rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const RELAY_ROTATION_ATTEMPTS: usize = 6;
 
     let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
-        location: Some(Constraint::Only(LocationConstraint::Location(
-            GeographicLocationConstraint::Country("se".to_string()),
-        ))),
-        wireguard_constraints: Some(WireguardConstraints {
-            use_multihop: true,
-            entry_location: Constraint::Only(LocationConstraint::Location(
-                GeographicLocationConstraint::Country("se".to_string()),
-            )),
-            ..Default::default()
-        }),
+        location: Some(Constraint::Any),
         ..Default::default()
     });
 
     update_relay_settings(&mut mullvad_client, relay_settings)
         .await
-        .expect("Failed to update relay settings");
+        .expect("failed to update relay settings");
 
-    connect_and_wait(&mut mullvad_client).await?;
+    let mut relays = vec![];
 
-    // Send traffic through the tunnel to sanity check that the internet is reachable.
-    log::info!("Test whether tunnel traffic works");
-    let geoip_lookup = geoip_lookup_with_retries(&rpc).await.unwrap();
-    assert!(geoip_lookup.mullvad_exit_ip, "Exit ip is not from Mullvad");
+    for attempt in 1..=RELAY_ROTATION_ATTEMPTS {
+        connect_and_wait(&mut mullvad_client).await?;
+
+        let relay = match helpers::get_tunnel_state(&mut mullvad_client).await {
+            TunnelState::Connected { endpoint, .. } => endpoint.endpoint.address.ip(),
+            actual => {
+                return Err(Error::DaemonError(format!(
+                    "expected connected state, got {actual:?}"
+                )))
+            }
+        };
+        log::debug!("Attempt {attempt}: connected to {relay}");
+
+        disconnect_and_wait(&mut mullvad_client).await?;
+
+        if !relays.contains(&relay) {
+            relays.push(relay);
+        }
+        if relays.len() > 1 {
+            return Ok(());
+        }
+    }
+
+    Err(Error::Other(format!(
+        "connected to the same relay {RELAY_ROTATION_ATTEMPTS} times in a row"
+    )))
+}
+
+/// Verify that the daemon uses a relay list placed in its cache. This test replaces the cached
+/// relay list with one that contains a single WireGuard relay, restarts the daemon, and fails
+/// if the daemon lists or connects to any other relay.
+///
+/// The original relay list is restored by fetching it from the API when the test finishes.
+#[test_function]
+pub async fn test_relay_list_override(
+    ctx: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let relay_list = mullvad_client
+        .get_relay_locations(())
+        .await
+        .map_err(|error| Error::DaemonError(format!("Failed to obtain relay list: {error}")))?
+        .into_inner();
+    let mut relay_list = RelayList::try_from(relay_list)
+        .map_err(|error| Error::Other(format!("Failed to convert relay list: {error:?}")))?;
+
+    // Keep only the first active WireGuard relay
+    let mut selected_relay = None;
+    for city in relay_list
+        .countries
+        .iter_mut()
+        .flat_map(|country| country.cities.iter_mut())
+    {
+        city.relays.retain(|relay| {
+            let keep = selected_relay.is_none()
+                && relay.active
+                && matches!(relay.endpoint_data, RelayEndpointData::Wireguard(_));
+            if keep {
+                selected_relay = Some((relay.hostname.clone(), relay.ipv4_addr_in));
+            }
+            keep
+        });
+    }
+    relay_list.countries.retain_mut(|country| {
+        country.cities.retain(|city| !city.relays.is_empty());
+        !country.cities.is_empty()
+    });
+    // Make sure the daemon does not consider the overridden list up to date with the API
+    relay_list.etag = None;
+
+    let (hostname, relay_ip) =
+        selected_relay.ok_or_else(|| Error::Other("No active WireGuard relay found".to_owned()))?;
+    log::info!("Restricting relay list to {hostname}");
+
+    let relay_list = serde_json::to_string(&relay_list)
+        .map_err(|error| Error::Other(format!("Failed to serialize relay list: {error}")))?;
+
+    drop(mullvad_client);
+
+    rpc.set_mullvad_daemon_service_state(false).await?;
+    rpc.set_relay_list_override(Some(relay_list)).await?;
+
+    // Every error below must be returned rather than panic, so that the override is always removed
+    let result = async {
+        rpc.set_mullvad_daemon_service_state(true).await?;
+
+        // NOTE: Need to create a new `mullvad_client` here after the restart otherwise we can't
+        // communicate with the daemon
+        let mut mullvad_client = ctx.rpc_provider.new_client().await;
+        helpers::RELAY_LIST_CACHE.invalidate();
+
+        let relays = helpers::filter_relays(&mut mullvad_client, |relay| relay.active).await?;
+        let hostnames: Vec<_> = relays.into_iter().map(|relay| relay.hostname).collect();
+        if hostnames != [hostname.as_str()] {
+            return Err(Error::Other(format!(
+                "expected only {hostname} in the relay list, found {hostnames:?}"
+            )));
+        }
+
+        let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
+            location: Some(Constraint::Any),
+            tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
+            ..Default::default()
+        });
+
+        update_relay_settings(&mut mullvad_client, relay_settings).await?;
+
+        connect_and_wait(&mut mullvad_client).await?;
+
+        let state = mullvad_client
+            .get_tunnel_state(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to get tunnel state: {error}")))?
+            .into_inner();
+        disconnect_and_wait(&mut mullvad_client).await?;
+
+        match TunnelState::try_from(state) {
+            Ok(TunnelState::Connected { endpoint, .. })
+                if endpoint.endpoint.address.ip() == IpAddr::V4(relay_ip) =>
+            {
+                Ok(())
+            }
+            actual => Err(Error::Other(format!(
+                "expected to be connected to {hostname}, got {actual:?}"
+            ))),
+        }
+    }
+    .await;
+
+    log::info!("Restoring relay list");
+    rpc.set_relay_list_override(None).await?;
+    let mut mullvad_client = ctx.rpc_provider.new_client().await;
+    helpers::ensure_updated_relay_list(&mut mullvad_client).await;
+
+    result
+}
+
+/// Verify that the relay selector respects the IP version constraint for WireGuard. This test
+/// connects using IPv4 and then IPv6, and fails if the address family of the relay endpoint does
+/// not match the constraint.
+///
+/// # Limitations
+///
+/// The IPv6 case is skipped if there is no active WireGuard relay with an IPv6 address, or if
+/// it is not possible to connect over IPv6, e.g. because the guest lacks IPv6 connectivity.
+#[test_function]
+pub async fn test_ip_version_constraint(
+    _: TestContext,
+    _rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    for ip_version in [IpVersion::V4, IpVersion::V6] {
+        log::info!("Connecting using {ip_version}");
+
+        if ip_version == IpVersion::V6 {
+            let ipv6_relays = helpers::filter_relays(&mut mullvad_client, |relay| {
+                relay.active
+                    && relay.endpoint_type == i32::from(types::relay::RelayType::Wireguard)
+                    && !relay.ipv6_addr_in.is_empty()
+            })
+            .await?;
+            if ipv6_relays.is_empty() {
+                log::warn!("Skipping IPv6: no WireGuard relay has an IPv6 address");
+                break;
+            }
+        }
+
+        let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
+            location: Some(Constraint::Any),
+            tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
+            wireguard_constraints: Some(WireguardConstraints {
+                ip_version: Constraint::Only(ip_version),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        update_relay_settings(&mut mullvad_client, relay_settings)
+            .await
+            .expect("failed to update relay settings");
+
+        match connect_and_wait(&mut mullvad_client).await {
+            Ok(()) => (),
+            Err(error) if ip_version == IpVersion::V6 => {
+                log::warn!("Skipping IPv6: failed to connect: {error}");
+                log::warn!("The guest may lack IPv6 connectivity");
+                disconnect_and_wait(&mut mullvad_client).await?;
+                break;
+            }
+            Err(error) => return Err(error),
+        }
+
+        let endpoint = match helpers::get_tunnel_state(&mut mullvad_client).await {
+            TunnelState::Connected { endpoint, .. } => endpoint.endpoint.address,
+            actual => {
+                return Err(Error::DaemonError(format!(
+                    "expected connected state, got {actual:?}"
+                )))
+            }
+        };
+
+        disconnect_and_wait(&mut mullvad_client).await?;
+
+        let matches_constraint = match ip_version {
+            IpVersion::V4 => endpoint.is_ipv4(),
+            IpVersion::V6 => endpoint.is_ipv6(),
+        };
+        assert!(
+            matches_constraint,
+            "expected an {ip_version} endpoint, got {endpoint}"
+        );
+    }
 
     Ok(())
 }
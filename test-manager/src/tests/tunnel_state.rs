@@ -1,10 +1,13 @@
+use super::config::TEST_CONFIG;
 use super::helpers::{
     self, connect_and_wait, disconnect_and_wait, geoip_lookup_with_retries, get_tunnel_state,
-    send_guest_probes, unreachable_wireguard_tunnel, update_relay_settings, wait_for_tunnel_state,
+    ping_with_timeout, send_guest_probes, unreachable_wireguard_tunnel, update_relay_settings,
+    wait_for_tunnel_state, AbortOnDrop, LeakTest,
 };
 use super::{ui, Error, TestContext};
 use crate::assert_tunnel_state;
-use crate::vm::network::DUMMY_LAN_INTERFACE_IP;
+use crate::network_monitor::{start_packet_monitor, MonitorOptions};
+use crate::vm::network::{self, DUMMY_LAN_INTERFACE_IP, NON_TUN_GATEWAY};
 
 use mullvad_management_interface::{types, ManagementServiceClient};
 use mullvad_types::relay_constraints::GeographicLocationConstraint;
@@ -15,10 +18,19 @@ use mullvad_types::{
     },
     states::TunnelState,
 };
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use talpid_types::net::{Endpoint, TransportProtocol, TunnelEndpoint, TunnelType};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
+use talpid_types::{
+    net::{Endpoint, TransportProtocol, TunnelEndpoint, TunnelType},
+    tunnel::{ErrorStateCause, ParameterGenerationError},
+};
 use test_macro::test_function;
-use test_rpc::{Interface, ServiceClient};
+use test_rpc::{
+    logging::Output, mullvad_daemon::MANAGEMENT_INTERFACE_EOF_MESSAGE, AddressFamily, Interface,
+    PingOptions, ServiceClient,
+};
 
 /// Verify that outgoing TCP, UDP, and ICMP packets can be observed
 /// in the disconnected state. The purpose is mostly to rule prevent
@@ -74,7 +86,7 @@ pub async fn test_disconnected_state(
 /// and one private IP address. They detect basic leaks but
 /// do not guarantee close conformity with the security
 /// document.
-#[test_function]
+#[test_function(security_critical = true)]
 pub async fn test_connecting_state(
     _: TestContext,
     rpc: ServiceClient,
@@ -82,8 +94,8 @@ pub async fn test_connecting_state(
 ) -> Result<(), Error> {
     let inet_destination = "1.1.1.1:1337".parse().unwrap();
     let lan_destination: SocketAddr = SocketAddr::new(IpAddr::V4(DUMMY_LAN_INTERFACE_IP), 1337);
-    let inet_dns = "1.1.1.1:53".parse().unwrap();
-    let lan_dns: SocketAddr = SocketAddr::new(IpAddr::V4(DUMMY_LAN_INTERFACE_IP), 53);
+    let inet_dns = "1.0.0.1:53".parse().unwrap();
+    let lan_dns: SocketAddr = SocketAddr::new(IpAddr::V4(NON_TUN_GATEWAY), 53);
 
     log::info!("Verify tunnel state: disconnected");
     assert_tunnel_state!(&mut mullvad_client, TunnelState::Disconnected);
@@ -119,30 +131,14 @@ pub async fn test_connecting_state(
     // Leak test
     //
 
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (inet)"
-    );
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), lan_destination)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (lan)"
-    );
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_dns)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (DNS, inet)"
-    );
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), lan_dns)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (DNS, lan)"
-    );
+    LeakTest::new(Some(Interface::NonTunnel))
+        .blocked(inet_destination)
+        .blocked(lan_destination)
+        .blocked(inet_dns)
+        .blocked(lan_dns)
+        .run(&rpc)
+        .await?
+        .into_result()?;
 
     assert_tunnel_state!(&mut mullvad_client, TunnelState::Connecting { .. });
 
@@ -168,7 +164,7 @@ pub async fn test_connecting_state(
 
 /// Try to produce leaks in the error state. Refer to the
 /// `test_connecting_state` documentation for details.
-#[test_function]
+#[test_function(security_critical = true)]
 pub async fn test_error_state(
     _: TestContext,
     rpc: ServiceClient,
@@ -176,8 +172,8 @@ pub async fn test_error_state(
 ) -> Result<(), Error> {
     let inet_destination = "1.1.1.1:1337".parse().unwrap();
     let lan_destination: SocketAddr = SocketAddr::new(IpAddr::V4(DUMMY_LAN_INTERFACE_IP), 1337);
-    let inet_dns = "1.1.1.1:53".parse().unwrap();
-    let lan_dns: SocketAddr = SocketAddr::new(IpAddr::V4(DUMMY_LAN_INTERFACE_IP), 53);
+    let inet_dns = "1.0.0.1:53".parse().unwrap();
+    let lan_dns: SocketAddr = SocketAddr::new(IpAddr::V4(NON_TUN_GATEWAY), 53);
 
     log::info!("Verify tunnel state: disconnected");
     assert_tunnel_state!(&mut mullvad_client, TunnelState::Disconnected);
@@ -205,36 +201,26 @@ pub async fn test_error_state(
         .expect("failed to update relay settings");
 
     let _ = connect_and_wait(&mut mullvad_client).await;
-    assert_tunnel_state!(&mut mullvad_client, TunnelState::Error { .. });
+    helpers::wait_for_error_state(mullvad_client.clone(), |cause| {
+        matches!(
+            cause,
+            ErrorStateCause::TunnelParameterError(ParameterGenerationError::NoMatchingRelay)
+        )
+    })
+    .await?;
 
     //
     // Leak test
     //
 
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (inet)"
-    );
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), lan_destination)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (lan)"
-    );
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_dns)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (DNS, inet)"
-    );
-    assert!(
-        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), lan_dns)
-            .await?
-            .none(),
-        "observed unexpected outgoing packets (DNS, lan)"
-    );
+    LeakTest::new(Some(Interface::NonTunnel))
+        .blocked(inet_destination)
+        .blocked(lan_destination)
+        .blocked(inet_dns)
+        .blocked(lan_dns)
+        .run(&rpc)
+        .await?
+        .into_result()?;
 
     //
     // Disconnect
@@ -259,17 +245,21 @@ pub async fn test_error_state(
 /// Connect to a single relay and verify that:
 /// * Traffic can be sent and received in the tunnel.
 ///   This is done by pinging a single public IP address
-///   and failing if there is no response.
+///   and failing if too many of the pings are lost.
 /// * The correct relay is used.
 /// * Leaks outside the tunnel are blocked. Refer to the
 ///   `test_connecting_state` documentation for details.
+/// * No errors are logged by the daemon.
 #[test_function]
 pub async fn test_connected_state(
     _: TestContext,
     rpc: ServiceClient,
     mut mullvad_client: ManagementServiceClient,
 ) -> Result<(), Error> {
-    let inet_destination = "1.1.1.1:1337".parse().unwrap();
+    const PING_COUNT: u32 = 10;
+    const MAX_PACKET_LOSS: f64 = 0.2;
+
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
 
     //
     // Set relay to use
@@ -281,10 +271,8 @@ pub async fn test_connected_state(
         relay.active && relay.endpoint_type == i32::from(types::relay::RelayType::Wireguard)
     };
 
-    let relay = helpers::filter_relays(&mut mullvad_client, relay_filter)
-        .await?
-        .pop()
-        .unwrap();
+    let (relay, expected_relay_ip) =
+        helpers::select_relay(&mut mullvad_client, relay_filter).await?;
 
     let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
         location: helpers::into_constraint(&relay),
@@ -326,7 +314,12 @@ pub async fn test_connected_state(
                 },
             ..
         } => {
-            assert_eq!(*addr.ip(), relay.ipv4_addr_in.parse::<Ipv4Addr>().unwrap());
+            assert_eq!(
+                *addr.ip(),
+                expected_relay_ip,
+                "endpoint does not match relay {}",
+                relay.hostname,
+            );
         }
         actual => panic!("unexpected tunnel state: {:?}", actual),
     }
@@ -350,7 +343,721 @@ pub async fn test_connected_state(
     assert!(geoip_lookup.mullvad_exit_ip, "Exit ip is not from Mullvad");
     assert_eq!(geoip_lookup.mullvad_exit_ip_hostname, relay.hostname);
 
+    log::info!("Test the packet loss in the tunnel");
+    let ping_summary = helpers::ping_with_options(
+        &rpc,
+        inet_destination.ip(),
+        Some(Interface::Tunnel),
+        PingOptions {
+            count: PING_COUNT,
+            interval: Duration::from_millis(200),
+            ..Default::default()
+        },
+    )
+    .await?;
+    log::debug!("Ping statistics: {ping_summary:?}");
+    assert!(
+        ping_summary.loss() < MAX_PACKET_LOSS,
+        "too many pings were lost: {}/{}",
+        ping_summary.sent - ping_summary.received.min(ping_summary.sent),
+        ping_summary.sent,
+    );
+
+    disconnect_and_wait(&mut mullvad_client).await?;
+
+    helpers::assert_no_daemon_errors(&rpc).await?;
+
+    Ok(())
+}
+
+/// Rapidly connect and disconnect `connect_churn_cycles` times, without waiting for the tunnel
+/// state to settle in between. "Always require VPN" is enabled during the churn, so that no
+/// non-tunnel traffic is allowed in any state. This test fails if:
+///
+/// * Outgoing non-tunnel traffic to a public IP is observed during the churn.
+/// * The daemon does not end up in the disconnected state.
+/// * Outgoing traffic is still blocked after "Always require VPN" has been disabled again.
+#[test_function]
+pub async fn test_connect_disconnect_churn(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
+    let bind_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+
+    mullvad_client
+        .set_block_when_disconnected(true)
+        .await
+        .expect("failed to enable block when disconnected");
+
+    //
+    // Begin monitoring outgoing traffic and pinging
+    //
+
+    let monitor = start_packet_monitor(
+        move |packet| packet.destination.ip() == inet_destination.ip(),
+        MonitorOptions::default(),
+    )
+    .await?;
+
+    let ping_rpc = rpc.clone();
+    let abort_on_drop = AbortOnDrop(tokio::spawn(async move {
+        loop {
+            let _ = ping_rpc.send_tcp(None, bind_addr, inet_destination).await;
+            let _ = ping_rpc.send_udp(None, bind_addr, inet_destination).await;
+            let _ = ping_with_timeout(&ping_rpc, inet_destination.ip(), None).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }));
+
+    //
+    // Churn
+    //
+
+    let cycles = TEST_CONFIG.connect_churn_cycles;
+    log::info!("Connecting and disconnecting {cycles} times");
+
+    for cycle in 0..cycles {
+        mullvad_client
+            .connect_tunnel(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("failed to begin connecting: {error}")))?;
+
+        // Vary the delay to interrupt the daemon in different states
+        tokio::time::sleep(Duration::from_millis(50 * (cycle % 5) as u64)).await;
+
+        mullvad_client
+            .disconnect_tunnel(())
+            .await
+            .map_err(|error| {
+                Error::DaemonError(format!("failed to begin disconnecting: {error}"))
+            })?;
+    }
+
+    log::info!("Verify tunnel state: disconnected");
+
+    let state = wait_for_tunnel_state(mullvad_client.clone(), |state| {
+        matches!(state, TunnelState::Disconnected)
+    })
+    .await?;
+    assert!(
+        matches!(state, TunnelState::Disconnected),
+        "unexpected tunnel state: {state:?}",
+    );
+
+    //
+    // Check if any traffic was observed
+    //
+
+    drop(abort_on_drop);
+    let monitor_result = monitor.into_result().await.unwrap();
+    assert_eq!(
+        monitor_result.packets.len(),
+        0,
+        "observed unexpected outgoing packets during churn"
+    );
+
+    //
+    // Verify that no blocking rules remain
+    //
+
+    mullvad_client
+        .set_block_when_disconnected(false)
+        .await
+        .expect("failed to disable block when disconnected");
+
+    log::info!("Test whether outgoing non-tunnel traffic is allowed");
+
+    let detected_probes =
+        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
+    assert!(
+        detected_probes.all(),
+        "did not see (all) outgoing packets to destination: {detected_probes:?}",
+    );
+
+    Ok(())
+}
+
+/// Verify that no traffic leaks while the daemon is not running after it has crashed, i.e. been
+/// killed without being able to clean up. The firewall rules must outlive the daemon process. The
+/// daemon is expected to be restarted by the service manager and connect again. This test fails
+/// if:
+///
+/// * Outgoing non-tunnel traffic to a public IP is observed after the daemon has been killed.
+/// * The daemon does not come back up and reconnect.
+///
+/// # Limitations
+///
+/// This is only tested on Linux and macOS, since crashes are handled differently on Windows.
+#[test_function(security_critical = true, os = "linux,macos")]
+pub async fn test_no_leak_on_daemon_crash(
+    ctx: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const MAX_RECOVERY_ATTEMPTS: usize = 10;
+    const RECOVERY_RETRY_DELAY: Duration = Duration::from_secs(3);
+    const DAEMON_PROCESS: &str = "mullvad-daemon";
+
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
+
+    mullvad_client
+        .set_block_when_disconnected(true)
+        .await
+        .expect("failed to enable block when disconnected");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    //
+    // Kill the daemon and immediately look for leaks
+    //
+
+    log::info!("Killing the daemon");
+
+    rpc.kill_process(DAEMON_PROCESS).await?;
+    drop(mullvad_client);
+
+    log::info!("Test whether outgoing non-tunnel traffic is blocked");
+
+    let detected_probes =
+        send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
+    assert!(
+        detected_probes.none(),
+        "observed unexpected outgoing packets after daemon crash: {detected_probes:?}",
+    );
+
+    //
+    // Wait for the daemon to be restarted and to reconnect
+    //
+
+    log::info!("Waiting for the daemon to recover");
+
+    let mut attempt = 0;
+    let mut mullvad_client = loop {
+        attempt += 1;
+        tokio::time::sleep(RECOVERY_RETRY_DELAY).await;
+
+        let mut mullvad_client = ctx.rpc_provider.new_client().await;
+        match connect_and_wait(&mut mullvad_client).await {
+            Ok(()) => break mullvad_client,
+            Err(error) if attempt < MAX_RECOVERY_ATTEMPTS => {
+                log::debug!("Daemon has not recovered yet: {error}");
+            }
+            Err(error) => return Err(error),
+        }
+    };
+
+    helpers::assert_tunnel_functional(&rpc).await?;
+
+    mullvad_client
+        .set_block_when_disconnected(false)
+        .await
+        .expect("failed to disable block when disconnected");
+
+    Ok(())
+}
+
+/// Verify that no traffic leaves the non-tunnel interface in the blocked state, using the traffic
+/// counters of the interface instead of packet capture. A number of UDP datagrams is sent to a
+/// public IP, first while disconnected and then with "block when disconnected" enabled. This test
+/// fails if:
+///
+/// * The datagrams sent while disconnected are not reflected in the counters. This rules out false
+///   negatives.
+/// * The transmitted packet counter grows by more than a few packets while blocked, compared to
+///   the background traffic counted over an equally long period without sending any datagrams.
+///
+/// # Limitations
+///
+/// Background traffic, such as ARP and DHCP, varies over time, so `MAX_EXTRA_PACKETS` packets in
+/// excess of the baseline are tolerated. Smaller leaks may therefore go unnoticed.
+#[test_function(security_critical = true)]
+pub async fn test_no_leak_interface_counters(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const DATAGRAM_COUNT: u64 = 50;
+    /// Number of packets sent while blocked that may exceed the background traffic
+    const MAX_EXTRA_PACKETS: u64 = 5;
+
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
+    let bind_addr = SocketAddr::new(
+        rpc.get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
+            .await?,
+        0,
+    );
+
+    // Return the number of transmitted packets and the time it took to send the datagrams
+    let sent_packets = |rpc: ServiceClient| async move {
+        let before = rpc.get_interface_stats(Interface::NonTunnel).await?;
+        let started = Instant::now();
+        for _ in 0..DATAGRAM_COUNT {
+            let _ = rpc
+                .send_udp(Some(Interface::NonTunnel), bind_addr, inet_destination)
+                .await;
+        }
+        let elapsed = started.elapsed();
+        let after = rpc.get_interface_stats(Interface::NonTunnel).await?;
+        Ok::<_, Error>((after.tx_packets.saturating_sub(before.tx_packets), elapsed))
+    };
+    let background_packets = |rpc: ServiceClient, duration: Duration| async move {
+        let before = rpc.get_interface_stats(Interface::NonTunnel).await?;
+        tokio::time::sleep(duration).await;
+        let after = rpc.get_interface_stats(Interface::NonTunnel).await?;
+        Ok::<_, Error>(after.tx_packets.saturating_sub(before.tx_packets))
+    };
+
+    log::info!("Verify tunnel state: disconnected");
+    assert_tunnel_state!(&mut mullvad_client, TunnelState::Disconnected);
+
+    let (disconnected_packets, _) = sent_packets(rpc.clone()).await?;
+    log::debug!("Sent {disconnected_packets} packets while disconnected");
+    if disconnected_packets < DATAGRAM_COUNT {
+        return Err(Error::Other(format!(
+            "Expected at least {DATAGRAM_COUNT} sent packets while disconnected, counted {disconnected_packets}"
+        )));
+    }
+
+    log::info!("Enable block when disconnected");
+
+    mullvad_client
+        .set_block_when_disconnected(true)
+        .await
+        .expect("failed to enable block when disconnected");
+
+    let result = async {
+        let (blocked_packets, elapsed) = sent_packets(rpc.clone()).await?;
+        let background_packets = background_packets(rpc.clone(), elapsed).await?;
+        Ok::<_, Error>((blocked_packets, background_packets))
+    }
+    .await;
+
+    mullvad_client
+        .set_block_when_disconnected(false)
+        .await
+        .expect("failed to disable block when disconnected");
+
+    let (blocked_packets, background_packets) = result?;
+    log::debug!(
+        "Sent {blocked_packets} packets while blocked, and {background_packets} background packets"
+    );
+    if blocked_packets > background_packets + MAX_EXTRA_PACKETS {
+        return Err(Error::Other(format!(
+            "Counted {blocked_packets} sent packets while blocked, expected at most {}",
+            background_packets + MAX_EXTRA_PACKETS
+        )));
+    }
+
+    Ok(())
+}
+
+/// Verify that the daemon keeps the tunnel up, or cleanly reconnects, when the DHCP lease of the
+/// non-tunnel interface is renewed while connected. This exercises the handling of interface
+/// address changes. This test fails if:
+///
+/// * Outgoing non-tunnel traffic to anything but the relay or a DHCP server is observed during
+///   the renewal.
+/// * The tunnel is not connected and working after the renewal.
+///
+/// # Limitations
+///
+/// This is only tested on Linux, and requires either `networkctl` or `dhclient` on the guest.
+#[test_function(security_critical = true, os = "linux")]
+pub async fn test_connected_state_after_dhcp_renewal(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const DHCP_SERVER_PORT: u16 = 67;
+    const RENEWAL_MONITOR_TIMEOUT: Duration = Duration::from_secs(15);
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    let relay_ip = match get_tunnel_state(&mut mullvad_client).await {
+        TunnelState::Connected { endpoint, .. } => endpoint.endpoint.address.ip(),
+        actual => {
+            return Err(Error::DaemonError(format!(
+                "expected connected state, got {actual:?}"
+            )))
+        }
+    };
+    let guest_ip = rpc
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
+        .await?;
+    let interface = rpc.get_interface_name(Interface::NonTunnel).await?;
+
+    //
+    // Renew the lease while looking for leaks
+    //
+
+    let monitor = start_packet_monitor(
+        move |packet| {
+            packet.source.ip() == guest_ip
+                && packet.destination.ip() != relay_ip
+                && packet.destination.port() != DHCP_SERVER_PORT
+        },
+        MonitorOptions {
+            direction: Some(crate::network_monitor::Direction::In),
+            timeout: Some(RENEWAL_MONITOR_TIMEOUT),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    log::info!("Renewing DHCP lease on {interface}");
+
+    renew_dhcp_lease(&rpc, &interface).await?;
+
+    let monitor_result = monitor.wait().await.unwrap();
+    assert!(
+        monitor_result.packets.is_empty(),
+        "observed unexpected outgoing packets during DHCP renewal: {:?}",
+        monitor_result.packets
+    );
+
+    //
+    // Verify that the tunnel is still working
+    //
+
+    let new_guest_ip = rpc
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
+        .await?;
+    if new_guest_ip != guest_ip {
+        log::debug!("Guest IP changed from {guest_ip} to {new_guest_ip}");
+    }
+
+    wait_for_tunnel_state(mullvad_client, |state| {
+        matches!(state, TunnelState::Connected { .. })
+    })
+    .await?;
+
+    helpers::assert_tunnel_functional(&rpc).await
+}
+
+/// Force a DHCP renewal on `interface`, using `networkctl` or, if that fails, `dhclient`.
+async fn renew_dhcp_lease(rpc: &ServiceClient, interface: &str) -> Result<(), Error> {
+    let networkctl = rpc
+        .exec("networkctl", ["renew", interface].into_iter(), None)
+        .await;
+    match networkctl {
+        Ok(result) if result.success() => return Ok(()),
+        Ok(result) => log::debug!(
+            "networkctl failed: {}",
+            String::from_utf8_lossy(&result.stderr)
+        ),
+        Err(error) => log::debug!("Failed to run networkctl: {error}"),
+    }
+
+    // `dhclient` may wait for a long time for a lease
+    let dhclient = rpc
+        .exec(
+            "dhclient",
+            ["-1", interface].into_iter(),
+            Some(Duration::from_secs(90)),
+        )
+        .await?;
+    if dhclient.success() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "failed to renew DHCP lease: {}",
+            String::from_utf8_lossy(&dhclient.stderr)
+        )))
+    }
+}
+
+/// Verify that the daemon remains usable if the management interface connection is closed while
+/// requests are in flight. Dropping the client closes the forwarded connection, which makes the
+/// test runner tear down its daemon socket and wait for a new client. This test fails if:
+///
+/// * The test runner does not report that it received the EOF.
+/// * The daemon does not respond to a new client.
+/// * The daemon is not in a consistent tunnel state, or cannot connect and disconnect afterwards.
+#[test_function]
+pub async fn test_management_interface_disconnect(
+    ctx: TestContext,
+    rpc: ServiceClient,
+    mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    //
+    // Start some requests and close the connection while they are in flight
+    //
+
+    let mut events_client = mullvad_client.clone();
+    let events_task = AbortOnDrop(tokio::spawn(async move {
+        if let Ok(events) = events_client.events_listen(()).await {
+            let mut events = events.into_inner();
+            while let Some(Ok(event)) = events.message().await.transpose() {
+                log::debug!("Received daemon event: {event:?}");
+            }
+        }
+    }));
+
+    let mut connect_client = mullvad_client.clone();
+    let connect_task = AbortOnDrop(tokio::spawn(async move {
+        let _ = connect_client.connect_tunnel(()).await;
+    }));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    log::info!("Closing the management interface connection");
+
+    drop(events_task);
+    drop(connect_task);
+    drop(mullvad_client);
+
+    //
+    // Reconnect and verify that the daemon is in a consistent state
+    //
+
+    log::info!("Reconnecting to the daemon");
+
+    let mut mullvad_client = ctx.rpc_provider.new_client().await;
+
+    mullvad_client
+        .get_settings(())
+        .await
+        .map_err(|error| Error::DaemonError(format!("Failed to get settings: {error}")))?;
+
+    // The new connection is only accepted once the old one has been torn down
+    let runner_output = rpc.try_poll_output().await?;
+    let saw_eof = runner_output.iter().any(|output| match output {
+        Output::Info(message) => message.contains(MANAGEMENT_INTERFACE_EOF_MESSAGE),
+        _ => false,
+    });
+    if !saw_eof {
+        return Err(Error::Other(format!(
+            "test runner did not close the connection on EOF: {runner_output:?}"
+        )));
+    }
+
+    let state = wait_for_tunnel_state(mullvad_client.clone(), |state| {
+        matches!(
+            state,
+            TunnelState::Connected { .. } | TunnelState::Disconnected | TunnelState::Error(..)
+        )
+    })
+    .await?;
+    if let TunnelState::Error(error_state) = state {
+        return Err(Error::DaemonError(format!(
+            "daemon entered the error state: {error_state:?}"
+        )));
+    }
+
+    connect_and_wait(&mut mullvad_client).await?;
+    helpers::assert_tunnel_functional(&rpc).await?;
     disconnect_and_wait(&mut mullvad_client).await?;
 
     Ok(())
 }
+
+/// Verify that the tunnel stays up when the guest clock jumps backwards while connected.
+/// WireGuard handshakes use monotonic timers, but the daemon relies on the wall clock for things
+/// like TLS and account expiry. This test fails if:
+///
+/// * Outgoing non-tunnel traffic to anything but the relay is observed after the clock jump.
+/// * The daemon leaves the connected state or is logged out.
+/// * The tunnel is not working after the clock jump.
+///
+/// The guest clock is set to the time of the host when the test finishes.
+#[test_function]
+pub async fn test_clock_jump(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    const CLOCK_JUMP: Duration = Duration::from_secs(60 * 60);
+    const MONITOR_TIMEOUT: Duration = Duration::from_secs(15);
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    let relay_ip = match get_tunnel_state(&mut mullvad_client).await {
+        TunnelState::Connected { endpoint, .. } => endpoint.endpoint.address.ip(),
+        actual => {
+            return Err(Error::DaemonError(format!(
+                "expected connected state, got {actual:?}"
+            )))
+        }
+    };
+    let guest_ip = rpc
+        .get_interface_ip(Interface::NonTunnel, AddressFamily::Ipv4)
+        .await?;
+
+    let monitor = start_packet_monitor(
+        move |packet| packet.source.ip() == guest_ip && packet.destination.ip() != relay_ip,
+        MonitorOptions {
+            direction: Some(crate::network_monitor::Direction::In),
+            timeout: Some(MONITOR_TIMEOUT),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Time synchronization would otherwise undo the clock jump
+    rpc.set_time_sync(false).await?;
+
+    log::info!("Moving the guest clock back by {CLOCK_JUMP:?}");
+
+    let result = async {
+        let guest_time = rpc.get_system_time().await?;
+        rpc.set_system_time(guest_time - CLOCK_JUMP).await?;
+
+        // Make sure that the change was not undone by time synchronization
+        let new_guest_time = rpc.get_system_time().await?;
+        if new_guest_time >= guest_time {
+            return Err(Error::Other(format!(
+                "guest clock was not moved back: {new_guest_time:?}"
+            )));
+        }
+
+        let monitor_result = monitor.wait().await.unwrap();
+        if !monitor_result.packets.is_empty() {
+            return Err(Error::Other(format!(
+                "observed unexpected outgoing packets after the clock jump: {:?}",
+                monitor_result.packets
+            )));
+        }
+
+        match get_tunnel_state(&mut mullvad_client).await {
+            TunnelState::Connected { .. } => (),
+            actual => {
+                return Err(Error::DaemonError(format!(
+                    "expected connected state after the clock jump, got {actual:?}"
+                )))
+            }
+        }
+
+        let device_state = mullvad_client
+            .get_device(())
+            .await
+            .map_err(|error| Error::DaemonError(format!("Failed to get device: {error}")))?
+            .into_inner();
+        if device_state.state != i32::from(types::device_state::State::LoggedIn) {
+            return Err(Error::DaemonError(format!(
+                "expected to be logged in after the clock jump, got {device_state:?}"
+            )));
+        }
+
+        helpers::assert_tunnel_functional(&rpc).await
+    }
+    .await;
+
+    log::info!("Restoring the guest clock");
+    let restore_result = rpc.set_system_time(SystemTime::now()).await;
+    rpc.set_time_sync(true).await?;
+    restore_result?;
+
+    result
+}
+
+/// Verify that the daemon recovers without leaking when the relay it is connected to becomes
+/// unreachable. The relay is blocked on the host while connected, and the daemon must then
+/// either connect to a different relay or enter a blocking error state. This test fails if:
+///
+/// * Outgoing non-tunnel traffic is observed while the relay is blocked.
+/// * The daemon keeps using the blocked relay.
+/// * The tunnel is not working after the block is lifted.
+///
+/// # Limitations
+///
+/// Blocking relays is only supported when the test manager runs on Linux. On other hosts, the test
+/// is reported as skipped.
+#[test_function]
+pub async fn test_relay_failover(
+    _: TestContext,
+    rpc: ServiceClient,
+    mut mullvad_client: ManagementServiceClient,
+) -> Result<(), Error> {
+    if cfg!(target_os = "macos") {
+        return Err(Error::Skipped(
+            "relays cannot be blocked on macOS hosts".to_string(),
+        ));
+    }
+
+    let inet_destination: SocketAddr = "1.1.1.1:1337".parse().unwrap();
+
+    let relay_settings = RelaySettingsUpdate::Normal(RelayConstraintsUpdate {
+        location: Some(Constraint::Any),
+        tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
+        ..Default::default()
+    });
+
+    update_relay_settings(&mut mullvad_client, relay_settings)
+        .await
+        .expect("failed to update relay settings");
+
+    connect_and_wait(&mut mullvad_client).await?;
+
+    let relay_ip = match get_tunnel_state(&mut mullvad_client).await {
+        TunnelState::Connected { endpoint, .. } => endpoint.endpoint.address.ip(),
+        actual => {
+            return Err(Error::DaemonError(format!(
+                "expected connected state, got {actual:?}"
+            )))
+        }
+    };
+
+    log::info!("Blocking relay {relay_ip}");
+
+    network::block_host(relay_ip)
+        .await
+        .map_err(|error| Error::Other(format!("Failed to block relay: {error}")))?;
+
+    let result = async {
+        let probes =
+            send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
+        if !probes.none() {
+            return Err(Error::Other(format!(
+                "observed unexpected outgoing packets after blocking the relay: {probes:?}"
+            )));
+        }
+
+        log::info!("Waiting for the daemon to stop using {relay_ip}");
+
+        let new_state = wait_for_tunnel_state(mullvad_client.clone(), |state| match state {
+            TunnelState::Connected { endpoint, .. } => endpoint.endpoint.address.ip() != relay_ip,
+            TunnelState::Error(_) => true,
+            _ => false,
+        })
+        .await?;
+
+        match &new_state {
+            TunnelState::Connected { endpoint, .. } => {
+                log::info!("Failed over to {}", endpoint.endpoint.address.ip())
+            }
+            TunnelState::Error(error_state) if error_state.is_blocking() => {
+                log::info!("Entered a blocking error state: {error_state:?}")
+            }
+            actual => {
+                return Err(Error::DaemonError(format!(
+                    "expected blocking error state, got {actual:?}"
+                )))
+            }
+        }
+
+        let probes =
+            send_guest_probes(rpc.clone(), Some(Interface::NonTunnel), inet_destination).await?;
+        if !probes.none() {
+            return Err(Error::Other(format!(
+                "observed unexpected outgoing packets after relay failover: {probes:?}"
+            )));
+        }
+
+        Ok(new_state)
+    }
+    .await;
+
+    log::info!("Unblocking relay {relay_ip}");
+
+    network::unblock_hosts()
+        .await
+        .map_err(|error| Error::Other(format!("Failed to unblock relay: {error}")))?;
+
+    if let TunnelState::Error(_) = result? {
+        connect_and_wait(&mut mullvad_client).await?;
+    }
+
+    helpers::assert_tunnel_functional(&rpc).await
+}
@@ -7,10 +7,14 @@ use std::{
     collections::BTreeMap,
     fmt::Debug,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use test_macro::test_function;
 use test_rpc::{meta::Os, ExecResult, ServiceClient};
 
+/// Maximum time that a run of the UI tests may take
+const UI_TESTS_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub async fn run_test<T: AsRef<str> + Debug>(
     rpc: &ServiceClient,
     params: &[T],
@@ -65,6 +69,7 @@ pub async fn run_test_env<
             bin_path.to_string_lossy().into_owned(),
             new_params.into_iter(),
             env,
+            Some(UI_TESTS_TIMEOUT),
         )
         .await?;
 
@@ -0,0 +1,64 @@
+use std::{io, path::Path, time::Duration};
+use tokio::{fs, io::AsyncWriteExt};
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Failed to open timing file")]
+    Open(#[error(source)] io::Error),
+    #[error(display = "Failed to write to timing file")]
+    Write(#[error(source)] io::Error),
+}
+
+/// Time spent in each phase of a test
+#[derive(Default, Clone, Copy)]
+pub struct TestTiming {
+    /// Time spent creating the management interface client and applying the default settings
+    pub setup: Duration,
+    /// Time spent running the test function
+    pub test: Duration,
+    /// Time spent resetting the daemon state after the test
+    pub cleanup: Duration,
+}
+
+/// Logger that outputs the time spent in each test phase as CSV
+pub struct TimingLogger {
+    file: fs::File,
+}
+
+impl TimingLogger {
+    /// Create a new logger and log to `path`. If `path` does not exist, it will be created. If it
+    /// already exists, it is truncated and overwritten.
+    pub async fn new(path: &Path) -> Result<TimingLogger, Error> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .await
+            .map_err(Error::Open)?;
+
+        file.write_all(b"test,setup_ms,test_ms,cleanup_ms\n")
+            .await
+            .map_err(Error::Write)?;
+
+        Ok(TimingLogger { file })
+    }
+
+    pub async fn log_test_timing(
+        &mut self,
+        test_name: &str,
+        timing: TestTiming,
+    ) -> Result<(), Error> {
+        let row = format!(
+            "{test_name},{},{},{}\n",
+            timing.setup.as_millis(),
+            timing.test.as_millis(),
+            timing.cleanup.as_millis()
+        );
+        self.file
+            .write_all(row.as_bytes())
+            .await
+            .map_err(Error::Write)
+    }
+}
@@ -1,5 +1,9 @@
 use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 
+const CONSOLE_LOG_PREFIX: &str = "[console] ";
+const CONSOLE_LOG_LEVEL: log::Level = log::Level::Debug;
+const CONSOLE_BAUD: u32 = 115200;
+
 pub async fn forward_logs<T: AsyncRead + Unpin>(prefix: &str, stdio: T, level: log::Level) {
     let reader = BufReader::new(stdio);
     let mut lines = reader.lines();
@@ -7,3 +11,15 @@ pub async fn forward_logs<T: AsyncRead + Unpin>(prefix: &str, stdio: T, level: l
         log::log!(level, "{prefix}{line}");
     }
 }
+
+/// Forward guest console output from the serial device at `pty_path` to the log
+pub fn forward_console_logs(pty_path: &str) -> Result<(), tokio_serial::Error> {
+    let serial_stream =
+        tokio_serial::SerialStream::open(&tokio_serial::new(pty_path, CONSOLE_BAUD))?;
+    tokio::spawn(forward_logs(
+        CONSOLE_LOG_PREFIX,
+        serial_stream,
+        CONSOLE_LOG_LEVEL,
+    ));
+    Ok(())
+}
@@ -7,6 +7,9 @@ use std::net::IpAddr;
 
 mod logging;
 pub mod network;
+pub mod persist;
+#[cfg(target_os = "linux")]
+mod podman;
 mod provision;
 mod qemu;
 mod ssh;
@@ -20,13 +23,37 @@ pub trait VmInstance {
     /// Path to pty on the host that corresponds to the serial device
     fn get_pty(&self) -> &str;
 
+    /// Path to pty on the host that corresponds to the serial device used for guest console
+    /// output, if one is attached
+    fn get_console_pty(&self) -> Option<&str>;
+
     /// Get initial IP address of guest
     fn get_ip(&self) -> &IpAddr;
 
     /// Wait for VM to destruct
     async fn wait(&mut self);
+
+    /// Save the current state of the VM, so that it can later be restored using `restore`.
+    async fn snapshot(&mut self) -> Result<SnapshotId> {
+        Err(anyhow::anyhow!("Snapshots are not supported for this VM"))
+    }
+
+    /// Restore a snapshot created using `snapshot`. This may restart the VM, in which case the
+    /// pty and IP address may change.
+    async fn restore(&mut self, _id: SnapshotId) -> Result<()> {
+        Err(anyhow::anyhow!("Snapshots are not supported for this VM"))
+    }
+
+    /// Discard a snapshot created using `snapshot`, keeping the current state of the VM.
+    async fn delete_snapshot(&mut self, _id: SnapshotId) -> Result<()> {
+        Err(anyhow::anyhow!("Snapshots are not supported for this VM"))
+    }
 }
 
+/// Identifies a snapshot created by [`VmInstance::snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotId(pub String);
+
 pub async fn set_config(config: &mut ConfigFile, vm_name: &str, vm_config: VmConfig) -> Result<()> {
     config
         .edit(|config| {
@@ -55,10 +82,29 @@ pub async fn run(config: &Config, name: &str) -> Result<Box<dyn VmInstance>> {
         ) as Box<_>,
         #[cfg(not(target_os = "macos"))]
         VmType::Tart => return Err(anyhow::anyhow!("Failed to run Tart VM on a non-macOS host")),
+        #[cfg(target_os = "linux")]
+        VmType::Container => Box::new(
+            podman::run(config, vm_conf)
+                .await
+                .context("Failed to run container")?,
+        ) as Box<_>,
+        #[cfg(not(target_os = "linux"))]
+        VmType::Container => {
+            return Err(anyhow::anyhow!(
+                "Failed to run container on a non-Linux host"
+            ))
+        }
     };
 
     log::info!("Started instance of \"{name}\" vm");
 
+    if let Some(console_pty) = instance.get_console_pty() {
+        log::debug!("Forwarding guest console output from {console_pty}");
+        if let Err(error) = logging::forward_console_logs(console_pty) {
+            log::error!("Failed to open console serial device: {error}");
+        }
+    }
+
     Ok(instance)
 }
 
@@ -69,7 +115,11 @@ pub async fn provision(
     app_manifest: &package::Manifest,
 ) -> Result<String> {
     let vm_config = get_vm_config(config, name)?;
-    provision::provision(vm_config, instance, app_manifest).await
+    let timeout = config
+        .runtime_opts
+        .provision_timeout
+        .unwrap_or(provision::DEFAULT_PROVISION_TIMEOUT);
+    provision::provision(vm_config, instance, app_manifest, timeout).await
 }
 
 pub async fn update_packages(
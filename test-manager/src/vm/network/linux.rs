@@ -3,7 +3,7 @@ use once_cell::sync::Lazy;
 use std::{
     ffi::OsStr,
     io,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     process::Stdio,
     str::FromStr,
 };
@@ -24,6 +24,13 @@ pub const TEST_SUBNET_DHCP_LAST: Ipv4Addr = Ipv4Addr::new(172, 29, 1, 128);
 pub const BRIDGE_NAME: &str = "br-mullvadtest";
 /// TAP interface used by the guest
 pub const TAP_NAME: &str = "tap-mullvadtest";
+/// Prefix of the host side of the veth pair used by a container guest. The PID of the container
+/// is appended to it, so that several containers can be attached at once
+const VETH_NAME_PREFIX: &str = "veth-mt";
+/// Container side of the veth pair used by a container guest
+const CONTAINER_VETH_NAME: &str = "eth0";
+/// Static IP of a container guest. This is outside of the DHCP range
+pub const CONTAINER_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 1, 129);
 
 /// Pingable dummy LAN interface (name)
 pub const DUMMY_LAN_INTERFACE_NAME: &str = "lan-mullvadtest";
@@ -57,6 +64,10 @@ pub const CUSTOM_TUN_REMOTE_REAL_PORT: u16 = 51820;
 pub const CUSTOM_TUN_LOCAL_TUN_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 15, 2);
 /// Tunnel address of the wireguard remote peer as defined in `setup-network.sh`.
 pub const CUSTOM_TUN_REMOTE_TUN_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 15, 1);
+/// IPv6 tunnel address of the wireguard local peer.
+pub const CUSTOM_TUN_LOCAL_TUN_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x15, 0, 0, 0, 0, 0, 2);
+/// IPv6 tunnel address of the wireguard remote peer.
+pub const CUSTOM_TUN_REMOTE_TUN_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x15, 0, 0, 0, 0, 0, 1);
 /// Gateway (and default DNS resolver) of the wireguard tunnel.
 #[allow(dead_code)]
 pub const CUSTOM_TUN_GATEWAY: Ipv4Addr = CUSTOM_TUN_REMOTE_TUN_ADDR;
@@ -298,7 +309,7 @@ ListenPort = {CUSTOM_TUN_REMOTE_REAL_PORT}
 
 [Peer]
 PublicKey = {CUSTOM_TUN_LOCAL_PUBKEY}
-AllowedIPs = {CUSTOM_TUN_LOCAL_TUN_ADDR}
+AllowedIPs = {CUSTOM_TUN_LOCAL_TUN_ADDR}, {CUSTOM_TUN_LOCAL_TUN_ADDR_V6}
 
 "
             )
@@ -323,6 +334,78 @@ AllowedIPs = {CUSTOM_TUN_LOCAL_TUN_ADDR}
     Ok(())
 }
 
+/// Connect the network namespace of the process `pid` to the test network using a veth pair, and
+/// return the IP of the namespace. The address is assigned statically rather than using DHCP.
+pub async fn attach_namespace(pid: u32) -> Result<IpAddr> {
+    // Interface names are limited to 15 characters, which fits any PID
+    let veth_name = format!("{VETH_NAME_PREFIX}{pid}");
+    let pid = pid.to_string();
+
+    log::debug!("Attach network namespace of {pid} to {BRIDGE_NAME} using {veth_name}");
+
+    run_ip_cmd([
+        "link",
+        "add",
+        &veth_name,
+        "type",
+        "veth",
+        "peer",
+        "name",
+        CONTAINER_VETH_NAME,
+        "netns",
+        &pid,
+    ])
+    .await?;
+    run_ip_cmd(["link", "set", &veth_name, "master", BRIDGE_NAME]).await?;
+    run_ip_cmd(["link", "set", &veth_name, "up"]).await?;
+
+    let address = format!("{CONTAINER_IP}/{}", TEST_SUBNET.prefix());
+    run_ip_cmd_in_namespace(&pid, ["addr", "add", "dev", CONTAINER_VETH_NAME, &address]).await?;
+    run_ip_cmd_in_namespace(&pid, ["link", "set", "dev", "lo", "up"]).await?;
+    run_ip_cmd_in_namespace(&pid, ["link", "set", "dev", CONTAINER_VETH_NAME, "up"]).await?;
+    run_ip_cmd_in_namespace(
+        &pid,
+        [
+            "route",
+            "add",
+            "default",
+            "via",
+            &NON_TUN_GATEWAY.to_string(),
+        ],
+    )
+    .await?;
+
+    Ok(IpAddr::V4(CONTAINER_IP))
+}
+
+/// Name of the nftables table that contains the rules added by `block_host`
+const BLOCK_TABLE_NAME: &str = "mullvad_test_block";
+
+/// Drop all forwarded traffic to and from `host`, e.g. to make a relay unreachable from the guest.
+/// The block remains until `unblock_hosts` is called.
+pub async fn block_host(host: IpAddr) -> Result<()> {
+    let family = match host {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    };
+    run_nft(&format!(
+        "
+table inet {BLOCK_TABLE_NAME} {{
+    chain FORWARD {{
+        type filter hook forward priority -1; policy accept;
+        {family} daddr {host} counter drop
+        {family} saddr {host} counter drop
+    }}
+}}"
+    ))
+    .await
+}
+
+/// Remove all blocks added by `block_host`.
+pub async fn unblock_hosts() -> Result<()> {
+    run_nft(&format!("delete table inet {BLOCK_TABLE_NAME}")).await
+}
+
 async fn run_ip_cmd<I, S>(args: I) -> Result<()>
 where
     I: IntoIterator<Item = S>,
@@ -337,6 +420,22 @@ where
     Ok(())
 }
 
+/// Run `ip` in the network namespace of the process `pid`
+async fn run_ip_cmd_in_namespace<I, S>(pid: &str, args: I) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = Command::new("nsenter");
+    cmd.args(["--target", pid, "--net", "ip"]);
+    cmd.args(args);
+    let output = cmd.output().await.map_err(Error::IpStart)?;
+    if !output.status.success() {
+        return Err(Error::IpFailed(output.status.code().unwrap()));
+    }
+    Ok(())
+}
+
 async fn run_nft(input: &str) -> Result<()> {
     let mut cmd = Command::new("nft");
     cmd.args(["-f", "-"]);
@@ -1,4 +1,4 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
 
 use anyhow::{anyhow, Context, Result};
 use tokio::{io::AsyncWriteExt, process::Command};
@@ -28,6 +28,10 @@ pub const CUSTOM_TUN_REMOTE_REAL_PORT: u16 = 51820;
 pub const CUSTOM_TUN_LOCAL_TUN_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 15, 2);
 /// Tunnel address of the wireguard remote peer as defined in `setup-network.sh`.
 pub const CUSTOM_TUN_REMOTE_TUN_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 15, 1);
+/// IPv6 tunnel address of the wireguard local peer.
+pub const CUSTOM_TUN_LOCAL_TUN_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x15, 0, 0, 0, 0, 0, 2);
+/// IPv6 tunnel address of the wireguard remote peer.
+pub const CUSTOM_TUN_REMOTE_TUN_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x15, 0, 0, 0, 0, 0, 1);
 /// Gateway (and default DNS resolver) of the wireguard tunnel.
 pub const CUSTOM_TUN_GATEWAY: Ipv4Addr = CUSTOM_TUN_REMOTE_TUN_ADDR;
 /// Gateway of the non-tunnel interface.
@@ -48,6 +52,16 @@ pub async fn setup_test_network() -> Result<()> {
     Ok(())
 }
 
+/// Drop all traffic to and from `host`. This is not yet implemented on macOS.
+pub async fn block_host(_host: std::net::IpAddr) -> Result<()> {
+    Err(anyhow!("Blocking hosts is not supported on macOS"))
+}
+
+/// Remove all blocks added by `block_host`. This is not yet implemented on macOS.
+pub async fn unblock_hosts() -> Result<()> {
+    Err(anyhow!("Blocking hosts is not supported on macOS"))
+}
+
 /// A hack to find the Tart bridge interface using `NON_TUN_GATEWAY`.
 /// It should be possible to retrieve this using the virtualization framework instead,
 /// but that requires an entitlement.
@@ -145,7 +159,7 @@ ListenPort = {CUSTOM_TUN_REMOTE_REAL_PORT}
 
 [Peer]
 PublicKey = {CUSTOM_TUN_LOCAL_PUBKEY}
-AllowedIPs = {CUSTOM_TUN_LOCAL_TUN_ADDR}
+AllowedIPs = {CUSTOM_TUN_LOCAL_TUN_ADDR}, {CUSTOM_TUN_LOCAL_TUN_ADDR_V6}
 
 "
             )
@@ -10,8 +10,9 @@ pub use macos as platform;
 
 // Import shared constants and functions
 pub use platform::{
-    setup_test_network, CUSTOM_TUN_GATEWAY, CUSTOM_TUN_INTERFACE_NAME, CUSTOM_TUN_LOCAL_PRIVKEY,
-    CUSTOM_TUN_LOCAL_TUN_ADDR, CUSTOM_TUN_REMOTE_PUBKEY, CUSTOM_TUN_REMOTE_REAL_ADDR,
-    CUSTOM_TUN_REMOTE_REAL_PORT, CUSTOM_TUN_REMOTE_TUN_ADDR, DUMMY_LAN_INTERFACE_IP,
+    block_host, setup_test_network, unblock_hosts, CUSTOM_TUN_GATEWAY, CUSTOM_TUN_INTERFACE_NAME,
+    CUSTOM_TUN_LOCAL_PRIVKEY, CUSTOM_TUN_LOCAL_TUN_ADDR, CUSTOM_TUN_LOCAL_TUN_ADDR_V6,
+    CUSTOM_TUN_REMOTE_PUBKEY, CUSTOM_TUN_REMOTE_REAL_ADDR, CUSTOM_TUN_REMOTE_REAL_PORT,
+    CUSTOM_TUN_REMOTE_TUN_ADDR, CUSTOM_TUN_REMOTE_TUN_ADDR_V6, DUMMY_LAN_INTERFACE_IP,
     NON_TUN_GATEWAY,
 };
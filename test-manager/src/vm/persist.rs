@@ -0,0 +1,74 @@
+//! Keep a VM running after a test run, so that later test runs can attach to it.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, path::PathBuf};
+
+use super::VmInstance;
+
+/// Connection details of a VM that was kept running after a test run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PersistedVm {
+    pub pty_path: String,
+    pub ip_addr: IpAddr,
+    /// Directory on the guest containing the provisioned artifacts
+    pub artifacts_dir: String,
+    /// PID of the test manager that owns the VM
+    pub pid: u32,
+}
+
+#[async_trait::async_trait]
+impl VmInstance for PersistedVm {
+    fn get_pty(&self) -> &str {
+        &self.pty_path
+    }
+
+    fn get_console_pty(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_ip(&self) -> &IpAddr {
+        &self.ip_addr
+    }
+
+    async fn wait(&mut self) {
+        // The VM is owned by another test manager
+    }
+}
+
+/// Path to the file containing the connection details of the VM `name`
+fn info_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("persisted-vm-{name}.json"))
+}
+
+/// Store the connection details of `instance` so that `load` can later find it.
+pub async fn save(name: &str, instance: &dyn VmInstance, artifacts_dir: &str) -> Result<()> {
+    let persisted = PersistedVm {
+        pty_path: instance.get_pty().to_owned(),
+        ip_addr: *instance.get_ip(),
+        artifacts_dir: artifacts_dir.to_owned(),
+        pid: std::process::id(),
+    };
+    let path = info_path(name);
+    let data = serde_json::to_vec_pretty(&persisted).context("Failed to serialize VM details")?;
+    tokio::fs::write(&path, data)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Read the connection details of a VM that was kept running using `save`.
+pub async fn load(name: &str) -> Result<PersistedVm> {
+    let path = info_path(name);
+    let data = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("No running VM found at {}", path.display()))?;
+    serde_json::from_slice(&data).context("Failed to parse VM details")
+}
+
+/// Remove the connection details stored by `save`.
+pub async fn remove(name: &str) {
+    let path = info_path(name);
+    if let Err(error) = tokio::fs::remove_file(&path).await {
+        log::warn!("Failed to remove {}: {error}", path.display());
+    }
+}
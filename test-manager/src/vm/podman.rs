@@ -0,0 +1,280 @@
+//! Run the test runner in a Linux container. This is much faster than booting a VM, but the
+//! container shares the kernel of the host, so it is only suitable for tests that do not depend
+//! on the firewall or other kernel state of the guest.
+//!
+//! Like the QEMU backend, this relies on the test manager having been relaunched in its own
+//! namespaces by [`crate::container::relaunch_with_rootlesskit`], which makes the test network
+//! private to this instance and lets podman and `ip` be used without privileges on the host.
+
+use crate::{
+    config::{Config, OsType, VmConfig},
+    vm::{logging::forward_logs, util::find_ptys},
+};
+use regex::Regex;
+use std::{ffi::OsStr, io, net::IpAddr, process::Stdio};
+use tokio::process::{Child, Command};
+use uuid::Uuid;
+
+use super::{network, VmInstance};
+
+const LOG_PREFIX: &str = "[podman] ";
+const SOCAT_LOG_PREFIX: &str = "[socat] ";
+const STDERR_LOG_LEVEL: log::Level = log::Level::Error;
+const STDOUT_LOG_LEVEL: log::Level = log::Level::Debug;
+
+/// Path of the serial device in the container
+const CONTAINER_PTY_PATH: &str = "/dev/ttyS0";
+/// Path of the test runner in the container
+const CONTAINER_RUNNER_PATH: &str = "/opt/testing/test-runner";
+
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "Containers can only be used for Linux guests")]
+    UnsupportedOs,
+    #[error(display = "The test manager must run in the namespaces set up by rootlesskit")]
+    NotNamespaced,
+    #[error(display = "Failed to set up network")]
+    Network(network::linux::Error),
+    #[error(display = "Failed to start socat")]
+    StartSocat(io::Error),
+    #[error(display = "Could not find pty")]
+    NoPty,
+    #[error(display = "Could not find the test runner")]
+    FindRunner(io::Error),
+    #[error(display = "Failed to start podman")]
+    StartPodman(io::Error),
+    #[error(display = "podman command failed: {}", _0)]
+    PodmanFailed(String),
+    #[error(display = "Invalid PID of container: {}", _0)]
+    InvalidPid(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct ContainerInstance {
+    pub pty_path: String,
+    pub ip_addr: IpAddr,
+    /// `podman wait`, which exits along with the container
+    waiter: Child,
+    _logs: Child,
+    _socat: Child,
+    _container: ContainerHandle,
+    _network_handle: network::linux::NetworkHandle,
+}
+
+#[async_trait::async_trait]
+impl VmInstance for ContainerInstance {
+    fn get_pty(&self) -> &str {
+        &self.pty_path
+    }
+
+    fn get_console_pty(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_ip(&self) -> &IpAddr {
+        &self.ip_addr
+    }
+
+    async fn wait(&mut self) {
+        let _ = self.waiter.wait().await;
+    }
+}
+
+/// Removes the container when dropped
+struct ContainerHandle {
+    name: String,
+}
+
+impl Drop for ContainerHandle {
+    fn drop(&mut self) {
+        log::debug!("Removing container {}", self.name);
+
+        let result = std::process::Command::new("podman")
+            .args(["rm", "--force", "--time", "0", &self.name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if !matches!(result, Ok(status) if status.success()) {
+            log::error!("Failed to remove container {}", self.name);
+        }
+    }
+}
+
+pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<ContainerInstance> {
+    if vm_config.os_type != OsType::Linux {
+        return Err(Error::UnsupportedOs);
+    }
+    if config.runtime_opts.keep_changes {
+        log::warn!("Changes to containers are never kept");
+    }
+    // `relaunch_with_rootlesskit` returns early when we are root, so this also holds if the
+    // test manager was started as root on the host
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(Error::NotNamespaced);
+    }
+
+    let network_handle = network::linux::setup_test_network()
+        .await
+        .map_err(Error::Network)?;
+
+    // Create a pair of connected ptys: one for the test manager and one for the test runner
+    let mut socat_cmd = Command::new("socat");
+    socat_cmd.args([
+        "-d",
+        "-d",
+        "-lf",
+        "/dev/stdout",
+        "pty,raw,echo=0",
+        "pty,raw,echo=0",
+    ]);
+    socat_cmd.stdout(Stdio::piped());
+    socat_cmd.stderr(Stdio::piped());
+    socat_cmd.kill_on_drop(true);
+
+    let mut socat = socat_cmd.spawn().map_err(Error::StartSocat)?;
+
+    tokio::spawn(forward_logs(
+        SOCAT_LOG_PREFIX,
+        socat.stderr.take().unwrap(),
+        STDERR_LOG_LEVEL,
+    ));
+
+    // match: N PTY is /dev/pts/3
+    let re = Regex::new(r"PTY is ([/a-zA-Z0-9]+)").unwrap();
+    let mut pty_paths = find_ptys(re, 2, &mut socat, STDOUT_LOG_LEVEL, SOCAT_LOG_PREFIX)
+        .await
+        .map_err(|_error| Error::NoPty)?
+        .into_iter();
+    let pty_path = pty_paths.next().unwrap();
+    let runner_pty_path = pty_paths.next().unwrap();
+
+    tokio::spawn(forward_logs(
+        SOCAT_LOG_PREFIX,
+        socat.stdout.take().unwrap(),
+        STDOUT_LOG_LEVEL,
+    ));
+
+    let runner_path = std::fs::canonicalize(vm_config.get_runner_dir().join("test-runner"))
+        .map_err(Error::FindRunner)?;
+
+    let container = ContainerHandle {
+        name: format!("mullvad-test-runner-{}", Uuid::new_v4()),
+    };
+
+    let mut run_args = vec![
+        "run".to_owned(),
+        "--rm".to_owned(),
+        "--detach".to_owned(),
+        "--name".to_owned(),
+        container.name.clone(),
+        // The container is attached to the test network below
+        "--network".to_owned(),
+        "none".to_owned(),
+        "--cap-add".to_owned(),
+        "NET_ADMIN".to_owned(),
+        "--device".to_owned(),
+        "/dev/net/tun".to_owned(),
+        "--device".to_owned(),
+        format!("{runner_pty_path}:{CONTAINER_PTY_PATH}"),
+        "--volume".to_owned(),
+        format!("{}:{CONTAINER_RUNNER_PATH}:ro", runner_path.display()),
+    ];
+
+    // Additional disks are mounted as volumes
+    for disk in &vm_config.disks {
+        run_args.extend(["--volume".to_owned(), disk.clone()]);
+    }
+
+    run_args.extend([
+        vm_config.image_path.clone(),
+        CONTAINER_RUNNER_PATH.to_owned(),
+        CONTAINER_PTY_PATH.to_owned(),
+        "serve".to_owned(),
+    ]);
+
+    log::debug!("Starting container {}", container.name);
+    podman(run_args).await?;
+
+    let logs = spawn_podman_logs(&container.name)?;
+    let waiter = Command::new("podman")
+        .args(["wait", &container.name])
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(Error::StartPodman)?;
+
+    let pid = podman(["inspect", "--format", "{{.State.Pid}}", &container.name]).await?;
+    let pid = pid.parse().map_err(|_| Error::InvalidPid(pid))?;
+
+    let ip_addr = network::linux::attach_namespace(pid)
+        .await
+        .map_err(Error::Network)?;
+    log::debug!("Guest IP: {ip_addr}");
+
+    // There is no DHCP client in the container, so use the DNS server of the test network
+    podman([
+        "exec",
+        &container.name,
+        "sh",
+        "-c",
+        &format!(
+            "echo 'nameserver {}' > /etc/resolv.conf",
+            network::linux::NON_TUN_GATEWAY
+        ),
+    ])
+    .await?;
+
+    Ok(ContainerInstance {
+        pty_path,
+        ip_addr,
+        waiter,
+        _logs: logs,
+        _socat: socat,
+        _container: container,
+        _network_handle: network_handle,
+    })
+}
+
+/// Forward the output of the container to the log
+fn spawn_podman_logs(name: &str) -> Result<Child> {
+    let mut child = Command::new("podman")
+        .args(["logs", "--follow", name])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(Error::StartPodman)?;
+
+    tokio::spawn(forward_logs(
+        LOG_PREFIX,
+        child.stdout.take().unwrap(),
+        STDOUT_LOG_LEVEL,
+    ));
+    tokio::spawn(forward_logs(
+        LOG_PREFIX,
+        child.stderr.take().unwrap(),
+        STDERR_LOG_LEVEL,
+    ));
+
+    Ok(child)
+}
+
+/// Run a podman command and return its trimmed output
+async fn podman<I, S>(args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new("podman")
+        .args(args)
+        .output()
+        .await
+        .map_err(Error::StartPodman)?;
+    if !output.status.success() {
+        return Err(Error::PodmanFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
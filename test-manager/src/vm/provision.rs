@@ -1,30 +1,91 @@
-use crate::config::{OsType, Provisioner, VmConfig};
+use crate::config::{OsType, ProvisionFile, Provisioner, SshAuth, VmConfig};
 use crate::package;
+use crate::vm::ssh::{authenticate, server_addr};
 use anyhow::{Context, Result};
 use ssh2::Session;
 use std::fs::File;
 use std::io::{self, Read};
-use std::net::IpAddr;
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{net::SocketAddr, path::Path};
 
+/// Default time to wait for provisioning to complete
+pub const DEFAULT_PROVISION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Directory that receives the payload. Any directory that the SSH user has access to.
+const REMOTE_TEMP_DIR: &str = "/tmp/";
+
+/// Provision the VM, failing if it does not complete within `timeout`. The error names the step
+/// that was in progress when the timeout was reached.
 pub async fn provision(
     config: &VmConfig,
     instance: &dyn super::VmInstance,
     app_manifest: &package::Manifest,
+    timeout: Duration,
+) -> Result<String> {
+    let step = ProvisionStep::default();
+
+    tokio::time::timeout(
+        timeout,
+        provision_inner(config, instance, app_manifest, timeout, step.clone()),
+    )
+    .await
+    .map_err(|_| {
+        anyhow::anyhow!(
+            "Provisioning timed out after {} seconds while {}",
+            timeout.as_secs(),
+            step.get()
+        )
+    })?
+}
+
+/// Describes the provisioning step that is currently in progress
+#[derive(Clone)]
+struct ProvisionStep(Arc<Mutex<&'static str>>);
+
+impl Default for ProvisionStep {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new("starting provisioning")))
+    }
+}
+
+impl ProvisionStep {
+    fn set(&self, step: &'static str) {
+        log::debug!("Provisioning: {step}");
+        *self.0.lock().unwrap() = step;
+    }
+
+    fn get(&self) -> &'static str {
+        *self.0.lock().unwrap()
+    }
+}
+
+async fn provision_inner(
+    config: &VmConfig,
+    instance: &dyn super::VmInstance,
+    app_manifest: &package::Manifest,
+    timeout: Duration,
+    step: ProvisionStep,
 ) -> Result<String> {
     match config.provisioner {
         Provisioner::Ssh => {
             log::info!("SSH provisioning");
 
-            let (user, password) = config.get_ssh_options().context("missing SSH config")?;
+            let (user, auth) = config.get_ssh_options().context(
+                "the SSH provisioner requires 'ssh_user' and either 'ssh_key_path' or 'ssh_password'",
+            )?;
+            let addr = server_addr(config, *instance.get_ip())?;
             ssh(
-                instance,
+                addr,
                 config.os_type,
                 config.get_runner_dir(),
                 app_manifest,
+                &config.provision_files,
                 user,
-                password,
+                auth,
+                timeout,
+                step,
             )
             .await
             .context("Failed to provision runner over SSH")
@@ -34,40 +95,65 @@ pub async fn provision(
                 .artifacts_dir
                 .as_ref()
                 .context("'artifacts_dir' must be set to a mountpoint")?;
+
+            if !config.provision_files.is_empty() {
+                let (user, auth) = config.get_ssh_options().context(
+                    "'provision_files' requires 'ssh_user' and either 'ssh_key_path' or 'ssh_password'",
+                )?;
+                let addr = server_addr(config, *instance.get_ip())?;
+                ssh_provision_files(
+                    addr,
+                    config.os_type,
+                    config.provision_files.clone(),
+                    user,
+                    auth,
+                    timeout,
+                    step,
+                )
+                .await
+                .context("Failed to send files to provision over SSH")?;
+            }
+
             Ok(dir.clone())
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn ssh(
-    instance: &dyn super::VmInstance,
+    addr: SocketAddr,
     os_type: OsType,
     local_runner_dir: &Path,
     local_app_manifest: &package::Manifest,
+    provision_files: &[ProvisionFile],
     user: &str,
-    password: &str,
+    auth: SshAuth,
+    timeout: Duration,
+    step: ProvisionStep,
 ) -> Result<String> {
-    let guest_ip = *instance.get_ip();
-
     let user = user.to_owned();
-    let password = password.to_owned();
 
     let remote_dir = match os_type {
-        OsType::Windows => r"C:\testing",
+        OsType::Windows => r"E:\",
         OsType::Macos | OsType::Linux => r"/opt/testing",
     };
 
     let local_runner_dir = local_runner_dir.to_owned();
     let local_app_manifest = local_app_manifest.to_owned();
+    let provision_files = provision_files.to_owned();
 
     tokio::task::spawn_blocking(move || {
         blocking_ssh(
             user,
-            password,
-            guest_ip,
+            auth,
+            addr,
+            os_type,
             &local_runner_dir,
             local_app_manifest,
+            &provision_files,
             remote_dir,
+            timeout,
+            step,
         )
     })
     .await
@@ -76,45 +162,72 @@ async fn ssh(
     Ok(remote_dir.to_string())
 }
 
+/// Only copy `provision_files` to the guest. This is used when the image already contains the
+/// test runner and the app packages.
+async fn ssh_provision_files(
+    addr: SocketAddr,
+    os_type: OsType,
+    provision_files: Vec<ProvisionFile>,
+    user: &str,
+    auth: SshAuth,
+    timeout: Duration,
+    step: ProvisionStep,
+) -> Result<()> {
+    let user = user.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let session = ssh_connect(&user, &auth, addr, timeout, &step)?;
+        ssh_send_provision_files(&session, os_type, &provision_files, &step)
+    })
+    .await
+    .context("Failed to join SSH task")?
+}
+
+#[allow(clippy::too_many_arguments)]
 fn blocking_ssh(
     user: String,
-    password: String,
-    guest_ip: IpAddr,
+    auth: SshAuth,
+    addr: SocketAddr,
+    os_type: OsType,
     local_runner_dir: &Path,
     local_app_manifest: package::Manifest,
+    provision_files: &[ProvisionFile],
     remote_dir: &str,
+    timeout: Duration,
+    step: ProvisionStep,
 ) -> Result<()> {
-    // Directory that receives the payload. Any directory that the SSH user has access to.
-    const REMOTE_TEMP_DIR: &str = "/tmp/";
+    // The setup script requires bash, so on Windows, files are sent straight to the testing
+    // drive. The test runner service is expected to be part of the image.
+    const REMOTE_WINDOWS_DIR: &str = "E:/";
     const SCRIPT_PAYLOAD: &[u8] = include_bytes!("../../../scripts/ssh-setup.sh");
     const OPENVPN_CERT: &[u8] = include_bytes!("../../../openvpn.ca.crt");
 
-    let temp_dir = Path::new(REMOTE_TEMP_DIR);
-
-    let stream = TcpStream::connect(SocketAddr::new(guest_ip, 22)).context("TCP connect failed")?;
+    let (temp_dir, runner_name) = match os_type {
+        OsType::Windows => (Path::new(REMOTE_WINDOWS_DIR), "test-runner.exe"),
+        OsType::Macos | OsType::Linux => (Path::new(REMOTE_TEMP_DIR), "test-runner"),
+    };
 
-    let mut session = Session::new().context("Failed to connect to SSH server")?;
-    session.set_tcp_stream(stream);
-    session.handshake()?;
-
-    session
-        .userauth_password(&user, &password)
-        .context("SSH auth failed")?;
+    let session = ssh_connect(&user, &auth, addr, timeout, &step)?;
 
     // Transfer a test runner
-    let source = local_runner_dir.join("test-runner");
+    step.set("sending the test runner");
+    let source = local_runner_dir.join(runner_name);
     ssh_send_file_path(&session, &source, temp_dir)
         .context("Failed to send test runner to remote")?;
 
     // Transfer app packages
+    step.set("sending the current app package");
     ssh_send_file_path(&session, &local_app_manifest.current_app_path, temp_dir)
         .context("Failed to send current app package to remote")?;
+    step.set("sending the previous app package");
     ssh_send_file_path(&session, &local_app_manifest.previous_app_path, temp_dir)
         .context("Failed to send previous app package to remote")?;
+    step.set("sending the UI test runner");
     ssh_send_file_path(&session, &local_app_manifest.ui_e2e_tests_path, temp_dir)
         .context("Failed to send UI test runner to remote")?;
 
     // Transfer openvpn cert
+    step.set("sending the OpenVPN certificate");
     let dest: std::path::PathBuf = temp_dir.join("openvpn.ca.crt");
     log::debug!("Copying remote openvpn.ca.crt -> {}", dest.display());
     #[allow(const_item_mutation)]
@@ -126,7 +239,12 @@ fn blocking_ssh(
     )
     .context("failed to send openvpn crt to remote")?;
 
+    if os_type == OsType::Windows {
+        return ssh_send_provision_files(&session, os_type, provision_files, &step);
+    }
+
     // Transfer setup script
+    step.set("sending the setup script");
     let dest = temp_dir.join("ssh-setup.sh");
     log::debug!("Copying remote setup script -> {}", dest.display());
     #[allow(const_item_mutation)]
@@ -159,15 +277,95 @@ fn blocking_ssh(
             .to_string_lossy(),
     );
 
+    step.set("running the setup script");
     log::debug!("Running setup script on remote, args: {args}");
     ssh_exec(&session, &format!("sudo {} {args}", dest.display()))
-        .map(drop)
-        .context("Failed to run setup script")
+        .context("Failed to run setup script")?;
+
+    ssh_send_provision_files(&session, os_type, provision_files, &step)
+}
+
+/// Connect to the SSH server at `addr` and authenticate as `user`.
+fn ssh_connect(
+    user: &str,
+    auth: &SshAuth,
+    addr: SocketAddr,
+    timeout: Duration,
+    step: &ProvisionStep,
+) -> Result<Session> {
+    step.set("connecting to the SSH server");
+    let stream = TcpStream::connect_timeout(&addr, timeout)
+        .with_context(|| format!("Failed to connect to SSH server at {addr}"))?;
+
+    let mut session = Session::new().context("Failed to create SSH session")?;
+    // Make sure that blocking SSH calls eventually return after the provisioning timed out
+    session.set_timeout(u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX));
+    session.set_tcp_stream(stream);
+    session
+        .handshake()
+        .with_context(|| format!("SSH handshake with {addr} failed"))?;
+
+    step.set("authenticating over SSH");
+    authenticate(&session, user, auth)
+        .with_context(|| format!("Failed to authenticate to {addr} as {user}"))?;
+
+    Ok(session)
+}
+
+/// Copy each of `provision_files` to its remote path.
+fn ssh_send_provision_files(
+    session: &Session,
+    os_type: OsType,
+    provision_files: &[ProvisionFile],
+    step: &ProvisionStep,
+) -> Result<()> {
+    if provision_files.is_empty() {
+        return Ok(());
+    }
+    step.set("sending additional files");
+
+    for file in provision_files {
+        let remote_path = Path::new(&file.remote_path);
+        let result = match os_type {
+            OsType::Windows => ssh_send_file_path_to(session, &file.local_path, remote_path),
+            OsType::Macos | OsType::Linux => {
+                // The SSH user may not be allowed to write to the destination, so move the file
+                // into place using sudo
+                let temp_dir = Path::new(REMOTE_TEMP_DIR);
+                ssh_send_file_path(session, &file.local_path, temp_dir).and_then(|()| {
+                    let temp_path = temp_dir.join(file.local_path.file_name().unwrap());
+                    let remote_dir = remote_path.parent().context("Invalid remote path")?;
+                    ssh_exec_checked(
+                        session,
+                        &format!(
+                            "sudo mkdir -p \"{}\" && sudo mv -f \"{}\" \"{}\"",
+                            remote_dir.display(),
+                            temp_path.display(),
+                            remote_path.display(),
+                        ),
+                    )
+                    .map(drop)
+                })
+            }
+        };
+        result.with_context(|| {
+            format!(
+                "Failed to send {} to {}",
+                file.local_path.display(),
+                file.remote_path
+            )
+        })?;
+    }
+
+    Ok(())
 }
 
 fn ssh_send_file_path(session: &Session, source: &Path, dest_dir: &Path) -> Result<()> {
     let dest = dest_dir.join(source.file_name().context("Missing source file name")?);
+    ssh_send_file_path_to(session, source, &dest)
+}
 
+fn ssh_send_file_path_to(session: &Session, source: &Path, dest: &Path) -> Result<()> {
     log::debug!(
         "Copying file to remote: {} -> {}",
         source.display(),
@@ -176,7 +374,7 @@ fn ssh_send_file_path(session: &Session, source: &Path, dest_dir: &Path) -> Resu
 
     let mut file = File::open(source).context("Failed to open file")?;
     let file_len = file.metadata().context("Failed to get file size")?.len();
-    ssh_send_file(session, &mut file, file_len, &dest)
+    ssh_send_file(session, &mut file, file_len, dest)
 }
 
 fn ssh_send_file<R: Read>(
@@ -205,3 +403,20 @@ fn ssh_exec(session: &Session, command: &str) -> Result<String> {
     channel.wait_close()?;
     Ok(output)
 }
+
+/// Execute an arbitrary string of commands via ssh, and fail if they return a non-zero exit code.
+fn ssh_exec_checked(session: &Session, command: &str) -> Result<String> {
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(output),
+        code => Err(anyhow::anyhow!(
+            "Command failed with exit code {code}: {output}"
+        )),
+    }
+}
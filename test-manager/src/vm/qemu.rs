@@ -1,6 +1,6 @@
 use crate::{
     config::{self, Config, VmConfig},
-    vm::{logging::forward_logs, util::find_pty},
+    vm::{logging::forward_logs, util::find_ptys},
 };
 use async_tempfile::TempFile;
 use regex::Regex;
@@ -13,17 +13,20 @@ use std::{
 };
 use tokio::{
     fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
     process::{Child, Command},
     time::timeout,
 };
 use uuid::Uuid;
 
-use super::{network, VmInstance};
+use super::{network, SnapshotId, VmInstance};
 
 const LOG_PREFIX: &str = "[qemu] ";
 const STDERR_LOG_LEVEL: log::Level = log::Level::Error;
 const STDOUT_LOG_LEVEL: log::Level = log::Level::Debug;
 const OBTAIN_IP_TIMEOUT: Duration = Duration::from_secs(60);
+const MONITOR_COMMAND_TIMEOUT: Duration = Duration::from_secs(180);
 
 #[derive(err_derive::Error, Debug)]
 pub enum Error {
@@ -49,14 +52,23 @@ pub enum Error {
     TpmSocketTimeout,
     #[error(display = "Failed to create temp dir")]
     MkTempDir(io::Error),
+    #[error(display = "Failed to communicate with the QEMU monitor")]
+    Monitor(io::Error),
+    #[error(display = "QEMU monitor command failed: {}", _0)]
+    MonitorCommand(String),
+    #[error(display = "Timed out waiting for the QEMU monitor")]
+    MonitorTimeout,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct QemuInstance {
     pub pty_path: String,
+    pub console_pty_path: Option<String>,
     pub ip_addr: IpAddr,
     child: Child,
+    monitor: Monitor,
+    keep_changes: bool,
     _network_handle: network::linux::NetworkHandle,
     _ovmf_handle: Option<OvmfHandle>,
     _tpm_emulator: Option<TpmEmulator>,
@@ -68,6 +80,10 @@ impl VmInstance for QemuInstance {
         &self.pty_path
     }
 
+    fn get_console_pty(&self) -> Option<&str> {
+        self.console_pty_path.as_deref()
+    }
+
     fn get_ip(&self) -> &IpAddr {
         &self.ip_addr
     }
@@ -75,6 +91,31 @@ impl VmInstance for QemuInstance {
     async fn wait(&mut self) {
         let _ = self.child.wait().await;
     }
+
+    async fn snapshot(&mut self) -> anyhow::Result<SnapshotId> {
+        if self.keep_changes {
+            // The snapshot would be stored in the image
+            return Err(anyhow::anyhow!(
+                "Snapshots cannot be used when changes are kept"
+            ));
+        }
+        let tag = format!("snapshot-{}", Uuid::new_v4());
+        log::debug!("Saving VM snapshot {tag}");
+        self.monitor.command(&format!("savevm {tag}")).await?;
+        Ok(SnapshotId(tag))
+    }
+
+    async fn restore(&mut self, id: SnapshotId) -> anyhow::Result<()> {
+        log::debug!("Loading VM snapshot {}", id.0);
+        self.monitor.command(&format!("loadvm {}", id.0)).await?;
+        Ok(())
+    }
+
+    async fn delete_snapshot(&mut self, id: SnapshotId) -> anyhow::Result<()> {
+        log::debug!("Deleting VM snapshot {}", id.0);
+        self.monitor.command(&format!("delvm {}", id.0)).await?;
+        Ok(())
+    }
 }
 
 pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<QemuInstance> {
@@ -108,6 +149,11 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<QemuInstance>
         "nec-usb-xhci,id=xhci",
     ]);
 
+    // The guest console, if enabled, is attached to the second serial device
+    if vm_config.console_serial {
+        qemu_cmd.args(["-serial", "pty"]);
+    }
+
     if !config.runtime_opts.keep_changes {
         qemu_cmd.arg("-snapshot");
     }
@@ -117,7 +163,8 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<QemuInstance>
             qemu_cmd.args(["-display", "none"]);
         }
         config::Display::Local => (),
-        config::Display::Vnc => {
+        config::Display::Vnc(_) => {
+            // rootlesskit forwards the requested port to this display
             log::debug!("Running VNC server on :1");
             qemu_cmd.args(["-display", "vnc=:1"]);
         }
@@ -150,6 +197,9 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<QemuInstance>
         None
     };
 
+    let monitor = Monitor::new().await?;
+    monitor.append_qemu_args(&mut qemu_cmd);
+
     qemu_cmd.stdin(Stdio::piped());
     qemu_cmd.stdout(Stdio::piped());
     qemu_cmd.stderr(Stdio::piped());
@@ -164,17 +214,21 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<QemuInstance>
         STDERR_LOG_LEVEL,
     ));
 
-    // find pty in stdout
+    // find ptys in stdout
     // match: char device redirected to /dev/pts/0 (label serial0)
     let re = Regex::new(r"char device redirected to ([/a-zA-Z0-9]+) \(").unwrap();
-    let pty_path = find_pty(re, &mut child, STDOUT_LOG_LEVEL, LOG_PREFIX)
+    let pty_count = if vm_config.console_serial { 2 } else { 1 };
+    let mut pty_paths = find_ptys(re, pty_count, &mut child, STDOUT_LOG_LEVEL, LOG_PREFIX)
         .await
         .map_err(|_error| {
             if let Ok(status) = child.try_wait() {
                 return Error::QemuFailed(status);
             }
             Error::NoPty
-        })?;
+        })?
+        .into_iter();
+    let pty_path = pty_paths.next().unwrap();
+    let console_pty_path = pty_paths.next();
 
     tokio::spawn(forward_logs(
         LOG_PREFIX,
@@ -191,8 +245,11 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<QemuInstance>
 
     Ok(QemuInstance {
         pty_path,
+        console_pty_path,
         ip_addr,
         child,
+        monitor,
+        keep_changes: config.runtime_opts.keep_changes,
         _network_handle: network_handle,
         _ovmf_handle: ovmf_handle,
         _tpm_emulator: tpm_emulator,
@@ -240,6 +297,75 @@ impl OvmfHandle {
     }
 }
 
+/// The QEMU human monitor, which is used to save and load snapshots. Note that snapshots
+/// cannot be saved if the VM uses a TPM, since the OVMF vars are not stored in a qcow2 image.
+struct Monitor {
+    temp_dir: TempDir,
+}
+
+impl Monitor {
+    const PROMPT: &'static [u8] = b"(qemu) ";
+
+    pub async fn new() -> Result<Self> {
+        Ok(Self {
+            temp_dir: TempDir::new().await?,
+        })
+    }
+
+    fn sock_path(&self) -> PathBuf {
+        self.temp_dir.0.join("monitor.sock")
+    }
+
+    pub fn append_qemu_args(&self, qemu_cmd: &mut Command) {
+        qemu_cmd.args([
+            "-monitor",
+            &format!("unix:{},server=on,wait=off", self.sock_path().display()),
+        ]);
+    }
+
+    /// Run `command` and return its output.
+    pub async fn command(&self, command: &str) -> Result<String> {
+        timeout(MONITOR_COMMAND_TIMEOUT, async {
+            let mut stream = UnixStream::connect(self.sock_path())
+                .await
+                .map_err(Error::Monitor)?;
+
+            // Skip the greeting
+            Self::read_until_prompt(&mut stream).await?;
+
+            stream
+                .write_all(format!("{command}\n").as_bytes())
+                .await
+                .map_err(Error::Monitor)?;
+            let output = Self::read_until_prompt(&mut stream).await?;
+
+            // HMP reports errors as text rather than through a status
+            if output.contains("Error") {
+                return Err(Error::MonitorCommand(output.trim().to_owned()));
+            }
+            Ok(output)
+        })
+        .await
+        .map_err(|_| Error::MonitorTimeout)?
+    }
+
+    async fn read_until_prompt(stream: &mut UnixStream) -> Result<String> {
+        let mut output = vec![];
+        let mut buffer = [0u8; 1024];
+        while !output.ends_with(Self::PROMPT) {
+            let num_bytes = stream.read(&mut buffer).await.map_err(Error::Monitor)?;
+            if num_bytes == 0 {
+                return Err(Error::Monitor(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )));
+            }
+            output.extend_from_slice(&buffer[..num_bytes]);
+        }
+        output.truncate(output.len() - Self::PROMPT.len());
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+}
+
 /// Runs a TPM emulator
 struct TpmEmulator {
     handle: tokio::task::JoinHandle<Result<()>>,
@@ -1,12 +1,37 @@
 /// A very thin wrapper on top of `ssh2`.
+use crate::config::{SshAuth, VmConfig};
 use anyhow::{Context, Result};
 use ssh2::Session;
 use std::io::Read;
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
 
 /// Default `ssh` port.
 const PORT: u16 = 22;
 
+/// Return the address of the SSH server. This is `ssh_host` if it is set, or the guest IP
+/// otherwise.
+pub fn server_addr(config: &VmConfig, guest_ip: IpAddr) -> Result<SocketAddr> {
+    match &config.ssh_host {
+        Some(host) => (host.as_str(), PORT)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve SSH host {host}"))?
+            .next()
+            .with_context(|| format!("No addresses found for SSH host {host}")),
+        None => Ok(SocketAddr::new(guest_ip, PORT)),
+    }
+}
+
+/// Authenticate an SSH session using either a password or a private key.
+pub fn authenticate(session: &Session, username: &str, auth: &SshAuth) -> Result<()> {
+    match auth {
+        SshAuth::Password(password) => session.userauth_password(username, password),
+        SshAuth::PrivateKey(key_path) => {
+            session.userauth_pubkey_file(username, None, key_path, None)
+        }
+    }
+    .context("SSH auth failed")
+}
+
 /// Handle to an `ssh` session.
 pub struct SSHSession {
     session: ssh2::Session,
@@ -17,16 +42,14 @@ impl SSHSession {
     /// This function is blocking while connecting.
     ///
     /// The tunnel is closed when the `SSHSession` is dropped.
-    pub fn connect(username: String, password: String, ip: IpAddr) -> Result<Self> {
+    pub fn connect(username: &str, auth: &SshAuth, addr: SocketAddr) -> Result<Self> {
         // Set up the SSH connection
         log::info!("initializing a new SSH session ..");
-        let stream = TcpStream::connect(SocketAddr::new(ip, PORT)).context("TCP connect failed")?;
+        let stream = TcpStream::connect(addr).context("TCP connect failed")?;
         let mut session = Session::new().context("Failed to connect to SSH server")?;
         session.set_tcp_stream(stream);
         session.handshake()?;
-        session
-            .userauth_password(&username, &password)
-            .context("SSH auth failed")?;
+        authenticate(&session, username, auth)?;
         Ok(Self { session })
     }
 
@@ -1,22 +1,37 @@
-use crate::config::{self, Config, VmConfig};
+use crate::config::{self, Config, OsType, VmConfig};
 use anyhow::{anyhow, Context, Result};
 use regex::Regex;
-use std::{net::IpAddr, process::Stdio, time::Duration};
-use tokio::process::{Child, Command};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+};
 use uuid::Uuid;
 
-use super::{logging::forward_logs, util::find_pty, VmInstance};
+use super::{logging::forward_logs, util::find_pty, SnapshotId, VmInstance};
 
 const LOG_PREFIX: &str = "[tart] ";
 const STDERR_LOG_LEVEL: log::Level = log::Level::Error;
 const STDOUT_LOG_LEVEL: log::Level = log::Level::Debug;
 const OBTAIN_IP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Port of the Screen Sharing (VNC) server of macOS guests
+const GUEST_VNC_PORT: u16 = 5900;
 
 pub struct TartInstance {
     pub pty_path: String,
     pub ip_addr: IpAddr,
     child: Child,
     machine_copy: Option<MachineCopy>,
+    /// Additional arguments for `tart run`
+    run_args: Vec<String>,
+    /// Clones created by `snapshot`. These are destroyed along with the instance.
+    snapshots: Vec<MachineCopy>,
+    vnc_forwarder: Option<VncForwarder>,
 }
 
 #[async_trait::async_trait]
@@ -25,6 +40,10 @@ impl VmInstance for TartInstance {
         &self.pty_path
     }
 
+    fn get_console_pty(&self) -> Option<&str> {
+        None
+    }
+
     fn get_ip(&self) -> &IpAddr {
         &self.ip_addr
     }
@@ -34,10 +53,91 @@ impl VmInstance for TartInstance {
         if let Some(machine) = self.machine_copy.take() {
             machine.cleanup().await;
         }
+        for snapshot in self.snapshots.drain(..) {
+            snapshot.cleanup().await;
+        }
+    }
+
+    /// Clone the disk of the VM. Tart cannot save the memory of a running VM, so restoring the
+    /// snapshot reboots the VM.
+    async fn snapshot(&mut self) -> Result<SnapshotId> {
+        let machine = self
+            .machine_copy
+            .as_ref()
+            .context("The VM is no longer running")?;
+        let snapshot_name = format!("snapshot-{}", Uuid::new_v4());
+        log::debug!("Saving VM snapshot {snapshot_name}");
+
+        tart_clone(&machine.name, &snapshot_name).await?;
+        self.snapshots.push(MachineCopy {
+            name: snapshot_name.clone(),
+            should_destroy: true,
+        });
+
+        Ok(SnapshotId(snapshot_name))
+    }
+
+    /// Replace the VM with a clone of the snapshot and boot it again.
+    async fn restore(&mut self, id: SnapshotId) -> Result<()> {
+        let machine = self
+            .machine_copy
+            .as_ref()
+            .context("The VM is no longer running")?;
+        if !machine.should_destroy {
+            // Restoring would overwrite the original VM
+            return Err(anyhow!(
+                "Snapshots can only be restored when changes are not kept"
+            ));
+        }
+        if !self.snapshots.iter().any(|snapshot| snapshot.name == id.0) {
+            return Err(anyhow!("Unknown snapshot: {}", id.0));
+        }
+        log::debug!("Restoring VM snapshot {}", id.0);
+
+        let _ = self.child.kill().await;
+        tart_delete(&machine.name).await?;
+        tart_clone(&id.0, &machine.name).await?;
+
+        let (child, pty_path, ip_addr) = start_vm(&machine.name, &self.run_args).await?;
+        self.child = child;
+        self.pty_path = pty_path;
+        self.ip_addr = ip_addr;
+
+        if let Some(forwarder) = &self.vnc_forwarder {
+            forwarder.set_target(SocketAddr::new(ip_addr, GUEST_VNC_PORT));
+        }
+
+        Ok(())
+    }
+
+    async fn delete_snapshot(&mut self, id: SnapshotId) -> Result<()> {
+        let index = self
+            .snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == id.0)
+            .with_context(|| format!("Unknown snapshot: {}", id.0))?;
+        log::debug!("Deleting VM snapshot {}", id.0);
+        self.snapshots.remove(index).cleanup().await;
+        Ok(())
     }
 }
 
 pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<TartInstance> {
+    let (run_args, vnc_port) = match config.runtime_opts.display {
+        config::Display::None => (vec!["--no-graphics".to_owned()], None),
+        config::Display::Local => (vec![], None),
+        config::Display::Vnc(port) => {
+            // `tart run --vnc` opens a VNC client on the host rather than exposing a port, so
+            // the Screen Sharing server of the guest is forwarded to the port instead
+            if vm_config.os_type != OsType::Macos {
+                return Err(anyhow!(
+                    "VNC is only supported for macOS guests when using Tart"
+                ));
+            }
+            (vec!["--no-graphics".to_owned()], Some(port))
+        }
+    };
+
     super::network::macos::setup_test_network()
         .await
         .context("Failed to set up networking")?;
@@ -49,24 +149,91 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<TartInstance>
         MachineCopy::clone_vm(&vm_config.image_path).await?
     };
 
-    // Start VM
-    let mut tart_cmd = Command::new("tart");
-    tart_cmd.args(["run", &machine_copy.name, "--serial"]);
-
     if !vm_config.disks.is_empty() {
         log::warn!("Mounting disks is not yet supported")
     }
 
-    match config.runtime_opts.display {
-        config::Display::None => {
-            tart_cmd.arg("--no-graphics");
+    let (child, pty_path, ip_addr) = start_vm(&machine_copy.name, &run_args).await?;
+
+    let vnc_forwarder = match vnc_port {
+        Some(port) => {
+            Some(VncForwarder::start(port, SocketAddr::new(ip_addr, GUEST_VNC_PORT)).await?)
         }
-        config::Display::Local => (),
-        config::Display::Vnc => {
-            //tart_cmd.args(["--vnc-experimental", "--no-graphics"]);
-            tart_cmd.args(["--vnc", "--no-graphics"]);
+        None => None,
+    };
+
+    Ok(TartInstance {
+        child,
+        pty_path,
+        ip_addr,
+        machine_copy: Some(machine_copy),
+        run_args,
+        snapshots: vec![],
+        vnc_forwarder,
+    })
+}
+
+/// Forwards connections to a port on localhost to the Screen Sharing server of the guest.
+/// Screen Sharing must be enabled in the guest.
+struct VncForwarder {
+    target: Arc<Mutex<SocketAddr>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl VncForwarder {
+    async fn start(port: u16, target: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .await
+            .with_context(|| format!("Failed to listen on VNC port {port}"))?;
+        log::info!("Forwarding VNC connections: 127.0.0.1:{port} -> {target}");
+
+        let target = Arc::new(Mutex::new(target));
+        let handle = tokio::spawn(Self::forward(listener, target.clone()));
+
+        Ok(Self { target, handle })
+    }
+
+    /// Forward new connections to `target`, e.g. after the VM has been restarted
+    fn set_target(&self, target: SocketAddr) {
+        *self.target.lock().unwrap() = target;
+    }
+
+    async fn forward(listener: TcpListener, target: Arc<Mutex<SocketAddr>>) {
+        loop {
+            let mut client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(error) => {
+                    log::error!("Failed to accept VNC connection: {error}");
+                    continue;
+                }
+            };
+            let target = *target.lock().unwrap();
+
+            tokio::spawn(async move {
+                let result = async {
+                    let mut server = TcpStream::connect(target).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut server).await
+                }
+                .await;
+                if let Err(error) = result {
+                    log::error!("VNC connection to {target} failed: {error}");
+                }
+            });
         }
     }
+}
+
+impl Drop for VncForwarder {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Boot the Tart VM `name`, and return its process, pty, and IP address.
+async fn start_vm(name: &str, run_args: &[String]) -> Result<(Child, String, IpAddr)> {
+    let mut tart_cmd = Command::new("tart");
+    tart_cmd.args(["run", name, "--serial"]);
+    tart_cmd.args(run_args);
 
     tart_cmd.stdin(Stdio::piped());
     tart_cmd.stdout(Stdio::piped());
@@ -106,7 +273,7 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<TartInstance>
     let mut tart_cmd = Command::new("tart");
     tart_cmd.args([
         "ip",
-        &machine_copy.name,
+        name,
         "--wait",
         &format!("{}", OBTAIN_IP_TIMEOUT.as_secs()),
     ]);
@@ -123,12 +290,33 @@ pub async fn run(config: &Config, vm_config: &VmConfig) -> Result<TartInstance>
     // IP. The reasons for this are poorly understood.
     crate::vm::network::macos::configure_tunnel().await?;
 
-    Ok(TartInstance {
-        child,
-        pty_path,
-        ip_addr,
-        machine_copy: Some(machine_copy),
-    })
+    Ok((child, pty_path, ip_addr))
+}
+
+async fn tart_clone(source: &str, destination: &str) -> Result<()> {
+    let mut tart_cmd = Command::new("tart");
+    tart_cmd.args(["clone", source, destination]);
+    let output = tart_cmd
+        .status()
+        .await
+        .context("failed to run 'tart clone'")?;
+    if !output.success() {
+        return Err(anyhow!("'tart clone' failed: {output}"));
+    }
+    Ok(())
+}
+
+async fn tart_delete(name: &str) -> Result<()> {
+    let mut tart_cmd = Command::new("tart");
+    tart_cmd.args(["delete", name]);
+    let output = tart_cmd
+        .status()
+        .await
+        .context("Failed to run 'tart delete'")?;
+    if !output.success() {
+        return Err(anyhow!("'tart delete' failed: {output}"));
+    }
+    Ok(())
 }
 
 /// Handle for a transient or borrowed Tart VM.
@@ -151,15 +339,7 @@ impl MachineCopy {
     pub async fn clone_vm(name: &str) -> Result<Self> {
         let clone_name = format!("test-{}", Uuid::new_v4());
 
-        let mut tart_cmd = Command::new("tart");
-        tart_cmd.args(["clone", name, &clone_name]);
-        let output = tart_cmd
-            .status()
-            .await
-            .context("failed to run 'tart clone'")?;
-        if !output.success() {
-            return Err(anyhow!("'tart clone' failed: {output}"));
-        }
+        tart_clone(name, &clone_name).await?;
 
         Ok(Self {
             name: clone_name,
@@ -1,5 +1,5 @@
 use crate::config::{OsType, PackageType, Provisioner, VmConfig};
-use crate::vm::ssh::SSHSession;
+use crate::vm::ssh::{self, SSHSession};
 use anyhow::{Context, Result};
 use std::fmt;
 
@@ -41,8 +41,8 @@ pub fn packages(config: &VmConfig, guest_ip: std::net::IpAddr) -> Result<Update>
         }
         Some(commands) => {
             log::info!("retrieving SSH credentials");
-            let (username, password) = config.get_ssh_options().context("missing SSH config")?;
-            let ssh = SSHSession::connect(username.to_string(), password.to_string(), guest_ip)?;
+            let (username, auth) = config.get_ssh_options().context("missing SSH config")?;
+            let ssh = SSHSession::connect(username, &auth, ssh::server_addr(config, guest_ip)?)?;
             let output: Result<Vec<_>> = commands
                 .iter()
                 .map(|command| {
@@ -11,23 +11,40 @@ const OBTAIN_PTY_TIMEOUT: Duration = Duration::from_secs(5);
 pub struct NoPty;
 
 /// Extract pty path from stdout
+#[cfg(target_os = "macos")]
 pub async fn find_pty(
     re: Regex,
     process: &mut tokio::process::Child,
     log_level: log::Level,
     log_prefix: &str,
 ) -> Result<String, NoPty> {
+    let mut pty_paths = find_ptys(re, 1, process, log_level, log_prefix).await?;
+    Ok(pty_paths.remove(0))
+}
+
+/// Extract `count` pty paths from stdout, in the order that they appear
+pub async fn find_ptys(
+    re: Regex,
+    count: usize,
+    process: &mut tokio::process::Child,
+    log_level: log::Level,
+    log_prefix: &str,
+) -> Result<Vec<String>, NoPty> {
     let stdout = process.stdout.take().unwrap();
     let stdout_reader = BufReader::new(stdout);
 
-    let (pty_path, reader) = timeout(OBTAIN_PTY_TIMEOUT, async {
+    let (pty_paths, reader) = timeout(OBTAIN_PTY_TIMEOUT, async {
         let mut lines = stdout_reader.lines();
+        let mut pty_paths = vec![];
 
         while let Ok(Some(line)) = lines.next_line().await {
             log::log!(log_level, "{log_prefix}{line}");
 
             if let Some(path) = re.captures(&line).and_then(|cap| cap.get(1)) {
-                return Ok((path.as_str().to_owned(), lines.into_inner()));
+                pty_paths.push(path.as_str().to_owned());
+                if pty_paths.len() == count {
+                    return Ok((pty_paths, lines.into_inner()));
+                }
             }
         }
 
@@ -38,5 +55,5 @@ pub async fn find_pty(
 
     process.stdout.replace(reader.into_inner());
 
-    Ok(pty_path)
+    Ok(pty_paths)
 }
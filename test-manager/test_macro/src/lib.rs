@@ -5,8 +5,10 @@
 //!     mut mullvad_client: mullvad_management_interface::ManagementServiceClient,
 //! ) -> Result<(), Error> {
 //! The `mullvad_client` argument can be removed or replaced with the `old_mullvad_management_interface` version.
-//! The `test_function` macro takes 4 optional arguments
-//! #[test_function(priority = -1337, cleanup = false, must_succeed = true, always_run = true)]
+//! The name of the test function is available in its body as `TEST_NAME: &str`. Items with the
+//! same name that are declared in the body take precedence.
+//! The `test_function` macro takes 12 optional arguments
+//! #[test_function(priority = -1337, cleanup = false, must_succeed = true, always_run = true, security_critical = true, xfail_on = "windows", fixture = "setup_fn", params = "1, 2", timeout = 120, retries = 2, os = "linux,macos", depends_on = "test_install_new_app")]
 //!
 //! `priority` is the order in which tests will
 //! be run where low numbers run before high numbers and tests with the same number run in
@@ -23,19 +25,63 @@
 //! `always_run` means that the test is always run regardless of what test filters are provided by
 //! the user.
 //! `always_run` defaults to false.
+//!
+//! `security_critical` means that the test run is considered failed unless this test passes when
+//! it is selected for the run. Being skipped, e.g. by returning `Error::Skipped` or because a
+//! dependency failed, does not count as passing.
+//! `security_critical` defaults to false.
+//!
+//! `xfail_on` is a comma-separated list of operating systems (`linux`, `macos`, `windows`) that
+//! the test is known to fail on. Failures on these are recorded as expected failures, and passes
+//! are flagged as unexpected.
+//! `xfail_on` defaults to no operating systems.
+//!
+//! `fixture` is the path of a function that is run before the test, for staging files or
+//! processes that the test needs. It has the signature
+//! `async fn setup_fn(&TestContext, &ServiceClient) -> Result<T, Error>`. The test is not run if
+//! the fixture fails. The returned value is kept alive until the test has finished, and is then
+//! dropped, so it can be used as a guard that tears down the staged state.
+//! `fixture` defaults to no function.
+//!
+//! `params` is a comma-separated list of expressions. The test is registered once for each of
+//! them, and each case is run and reported separately as `<name>[<arg>=<value>]`, with whitespace
+//! removed. The value is passed to the test function as an additional last argument, after the
+//! `mullvad_client` argument if there is one, e.g. `port: u16` for `params = "53, 51820"`.
+//! `params` defaults to a single case without an additional argument.
+//!
+//! `timeout` is the maximum number of seconds that the test may run. A test that exceeds it is
+//! cancelled and fails.
+//! `timeout` defaults to no limit.
+//!
+//! `retries` is the number of times that a failing test is run again before it is reported as
+//! failed. The cleanup function is run between attempts. `must_succeed` tests only stop the test
+//! run once all attempts have failed.
+//! `retries` defaults to 0.
+//!
+//! `os` is a comma-separated list of operating systems (`linux`, `macos`, `windows`) that the test
+//! is run on. On other operating systems, the test is reported as skipped. Security-critical
+//! tests are not required to pass on them.
+//! `os` defaults to all operating systems.
+//!
+//! `depends_on` is a comma-separated list of names of tests that must run before this test. The
+//! test is skipped if any of them did not pass. Dependencies take precedence over `priority`, and
+//! cyclic dependencies are an error.
+//! `depends_on` defaults to no tests.
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
 use syn::{AttributeArgs, Lit, Meta, NestedMeta};
 
 #[proc_macro_attribute]
 pub fn test_function(attributes: TokenStream, code: TokenStream) -> TokenStream {
-    let function: syn::ItemFn = syn::parse(code).unwrap();
+    let mut function: syn::ItemFn = syn::parse(code).unwrap();
     let attributes = syn::parse_macro_input!(attributes as AttributeArgs);
 
     let test_function = parse_marked_test_function(&attributes, &function);
 
     let register_test = create_test(test_function);
 
+    declare_test_name(&mut function);
+
     quote! {
         #function
         #register_test
@@ -47,7 +93,8 @@ pub fn test_function(attributes: TokenStream, code: TokenStream) -> TokenStream
 fn parse_marked_test_function(attributes: &AttributeArgs, function: &syn::ItemFn) -> TestFunction {
     let macro_parameters = get_test_macro_parameters(attributes);
 
-    let function_parameters = get_test_function_parameters(&function.sig.inputs);
+    let function_parameters =
+        get_test_function_parameters(&function.sig.inputs, !macro_parameters.params.is_empty());
 
     TestFunction {
         name: function.sig.ident.clone(),
@@ -61,6 +108,14 @@ fn get_test_macro_parameters(attributes: &syn::AttributeArgs) -> MacroParameters
     let mut cleanup = true;
     let mut always_run = false;
     let mut must_succeed = false;
+    let mut security_critical = false;
+    let mut xfail_on = vec![];
+    let mut fixture = None;
+    let mut params = vec![];
+    let mut timeout = None;
+    let mut retries = 0;
+    let mut targets = vec![];
+    let mut depends_on = vec![];
     for attribute in attributes {
         if let NestedMeta::Meta(Meta::NameValue(nv)) = attribute {
             if nv.path.is_ident("priority") {
@@ -91,6 +146,81 @@ fn get_test_macro_parameters(attributes: &syn::AttributeArgs) -> MacroParameters
                     }
                     _ => panic!("'cleanup' should have a bool value"),
                 }
+            } else if nv.path.is_ident("security_critical") {
+                match &nv.lit {
+                    Lit::Bool(lit_bool) => {
+                        security_critical = lit_bool.value();
+                    }
+                    _ => panic!("'security_critical' should have a bool value"),
+                }
+            } else if nv.path.is_ident("xfail_on") {
+                match &nv.lit {
+                    Lit::Str(lit_str) => {
+                        xfail_on = parse_os_list("xfail_on", &lit_str.value());
+                    }
+                    _ => panic!("'xfail_on' should have a string value"),
+                }
+            } else if nv.path.is_ident("fixture") {
+                match &nv.lit {
+                    Lit::Str(lit_str) => {
+                        fixture = Some(
+                            lit_str
+                                .parse::<syn::Path>()
+                                .expect("'fixture' should be the path of a function"),
+                        );
+                    }
+                    _ => panic!("'fixture' should have a string value"),
+                }
+            } else if nv.path.is_ident("params") {
+                match &nv.lit {
+                    Lit::Str(lit_str) => {
+                        params = lit_str
+                            .parse_with(
+                                syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated,
+                            )
+                            .expect("'params' should be a list of expressions")
+                            .into_iter()
+                            .collect();
+                    }
+                    _ => panic!("'params' should have a string value"),
+                }
+            } else if nv.path.is_ident("timeout") {
+                match &nv.lit {
+                    Lit::Int(lit_int) => {
+                        timeout = Some(lit_int.base10_parse::<u64>().unwrap_or_else(|_| {
+                            panic!("'timeout' should be a non-negative number of seconds")
+                        }));
+                    }
+                    _ => panic!("'timeout' should have an integer value"),
+                }
+            } else if nv.path.is_ident("retries") {
+                match &nv.lit {
+                    Lit::Int(lit_int) => {
+                        retries = lit_int.base10_parse::<usize>().unwrap_or_else(|_| {
+                            panic!("'retries' should be a non-negative integer")
+                        });
+                    }
+                    _ => panic!("'retries' should have an integer value"),
+                }
+            } else if nv.path.is_ident("os") {
+                match &nv.lit {
+                    Lit::Str(lit_str) => {
+                        targets = parse_os_list("os", &lit_str.value());
+                    }
+                    _ => panic!("'os' should have a string value"),
+                }
+            } else if nv.path.is_ident("depends_on") {
+                match &nv.lit {
+                    Lit::Str(lit_str) => {
+                        depends_on = lit_str
+                            .value()
+                            .split(',')
+                            .map(|name| name.trim().to_owned())
+                            .filter(|name| !name.is_empty())
+                            .collect();
+                    }
+                    _ => panic!("'depends_on' should have a string value"),
+                }
             }
         }
     }
@@ -100,21 +230,102 @@ fn get_test_macro_parameters(attributes: &syn::AttributeArgs) -> MacroParameters
         cleanup,
         always_run,
         must_succeed,
+        security_critical,
+        xfail_on,
+        fixture,
+        params,
+        timeout,
+        retries,
+        targets,
+        depends_on,
     }
 }
 
+fn parse_os_list(option: &str, os_list: &str) -> Vec<proc_macro2::TokenStream> {
+    os_list
+        .split(',')
+        .map(|os| match os.trim().to_lowercase().as_str() {
+            "linux" => quote! { test_rpc::meta::Os::Linux },
+            "macos" => quote! { test_rpc::meta::Os::Macos },
+            "windows" => quote! { test_rpc::meta::Os::Windows },
+            os => panic!("unknown OS in '{option}': {os}"),
+        })
+        .collect()
+}
+
+/// Declare `TEST_NAME` in the body of `function`. The original body is moved into an inner block,
+/// so that items declared by the test can shadow it.
+fn declare_test_name(function: &mut syn::ItemFn) {
+    let name = function.sig.ident.to_string();
+    let body = &function.block;
+    *function.block = syn::parse_quote! {
+        {
+            #[allow(dead_code)]
+            const TEST_NAME: &str = #name;
+            #body
+        }
+    };
+}
+
+/// Register `test_function`. Parametrized tests are registered once per case.
 fn create_test(test_function: TestFunction) -> proc_macro2::TokenStream {
-    let test_function_priority = match test_function.macro_parameters.priority {
+    let param_name = match &test_function.function_parameters.param {
+        Some(param) => param.to_token_stream().to_string().replace(' ', ""),
+        None => return create_test_case(&test_function, None),
+    };
+
+    let cases = test_function.macro_parameters.params.iter().map(|value| {
+        let value_str = value.to_token_stream().to_string().replace(' ', "");
+        let case = TestCase {
+            name: format!("{}[{param_name}={value_str}]", test_function.name),
+            value,
+        };
+        create_test_case(&test_function, Some(case))
+    });
+
+    quote! { #(#cases)* }
+}
+
+/// A single case of a parametrized test
+struct TestCase<'a> {
+    name: String,
+    value: &'a syn::Expr,
+}
+
+fn create_test_case(
+    test_function: &TestFunction,
+    case: Option<TestCase<'_>>,
+) -> proc_macro2::TokenStream {
+    let test_function_priority = match &test_function.macro_parameters.priority {
         Some(priority) => quote! {Some(#priority)},
         None => quote! {None},
     };
     let should_cleanup = test_function.macro_parameters.cleanup;
     let always_run = test_function.macro_parameters.always_run;
     let must_succeed = test_function.macro_parameters.must_succeed;
+    let security_critical = test_function.macro_parameters.security_critical;
+    let xfail_on = &test_function.macro_parameters.xfail_on;
+    let retries = test_function.macro_parameters.retries;
+    let targets = &test_function.macro_parameters.targets;
+    let depends_on = &test_function.macro_parameters.depends_on;
+    let timeout = match test_function.macro_parameters.timeout {
+        Some(timeout) => quote! { Some(std::time::Duration::from_secs(#timeout)) },
+        None => quote! { None },
+    };
+    let run_fixture = match &test_function.macro_parameters.fixture {
+        Some(fixture) => quote! {
+            let _fixture = #fixture(&test_context, &rpc).await?;
+        },
+        None => quote! {},
+    };
 
-    let func_name = test_function.name;
+    let func_name = &test_function.name;
+    let (test_name, param_arg) = match case {
+        Some(TestCase { name, value }) => (quote! { #name }, quote! { , #value }),
+        None => (quote! { stringify!(#func_name) }, quote! {}),
+    };
     let function_mullvad_version = test_function.function_parameters.mullvad_client.version();
-    let wrapper_closure = match test_function.function_parameters.mullvad_client {
+    let wrapper_closure = match &test_function.function_parameters.mullvad_client {
         MullvadClient::New {
             mullvad_client_type,
             ..
@@ -123,7 +334,6 @@ fn create_test(test_function: TestFunction) -> proc_macro2::TokenStream {
             mullvad_client_type,
             ..
         } => {
-            let mullvad_client_type = *mullvad_client_type;
             quote! {
                 |test_context: crate::tests::TestContext,
                 rpc: test_rpc::ServiceClient,
@@ -132,7 +342,8 @@ fn create_test(test_function: TestFunction) -> proc_macro2::TokenStream {
                     use std::any::Any;
                     let mullvad_client = mullvad_client.downcast::<#mullvad_client_type>().expect("invalid mullvad client");
                     Box::pin(async move {
-                        #func_name(test_context, rpc, *mullvad_client).await
+                        #run_fixture
+                        #func_name(test_context, rpc, *mullvad_client #param_arg).await
                     })
                 }
             }
@@ -143,7 +354,8 @@ fn create_test(test_function: TestFunction) -> proc_macro2::TokenStream {
                 rpc: test_rpc::ServiceClient,
                 mullvad_client: Box<dyn std::any::Any + Send>| {
                     Box::pin(async move {
-                        #func_name(test_context, rpc).await
+                        #run_fixture
+                        #func_name(test_context, rpc #param_arg).await
                     })
                 }
             }
@@ -152,14 +364,20 @@ fn create_test(test_function: TestFunction) -> proc_macro2::TokenStream {
 
     quote! {
         inventory::submit!(crate::tests::test_metadata::TestMetadata {
-            name: stringify!(#func_name),
-            command: stringify!(#func_name),
+            name: #test_name,
+            command: #test_name,
             mullvad_client_version: #function_mullvad_version,
             func: Box::new(#wrapper_closure),
             priority: #test_function_priority,
             always_run: #always_run,
             must_succeed: #must_succeed,
+            security_critical: #security_critical,
+            xfail_on: &[#(#xfail_on),*],
+            targets: &[#(#targets),*],
+            depends_on: &[#(#depends_on),*],
             cleanup: #should_cleanup,
+            timeout: #timeout,
+            retries: #retries,
         });
     }
 }
@@ -175,6 +393,14 @@ struct MacroParameters {
     cleanup: bool,
     always_run: bool,
     must_succeed: bool,
+    security_critical: bool,
+    xfail_on: Vec<proc_macro2::TokenStream>,
+    fixture: Option<syn::Path>,
+    params: Vec<syn::Expr>,
+    timeout: Option<u64>,
+    retries: usize,
+    targets: Vec<proc_macro2::TokenStream>,
+    depends_on: Vec<String>,
 }
 
 enum MullvadClient {
@@ -211,11 +437,34 @@ impl MullvadClient {
 
 struct FunctionParameters {
     mullvad_client: MullvadClient,
+    /// Pattern of the last argument, which the value of a parametrized test is passed as
+    param: Option<Box<syn::Pat>>,
 }
 
 fn get_test_function_parameters(
     inputs: &syn::punctuated::Punctuated<syn::FnArg, syn::Token![,]>,
+    parametrized: bool,
 ) -> FunctionParameters {
+    let mut inputs = inputs.clone();
+    let param = if parametrized {
+        match inputs.pop().map(|pair| pair.into_value()) {
+            Some(syn::FnArg::Typed(pat_type)) => Some(pat_type.pat),
+            _ => panic!("'params' requires an additional argument"),
+        }
+    } else {
+        None
+    };
+
+    let mullvad_client = get_mullvad_client(&inputs);
+    FunctionParameters {
+        mullvad_client,
+        param,
+    }
+}
+
+fn get_mullvad_client(
+    inputs: &syn::punctuated::Punctuated<syn::FnArg, syn::Token![,]>,
+) -> MullvadClient {
     if inputs.len() > 2 {
         match inputs[2].clone() {
             syn::FnArg::Typed(pat_type) => {
@@ -242,15 +491,13 @@ fn get_test_function_parameters(
                     }
                     _ => panic!("unexpected 'mullvad_client' type"),
                 };
-                FunctionParameters { mullvad_client }
+                mullvad_client
             }
             syn::FnArg::Receiver(_) => panic!("unexpected 'mullvad_client' arg"),
         }
     } else {
-        FunctionParameters {
-            mullvad_client: MullvadClient::None {
-                mullvad_client_version: quote! { test_rpc::mullvad_daemon::MullvadClientVersion::None },
-            },
+        MullvadClient::None {
+            mullvad_client_version: quote! { test_rpc::mullvad_daemon::MullvadClientVersion::None },
         }
     }
 }
@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    path::Path,
     time::{Duration, SystemTime},
 };
 
@@ -8,13 +9,36 @@ use crate::mullvad_daemon::ServiceStatus;
 use super::*;
 
 const INSTALL_TIMEOUT: Duration = Duration::from_secs(300);
+/// Default time to wait for a program started by `exec` to exit
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
 const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
 const LOG_LEVEL_TIMEOUT: Duration = Duration::from_secs(60);
+const TRACEROUTE_TIMEOUT: Duration = Duration::from_secs(150);
+/// Time to wait for `send_ping`, in addition to the time spent sending echo requests.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time to wait for `read_file` and `write_file`, in addition to the time spent transferring the
+/// contents.
+const FILE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lowest expected throughput of file contents, in bytes per second. The contents are sent as
+/// JSON over the serial connection, so this is well below the baud rate.
+const MIN_FILE_TRANSFER_RATE: usize = 2 * 1024;
+
+/// Reasonable timeout for `http_get`.
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of lines returned by `get_logs_tail` by default.
+pub const DEFAULT_LOG_TAIL_LINES: usize = 100;
+
+/// Time to wait for a file of `size` bytes to be read or written.
+fn file_transfer_timeout(size: usize) -> Duration {
+    FILE_TIMEOUT + Duration::from_secs(u64::try_from(size / MIN_FILE_TRANSFER_RATE).unwrap())
+}
 
 #[derive(Debug, Clone)]
 pub struct ServiceClient {
     connection_handle: transport::ConnectionHandle,
     client: service::ServiceClient,
+    file_sender: file_transfer::FileSender,
 }
 
 // TODO: implement wrapper methods using macro on Service trait
@@ -26,11 +50,13 @@ impl ServiceClient {
             tarpc::Response<service::ServiceResponse>,
             tarpc::ClientMessage<service::ServiceRequest>,
         >,
+        file_forwarder: transport::FileForwarder,
     ) -> Self {
         Self {
             connection_handle,
             client: super::service::ServiceClient::new(tarpc::client::Config::default(), transport)
                 .spawn(),
+            file_sender: file_transfer::FileSender::new(file_forwarder),
         }
     }
 
@@ -45,6 +71,28 @@ impl ServiceClient {
             .map_err(Error::Tarpc)?
     }
 
+    /// Send the app package at `path` on the host to the guest, and install it. Unlike
+    /// `install_app`, this does not require the package to be provisioned on the guest.
+    pub async fn install_app_from_host(&self, path: &Path) -> Result<(), Error> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::FileSystem(format!("Invalid package path: {}", path.display())))?
+            .to_string_lossy()
+            .into_owned();
+
+        log::debug!("Sending package {}", path.display());
+        self.file_sender
+            .send_file(&name, path)
+            .await
+            .map_err(|error| Error::FileSystem(error.to_string()))?;
+
+        self.install_app(package::Package {
+            path: PathBuf::from(name),
+            streamed: true,
+        })
+        .await
+    }
+
     /// Remove app package.
     pub async fn uninstall_app(&self, env: HashMap<String, String>) -> Result<(), Error> {
         let mut ctx = tarpc::context::current();
@@ -53,7 +101,8 @@ impl ServiceClient {
         self.client.uninstall_app(ctx, env).await?
     }
 
-    /// Execute a program.
+    /// Execute a program. This fails with `Error::TestRunnerTimeout` if the program does not exit
+    /// within `timeout`, or 30 seconds if no timeout is given.
     pub async fn exec_env<
         I: Iterator<Item = T>,
         M: IntoIterator<Item = (K, T)>,
@@ -64,9 +113,12 @@ impl ServiceClient {
         path: T,
         args: I,
         env: M,
+        timeout: Option<Duration>,
     ) -> Result<ExecResult, Error> {
         let mut ctx = tarpc::context::current();
-        ctx.deadline = SystemTime::now().checked_add(INSTALL_TIMEOUT).unwrap();
+        ctx.deadline = SystemTime::now()
+            .checked_add(timeout.unwrap_or(DEFAULT_EXEC_TIMEOUT))
+            .unwrap();
         self.client
             .exec(
                 ctx,
@@ -76,40 +128,147 @@ impl ServiceClient {
                     .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
                     .collect(),
             )
-            .await?
+            .await
+            .map_err(|error| match error {
+                tarpc::client::RpcError::DeadlineExceeded => Error::TestRunnerTimeout,
+                error => Error::Tarpc(error),
+            })?
     }
 
-    /// Execute a program.
+    /// Execute a program. See `exec_env`.
     pub async fn exec<I: Iterator<Item = T>, T: AsRef<str>>(
         &self,
         path: T,
         args: I,
+        timeout: Option<Duration>,
     ) -> Result<ExecResult, Error> {
         let env: [(&str, T); 0] = [];
-        self.exec_env(path, args, env).await
+        self.exec_env(path, args, env, timeout).await
+    }
+
+    /// Start a program without waiting for it to exit. Unlike `exec`, its output can be read
+    /// while it is running, and it can be killed.
+    pub async fn spawn_env<
+        I: Iterator<Item = T>,
+        M: IntoIterator<Item = (K, T)>,
+        T: AsRef<str>,
+        K: AsRef<str>,
+    >(
+        &self,
+        path: T,
+        args: I,
+        env: M,
+    ) -> Result<ProcessHandle, Error> {
+        let id = self
+            .client
+            .spawn(
+                tarpc::context::current(),
+                path.as_ref().to_string(),
+                args.into_iter().map(|v| v.as_ref().to_string()).collect(),
+                env.into_iter()
+                    .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+                    .collect(),
+            )
+            .await??;
+        Ok(ProcessHandle {
+            client: self.clone(),
+            id,
+        })
+    }
+
+    /// Start a program without waiting for it to exit.
+    pub async fn spawn<I: Iterator<Item = T>, T: AsRef<str>>(
+        &self,
+        path: T,
+        args: I,
+    ) -> Result<ProcessHandle, Error> {
+        let env: [(&str, T); 0] = [];
+        self.spawn_env(path, args, env).await
+    }
+
+    /// Return the output of a process started using `spawn` since the last call.
+    pub async fn poll_process(&self, id: ProcessId) -> Result<ProcessOutput, Error> {
+        self.client
+            .poll_process(tarpc::context::current(), id)
+            .await?
+    }
+
+    /// Forcefully kill a process started using `spawn`.
+    pub async fn kill(&self, id: ProcessId) -> Result<(), Error> {
+        log::debug!("Killing process {}", id.0);
+        self.client.kill(tarpc::context::current(), id).await?
     }
 
     /// Get the output of the runners stdout logs since the last time this function was called.
-    /// Block if there is no output until some output is provided by the runner.
+    /// If there is no output, wait for some output for a few seconds before returning.
     pub async fn poll_output(&self) -> Result<Vec<logging::Output>, Error> {
         self.client.poll_output(tarpc::context::current()).await?
     }
 
+    /// Like `poll_output`, but wait for at most `timeout` for output.
+    pub async fn poll_output_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Vec<logging::Output>, Error> {
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now()
+            .checked_add(timeout + Duration::from_secs(5))
+            .unwrap();
+        self.client.poll_output_timeout(ctx, timeout).await?
+    }
+
     /// Get the output of the runners stdout logs since the last time this function was called.
-    /// Block if there is no output until some output is provided by the runner.
+    /// Return immediately if there is no output.
     pub async fn try_poll_output(&self) -> Result<Vec<logging::Output>, Error> {
         self.client
             .try_poll_output(tarpc::context::current())
             .await?
     }
 
-    pub async fn get_mullvad_app_logs(&self) -> Result<logging::LogOutput, Error> {
+    /// Return the daemon settings and logs. If `min_level` is set, only messages of at least
+    /// that severity are included.
+    pub async fn get_mullvad_app_logs(
+        &self,
+        min_level: Option<logging::LogLevel>,
+    ) -> Result<logging::LogOutput, Error> {
         self.client
-            .get_mullvad_app_logs(tarpc::context::current())
+            .get_mullvad_app_logs(tarpc::context::current(), min_level)
             .await
             .map_err(Error::Tarpc)
     }
 
+    /// Return the contents of all daemon log files. Unlike `get_mullvad_app_logs`, this does not
+    /// include the GUI logs or the settings.
+    pub async fn get_daemon_logs(&self) -> Result<Vec<logging::Result<logging::LogFile>>, Error> {
+        self.client
+            .get_daemon_logs(tarpc::context::current())
+            .await?
+    }
+
+    /// Return the contents of all GUI log files.
+    pub async fn get_gui_logs(&self) -> Result<Vec<logging::Result<logging::LogFile>>, Error> {
+        self.client.get_gui_logs(tarpc::context::current()).await?
+    }
+
+    /// Return the last `num_lines` lines of the daemon log, or `DEFAULT_LOG_TAIL_LINES` lines if
+    /// `num_lines` is `None`. Unlike `get_mullvad_app_logs`, this does not transfer the entire log.
+    pub async fn get_logs_tail(&self, num_lines: Option<usize>) -> Result<Vec<String>, Error> {
+        self.client
+            .get_logs_tail(
+                tarpc::context::current(),
+                num_lines.unwrap_or(DEFAULT_LOG_TAIL_LINES),
+            )
+            .await?
+    }
+
+    /// Return recent kernel log messages from the guest, e.g. from the WireGuard kernel module
+    /// or the split tunnel driver.
+    pub async fn get_kernel_logs(&self) -> Result<String, Error> {
+        self.client
+            .get_kernel_logs(tarpc::context::current())
+            .await?
+    }
+
     /// Return the OS of the guest.
     pub async fn get_os(&self) -> Result<meta::Os, Error> {
         self.client
@@ -146,6 +305,20 @@ impl ServiceClient {
             .map_err(Error::Tarpc)
     }
 
+    /// Return the version of the installed app.
+    pub async fn mullvad_daemon_get_version(&self) -> Result<String, Error> {
+        self.client
+            .mullvad_daemon_get_version(tarpc::context::current())
+            .await?
+    }
+
+    /// Returns the path, ownership, and permissions of the management interface socket.
+    pub async fn get_daemon_socket_info(&self) -> Result<mullvad_daemon::SocketInfo, Error> {
+        self.client
+            .get_daemon_socket_info(tarpc::context::current())
+            .await?
+    }
+
     /// Returns all Mullvad app files, directories, and other data found on the system.
     pub async fn find_mullvad_app_traces(&self) -> Result<Vec<AppTrace>, Error> {
         self.client
@@ -153,6 +326,41 @@ impl ServiceClient {
             .await?
     }
 
+    /// Returns the size, in bytes, of each app install and data directory found on the system.
+    pub async fn get_disk_usage(&self) -> Result<BTreeMap<PathBuf, u64>, Error> {
+        self.client
+            .get_disk_usage(tarpc::context::current())
+            .await?
+    }
+
+    /// Read the file at `path` on the guest.
+    pub async fn read_file(&self, path: impl Into<PathBuf>) -> Result<Vec<u8>, Error> {
+        // The size is not known in advance, so allow for the largest possible file
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now()
+            .checked_add(file_transfer_timeout(MAX_FILE_SIZE))
+            .unwrap();
+        self.client.read_file(ctx, path.into()).await?
+    }
+
+    /// Create or replace the file at `path` on the guest.
+    pub async fn write_file(
+        &self,
+        path: impl Into<PathBuf>,
+        contents: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let contents = contents.into();
+        // Fail early rather than sending an oversized request
+        if contents.len() > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now()
+            .checked_add(file_transfer_timeout(contents.len()))
+            .unwrap();
+        self.client.write_file(ctx, path.into(), contents).await?
+    }
+
     /// Send TCP packet
     pub async fn send_tcp(
         &self,
@@ -177,21 +385,104 @@ impl ServiceClient {
             .await?
     }
 
-    /// Send ICMP
+    /// Connect to `destination` over TCP and send `payload`. Unlike `send_tcp`, this waits for
+    /// the connection to be established and the entire payload to be sent.
+    pub async fn send_tcp_payload(
+        &self,
+        interface: Option<Interface>,
+        bind_addr: SocketAddr,
+        destination: SocketAddr,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.client
+            .send_tcp_payload(
+                tarpc::context::current(),
+                interface,
+                bind_addr,
+                destination,
+                payload,
+            )
+            .await?
+    }
+
+    /// Send a UDP datagram containing `payload`.
+    pub async fn send_udp_payload(
+        &self,
+        interface: Option<Interface>,
+        bind_addr: SocketAddr,
+        destination: SocketAddr,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.client
+            .send_udp_payload(
+                tarpc::context::current(),
+                interface,
+                bind_addr,
+                destination,
+                payload,
+            )
+            .await?
+    }
+
+    /// Send ICMP echo requests, and return the statistics reported by `ping`. This succeeds
+    /// even if no replies are received.
     pub async fn send_ping(
         &self,
         interface: Option<Interface>,
         destination: IpAddr,
-    ) -> Result<(), Error> {
+        options: PingOptions,
+    ) -> Result<PingSummary, Error> {
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now()
+            .checked_add(PING_TIMEOUT + options.interval * options.count)
+            .unwrap();
         self.client
-            .send_ping(tarpc::context::current(), interface, destination)
+            .send_ping(ctx, interface, destination, options)
             .await?
     }
 
     /// Fetch the current location.
-    pub async fn geoip_lookup(&self, mullvad_host: String) -> Result<AmIMullvad, Error> {
+    pub async fn geoip_lookup(
+        &self,
+        mullvad_host: String,
+        options: GeoIpOptions,
+    ) -> Result<AmIMullvad, Error> {
+        self.client
+            .geoip_lookup(tarpc::context::current(), mullvad_host, options)
+            .await?
+    }
+
+    /// Trace the path to `destination`, optionally from the given interface.
+    pub async fn traceroute(
+        &self,
+        interface: Option<Interface>,
+        destination: IpAddr,
+    ) -> Result<Vec<Hop>, Error> {
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now().checked_add(TRACEROUTE_TIMEOUT).unwrap();
+
+        self.client.traceroute(ctx, interface, destination).await?
+    }
+
+    /// Perform an HTTP(S) GET request, optionally using a socket bound to the given interface, as
+    /// for `send_tcp`. Returns the status code and body of the response, or `Error::Timeout` if
+    /// no response is received within `timeout`. See `DEFAULT_HTTP_TIMEOUT`.
+    ///
+    /// Only servers with certificates issued by Let's Encrypt can be reached over HTTPS.
+    pub async fn http_get(
+        &self,
+        interface: Option<Interface>,
+        url: &str,
+        timeout: Duration,
+    ) -> Result<HttpResponse, Error> {
+        // Leave some time for the runner to report the timeout
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now()
+            .checked_add(timeout + Duration::from_secs(5))
+            .unwrap();
+
         self.client
-            .geoip_lookup(tarpc::context::current(), mullvad_host)
+            .http_get(ctx, interface, url.to_owned(), timeout)
             .await?
     }
 
@@ -203,9 +494,41 @@ impl ServiceClient {
     }
 
     /// Returns the IP of the given interface.
-    pub async fn get_interface_ip(&self, interface: Interface) -> Result<IpAddr, Error> {
+    pub async fn get_interface_ip(
+        &self,
+        interface: Interface,
+        family: AddressFamily,
+    ) -> Result<IpAddr, Error> {
         self.client
-            .get_interface_ip(tarpc::context::current(), interface)
+            .get_interface_ip(tarpc::context::current(), interface, family)
+            .await?
+    }
+
+    /// Returns all IPv6 addresses of the given interface, including link-local addresses.
+    pub async fn get_interface_ipv6(&self, interface: Interface) -> Result<Vec<Ipv6Addr>, Error> {
+        self.client
+            .get_interface_ipv6(tarpc::context::current(), interface)
+            .await?
+    }
+
+    /// Returns all network interfaces on the guest and their addresses.
+    pub async fn get_interfaces(&self) -> Result<Vec<InterfaceInfo>, Error> {
+        self.client
+            .get_interfaces(tarpc::context::current())
+            .await?
+    }
+
+    /// Returns all open TCP and UDP sockets on the guest.
+    pub async fn list_connections(&self) -> Result<Vec<ConnectionInfo>, Error> {
+        self.client
+            .list_connections(tarpc::context::current())
+            .await?
+    }
+
+    /// Returns the cumulative traffic counters of the given interface.
+    pub async fn get_interface_stats(&self, interface: Interface) -> Result<IfStats, Error> {
+        self.client
+            .get_interface_stats(tarpc::context::current(), interface)
             .await?
     }
 
@@ -281,9 +604,93 @@ impl ServiceClient {
         Ok(())
     }
 
+    /// Forcefully kill all processes named `process_name`, without letting them clean up. On
+    /// Windows, the name should include the `.exe` extension.
+    pub async fn kill_process(&self, process_name: &str) -> Result<(), Error> {
+        self.client
+            .kill_process(tarpc::context::current(), process_name.to_owned())
+            .await?
+    }
+
     pub async fn make_device_json_old(&self) -> Result<(), Error> {
         self.client
             .make_device_json_old(tarpc::context::current())
             .await?
     }
+
+    pub async fn set_relay_list_override(&self, relay_list: Option<String>) -> Result<(), Error> {
+        log::debug!("Setting relay list override");
+        self.client
+            .set_relay_list_override(tarpc::context::current(), relay_list)
+            .await?
+    }
+
+    /// Reset the daemon settings and cache. This restarts the daemon.
+    pub async fn reset_settings(&self) -> Result<Vec<PathBuf>, Error> {
+        log::debug!("Resetting daemon settings");
+        let mut ctx = tarpc::context::current();
+        ctx.deadline = SystemTime::now().checked_add(REBOOT_TIMEOUT).unwrap();
+        self.client.reset_settings(ctx).await?
+    }
+
+    pub async fn get_system_time(&self) -> Result<SystemTime, Error> {
+        self.client
+            .get_system_time(tarpc::context::current())
+            .await?
+    }
+
+    pub async fn set_system_time(&self, time: SystemTime) -> Result<(), Error> {
+        log::debug!("Setting system time");
+        self.client
+            .set_system_time(tarpc::context::current(), time)
+            .await?
+    }
+
+    pub async fn set_time_sync(&self, enabled: bool) -> Result<(), Error> {
+        log::debug!("Setting time synchronization: {enabled}");
+        self.client
+            .set_time_sync(tarpc::context::current(), enabled)
+            .await?
+    }
+}
+
+/// A process on the guest started using `ServiceClient::spawn`
+#[derive(Debug, Clone)]
+pub struct ProcessHandle {
+    client: ServiceClient,
+    id: ProcessId,
+}
+
+impl ProcessHandle {
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    /// Return the output of the process since the last call.
+    pub async fn poll(&self) -> Result<ProcessOutput, Error> {
+        self.client.poll_process(self.id).await
+    }
+
+    /// Forcefully kill the process.
+    pub async fn kill(&self) -> Result<(), Error> {
+        self.client.kill(self.id).await
+    }
+
+    /// Wait for the process to exit, and return all output that has not already been polled.
+    pub async fn wait(self) -> Result<ExecResult, Error> {
+        let mut result = ExecResult {
+            code: None,
+            stdout: vec![],
+            stderr: vec![],
+        };
+        loop {
+            let output = self.poll().await?;
+            result.stdout.extend(output.stdout);
+            result.stderr.extend(output.stderr);
+            if output.exited {
+                result.code = output.code;
+                return Ok(result);
+            }
+        }
+    }
 }
@@ -0,0 +1,156 @@
+//! Transfer files over the file transfer channel of the serial connection.
+//!
+//! A transfer begins with a frame containing the name of the file, followed by its contents in
+//! chunks of at most `CHUNK_SIZE` bytes. An empty frame ends the transfer.
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Framed, LengthDelimitedCodec};
+
+use crate::transport::FileForwarder;
+
+/// Size of each chunk. This is kept below the buffer size of the channel.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Sends files to the other end of the file transfer channel.
+#[derive(Debug, Clone)]
+pub struct FileSender {
+    channel: Arc<tokio::sync::Mutex<Framed<FileForwarder, LengthDelimitedCodec>>>,
+}
+
+impl FileSender {
+    pub fn new(forwarder: FileForwarder) -> Self {
+        Self {
+            channel: Arc::new(tokio::sync::Mutex::new(
+                LengthDelimitedCodec::new().framed(forwarder),
+            )),
+        }
+    }
+
+    /// Send the file at `path`. The receiver makes it available as `name`.
+    pub async fn send_file(&self, name: &str, path: &Path) -> io::Result<()> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut channel = self.channel.lock().await;
+
+        channel.send(Bytes::from(name.to_owned())).await?;
+
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let result = loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break Ok(()),
+                Ok(num_bytes) => {
+                    channel
+                        .send(Bytes::copy_from_slice(&buffer[..num_bytes]))
+                        .await?
+                }
+                Err(error) => break Err(error),
+            }
+        };
+
+        // Always end the transfer, so that the channel can be used again
+        channel.send(Bytes::new()).await?;
+
+        result
+    }
+}
+
+/// Files that have been received by `receive_files`, by name.
+#[derive(Debug, Clone, Default)]
+pub struct ReceivedFiles {
+    files: Arc<std::sync::Mutex<HashMap<String, PathBuf>>>,
+    notify: Arc<tokio::sync::Notify>,
+}
+
+impl ReceivedFiles {
+    /// Wait until the file `name` has been received, and return its path. Each received file
+    /// can only be taken once.
+    pub async fn take(&self, name: &str) -> PathBuf {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(path) = self.files.lock().unwrap().remove(name) {
+                return path;
+            }
+            notified.await;
+        }
+    }
+
+    fn insert(&self, name: String, path: PathBuf) {
+        self.files.lock().unwrap().insert(name, path);
+        self.notify.notify_waiters();
+    }
+}
+
+/// Write all files received on `forwarder` to `dir`, and add them to `received`. This returns
+/// when the channel is closed.
+pub async fn receive_files(forwarder: FileForwarder, dir: PathBuf, received: ReceivedFiles) {
+    let mut channel = LengthDelimitedCodec::new().framed(forwarder);
+
+    loop {
+        let name = match channel.next().await {
+            Some(Ok(name)) => String::from_utf8_lossy(&name).into_owned(),
+            Some(Err(error)) => {
+                log::error!("File transfer channel error: {error}");
+                break;
+            }
+            None => break,
+        };
+
+        // Only use the file name, so that files cannot be written outside `dir`
+        let path = Path::new(&name)
+            .file_name()
+            .map(|file_name| dir.join(file_name));
+        let mut file = match &path {
+            Some(path) => match tokio::fs::File::create(path).await {
+                Ok(file) => Some(file),
+                Err(error) => {
+                    log::error!("Failed to create {}: {error}", path.display());
+                    None
+                }
+            },
+            None => {
+                log::error!("Received file has an invalid name: {name}");
+                None
+            }
+        };
+
+        log::debug!("Receiving file {name}");
+
+        // Consume the entire transfer even if the file cannot be written
+        loop {
+            let chunk = match channel.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(error)) => {
+                    log::error!("File transfer channel error: {error}");
+                    return;
+                }
+                None => return,
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            if let Some(writer) = file.as_mut() {
+                if let Err(error) = writer.write_all(&chunk).await {
+                    log::error!("Failed to write {name}: {error}");
+                    file = None;
+                }
+            }
+        }
+
+        if let (Some(mut file), Some(path)) = (file, path) {
+            match file.flush().await {
+                Ok(()) => {
+                    log::debug!("Received file {name}");
+                    received.insert(name, path);
+                }
+                Err(error) => log::error!("Failed to write {name}: {error}"),
+            }
+        }
+    }
+}
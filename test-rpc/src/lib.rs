@@ -1,11 +1,13 @@
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     path::PathBuf,
+    time::{Duration, SystemTime},
 };
 
 pub mod client;
+pub mod file_transfer;
 pub mod logging;
 pub mod meta;
 pub mod mullvad_daemon;
@@ -13,6 +15,10 @@ pub mod net;
 pub mod package;
 pub mod transport;
 
+/// Maximum size, in bytes, of files transferred using `read_file` and `write_file`. This must
+/// be well below the maximum frame length of the transport.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
 #[derive(err_derive::Error, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Error {
     #[error(display = "Test runner RPC failed")]
@@ -53,6 +59,24 @@ pub enum Error {
     InvalidUrl,
     #[error(display = "Timeout")]
     Timeout,
+    #[error(display = "Failed to kill process")]
+    KillProcess(String),
+    #[error(display = "Failed to list connections")]
+    ListConnections(String),
+    #[error(display = "Failed to set the system time")]
+    SetSystemTime(String),
+    #[error(display = "Failed to read interface statistics")]
+    InterfaceStats(String),
+    #[error(display = "Failed to obtain the app version")]
+    AppVersion(String),
+    #[error(display = "File exceeds the maximum transfer size")]
+    FileTooLarge,
+    #[error(display = "No process with the given ID was started")]
+    UnknownProcess,
+    #[error(display = "Failed to list network interfaces")]
+    ListInterfaces(String),
+    #[error(display = "Failed to trace the route to the destination")]
+    Traceroute(String),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
@@ -61,12 +85,156 @@ pub enum Interface {
     NonTunnel,
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
 /// Response from am.i.mullvad.net
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AmIMullvad {
     pub ip: IpAddr,
     pub mullvad_exit_ip: bool,
     pub mullvad_exit_ip_hostname: String,
+    /// URL that the response was fetched from. This is not part of the response itself.
+    #[serde(default)]
+    pub endpoint: String,
+}
+
+/// Options for `geoip_lookup`. The defaults look up the IPv4 address using
+/// `am.i.<mullvad_host>`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct GeoIpOptions {
+    /// Host to use instead of `am.i.<mullvad_host>`
+    pub host: Option<String>,
+    /// Address family to look up. Defaults to IPv4.
+    pub family: Option<AddressFamily>,
+}
+
+/// Transport protocol of a socket
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum SocketProtocol {
+    Tcp,
+    Udp,
+}
+
+/// An open socket on the guest
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectionInfo {
+    pub protocol: SocketProtocol,
+    pub local_addr: SocketAddr,
+    /// Remote address, if the socket is connected
+    pub remote_addr: Option<SocketAddr>,
+    /// Connection state, e.g. `ESTABLISHED` or `LISTEN`. This is platform-specific and not
+    /// available for all sockets
+    pub state: Option<String>,
+    /// ID of the process that owns the socket, if known
+    pub pid: Option<u32>,
+    /// Name of the process that owns the socket, if known
+    pub process_name: Option<String>,
+}
+
+/// A network interface on the guest
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    pub name: String,
+    /// Interface index, if known
+    pub index: Option<u32>,
+    /// Hardware address, if the interface has one
+    pub mac: Option<[u8; 6]>,
+    /// All IP addresses assigned to the interface, including link-local addresses
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Response to an HTTP(S) request
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// A hop on the path to a destination, as reported by `traceroute`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub ttl: u8,
+    /// Address of the hop, or `None` if it did not respond
+    pub addr: Option<IpAddr>,
+    /// Round-trip time of the first probe that was answered
+    pub rtt: Option<Duration>,
+}
+
+impl std::fmt::Display for Hop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>2} ", self.ttl)?;
+        match self.addr {
+            Some(addr) => write!(f, "{addr}")?,
+            None => f.write_str("*")?,
+        }
+        if let Some(rtt) = self.rtt {
+            write!(f, " ({} ms)", rtt.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// Options for `send_ping`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct PingOptions {
+    /// Size of the payload of each echo request, in bytes. If not set, the default size of
+    /// `ping` is used.
+    pub size: Option<usize>,
+    /// Number of echo requests to send
+    pub count: u32,
+    /// Time between echo requests. This is ignored on Windows.
+    pub interval: Duration,
+}
+
+impl Default for PingOptions {
+    /// Send a single echo request of the default size
+    fn default() -> Self {
+        Self {
+            size: None,
+            count: 1,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Statistics reported by `send_ping`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PingSummary {
+    pub sent: u32,
+    pub received: u32,
+    /// Average round-trip time of the echo replies, if any were received
+    pub avg_rtt: Option<Duration>,
+}
+
+impl PingSummary {
+    /// Fraction of the echo requests that were not answered
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 1.0;
+        }
+        1.0 - f64::from(self.received.min(self.sent)) / f64::from(self.sent)
+    }
+}
+
+/// Cumulative traffic counters of a network interface
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct IfStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    /// Time at which the counters were read
+    pub timestamp: SystemTime,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -82,13 +250,31 @@ impl ExecResult {
     }
 }
 
+/// Identifies a process started using `spawn`. This is not the PID of the process.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProcessId(pub u32);
+
+/// Output of a process started using `spawn`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProcessOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether the process has exited. No more output follows once this is set
+    pub exited: bool,
+    /// Exit code of the process, if it has exited normally
+    pub code: Option<i32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum AppTrace {
     Path(PathBuf),
 }
 
 mod service {
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
 
     pub use super::*;
 
@@ -107,15 +293,49 @@ mod service {
             env: BTreeMap<String, String>,
         ) -> Result<ExecResult, Error>;
 
+        /// Start a program without waiting for it to exit. Its output can be read using
+        /// `poll_process`.
+        async fn spawn(
+            path: String,
+            args: Vec<String>,
+            env: BTreeMap<String, String>,
+        ) -> Result<ProcessId, Error>;
+
+        /// Return the output of a process started using `spawn` since the last time this
+        /// function was called. This blocks for a short while if there is no new output. Once the
+        /// process has exited and all of its output has been returned, the process is forgotten.
+        async fn poll_process(id: ProcessId) -> Result<ProcessOutput, Error>;
+
+        /// Forcefully kill a process started using `spawn`. Its remaining output can still be
+        /// read using `poll_process`.
+        async fn kill(id: ProcessId) -> Result<(), Error>;
+
         /// Get the output of the runners stdout logs since the last time this function was called.
-        /// Block if there is no output until some output is provided by the runner.
+        /// If there is no output, wait for some output for a few seconds before returning.
         async fn poll_output() -> Result<Vec<logging::Output>, Error>;
 
+        /// Like `poll_output`, but wait for at most `timeout` for output.
+        async fn poll_output_timeout(timeout: Duration) -> Result<Vec<logging::Output>, Error>;
+
         /// Get the output of the runners stdout logs since the last time this function was called.
-        /// Block if there is no output until some output is provided by the runner.
+        /// Return immediately if there is no output.
         async fn try_poll_output() -> Result<Vec<logging::Output>, Error>;
 
-        async fn get_mullvad_app_logs() -> logging::LogOutput;
+        /// Return the daemon settings and logs. If `min_level` is set, only messages of at least
+        /// that severity are included.
+        async fn get_mullvad_app_logs(min_level: Option<logging::LogLevel>) -> logging::LogOutput;
+
+        /// Return the contents of all daemon log files.
+        async fn get_daemon_logs() -> Result<Vec<logging::Result<logging::LogFile>>, Error>;
+
+        /// Return the contents of all GUI log files.
+        async fn get_gui_logs() -> Result<Vec<logging::Result<logging::LogFile>>, Error>;
+
+        /// Return the last `num_lines` lines of the daemon log.
+        async fn get_logs_tail(num_lines: usize) -> Result<Vec<String>, Error>;
+
+        /// Return recent kernel log messages from the guest.
+        async fn get_kernel_logs() -> Result<String, Error>;
 
         /// Return the OS of the guest.
         async fn get_os() -> meta::Os;
@@ -123,9 +343,26 @@ mod service {
         /// Return status of the system service.
         async fn mullvad_daemon_get_status() -> mullvad_daemon::ServiceStatus;
 
+        /// Return the version of the installed app, as reported by the CLI.
+        async fn mullvad_daemon_get_version() -> Result<String, Error>;
+
+        /// Return the path, ownership, and permissions of the management interface socket.
+        async fn get_daemon_socket_info() -> Result<mullvad_daemon::SocketInfo, Error>;
+
         /// Returns all Mullvad app files, directories, and other data found on the system.
         async fn find_mullvad_app_traces() -> Result<Vec<AppTrace>, Error>;
 
+        /// Returns the size, in bytes, of each app install and data directory found on the system.
+        async fn get_disk_usage() -> Result<BTreeMap<PathBuf, u64>, Error>;
+
+        /// Return the contents of the file at `path`. Files larger than `MAX_FILE_SIZE` are
+        /// rejected.
+        async fn read_file(path: PathBuf) -> Result<Vec<u8>, Error>;
+
+        /// Create or replace the file at `path` with `contents`. At most `MAX_FILE_SIZE` bytes
+        /// may be written.
+        async fn write_file(path: PathBuf, contents: Vec<u8>) -> Result<(), Error>;
+
         /// Send TCP packet
         async fn send_tcp(
             interface: Option<Interface>,
@@ -140,17 +377,73 @@ mod service {
             destination: SocketAddr,
         ) -> Result<(), Error>;
 
-        /// Send ICMP
-        async fn send_ping(interface: Option<Interface>, destination: IpAddr) -> Result<(), Error>;
+        /// Connect to `destination` over TCP and send `payload`. Unlike `send_tcp`, this waits for
+        /// the connection to be established and the entire payload to be sent.
+        async fn send_tcp_payload(
+            interface: Option<Interface>,
+            bind_addr: SocketAddr,
+            destination: SocketAddr,
+            payload: Vec<u8>,
+        ) -> Result<(), Error>;
+
+        /// Send a UDP datagram containing `payload`.
+        async fn send_udp_payload(
+            interface: Option<Interface>,
+            bind_addr: SocketAddr,
+            destination: SocketAddr,
+            payload: Vec<u8>,
+        ) -> Result<(), Error>;
+
+        /// Send ICMP echo requests, and return the statistics reported by `ping`. This succeeds
+        /// even if no replies are received.
+        async fn send_ping(
+            interface: Option<Interface>,
+            destination: IpAddr,
+            options: PingOptions,
+        ) -> Result<PingSummary, Error>;
 
         /// Fetch the current location.
-        async fn geoip_lookup(mullvad_host: String) -> Result<AmIMullvad, Error>;
+        async fn geoip_lookup(
+            mullvad_host: String,
+            options: GeoIpOptions,
+        ) -> Result<AmIMullvad, Error>;
+
+        /// Trace the path to `destination`, optionally from the given interface. Hops that do not
+        /// respond are included with no address.
+        async fn traceroute(
+            interface: Option<Interface>,
+            destination: IpAddr,
+        ) -> Result<Vec<Hop>, Error>;
+
+        /// Perform an HTTP(S) GET request, optionally using a socket bound to the given interface.
+        /// This fails with `Error::Timeout` if no complete response is received within `timeout`.
+        async fn http_get(
+            interface: Option<Interface>,
+            url: String,
+            timeout: Duration,
+        ) -> Result<HttpResponse, Error>;
 
         /// Returns the name of the given interface.
         async fn get_interface_name(interface: Interface) -> Result<String, Error>;
 
-        /// Returns the IP of the given interface.
-        async fn get_interface_ip(interface: Interface) -> Result<IpAddr, Error>;
+        /// Returns the first IP of the given interface that belongs to `family`. Link-local IPv6
+        /// addresses are ignored.
+        async fn get_interface_ip(
+            interface: Interface,
+            family: AddressFamily,
+        ) -> Result<IpAddr, Error>;
+
+        /// Returns all IPv6 addresses of the given interface, including link-local addresses.
+        async fn get_interface_ipv6(interface: Interface) -> Result<Vec<Ipv6Addr>, Error>;
+
+        /// Returns all network interfaces on the guest and their addresses.
+        async fn get_interfaces() -> Result<Vec<InterfaceInfo>, Error>;
+
+        /// Returns all open TCP and UDP sockets on the guest.
+        async fn list_connections() -> Result<Vec<ConnectionInfo>, Error>;
+
+        /// Returns the cumulative traffic counters of the given interface.
+        async fn get_interface_stats(interface: Interface) -> Result<IfStats, Error>;
 
         /// Perform DNS resolution.
         async fn resolve_hostname(hostname: String) -> Result<Vec<SocketAddr>, Error>;
@@ -171,7 +464,29 @@ mod service {
 
         async fn set_mullvad_daemon_service_state(on: bool) -> Result<(), Error>;
 
+        /// Forcefully kill all processes named `process_name`, without letting them clean up.
+        async fn kill_process(process_name: String) -> Result<(), Error>;
+
         async fn make_device_json_old() -> Result<(), Error>;
+
+        /// Replace the relay list cached by the daemon with `relay_list`, which must be a JSON
+        /// serialized relay list. If `relay_list` is `None`, the cached relay list is removed.
+        /// The daemon only reads the cache when it starts, so it must be restarted afterwards.
+        async fn set_relay_list_override(relay_list: Option<String>) -> Result<(), Error>;
+
+        /// Stop the daemon, remove its settings and cached files, and start it again. The device
+        /// is kept, so the daemon remains logged in. This returns the paths of all removed files.
+        async fn reset_settings() -> Result<Vec<PathBuf>, Error>;
+
+        /// Return the current time according to the system clock of the guest.
+        async fn get_system_time() -> Result<SystemTime, Error>;
+
+        /// Set the system clock of the guest to `time`. Automatic time synchronization may undo
+        /// the change, so it should be disabled first using `set_time_sync`.
+        async fn set_system_time(time: SystemTime) -> Result<(), Error>;
+
+        /// Enable or disable automatic time synchronization (NTP) on the guest.
+        async fn set_time_sync(enabled: bool) -> Result<(), Error>;
     }
 }
 
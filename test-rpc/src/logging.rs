@@ -9,6 +9,8 @@ pub enum Error {
     StandardOutput,
     #[error(display = "Could not get mullvad app logs from runner")]
     Logs(String),
+    #[error(display = "Could not get kernel logs from runner")]
+    KernelLogs(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,5 +41,116 @@ pub struct LogOutput {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogFile {
     pub name: std::path::PathBuf,
+    /// Unparsed contents of the log
     pub content: String,
 }
+
+impl LogFile {
+    /// Parse the contents of the log. See `LogLine`.
+    pub fn lines(&self) -> Vec<LogLine> {
+        parse_log(&self.content)
+    }
+
+    /// Keep only the lines of at least the severity `min_level`.
+    pub fn filter(self, min_level: LogLevel) -> LogFile {
+        let content = self
+            .lines()
+            .into_iter()
+            .filter(|line| line.level <= min_level)
+            .map(|line| format!("{line}\n"))
+            .collect();
+        LogFile {
+            name: self.name,
+            content,
+        }
+    }
+}
+
+/// Level of a log line, from most to least severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(level: &str) -> std::result::Result<Self, Self::Err> {
+        match level {
+            "ERROR" => Ok(LogLevel::Error),
+            "WARN" => Ok(LogLevel::Warn),
+            "INFO" => Ok(LogLevel::Info),
+            "DEBUG" => Ok(LogLevel::Debug),
+            "TRACE" => Ok(LogLevel::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        })
+    }
+}
+
+/// A message in a daemon log, such as
+/// `[2023-05-10 12:34:56.789][mullvad_daemon::version_check][INFO] Version check is disabled`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+    pub timestamp: String,
+    pub level: LogLevel,
+    pub target: String,
+    /// The message, which may span several lines
+    pub message: String,
+}
+
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}][{}][{}] {}",
+            self.timestamp, self.target, self.level, self.message
+        )
+    }
+}
+
+/// Parse a daemon log into messages. Lines without a header are appended to the previous
+/// message, and lines before the first header are ignored.
+pub fn parse_log(content: &str) -> Vec<LogLine> {
+    let mut lines: Vec<LogLine> = vec![];
+    for line in content.lines() {
+        match parse_log_header(line) {
+            Some(log_line) => lines.push(log_line),
+            None => {
+                if let Some(last) = lines.last_mut() {
+                    last.message.push('\n');
+                    last.message.push_str(line);
+                }
+            }
+        }
+    }
+    lines
+}
+
+fn parse_log_header(line: &str) -> Option<LogLine> {
+    let rest = line.strip_prefix('[')?;
+    let (timestamp, rest) = rest.split_once("][")?;
+    let (target, rest) = rest.split_once("][")?;
+    let (level, message) = rest.split_once(']')?;
+    Some(LogLine {
+        timestamp: timestamp.to_owned(),
+        level: level.parse().ok()?,
+        target: target.to_owned(),
+        message: message.strip_prefix(' ').unwrap_or(message).to_owned(),
+    })
+}
@@ -5,6 +5,9 @@ pub const SOCKET_PATH: &str = "/var/run/mullvad-vpn";
 #[cfg(windows)]
 pub const SOCKET_PATH: &str = "//./pipe/Mullvad VPN";
 
+/// Logged by the test runner when the test manager closes the management interface connection
+pub const MANAGEMENT_INTERFACE_EOF_MESSAGE: &str = "management interface EOF; restarting server";
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Error {
     ConnectError,
@@ -20,6 +23,21 @@ pub enum ServiceStatus {
     Running,
 }
 
+/// Location and access control of the management interface socket
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SocketInfo {
+    pub path: String,
+    pub permissions: SocketPermissions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SocketPermissions {
+    /// Mode and ownership of a Unix domain socket
+    Unix { mode: u32, uid: u32, gid: u32 },
+    /// Owner and DACL of a named pipe, in SDDL form
+    Windows { security_descriptor: String },
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub enum Verbosity {
     Info,
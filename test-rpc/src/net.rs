@@ -1,9 +1,18 @@
-use hyper::{Client, Uri};
+use futures::future::BoxFuture;
+use hyper::{service::Service, Client, Uri};
 use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::TcpStream;
 use tokio_rustls::rustls::ClientConfig;
 
-use crate::{AmIMullvad, Error};
+use crate::{AddressFamily, AmIMullvad, Error, GeoIpOptions, HttpResponse};
 
 const LE_ROOT_CERT: &[u8] = include_bytes!("./le_root_cert.pem");
 
@@ -17,38 +26,131 @@ static CLIENT_CONFIG: Lazy<ClientConfig> = Lazy::new(|| {
         .with_no_client_auth()
 });
 
-pub async fn geoip_lookup(mullvad_host: String) -> Result<AmIMullvad, Error> {
-    let uri = Uri::try_from(format!("https://ipv4.am.i.{mullvad_host}/json"))
-        .map_err(|_| Error::InvalidUrl)?;
-    http_get(uri).await
+pub async fn geoip_lookup(
+    mullvad_host: String,
+    options: GeoIpOptions,
+) -> Result<AmIMullvad, Error> {
+    let host = options
+        .host
+        .unwrap_or_else(|| format!("am.i.{mullvad_host}"));
+    let family = match options.family.unwrap_or(AddressFamily::Ipv4) {
+        AddressFamily::Ipv4 => "ipv4",
+        AddressFamily::Ipv6 => "ipv6",
+    };
+    let endpoint = format!("https://{family}.{host}/json");
+    let uri = Uri::try_from(endpoint.as_str()).map_err(|_| Error::InvalidUrl)?;
+    let mut response: AmIMullvad = http_get(uri).await?;
+    response.endpoint = endpoint;
+    Ok(response)
 }
 
+/// Opens a TCP connection to the given address
+pub type Connect =
+    Arc<dyn Fn(SocketAddr) -> BoxFuture<'static, io::Result<TcpStream>> + Send + Sync>;
+
 pub async fn http_get<T: DeserializeOwned>(url: Uri) -> Result<T, Error> {
+    let (_status, body) = send_get_request(url, None, true).await?;
+
+    serde_json::from_slice(&body).map_err(|error| {
+        log::error!("Failed to deserialize response: {}", error);
+        Error::DeserializeBody
+    })
+}
+
+/// Perform an HTTP(S) GET request and return the status code and body of the response. If
+/// `connect` is set, it is used to open the connection, e.g. to bind it to an interface.
+pub async fn http_get_with_status(
+    url: String,
+    connect: Option<Connect>,
+    timeout: Duration,
+) -> Result<HttpResponse, Error> {
+    let url = Uri::try_from(url).map_err(|_| Error::InvalidUrl)?;
+    let (status, body) = tokio::time::timeout(timeout, send_get_request(url, connect, false))
+        .await
+        .map_err(|_elapsed| Error::Timeout)??;
+    Ok(HttpResponse { status, body })
+}
+
+async fn send_get_request(
+    url: Uri,
+    connect: Option<Connect>,
+    https_only: bool,
+) -> Result<(u16, Vec<u8>), Error> {
     log::debug!("GET {url}");
 
-    let https = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_tls_config(CLIENT_CONFIG.clone())
-        .https_only()
-        .enable_http1()
-        .build();
+    let http = TcpConnector(connect.unwrap_or_else(|| {
+        Arc::new(
+            |address: SocketAddr| -> BoxFuture<'static, io::Result<TcpStream>> {
+                Box::pin(TcpStream::connect(address))
+            },
+        )
+    }));
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new().with_tls_config(CLIENT_CONFIG.clone());
+    let https = if https_only {
+        https.https_only()
+    } else {
+        https.https_or_http()
+    };
+    let https = https.enable_http1().wrap_connector(http);
 
     let client: Client<_, hyper::Body> = Client::builder().build(https);
-    let body = client
+    let response = client
         .get(url)
         .await
-        .map_err(|error| Error::HttpRequest(error.to_string()))?
-        .into_body();
+        .map_err(|error| Error::HttpRequest(error.to_string()))?;
+    let status = response.status().as_u16();
 
     // TODO: limit length
-    let bytes = hyper::body::to_bytes(body).await.map_err(|error| {
-        log::error!("Failed to convert body to bytes buffer: {}", error);
-        Error::DeserializeBody
-    })?;
+    let bytes = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|error| {
+            log::error!("Failed to convert body to bytes buffer: {}", error);
+            Error::DeserializeBody
+        })?;
 
-    serde_json::from_slice(&bytes).map_err(|error| {
-        log::error!("Failed to deserialize response: {}", error);
-        Error::DeserializeBody
-    })
+    Ok((status, bytes.to_vec()))
+}
+
+/// Connector that resolves the host of a URI and opens TCP connections using a `Connect`
+/// function
+#[derive(Clone)]
+struct TcpConnector(Connect);
+
+impl Service<Uri> for TcpConnector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<TcpStream>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connect = self.0.clone();
+
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+
+            let mut last_error =
+                io::Error::new(io::ErrorKind::NotFound, "no addresses found for host");
+            for address in tokio::net::lookup_host((host, port)).await? {
+                match connect(address).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(error) => last_error = error,
+                }
+            }
+            Err(last_error)
+        })
+    }
 }
 
 fn read_cert_store() -> tokio_rustls::rustls::RootCertStore {
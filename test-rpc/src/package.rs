@@ -42,5 +42,9 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Package {
+    /// Path to the package on the guest. If `streamed` is set, this is instead the name that the
+    /// package was sent as using the file transfer channel.
     pub path: PathBuf,
+    #[serde(default)]
+    pub streamed: bool,
 }
@@ -31,6 +31,7 @@ pub enum Frame {
     Handshake,
     TestRunner(Bytes),
     DaemonRpc(Bytes),
+    FileTransfer(Bytes),
 }
 
 #[repr(u8)]
@@ -38,6 +39,7 @@ enum FrameType {
     Handshake,
     TestRunner,
     DaemonRpc,
+    FileTransfer,
 }
 
 impl TryFrom<u8> for FrameType {
@@ -48,12 +50,15 @@ impl TryFrom<u8> for FrameType {
             i if i == FrameType::Handshake as u8 => Ok(FrameType::Handshake),
             i if i == FrameType::TestRunner as u8 => Ok(FrameType::TestRunner),
             i if i == FrameType::DaemonRpc as u8 => Ok(FrameType::DaemonRpc),
+            i if i == FrameType::FileTransfer as u8 => Ok(FrameType::FileTransfer),
             _ => Err(()),
         }
     }
 }
 
 pub type GrpcForwarder = tokio::io::DuplexStream;
+/// Channel for transferring files. See [`crate::file_transfer`].
+pub type FileForwarder = tokio::io::DuplexStream;
 pub type CompletionHandle = tokio::task::JoinHandle<()>;
 
 #[derive(Debug, Clone)]
@@ -128,11 +133,13 @@ pub fn create_server_transports(
         Response<ServiceResponse>,
     >,
     GrpcForwarder,
+    FileForwarder,
     CompletionHandle,
 ) {
     let (runner_forwarder_1, runner_forwarder_2) = tarpc::transport::channel::unbounded();
 
     let (daemon_rx, mullvad_daemon_forwarder) = tokio::io::duplex(DAEMON_CHANNEL_BUF_SIZE);
+    let (file_rx, file_forwarder) = tokio::io::duplex(DAEMON_CHANNEL_BUF_SIZE);
 
     let (handshake_tx, handshake_rx) = mpsc::unbounded();
 
@@ -143,6 +150,7 @@ pub fn create_server_transports(
             serial_stream,
             runner_forwarder_2,
             mullvad_daemon_forwarder,
+            file_forwarder,
             (handshake_tx, handshake_rx),
             None,
             // The server needs to be init to connected, or it will skip things it shouldn't
@@ -159,7 +167,7 @@ pub fn create_server_transports(
         }
     });
 
-    (runner_forwarder_1, daemon_rx, completion_handle)
+    (runner_forwarder_1, daemon_rx, file_rx, completion_handle)
 }
 
 pub async fn create_client_transports(
@@ -171,6 +179,7 @@ pub async fn create_client_transports(
             ClientMessage<ServiceRequest>,
         >,
         GrpcForwarder,
+        FileForwarder,
         ConnectionHandle,
         CompletionHandle,
     ),
@@ -179,6 +188,7 @@ pub async fn create_client_transports(
     let (runner_forwarder_1, runner_forwarder_2) = tarpc::transport::channel::unbounded();
 
     let (daemon_rx, mullvad_daemon_forwarder) = tokio::io::duplex(DAEMON_CHANNEL_BUF_SIZE);
+    let (file_rx, file_forwarder) = tokio::io::duplex(DAEMON_CHANNEL_BUF_SIZE);
 
     let (handshake_tx, handshake_rx) = mpsc::unbounded();
 
@@ -193,6 +203,7 @@ pub async fn create_client_transports(
             serial_stream,
             runner_forwarder_1,
             mullvad_daemon_forwarder,
+            file_forwarder,
             (handshake_tx, handshake_rx),
             Some(handshake_fwd_tx),
             connected_state,
@@ -211,6 +222,7 @@ pub async fn create_client_transports(
     Ok((
         runner_forwarder_2,
         daemon_rx,
+        file_rx,
         conn_handle,
         completion_handle,
     ))
@@ -234,6 +246,9 @@ enum ForwardError {
     #[error(display = "Daemon channel error")]
     DaemonChannel(#[error(source)] io::Error),
 
+    #[error(display = "File transfer channel error")]
+    FileChannel(#[error(source)] io::Error),
+
     #[error(display = "Handshake error")]
     HandshakeError(#[error(source)] io::Error),
 }
@@ -245,6 +260,7 @@ async fn forward_messages<
     serial_stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
     mut runner_forwarder: tarpc::transport::channel::UnboundedChannel<T, S>,
     mullvad_daemon_forwarder: GrpcForwarder,
+    file_forwarder: FileForwarder,
     mut handshaker: (mpsc::UnboundedSender<()>, mpsc::UnboundedReceiver<()>),
     handshake_fwd: Option<mpsc::UnboundedSender<()>>,
     connected_state: Arc<AtomicBool>,
@@ -254,11 +270,15 @@ async fn forward_messages<
 
     // Needs to be framed to allow empty messages.
     let mut mullvad_daemon_forwarder = LengthDelimitedCodec::new().framed(mullvad_daemon_forwarder);
+    let mut file_forwarder = LengthDelimitedCodec::new().framed(file_forwarder);
 
     loop {
         match futures::future::select(
             futures::future::select(serial_stream.next(), handshaker.1.next()),
-            futures::future::select(runner_forwarder.next(), mullvad_daemon_forwarder.next()),
+            futures::future::select(
+                runner_forwarder.next(),
+                futures::future::select(mullvad_daemon_forwarder.next(), file_forwarder.next()),
+            ),
         )
         .await
         {
@@ -284,6 +304,12 @@ async fn forward_messages<
                             .await
                             .map_err(ForwardError::DaemonChannel)?;
                     }
+                    Frame::FileTransfer(data) => {
+                        file_forwarder
+                            .send(data)
+                            .await
+                            .map_err(ForwardError::FileChannel)?;
+                    }
                     Frame::Handshake => {
                         log::trace!("shake: recv");
                         if let Some(shake_fwd) = handshake_fwd.as_ref() {
@@ -322,7 +348,7 @@ async fn forward_messages<
                     .map_err(ForwardError::SerialConnection)?;
             }
             futures::future::Either::Right((
-                futures::future::Either::Right((Some(data), _)),
+                futures::future::Either::Right((futures::future::Either::Left((Some(data), _)), _)),
                 _,
             )) => {
                 let data = data.map_err(ForwardError::DaemonChannel)?;
@@ -336,7 +362,24 @@ async fn forward_messages<
                     .await
                     .map_err(ForwardError::SerialConnection)?;
             }
-            futures::future::Either::Right((futures::future::Either::Right((None, _)), _)) => {
+            futures::future::Either::Right((
+                futures::future::Either::Right((
+                    futures::future::Either::Right((Some(data), _)),
+                    _,
+                )),
+                _,
+            )) => {
+                let data = data.map_err(ForwardError::FileChannel)?;
+
+                serial_stream
+                    .send(Frame::FileTransfer(data.into()))
+                    .await
+                    .map_err(ForwardError::SerialConnection)?;
+            }
+            futures::future::Either::Right((
+                futures::future::Either::Right((futures::future::Either::Left((None, _)), _)),
+                _,
+            )) => {
                 //
                 // Force management interface socket to close
                 //
@@ -387,6 +430,7 @@ impl MultiplexCodec {
             FrameType::Handshake => Ok(Frame::Handshake),
             FrameType::TestRunner => Ok(Frame::TestRunner(frame.into())),
             FrameType::DaemonRpc => Ok(Frame::DaemonRpc(frame.into())),
+            FrameType::FileTransfer => Ok(Frame::FileTransfer(frame.into())),
         }
     }
 
@@ -477,6 +521,9 @@ impl Encoder<Frame> for MultiplexCodec {
             ),
             Frame::TestRunner(bytes) => self.encode_frame(FrameType::TestRunner, Some(bytes), dst),
             Frame::DaemonRpc(bytes) => self.encode_frame(FrameType::DaemonRpc, Some(bytes), dst),
+            Frame::FileTransfer(bytes) => {
+                self.encode_frame(FrameType::FileTransfer, Some(bytes), dst)
+            }
         }
     }
 }
@@ -1,5 +1,9 @@
 use chrono::{DateTime, Utc};
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
 
 use test_rpc::{AppTrace, Error};
 
@@ -92,6 +96,80 @@ pub fn find_traces() -> Result<Vec<AppTrace>, Error> {
         .collect())
 }
 
+/// Returns the size of each app install and data directory that exists on the system.
+pub async fn get_disk_usage() -> Result<BTreeMap<PathBuf, u64>, Error> {
+    let paths = install_dirs()?;
+
+    tokio::task::spawn_blocking(move || {
+        let mut usage = BTreeMap::new();
+        for path in paths {
+            let path_exists = path.try_exists().map_err(|error| {
+                log::error!("Failed to check whether path exists: {error}");
+                Error::Syscall
+            })?;
+            if !path_exists {
+                continue;
+            }
+            let size = disk_usage(&path).map_err(|error| {
+                log::error!("Failed to obtain disk usage of {}: {error}", path.display());
+                Error::FileSystem(error.to_string())
+            })?;
+            usage.insert(path, size);
+        }
+        Ok(usage)
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(target_os = "windows")]
+fn install_dirs() -> Result<Vec<PathBuf>, Error> {
+    let settings_dir = mullvad_paths::get_default_settings_dir().map_err(|error| {
+        log::error!("Failed to obtain system app data: {error}");
+        Error::Syscall
+    })?;
+
+    Ok(vec![
+        PathBuf::from(r"C:\Program Files\Mullvad VPN"),
+        PathBuf::from(r"C:\ProgramData\Mullvad VPN"),
+        settings_dir,
+    ])
+}
+
+#[cfg(target_os = "linux")]
+fn install_dirs() -> Result<Vec<PathBuf>, Error> {
+    Ok(vec![
+        PathBuf::from(r"/opt/Mullvad VPN/"),
+        PathBuf::from(r"/etc/mullvad-vpn/"),
+        PathBuf::from(r"/var/log/mullvad-vpn/"),
+        PathBuf::from(r"/var/cache/mullvad-vpn/"),
+    ])
+}
+
+#[cfg(target_os = "macos")]
+fn install_dirs() -> Result<Vec<PathBuf>, Error> {
+    Ok(vec![
+        PathBuf::from(r"/Applications/Mullvad VPN.app/"),
+        PathBuf::from(r"/etc/mullvad-vpn/"),
+        PathBuf::from(r"/var/log/mullvad-vpn/"),
+        PathBuf::from(r"/Library/Caches/mullvad-vpn/"),
+    ])
+}
+
+/// Sum the sizes of all files under `path`. Symbolic links are not followed.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += disk_usage(&entry?.path())?;
+    }
+    Ok(size)
+}
+
 fn filter_non_existent_paths(paths: &mut Vec<&Path>) -> Result<(), Error> {
     for i in (0..paths.len()).rev() {
         let path_exists = paths[i].try_exists().map_err(|error| {
@@ -142,3 +220,175 @@ pub async fn make_device_json_old() -> Result<(), Error> {
 
     Ok(())
 }
+
+/// Name of the file in the daemon cache directory that contains the relay list
+const RELAY_LIST_FILENAME: &str = "relays.json";
+
+/// Replace the relay list cached by the daemon, or remove it if `relay_list` is `None`.
+pub async fn set_relay_list_override(relay_list: Option<String>) -> Result<(), Error> {
+    let cache_dir = mullvad_paths::get_default_cache_dir().map_err(|error| {
+        log::error!("Failed to obtain cache directory: {error}");
+        Error::Syscall
+    })?;
+    let relay_list_path = cache_dir.join(RELAY_LIST_FILENAME);
+
+    let result = match relay_list {
+        Some(relay_list) => tokio::fs::write(&relay_list_path, relay_list.as_bytes()).await,
+        None => match tokio::fs::remove_file(&relay_list_path).await {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        },
+    };
+    result.map_err(|error| {
+        log::error!(
+            "Failed to update relay list at {}: {error}",
+            relay_list_path.display()
+        );
+        Error::FileSystem(error.to_string())
+    })
+}
+
+/// Files in the settings directory that are removed by [`reset_settings`]. The device file is
+/// kept, since removing it would log out the daemon without revoking the device.
+const SETTINGS_FILES: &[&str] = &["settings.json"];
+
+/// Stop the daemon, remove its settings and cache, and start it again. This returns the paths of
+/// all files that were removed.
+pub async fn reset_settings() -> Result<Vec<PathBuf>, Error> {
+    let settings_dir = mullvad_paths::get_default_settings_dir().map_err(|error| {
+        log::error!("Failed to obtain settings directory: {error}");
+        Error::Syscall
+    })?;
+    let cache_dir = mullvad_paths::get_default_cache_dir().map_err(|error| {
+        log::error!("Failed to obtain cache directory: {error}");
+        Error::Syscall
+    })?;
+
+    crate::sys::set_mullvad_daemon_service_state(false).await?;
+
+    let result = async {
+        let mut paths: Vec<PathBuf> = SETTINGS_FILES
+            .iter()
+            .map(|file| settings_dir.join(file))
+            .collect();
+        paths.extend(list_files(&cache_dir).await?);
+
+        let mut removed = vec![];
+        for path in paths {
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed.push(path),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+                Err(error) => {
+                    log::error!("Failed to remove {}: {error}", path.display());
+                    return Err(Error::FileSystem(error.to_string()));
+                }
+            }
+        }
+        Ok(removed)
+    }
+    .await;
+
+    // Start the daemon again even if some files could not be removed
+    crate::sys::set_mullvad_daemon_service_state(true).await?;
+
+    result
+}
+
+/// Return all regular files in `dir`, or nothing if `dir` does not exist.
+async fn list_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => {
+            log::error!("Failed to read {}: {error}", dir.display());
+            return Err(Error::FileSystem(error.to_string()));
+        }
+    };
+
+    let mut files = vec![];
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|error| Error::FileSystem(error.to_string()))?
+    {
+        let is_file = entry
+            .file_type()
+            .await
+            .map_err(|error| Error::FileSystem(error.to_string()))?
+            .is_file();
+        if is_file {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Path to the CLI, used if it is not in `PATH`.
+#[cfg(target_os = "linux")]
+const CLI_PATH: &str = "/usr/bin/mullvad";
+#[cfg(target_os = "macos")]
+const CLI_PATH: &str = "/usr/local/bin/mullvad";
+#[cfg(target_os = "windows")]
+const CLI_PATH: &str = r"C:\Program Files\Mullvad VPN\resources\mullvad.exe";
+
+/// Return the version of the installed app, as reported by `mullvad --version`. On Windows, the
+/// version resource of the daemon binary is used if the CLI cannot be run.
+pub async fn get_version() -> Result<String, Error> {
+    let mut errors = vec![];
+
+    for cli in ["mullvad", CLI_PATH] {
+        match get_cli_version(cli).await {
+            Ok(version) => return Ok(version),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    match get_daemon_file_version().await {
+        Ok(version) => return Ok(version),
+        Err(error) => errors.push(error),
+    }
+
+    log::error!("Failed to obtain app version: {}", errors.join(", "));
+    Err(Error::AppVersion(errors.join(", ")))
+}
+
+async fn get_cli_version(cli: &str) -> Result<String, String> {
+    let output = tokio::process::Command::new(cli)
+        .arg("--version")
+        .output()
+        .await
+        .map_err(|error| format!("failed to run {cli}: {error}"))?;
+    if !output.status.success() {
+        return Err(format!("{cli} --version failed: {}", output.status));
+    }
+    // The output has the form "mullvad-cli 2023.4"
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .last()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("{cli} --version printed no version"))
+}
+
+#[cfg(target_os = "windows")]
+async fn get_daemon_file_version() -> Result<String, String> {
+    const DAEMON_PATH: &str = r"C:\Program Files\Mullvad VPN\resources\mullvad-daemon.exe";
+
+    let output = tokio::process::Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-Command",
+            &format!("(Get-Item '{DAEMON_PATH}').VersionInfo.ProductVersion"),
+        ])
+        .output()
+        .await
+        .map_err(|error| format!("failed to run powershell: {error}"))?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if !output.status.success() || version.is_empty() {
+        return Err(format!(
+            "failed to read version resource of {DAEMON_PATH}: {}",
+            output.status
+        ));
+    }
+    Ok(version)
+}
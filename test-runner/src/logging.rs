@@ -1,25 +1,66 @@
 use lazy_static::lazy_static;
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
-use std::path::{Path, PathBuf};
-use test_rpc::logging::Error;
-use test_rpc::logging::{LogFile, LogOutput, Output};
-use tokio::{
-    fs::read_to_string,
-    sync::{
-        broadcast::{channel, Receiver, Sender},
-        Mutex,
-    },
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
 };
+use test_rpc::logging::Error;
+use test_rpc::logging::{LogFile, LogLevel, LogOutput, Output};
+use tokio::{fs::read_to_string, sync::Notify};
 
-const MAX_OUTPUT_BUFFER: usize = 10_000;
+/// Maximum number of messages kept by `LOGGER`. When the buffer is full, the oldest messages are
+/// discarded.
+const OUTPUT_BUFFER_SIZE: usize = 10_000;
+/// Maximum time that `poll_output` waits for output.
+pub const POLL_OUTPUT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of bytes read from the end of a log by `get_logs_tail`.
+const MAX_TAIL_BYTES: u64 = 1024 * 1024;
+/// Size of each chunk read by `get_logs_tail`.
+const TAIL_CHUNK_SIZE: u64 = 8 * 1024;
 lazy_static! {
-    pub static ref LOGGER: StdOutBuffer = {
-        let (sender, listener) = channel(MAX_OUTPUT_BUFFER);
-        StdOutBuffer(Mutex::new(listener), sender)
-    };
+    pub static ref LOGGER: StdOutBuffer = StdOutBuffer::default();
+}
+
+/// Bounded buffer of log messages that have not yet been polled
+#[derive(Default)]
+pub struct StdOutBuffer {
+    buffer: Mutex<VecDeque<Output>>,
+    notify: Notify,
 }
 
-pub struct StdOutBuffer(pub Mutex<Receiver<Output>>, pub Sender<Output>);
+impl StdOutBuffer {
+    fn push(&self, output: Output) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() >= OUTPUT_BUFFER_SIZE {
+            buffer.pop_front();
+        }
+        buffer.push_back(output);
+        self.notify.notify_waiters();
+    }
+
+    /// Return all buffered messages without waiting.
+    pub fn take(&self) -> Vec<Output> {
+        self.buffer.lock().unwrap().drain(..).collect()
+    }
+
+    /// Wait until there are buffered messages, for at most `timeout`, and return them. If no
+    /// messages are logged in time, nothing is returned.
+    pub async fn wait_and_take(&self, timeout: Duration) -> Vec<Output> {
+        let wait_for_output = async {
+            loop {
+                let notified = self.notify.notified();
+                if !self.buffer.lock().unwrap().is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait_for_output).await;
+        self.take()
+    }
+}
 
 impl log::Log for StdOutBuffer {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -29,21 +70,11 @@ impl log::Log for StdOutBuffer {
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             match record.metadata().level() {
-                Level::Error => {
-                    self.1
-                        .send(Output::Error(format!("{}", record.args())))
-                        .unwrap();
-                }
-                Level::Warn => {
-                    self.1
-                        .send(Output::Warning(format!("{}", record.args())))
-                        .unwrap();
-                }
+                Level::Error => self.push(Output::Error(format!("{}", record.args()))),
+                Level::Warn => self.push(Output::Warning(format!("{}", record.args()))),
                 Level::Info => {
                     if !record.metadata().target().contains("tarpc") {
-                        self.1
-                            .send(Output::Info(format!("{}", record.args())))
-                            .unwrap();
+                        self.push(Output::Info(format!("{}", record.args())));
                     }
                 }
                 _ => (),
@@ -59,13 +90,150 @@ pub fn init_logger() -> Result<(), SetLoggerError> {
     log::set_logger(&*LOGGER).map(|()| log::set_max_level(LevelFilter::Info))
 }
 
-pub async fn get_mullvad_app_logs() -> LogOutput {
+pub async fn get_mullvad_app_logs(min_level: Option<LogLevel>) -> LogOutput {
+    let log_files = get_daemon_logs().await.map(|log_files| match min_level {
+        Some(min_level) => log_files
+            .into_iter()
+            .map(|log_file| log_file.map(|log_file| log_file.filter(min_level)))
+            .collect(),
+        None => log_files,
+    });
     LogOutput {
         settings_json: read_settings_file().await,
-        log_files: read_log_files().await,
+        log_files,
     }
 }
 
+/// Return the contents of all log files in the daemon log directory.
+pub async fn get_daemon_logs() -> Result<Vec<Result<LogFile, Error>>, Error> {
+    let log_dir =
+        mullvad_paths::get_default_log_dir().map_err(|error| Error::Logs(format!("{}", error)))?;
+    read_log_files(log_dir).await
+}
+
+/// Return the contents of all log files in the GUI log directory.
+pub async fn get_gui_logs() -> Result<Vec<Result<LogFile, Error>>, Error> {
+    read_log_files(gui_log_dir()?).await
+}
+
+/// Return the directory that the GUI writes its logs to. This is the Electron default for each
+/// platform, for the user running the test runner.
+#[cfg(target_os = "linux")]
+fn gui_log_dir() -> Result<PathBuf, Error> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("/root/.config"));
+    Ok(config_dir.join("Mullvad VPN").join("logs"))
+}
+
+#[cfg(target_os = "macos")]
+fn gui_log_dir() -> Result<PathBuf, Error> {
+    let home_dir =
+        std::env::var_os("HOME").ok_or_else(|| Error::Logs("HOME is not set".to_string()))?;
+    Ok(Path::new(&home_dir)
+        .join("Library")
+        .join("Logs")
+        .join("Mullvad VPN"))
+}
+
+#[cfg(target_os = "windows")]
+fn gui_log_dir() -> Result<PathBuf, Error> {
+    let appdata_dir =
+        std::env::var_os("APPDATA").ok_or_else(|| Error::Logs("APPDATA is not set".to_string()))?;
+    Ok(Path::new(&appdata_dir).join("Mullvad VPN").join("logs"))
+}
+
+/// Return the last `num_lines` lines of the daemon log. The log is read backwards in chunks, so
+/// only the end of it is read.
+pub async fn get_logs_tail(num_lines: usize) -> Result<Vec<String>, Error> {
+    let log_path = mullvad_paths::get_default_log_dir()
+        .map_err(|error| Error::Logs(format!("{}", error)))?
+        .join("daemon.log");
+
+    tokio::task::spawn_blocking(move || {
+        read_tail(&log_path, num_lines)
+            .map_err(|error| Error::Logs(format!("{}: {}", log_path.display(), error)))
+    })
+    .await
+    .map_err(|error| Error::Logs(format!("{}", error)))?
+}
+
+/// Return recent messages from the kernel log.
+pub async fn get_kernel_logs() -> Result<String, Error> {
+    #[cfg(target_os = "linux")]
+    let (program, args) = ("dmesg", ["--ctime"].as_slice());
+    #[cfg(target_os = "macos")]
+    let (program, args) = (
+        "log",
+        [
+            "show",
+            "--last",
+            "15m",
+            "--style",
+            "compact",
+            "--predicate",
+            "processID == 0",
+        ]
+        .as_slice(),
+    );
+    #[cfg(target_os = "windows")]
+    let (program, args) = (
+        "powershell",
+        [
+            "-Command",
+            "Get-WinEvent -LogName System -MaxEvents 500 | Format-List TimeCreated, ProviderName, Id, LevelDisplayName, Message | Out-String -Width 200",
+        ]
+        .as_slice(),
+    );
+
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|error| Error::KernelLogs(format!("Failed to run {program}: {error}")))?;
+    if !output.status.success() {
+        return Err(Error::KernelLogs(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn read_tail(path: &Path, num_lines: usize) -> std::io::Result<Vec<String>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let start_limit = file_len.saturating_sub(MAX_TAIL_BYTES);
+
+    // Read chunks from the end until there are more than `num_lines` newlines, since the last line
+    // may not be terminated
+    let mut offset = file_len;
+    let mut buffer = Vec::new();
+    while offset > start_limit && bytecount(&buffer, b'\n') <= num_lines {
+        let chunk_size = TAIL_CHUNK_SIZE.min(offset - start_limit);
+        offset -= chunk_size;
+
+        let mut chunk = vec![0u8; chunk_size as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut chunk)?;
+
+        chunk.append(&mut buffer);
+        buffer = chunk;
+    }
+
+    let content = String::from_utf8_lossy(&buffer);
+    let lines: Vec<_> = content.lines().collect();
+    let skip = lines.len().saturating_sub(num_lines);
+    Ok(lines[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+fn bytecount(buffer: &[u8], byte: u8) -> usize {
+    buffer.iter().filter(|b| **b == byte).count()
+}
+
 async fn read_settings_file() -> Result<String, Error> {
     let mut settings_path = mullvad_paths::get_default_settings_dir()
         .map_err(|error| Error::Logs(format!("{}", error)))?;
@@ -75,9 +243,7 @@ async fn read_settings_file() -> Result<String, Error> {
         .map_err(|error| Error::Logs(format!("{}: {}", settings_path.display(), error)))
 }
 
-async fn read_log_files() -> Result<Vec<Result<LogFile, Error>>, Error> {
-    let log_dir =
-        mullvad_paths::get_default_log_dir().map_err(|error| Error::Logs(format!("{}", error)))?;
+async fn read_log_files(log_dir: PathBuf) -> Result<Vec<Result<LogFile, Error>>, Error> {
     let paths = list_logs(log_dir)
         .await
         .map_err(|error| Error::Logs(format!("{}", error)))?;
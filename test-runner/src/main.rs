@@ -2,20 +2,22 @@ use futures::{pin_mut, SinkExt, StreamExt};
 use logging::LOGGER;
 use std::{
     collections::{BTreeMap, HashMap},
-    net::{IpAddr, SocketAddr},
-    path::Path,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
 };
 
 use tarpc::context;
 use tarpc::server::Channel;
 use test_rpc::{
+    file_transfer::{self, ReceivedFiles},
     meta,
-    mullvad_daemon::{ServiceStatus, SOCKET_PATH},
+    mullvad_daemon::{
+        ServiceStatus, SocketInfo, SocketPermissions, MANAGEMENT_INTERFACE_EOF_MESSAGE, SOCKET_PATH,
+    },
     package::Package,
     transport::GrpcForwarder,
-    AppTrace, Interface, Service,
+    AddressFamily, AppTrace, Interface, Service,
 };
-use tokio::sync::broadcast::error::TryRecvError;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     process::Command,
@@ -26,10 +28,19 @@ mod app;
 mod logging;
 mod net;
 mod package;
+mod process;
 mod sys;
 
+/// Time to wait for a streamed package to be received after it is requested to be installed
+const RECEIVE_PACKAGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
 #[derive(Clone)]
-pub struct TestServer(pub ());
+pub struct TestServer {
+    /// Files sent by the test manager over the file transfer channel
+    received_files: ReceivedFiles,
+    /// Processes started using `spawn`
+    processes: process::Processes,
+}
 
 #[tarpc::server]
 impl Service for TestServer {
@@ -38,10 +49,37 @@ impl Service for TestServer {
         _: context::Context,
         package: Package,
     ) -> Result<(), test_rpc::Error> {
-        log::debug!("Installing app");
+        if !package.streamed {
+            log::debug!("Installing app");
+            package::install_package(package).await?;
+            log::debug!("Install complete");
+            return Ok(());
+        }
 
-        package::install_package(package).await?;
+        log::debug!("Waiting for package {}", package.path.display());
+        let path = tokio::time::timeout(
+            RECEIVE_PACKAGE_TIMEOUT,
+            self.received_files.take(&package.path.to_string_lossy()),
+        )
+        .await
+        .map_err(|_| {
+            log::error!("Timed out waiting for package {}", package.path.display());
+            test_rpc::Error::FileSystem(format!(
+                "Package {} was not received",
+                package.path.display()
+            ))
+        })?;
 
+        log::debug!("Installing app");
+        let result = package::install_package(Package {
+            path: path.clone(),
+            streamed: false,
+        })
+        .await;
+        if let Err(error) = tokio::fs::remove_file(&path).await {
+            log::warn!("Failed to remove {}: {error}", path.display());
+        }
+        result?;
         log::debug!("Install complete");
 
         Ok(())
@@ -63,7 +101,7 @@ impl Service for TestServer {
 
     async fn exec(
         self,
-        _: context::Context,
+        ctx: context::Context,
         path: String,
         args: Vec<String>,
         env: BTreeMap<String, String>,
@@ -72,6 +110,8 @@ impl Service for TestServer {
 
         let mut cmd = Command::new(&path);
         cmd.args(args);
+        // Kill the process if we give up on it
+        cmd.kill_on_drop(true);
 
         // Make sure that PATH is updated
         // TODO: We currently do not need this on non-Windows
@@ -80,10 +120,22 @@ impl Service for TestServer {
 
         cmd.envs(env);
 
-        let output = cmd.output().await.map_err(|error| {
-            log::error!("Failed to exec {}: {error}", path);
-            test_rpc::Error::Syscall
-        })?;
+        // The caller stops waiting once the deadline has passed, so there is no point in letting
+        // the process outlive it
+        let timeout = ctx
+            .deadline
+            .duration_since(std::time::SystemTime::now())
+            .unwrap_or_default();
+        let output = tokio::time::timeout(timeout, cmd.output())
+            .await
+            .map_err(|_| {
+                log::error!("Killing {} since it did not exit before the deadline", path);
+                test_rpc::Error::TestRunnerTimeout
+            })?
+            .map_err(|error| {
+                log::error!("Failed to exec {}: {error}", path);
+                test_rpc::Error::Syscall
+            })?;
 
         let result = test_rpc::ExecResult {
             code: output.status.code(),
@@ -96,6 +148,32 @@ impl Service for TestServer {
         Ok(result)
     }
 
+    async fn spawn(
+        self,
+        _: context::Context,
+        path: String,
+        args: Vec<String>,
+        env: BTreeMap<String, String>,
+    ) -> Result<test_rpc::ProcessId, test_rpc::Error> {
+        self.processes.spawn(path, args, env)
+    }
+
+    async fn poll_process(
+        self,
+        _: context::Context,
+        id: test_rpc::ProcessId,
+    ) -> Result<test_rpc::ProcessOutput, test_rpc::Error> {
+        self.processes.poll(id).await
+    }
+
+    async fn kill(
+        self,
+        _: context::Context,
+        id: test_rpc::ProcessId,
+    ) -> Result<(), test_rpc::Error> {
+        self.processes.kill(id)
+    }
+
     async fn get_os(self, _: context::Context) -> meta::Os {
         meta::CURRENT_OS
     }
@@ -107,6 +185,20 @@ impl Service for TestServer {
         get_pipe_status()
     }
 
+    async fn mullvad_daemon_get_version(
+        self,
+        _: context::Context,
+    ) -> Result<String, test_rpc::Error> {
+        app::get_version().await
+    }
+
+    async fn get_daemon_socket_info(
+        self,
+        _: context::Context,
+    ) -> Result<test_rpc::mullvad_daemon::SocketInfo, test_rpc::Error> {
+        get_socket_info()
+    }
+
     async fn find_mullvad_app_traces(
         self,
         _: context::Context,
@@ -114,6 +206,30 @@ impl Service for TestServer {
         app::find_traces()
     }
 
+    async fn get_disk_usage(
+        self,
+        _: context::Context,
+    ) -> Result<BTreeMap<PathBuf, u64>, test_rpc::Error> {
+        app::get_disk_usage().await
+    }
+
+    async fn read_file(
+        self,
+        _: context::Context,
+        path: PathBuf,
+    ) -> Result<Vec<u8>, test_rpc::Error> {
+        sys::read_file(&path).await
+    }
+
+    async fn write_file(
+        self,
+        _: context::Context,
+        path: PathBuf,
+        contents: Vec<u8>,
+    ) -> Result<(), test_rpc::Error> {
+        sys::write_file(&path, &contents).await
+    }
+
     async fn send_tcp(
         self,
         _: context::Context,
@@ -134,21 +250,65 @@ impl Service for TestServer {
         net::send_udp(interface, bind_addr, destination).await
     }
 
+    async fn send_tcp_payload(
+        self,
+        _: context::Context,
+        interface: Option<Interface>,
+        bind_addr: SocketAddr,
+        destination: SocketAddr,
+        payload: Vec<u8>,
+    ) -> Result<(), test_rpc::Error> {
+        net::send_tcp_payload(interface, bind_addr, destination, payload).await
+    }
+
+    async fn send_udp_payload(
+        self,
+        _: context::Context,
+        interface: Option<Interface>,
+        bind_addr: SocketAddr,
+        destination: SocketAddr,
+        payload: Vec<u8>,
+    ) -> Result<(), test_rpc::Error> {
+        net::send_udp_payload(interface, bind_addr, destination, payload).await
+    }
+
     async fn send_ping(
         self,
         _: context::Context,
         interface: Option<Interface>,
         destination: IpAddr,
-    ) -> Result<(), test_rpc::Error> {
-        net::send_ping(interface, destination).await
+        options: test_rpc::PingOptions,
+    ) -> Result<test_rpc::PingSummary, test_rpc::Error> {
+        net::send_ping(interface, destination, options).await
     }
 
     async fn geoip_lookup(
         self,
         _: context::Context,
         mullvad_host: String,
+        options: test_rpc::GeoIpOptions,
     ) -> Result<test_rpc::AmIMullvad, test_rpc::Error> {
-        test_rpc::net::geoip_lookup(mullvad_host).await
+        test_rpc::net::geoip_lookup(mullvad_host, options).await
+    }
+
+    async fn traceroute(
+        self,
+        _: context::Context,
+        interface: Option<Interface>,
+        destination: IpAddr,
+    ) -> Result<Vec<test_rpc::Hop>, test_rpc::Error> {
+        net::traceroute(interface, destination).await
+    }
+
+    async fn http_get(
+        self,
+        _: context::Context,
+        interface: Option<Interface>,
+        url: String,
+        timeout: std::time::Duration,
+    ) -> Result<test_rpc::HttpResponse, test_rpc::Error> {
+        let connect = interface.map(net::interface_connector);
+        test_rpc::net::http_get_with_status(url, connect, timeout).await
     }
 
     async fn resolve_hostname(
@@ -170,57 +330,110 @@ impl Service for TestServer {
         _: context::Context,
         interface: Interface,
     ) -> Result<String, test_rpc::Error> {
-        Ok(net::get_interface_name(interface).to_owned())
+        Ok(net::get_interface_name(interface))
     }
 
     async fn get_interface_ip(
         self,
         _: context::Context,
         interface: Interface,
+        family: AddressFamily,
     ) -> Result<IpAddr, test_rpc::Error> {
-        net::get_interface_ip(interface)
+        net::get_interface_ip(interface, family)
+    }
+
+    async fn get_interface_ipv6(
+        self,
+        _: context::Context,
+        interface: Interface,
+    ) -> Result<Vec<Ipv6Addr>, test_rpc::Error> {
+        net::get_interface_ipv6(interface)
+    }
+
+    async fn get_interfaces(
+        self,
+        _: context::Context,
+    ) -> Result<Vec<test_rpc::InterfaceInfo>, test_rpc::Error> {
+        net::get_interfaces().await
+    }
+
+    async fn list_connections(
+        self,
+        _: context::Context,
+    ) -> Result<Vec<test_rpc::ConnectionInfo>, test_rpc::Error> {
+        net::list_connections().await
+    }
+
+    async fn get_interface_stats(
+        self,
+        _: context::Context,
+        interface: Interface,
+    ) -> Result<test_rpc::IfStats, test_rpc::Error> {
+        net::get_interface_stats(interface).await
     }
 
     async fn poll_output(
         self,
         _: context::Context,
     ) -> Result<Vec<test_rpc::logging::Output>, test_rpc::Error> {
-        let mut listener = LOGGER.0.lock().await;
-        if let Ok(output) = listener.recv().await {
-            let mut buffer = vec![output];
-            while let Ok(output) = listener.try_recv() {
-                buffer.push(output);
-            }
-            Ok(buffer)
-        } else {
-            Err(test_rpc::Error::Logger(
-                test_rpc::logging::Error::StandardOutput,
-            ))
-        }
+        Ok(LOGGER.wait_and_take(logging::POLL_OUTPUT_TIMEOUT).await)
+    }
+
+    async fn poll_output_timeout(
+        self,
+        _: context::Context,
+        timeout: std::time::Duration,
+    ) -> Result<Vec<test_rpc::logging::Output>, test_rpc::Error> {
+        Ok(LOGGER.wait_and_take(timeout).await)
     }
 
     async fn try_poll_output(
         self,
         _: context::Context,
     ) -> Result<Vec<test_rpc::logging::Output>, test_rpc::Error> {
-        let mut listener = LOGGER.0.lock().await;
-        match listener.try_recv() {
-            Ok(output) => {
-                let mut buffer = vec![output];
-                while let Ok(output) = listener.try_recv() {
-                    buffer.push(output);
-                }
-                Ok(buffer)
-            }
-            Err(TryRecvError::Empty) => Ok(Vec::new()),
-            Err(_) => Err(test_rpc::Error::Logger(
-                test_rpc::logging::Error::StandardOutput,
-            )),
-        }
+        Ok(LOGGER.take())
+    }
+
+    async fn get_mullvad_app_logs(
+        self,
+        _: context::Context,
+        min_level: Option<test_rpc::logging::LogLevel>,
+    ) -> test_rpc::logging::LogOutput {
+        logging::get_mullvad_app_logs(min_level).await
+    }
+
+    async fn get_daemon_logs(
+        self,
+        _: context::Context,
+    ) -> Result<Vec<test_rpc::logging::Result<test_rpc::logging::LogFile>>, test_rpc::Error> {
+        logging::get_daemon_logs()
+            .await
+            .map_err(test_rpc::Error::Logger)
+    }
+
+    async fn get_gui_logs(
+        self,
+        _: context::Context,
+    ) -> Result<Vec<test_rpc::logging::Result<test_rpc::logging::LogFile>>, test_rpc::Error> {
+        logging::get_gui_logs()
+            .await
+            .map_err(test_rpc::Error::Logger)
     }
 
-    async fn get_mullvad_app_logs(self, _: context::Context) -> test_rpc::logging::LogOutput {
-        logging::get_mullvad_app_logs().await
+    async fn get_logs_tail(
+        self,
+        _: context::Context,
+        num_lines: usize,
+    ) -> Result<Vec<String>, test_rpc::Error> {
+        logging::get_logs_tail(num_lines)
+            .await
+            .map_err(test_rpc::Error::Logger)
+    }
+
+    async fn get_kernel_logs(self, _: context::Context) -> Result<String, test_rpc::Error> {
+        logging::get_kernel_logs()
+            .await
+            .map_err(test_rpc::Error::Logger)
     }
 
     async fn set_daemon_log_level(
@@ -264,18 +477,194 @@ impl Service for TestServer {
         sys::set_mullvad_daemon_service_state(on).await
     }
 
+    async fn kill_process(
+        self,
+        _: context::Context,
+        process_name: String,
+    ) -> Result<(), test_rpc::Error> {
+        sys::kill_process(&process_name).await
+    }
+
     async fn make_device_json_old(self, _: context::Context) -> Result<(), test_rpc::Error> {
         app::make_device_json_old().await
     }
+
+    async fn set_relay_list_override(
+        self,
+        _: context::Context,
+        relay_list: Option<String>,
+    ) -> Result<(), test_rpc::Error> {
+        app::set_relay_list_override(relay_list).await
+    }
+
+    async fn reset_settings(self, _: context::Context) -> Result<Vec<PathBuf>, test_rpc::Error> {
+        app::reset_settings().await
+    }
+
+    async fn get_system_time(
+        self,
+        _: context::Context,
+    ) -> Result<std::time::SystemTime, test_rpc::Error> {
+        Ok(std::time::SystemTime::now())
+    }
+
+    async fn set_system_time(
+        self,
+        _: context::Context,
+        time: std::time::SystemTime,
+    ) -> Result<(), test_rpc::Error> {
+        sys::set_system_time(time).await
+    }
+
+    async fn set_time_sync(
+        self,
+        _: context::Context,
+        enabled: bool,
+    ) -> Result<(), test_rpc::Error> {
+        sys::set_time_sync(enabled).await
+    }
 }
 
+#[cfg(unix)]
 fn get_pipe_status() -> ServiceStatus {
-    match Path::new(SOCKET_PATH).exists() {
+    match std::path::Path::new(SOCKET_PATH).exists() {
         true => ServiceStatus::Running,
         false => ServiceStatus::NotRunning,
     }
 }
 
+/// Probe the named pipe of the daemon without connecting to it. A named pipe is not a file, so
+/// checking whether the path exists is not reliable, and opening it would consume a pipe instance.
+#[cfg(windows)]
+fn get_pipe_status() -> ServiceStatus {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt};
+    use windows_sys::Win32::{Foundation::ERROR_SEM_TIMEOUT, System::Pipes::WaitNamedPipeW};
+
+    // Do not wait for a busy pipe instance to become available
+    const PIPE_WAIT_TIMEOUT_MS: u32 = 1;
+
+    let pipe_name: Vec<u16> = OsStr::new(&SOCKET_PATH.replace('/', "\\"))
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+
+    if unsafe { WaitNamedPipeW(pipe_name.as_ptr(), PIPE_WAIT_TIMEOUT_MS) } != 0 {
+        return ServiceStatus::Running;
+    }
+
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        // All instances are busy, but the pipe exists
+        Some(code) if code == ERROR_SEM_TIMEOUT as i32 => ServiceStatus::Running,
+        _ => {
+            log::trace!("Daemon pipe is not available: {error}");
+            ServiceStatus::NotRunning
+        }
+    }
+}
+
+#[cfg(unix)]
+fn get_socket_info() -> Result<SocketInfo, test_rpc::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Do not follow symlinks, since the socket itself must not be replaced by one
+    let metadata = std::fs::symlink_metadata(SOCKET_PATH).map_err(|error| {
+        log::error!("Failed to obtain metadata of {SOCKET_PATH}: {error}");
+        test_rpc::Error::FileSystem(error.to_string())
+    })?;
+
+    Ok(SocketInfo {
+        path: SOCKET_PATH.to_owned(),
+        permissions: SocketPermissions::Unix {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        },
+    })
+}
+
+/// Read the owner and DACL of the daemon's named pipe. Note that this opens the pipe, and
+/// therefore consumes a pipe instance.
+#[cfg(windows)]
+fn get_socket_info() -> Result<SocketInfo, test_rpc::Error> {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt};
+    use windows_sys::Win32::{
+        Foundation::ERROR_SUCCESS,
+        Security::{
+            Authorization::{
+                ConvertSecurityDescriptorToStringSecurityDescriptorW, GetNamedSecurityInfoW,
+                SDDL_REVISION_1, SE_FILE_OBJECT,
+            },
+            DACL_SECURITY_INFORMATION, OWNER_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR,
+        },
+        System::Memory::LocalFree,
+    };
+
+    let pipe_path = SOCKET_PATH.replace('/', "\\");
+    let pipe_name: Vec<u16> = OsStr::new(&pipe_path)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let security_info = OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    let status = unsafe {
+        GetNamedSecurityInfoW(
+            pipe_name.as_ptr(),
+            SE_FILE_OBJECT,
+            security_info,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        let error = std::io::Error::from_raw_os_error(status as i32);
+        log::error!("Failed to obtain security info of {pipe_path}: {error}");
+        return Err(test_rpc::Error::Syscall);
+    }
+
+    let mut sddl = std::ptr::null_mut();
+    let mut sddl_len = 0;
+    let converted = unsafe {
+        ConvertSecurityDescriptorToStringSecurityDescriptorW(
+            descriptor,
+            SDDL_REVISION_1,
+            security_info,
+            &mut sddl,
+            &mut sddl_len,
+        )
+    };
+    let result = if converted != 0 {
+        // `sddl_len` includes the null terminator
+        let sddl_slice =
+            unsafe { std::slice::from_raw_parts(sddl, sddl_len.saturating_sub(1) as usize) };
+        Ok(String::from_utf16_lossy(sddl_slice))
+    } else {
+        log::error!(
+            "Failed to convert security descriptor: {}",
+            std::io::Error::last_os_error()
+        );
+        Err(test_rpc::Error::Syscall)
+    };
+
+    unsafe {
+        if !sddl.is_null() {
+            LocalFree(sddl as isize);
+        }
+        LocalFree(descriptor as isize);
+    }
+
+    Ok(SocketInfo {
+        path: pipe_path,
+        permissions: SocketPermissions::Windows {
+            security_descriptor: result?,
+        },
+    })
+}
+
 const BAUD: u32 = 115200;
 
 #[derive(err_derive::Error, Debug)]
@@ -297,7 +686,7 @@ async fn main() -> Result<(), Error> {
 
         let serial_stream =
             tokio_serial::SerialStream::open(&tokio_serial::new(&path, BAUD)).unwrap();
-        let (runner_transport, mullvad_daemon_transport, _completion_handle) =
+        let (runner_transport, mullvad_daemon_transport, file_transport, _completion_handle) =
             test_rpc::transport::create_server_transports(serial_stream);
 
         log::info!("Running server");
@@ -306,8 +695,23 @@ async fn main() -> Result<(), Error> {
             mullvad_daemon_transport,
         ));
 
+        let received_files = ReceivedFiles::default();
+        tokio::spawn(file_transfer::receive_files(
+            file_transport,
+            std::env::temp_dir(),
+            received_files.clone(),
+        ));
+
         let server = tarpc::server::BaseChannel::with_defaults(runner_transport);
-        server.execute(TestServer(()).serve()).await;
+        server
+            .execute(
+                TestServer {
+                    received_files,
+                    processes: process::Processes::default(),
+                }
+                .serve(),
+            )
+            .await;
 
         log::error!("Restarting server since it stopped");
     }
@@ -387,7 +791,7 @@ async fn forward_to_mullvad_daemon_interface(proxy_transport: GrpcForwarder) {
                 futures::future::Either::Right((read, _)) => match read {
                     Some(Ok(bytes)) => {
                         if bytes.is_empty() {
-                            log::debug!("management interface EOF; restarting server");
+                            log::info!("{MANAGEMENT_INTERFACE_EOF_MESSAGE}");
                             break;
                         }
                         if let Err(error) = daemon_socket_endpoint.write_all(&bytes).await {
@@ -1,17 +1,25 @@
+use futures::future::BoxFuture;
 use socket2::SockAddr;
 #[cfg(target_os = "macos")]
 use std::{ffi::CString, num::NonZeroU32};
 use std::{
-    net::{IpAddr, SocketAddr},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     process::Output,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use test_rpc::{
+    AddressFamily, ConnectionInfo, Hop, IfStats, Interface, InterfaceInfo, PingOptions,
+    PingSummary, SocketProtocol,
 };
-use test_rpc::Interface;
 use tokio::{
     io::AsyncWriteExt,
-    net::{TcpStream, UdpSocket},
+    net::{TcpSocket, TcpStream, UdpSocket},
     process::Command,
 };
 
+/// Name of the tunnel device, if it cannot be detected. See `tunnel_interface`.
 #[cfg(target_os = "linux")]
 const TUNNEL_INTERFACE: &str = "wg-mullvad";
 
@@ -26,6 +34,98 @@ pub async fn send_tcp(
     bind_addr: SocketAddr,
     destination: SocketAddr,
 ) -> Result<(), test_rpc::Error> {
+    let sock = bind_tcp_socket(bind_interface, bind_addr, destination)?;
+
+    log::debug!("Connecting from {bind_addr} to {destination}/TCP");
+
+    sock.connect(&SockAddr::from(destination))
+        .map_err(|error| {
+            log::error!("Failed to connect to {destination}: {error}");
+            test_rpc::Error::SendTcp
+        })?;
+
+    let std_stream = std::net::TcpStream::from(sock);
+    let mut stream = TcpStream::from_std(std_stream).map_err(|error| {
+        log::error!("Failed to convert to TCP stream to tokio stream: {error}");
+        test_rpc::Error::SendTcp
+    })?;
+
+    stream.write_all(b"hello").await.map_err(|error| {
+        log::error!("Failed to send message to {destination}: {error}");
+        test_rpc::Error::SendTcp
+    })?;
+
+    Ok(())
+}
+
+/// Connect to `destination`, send `payload`, and close the connection. Unlike `send_tcp`, this
+/// waits for the connection to be established.
+pub async fn send_tcp_payload(
+    bind_interface: Option<Interface>,
+    bind_addr: SocketAddr,
+    destination: SocketAddr,
+    payload: Vec<u8>,
+) -> Result<(), test_rpc::Error> {
+    let sock = bind_tcp_socket(bind_interface, bind_addr, destination)?;
+
+    log::debug!(
+        "Sending {} bytes from {bind_addr} to {destination}/TCP",
+        payload.len()
+    );
+
+    let socket = TcpSocket::from_std_stream(std::net::TcpStream::from(sock));
+    let mut stream = socket.connect(destination).await.map_err(|error| {
+        log::error!("Failed to connect to {destination}: {error}");
+        test_rpc::Error::SendTcp
+    })?;
+
+    stream.write_all(&payload).await.map_err(|error| {
+        log::error!("Failed to send payload to {destination}: {error}");
+        test_rpc::Error::SendTcp
+    })?;
+
+    stream.shutdown().await.map_err(|error| {
+        log::error!("Failed to close connection to {destination}: {error}");
+        test_rpc::Error::SendTcp
+    })?;
+
+    Ok(())
+}
+
+/// Return a function that opens TCP connections from `interface`. The sockets are bound to the
+/// interface in the same way as for `send_tcp`.
+pub fn interface_connector(interface: Interface) -> test_rpc::net::Connect {
+    Arc::new(
+        move |destination: SocketAddr| -> BoxFuture<'static, io::Result<TcpStream>> {
+            Box::pin(async move {
+                let family = match destination {
+                    SocketAddr::V4(_) => AddressFamily::Ipv4,
+                    SocketAddr::V6(_) => AddressFamily::Ipv6,
+                };
+                let to_io_error = |error: test_rpc::Error| {
+                    io::Error::new(io::ErrorKind::Other, error.to_string())
+                };
+
+                let bind_ip = get_interface_ip(interface, family).map_err(to_io_error)?;
+                let sock =
+                    bind_tcp_socket(Some(interface), SocketAddr::new(bind_ip, 0), destination)
+                        .map_err(to_io_error)?;
+
+                log::debug!("Connecting from {bind_ip} to {destination}/TCP");
+
+                TcpSocket::from_std_stream(std::net::TcpStream::from(sock))
+                    .connect(destination)
+                    .await
+            })
+        },
+    )
+}
+
+fn bind_tcp_socket(
+    bind_interface: Option<Interface>,
+    bind_addr: SocketAddr,
+    destination: SocketAddr,
+) -> Result<socket2::Socket, test_rpc::Error> {
     let family = match &destination {
         SocketAddr::V4(_) => socket2::Domain::IPV4,
         SocketAddr::V6(_) => socket2::Domain::IPV6,
@@ -46,7 +146,7 @@ pub async fn send_tcp(
 
         #[cfg(target_os = "macos")]
         let interface_index = unsafe {
-            let name = CString::new(iface).unwrap();
+            let name = CString::new(iface.as_str()).unwrap();
             let index = libc::if_nametoindex(name.as_bytes_with_nul().as_ptr() as _);
             NonZeroU32::new(index).ok_or_else(|| {
                 log::error!("Invalid interface index");
@@ -76,32 +176,23 @@ pub async fn send_tcp(
         test_rpc::Error::SendTcp
     })?;
 
-    log::debug!("Connecting from {bind_addr} to {destination}/TCP");
-
-    sock.connect(&SockAddr::from(destination))
-        .map_err(|error| {
-            log::error!("Failed to connect to {destination}: {error}");
-            test_rpc::Error::SendTcp
-        })?;
-
-    let std_stream = std::net::TcpStream::from(sock);
-    let mut stream = TcpStream::from_std(std_stream).map_err(|error| {
-        log::error!("Failed to convert to TCP stream to tokio stream: {error}");
-        test_rpc::Error::SendTcp
-    })?;
-
-    stream.write_all(b"hello").await.map_err(|error| {
-        log::error!("Failed to send message to {destination}: {error}");
-        test_rpc::Error::SendTcp
-    })?;
-
-    Ok(())
+    Ok(sock)
 }
 
 pub async fn send_udp(
     bind_interface: Option<Interface>,
     bind_addr: SocketAddr,
     destination: SocketAddr,
+) -> Result<(), test_rpc::Error> {
+    send_udp_payload(bind_interface, bind_addr, destination, b"hello".to_vec()).await
+}
+
+/// Send a single UDP datagram containing `payload`.
+pub async fn send_udp_payload(
+    bind_interface: Option<Interface>,
+    bind_addr: SocketAddr,
+    destination: SocketAddr,
+    payload: Vec<u8>,
 ) -> Result<(), test_rpc::Error> {
     let family = match &destination {
         SocketAddr::V4(_) => socket2::Domain::IPV4,
@@ -123,7 +214,7 @@ pub async fn send_udp(
 
         #[cfg(target_os = "macos")]
         let interface_index = unsafe {
-            let name = CString::new(iface).unwrap();
+            let name = CString::new(iface.as_str()).unwrap();
             let index = libc::if_nametoindex(name.as_bytes_with_nul().as_ptr() as _);
             NonZeroU32::new(index).ok_or_else(|| {
                 log::error!("Invalid interface index");
@@ -153,7 +244,10 @@ pub async fn send_udp(
         test_rpc::Error::SendUdp
     })?;
 
-    log::debug!("Send message from {bind_addr} to {destination}/UDP");
+    log::debug!(
+        "Send {} bytes from {bind_addr} to {destination}/UDP",
+        payload.len()
+    );
 
     let std_socket = std::net::UdpSocket::from(sock);
     let tokio_socket = UdpSocket::from_std(std_socket).map_err(|error| {
@@ -162,7 +256,7 @@ pub async fn send_udp(
     })?;
 
     tokio_socket
-        .send_to(b"hello", destination)
+        .send_to(&payload, destination)
         .await
         .map_err(|error| {
             log::error!("Failed to send message to {destination}: {error}");
@@ -175,7 +269,8 @@ pub async fn send_udp(
 pub async fn send_ping(
     interface: Option<Interface>,
     destination: IpAddr,
-) -> Result<(), test_rpc::Error> {
+    options: PingOptions,
+) -> Result<PingSummary, test_rpc::Error> {
     #[cfg(target_os = "windows")]
     let mut source_ip = None;
     #[cfg(target_os = "windows")]
@@ -196,10 +291,23 @@ pub async fn send_ping(
     cmd.arg(destination.to_string());
 
     #[cfg(target_os = "windows")]
-    cmd.args(["-n", "1"]);
+    cmd.args(["-n", &options.count.to_string()]);
 
     #[cfg(not(target_os = "windows"))]
-    cmd.args(["-c", "1"]);
+    cmd.args([
+        "-c",
+        &options.count.to_string(),
+        "-i",
+        &options.interval.as_secs_f64().to_string(),
+    ]);
+
+    if let Some(size) = options.size {
+        #[cfg(target_os = "windows")]
+        cmd.args(["-l", &size.to_string()]);
+
+        #[cfg(not(target_os = "windows"))]
+        cmd.args(["-s", &size.to_string()]);
+    }
 
     match interface {
         Some(Interface::Tunnel) => {
@@ -211,10 +319,10 @@ pub async fn send_ping(
             }
 
             #[cfg(target_os = "windows")]
-            cmd.args(["-I", TUNNEL_INTERFACE]);
+            cmd.args(["-I", &tunnel_interface()]);
 
             #[cfg(target_os = "macos")]
-            cmd.args(["-b", TUNNEL_INTERFACE]);
+            cmd.args(["-b", &tunnel_interface()]);
         }
         Some(Interface::NonTunnel) => {
             log::info!("Pinging {destination} outside tunnel");
@@ -235,7 +343,8 @@ pub async fn send_ping(
 
     cmd.kill_on_drop(true);
 
-    cmd.spawn()
+    let output = cmd
+        .spawn()
         .map_err(|error| {
             log::error!("Failed to spawn ping process: {error}");
             test_rpc::Error::Ping
@@ -245,61 +354,426 @@ pub async fn send_ping(
         .map_err(|error| {
             log::error!("Failed to wait on ping: {error}");
             test_rpc::Error::Ping
+        })?;
+
+    // `ping` fails if no replies are received, but the statistics are still printed
+    match parse_ping_summary(&String::from_utf8_lossy(&output.stdout)) {
+        Some(summary) => Ok(summary),
+        None => {
+            result_from_output("ping", output, test_rpc::Error::Ping)?;
+            log::error!("Failed to parse ping statistics");
+            Err(test_rpc::Error::Ping)
+        }
+    }
+}
+
+/// Parse the statistics printed by iputils `ping` on Linux, or BSD `ping` on macOS:
+///
+/// ```text
+/// 4 packets transmitted, 4 received, 0% packet loss, time 3004ms
+/// rtt min/avg/max/mdev = 10.123/11.456/12.789/0.512 ms
+/// ```
+///
+/// ```text
+/// 4 packets transmitted, 4 packets received, 0.0% packet loss
+/// round-trip min/avg/max/stddev = 10.123/11.456/12.789/0.512 ms
+/// ```
+#[cfg(unix)]
+fn parse_ping_summary(output: &str) -> Option<PingSummary> {
+    let mut counts = None;
+    let mut avg_rtt = None;
+
+    for line in output.lines() {
+        if line.contains("packets transmitted") {
+            let mut fields = line
+                .split(',')
+                .map(|field| field.split_whitespace().next()?.parse().ok());
+            counts = Some((fields.next()??, fields.next()??));
+        } else if line.starts_with("rtt") || line.starts_with("round-trip") {
+            let (_, values) = line.split_once(" = ")?;
+            avg_rtt = values.split('/').nth(1).and_then(parse_rtt_ms);
+        }
+    }
+
+    let (sent, received) = counts?;
+    Some(PingSummary {
+        sent,
+        received,
+        avg_rtt,
+    })
+}
+
+/// Parse the statistics printed by `ping` on Windows:
+///
+/// ```text
+///     Packets: Sent = 4, Received = 4, Lost = 0 (0% loss),
+/// Approximate round trip times in milli-seconds:
+///     Minimum = 10ms, Maximum = 12ms, Average = 11ms
+/// ```
+#[cfg(target_os = "windows")]
+fn parse_ping_summary(output: &str) -> Option<PingSummary> {
+    // Return the leading digits of the value following `name = `
+    fn value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+        let (_, rest) = line.split_once(&format!("{name} = "))?;
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        Some(&rest[..end])
+    }
+
+    let mut counts = None;
+    let mut avg_rtt = None;
+
+    for line in output.lines() {
+        if line.trim_start().starts_with("Packets:") {
+            counts = Some((
+                value(line, "Sent")?.parse().ok()?,
+                value(line, "Received")?.parse().ok()?,
+            ));
+        } else if let Some(average) = value(line, "Average") {
+            avg_rtt = parse_rtt_ms(average);
+        }
+    }
+
+    let (sent, received) = counts?;
+    Some(PingSummary {
+        sent,
+        received,
+        avg_rtt,
+    })
+}
+
+/// Maximum number of hops probed by `traceroute`
+const TRACEROUTE_MAX_HOPS: &str = "20";
+
+/// Trace the path to `destination` using `traceroute`.
+#[cfg(unix)]
+pub async fn traceroute(
+    interface: Option<Interface>,
+    destination: IpAddr,
+) -> Result<Vec<Hop>, test_rpc::Error> {
+    #[cfg(target_os = "linux")]
+    let mut cmd = {
+        let mut cmd = Command::new("traceroute");
+        if destination.is_ipv6() {
+            cmd.arg("-6");
+        }
+        cmd
+    };
+
+    #[cfg(target_os = "macos")]
+    let mut cmd = Command::new(if destination.is_ipv6() {
+        "traceroute6"
+    } else {
+        "traceroute"
+    });
+
+    // Do not resolve hostnames, and send a single probe per hop
+    cmd.args(["-n", "-q", "1", "-w", "2", "-m", TRACEROUTE_MAX_HOPS]);
+    if let Some(interface) = interface {
+        cmd.args(["-i", &get_interface_name(interface)]);
+    }
+    cmd.arg(destination.to_string());
+
+    // Example output:
+    // traceroute to 1.1.1.1 (1.1.1.1), 20 hops max, 60 byte packets
+    //  1  10.64.0.1  12.345 ms
+    //  2  *
+    let output = traceroute_command_output(cmd).await?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut cols = line.split_whitespace();
+            Some(Hop {
+                ttl: cols.next()?.parse().ok()?,
+                addr: cols.next().and_then(|addr| addr.parse().ok()),
+                rtt: cols.next().and_then(parse_rtt_ms),
+            })
+        })
+        .collect())
+}
+
+/// Trace the path to `destination` using `tracert`. `tracert` can only select a source address
+/// for IPv6, so selecting an interface fails for IPv4 destinations.
+#[cfg(target_os = "windows")]
+pub async fn traceroute(
+    interface: Option<Interface>,
+    destination: IpAddr,
+) -> Result<Vec<Hop>, test_rpc::Error> {
+    let mut cmd = Command::new("tracert");
+    // Do not resolve hostnames
+    cmd.args(["-d", "-w", "2000", "-h", TRACEROUTE_MAX_HOPS]);
+    if let Some(interface) = interface {
+        if !destination.is_ipv6() {
+            log::error!("Cannot trace the route to {destination} from interface {interface:?}");
+            return Err(test_rpc::Error::Traceroute(
+                "tracert only supports selecting the source address for IPv6".to_owned(),
+            ));
+        }
+        let source_ip =
+            get_interface_ip_for_family(interface, talpid_windows_net::AddressFamily::Ipv6)
+                .map_err(|_error| test_rpc::Error::Syscall)?
+                .ok_or_else(|| {
+                    log::error!("Failed to obtain interface IP");
+                    test_rpc::Error::InterfaceNotFound
+                })?;
+        cmd.args(["-S", &source_ip.to_string()]);
+    }
+    cmd.arg(destination.to_string());
+
+    // Example output:
+    //   1    <1 ms    <1 ms    <1 ms  192.168.1.1
+    //   2     *        *        *     Request timed out.
+    let output = traceroute_command_output(cmd).await?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<_> = line.split_whitespace().collect();
+            Some(Hop {
+                ttl: cols.first()?.parse().ok()?,
+                addr: cols.last().and_then(|addr| addr.parse().ok()),
+                rtt: cols
+                    .windows(2)
+                    .find(|pair| pair[1] == "ms")
+                    .and_then(|pair| parse_rtt_ms(pair[0])),
+            })
         })
-        .and_then(|output| result_from_output("ping", output, test_rpc::Error::Ping))
+        .collect())
+}
+
+async fn traceroute_command_output(mut cmd: Command) -> Result<String, test_rpc::Error> {
+    let output = cmd
+        .output()
+        .await
+        .map_err(|error| test_rpc::Error::Traceroute(error.to_string()))?;
+    if !output.status.success() {
+        return Err(test_rpc::Error::Traceroute(format!(
+            "traceroute failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse a round-trip time in milliseconds, such as `12.345` or `<1`.
+fn parse_rtt_ms(rtt: &str) -> Option<Duration> {
+    let millis: f64 = rtt.trim_start_matches('<').parse().ok()?;
+    Duration::try_from_secs_f64(millis / 1000.0).ok()
 }
 
 #[cfg(unix)]
-pub fn get_interface_ip(interface: Interface) -> Result<IpAddr, test_rpc::Error> {
-    // TODO: IPv6
-    use std::net::Ipv4Addr;
+pub fn get_interface_ip(
+    interface: Interface,
+    family: AddressFamily,
+) -> Result<IpAddr, test_rpc::Error> {
+    let alias = get_interface_name(interface);
+
+    if let Some(ip) = find_interface_ip(&alias, family)? {
+        return Ok(ip);
+    }
 
+    log::error!("Could not find interface {alias}");
+    Err(test_rpc::Error::InterfaceNotFound)
+}
+
+/// Destination used to determine which interface public traffic is routed through.
+#[cfg(unix)]
+const PUBLIC_ROUTE_DESTINATION: &str = "1.1.1.1";
+
+#[cfg(unix)]
+pub fn get_interface_ipv6(interface: Interface) -> Result<Vec<Ipv6Addr>, test_rpc::Error> {
     let alias = get_interface_name(interface);
 
+    if let Some(ips) = find_interface_ipv6(&alias)? {
+        return Ok(ips);
+    }
+
+    log::error!("Could not find interface {alias}");
+    Err(test_rpc::Error::InterfaceNotFound)
+}
+
+/// Return all IPv6 addresses of the interface `name`, or `None` if there is no such interface.
+#[cfg(unix)]
+fn find_interface_ipv6(name: &str) -> Result<Option<Vec<Ipv6Addr>>, test_rpc::Error> {
+    let addrs = nix::ifaddrs::getifaddrs().map_err(|error| {
+        log::error!("Failed to obtain interfaces: {}", error);
+        test_rpc::Error::Syscall
+    })?;
+
+    let mut found = false;
+    let mut ips = vec![];
+    for addr in addrs.filter(|addr| addr.interface_name == name) {
+        found = true;
+        if let Some(sockaddr) = addr
+            .address
+            .as_ref()
+            .and_then(|address| address.as_sockaddr_in6())
+        {
+            ips.push(sockaddr.ip());
+        }
+    }
+
+    Ok(found.then_some(ips))
+}
+
+/// Return the first address of the interface `name` that belongs to `family`. Link-local IPv6
+/// addresses are skipped, since they cannot be used to reach other networks.
+#[cfg(unix)]
+fn find_interface_ip(name: &str, family: AddressFamily) -> Result<Option<IpAddr>, test_rpc::Error> {
+    use std::net::Ipv4Addr;
+
     let addrs = nix::ifaddrs::getifaddrs().map_err(|error| {
         log::error!("Failed to obtain interfaces: {}", error);
         test_rpc::Error::Syscall
     })?;
     for addr in addrs {
-        if addr.interface_name == alias {
-            if let Some(address) = addr.address {
+        if addr.interface_name != name {
+            continue;
+        }
+        let address = match addr.address {
+            Some(address) => address,
+            None => continue,
+        };
+        match family {
+            AddressFamily::Ipv4 => {
                 if let Some(sockaddr) = address.as_sockaddr_in() {
-                    return Ok(IpAddr::V4(Ipv4Addr::from(sockaddr.ip())));
+                    return Ok(Some(IpAddr::V4(Ipv4Addr::from(sockaddr.ip()))));
+                }
+            }
+            AddressFamily::Ipv6 => {
+                if let Some(sockaddr) = address.as_sockaddr_in6() {
+                    let ip = sockaddr.ip();
+                    if (ip.segments()[0] & 0xffc0) != 0xfe80 {
+                        return Ok(Some(IpAddr::V6(ip)));
+                    }
                 }
             }
         }
     }
 
-    log::error!("Could not find tunnel interface");
-    Err(test_rpc::Error::InterfaceNotFound)
+    Ok(None)
+}
+
+/// Return the name of the interface that traffic to `destination` is routed through.
+#[cfg(target_os = "linux")]
+fn routed_interface(destination: &str) -> Option<String> {
+    let output = std::process::Command::new("ip")
+        .args(["-4", "route", "get", destination])
+        .output()
+        .ok()?;
+    // Example: "1.1.1.1 dev wg-mullvad table 1836018789 src 10.64.0.2 uid 0"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut words = stdout.split_whitespace();
+    words.find(|word| *word == "dev")?;
+    words.next().map(str::to_owned)
+}
+
+/// Return the name of the interface that traffic to `destination` is routed through.
+#[cfg(target_os = "macos")]
+fn routed_interface(destination: &str) -> Option<String> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", destination])
+        .output()
+        .ok()?;
+    // Example: "  interface: utun3"
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("interface:"))
+        .map(|name| name.trim().to_owned())
 }
 
-pub fn get_interface_name(interface: Interface) -> &'static str {
+pub fn get_interface_name(interface: Interface) -> String {
     match interface {
-        Interface::Tunnel => TUNNEL_INTERFACE,
-        Interface::NonTunnel => non_tunnel_interface(),
+        Interface::Tunnel => tunnel_interface(),
+        Interface::NonTunnel => non_tunnel_interface().to_owned(),
     }
 }
 
+/// Return the name of the tunnel device, or `TUNNEL_INTERFACE` if it cannot be found. This is
+/// looked up every time, since the device may change when the daemon reconnects.
+#[cfg(unix)]
+fn tunnel_interface() -> String {
+    find_tunnel_interface().unwrap_or_else(|| {
+        log::warn!("Could not find tunnel interface. Assuming {TUNNEL_INTERFACE}");
+        TUNNEL_INTERFACE.to_owned()
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn find_tunnel_interface() -> Option<String> {
+    if nix::net::if_::if_nametoindex(TUNNEL_INTERFACE).is_ok() {
+        return Some(TUNNEL_INTERFACE.to_owned());
+    }
+    // OpenVPN uses a different device. Public traffic is routed through it when connected.
+    routed_interface(PUBLIC_ROUTE_DESTINATION).filter(|name| *name != non_tunnel_interface())
+}
+
+#[cfg(target_os = "macos")]
+fn find_tunnel_interface() -> Option<String> {
+    // Other utun devices may exist, so look for the one that has been assigned a tunnel IP
+    let addrs = nix::ifaddrs::getifaddrs().ok()?;
+    for addr in addrs {
+        if !addr.interface_name.starts_with("utun") {
+            continue;
+        }
+        let is_tunnel_ip = addr
+            .address
+            .as_ref()
+            .and_then(|address| address.as_sockaddr_in())
+            .map(|sockaddr| Ipv4Addr::from(sockaddr.ip()).octets()[0] == 10)
+            .unwrap_or(false);
+        if is_tunnel_ip {
+            return Some(addr.interface_name);
+        }
+    }
+    routed_interface(PUBLIC_ROUTE_DESTINATION).filter(|name| name.starts_with("utun"))
+}
+
+/// Return the name of the tunnel device. Unlike on other platforms, its name is fixed, so this
+/// only checks that it exists.
 #[cfg(target_os = "windows")]
-pub fn get_interface_ip(interface: Interface) -> Result<IpAddr, test_rpc::Error> {
-    // TODO: IPv6
+fn tunnel_interface() -> String {
+    if talpid_windows_net::luid_from_alias(TUNNEL_INTERFACE).is_err() {
+        log::warn!("Could not find tunnel interface {TUNNEL_INTERFACE}");
+    }
+    TUNNEL_INTERFACE.to_owned()
+}
 
-    get_interface_ip_for_family(interface, talpid_windows_net::AddressFamily::Ipv4)
+#[cfg(target_os = "windows")]
+pub fn get_interface_ip(
+    interface: Interface,
+    family: AddressFamily,
+) -> Result<IpAddr, test_rpc::Error> {
+    let family = match family {
+        AddressFamily::Ipv4 => talpid_windows_net::AddressFamily::Ipv4,
+        AddressFamily::Ipv6 => talpid_windows_net::AddressFamily::Ipv6,
+    };
+    get_interface_ip_for_family(interface, family)
         .map_err(|_error| test_rpc::Error::Syscall)?
         .ok_or(test_rpc::Error::InterfaceNotFound)
 }
 
+#[cfg(target_os = "windows")]
+pub fn get_interface_ipv6(interface: Interface) -> Result<Vec<Ipv6Addr>, test_rpc::Error> {
+    // Only one address is reported on Windows
+    let ip = get_interface_ip_for_family(interface, talpid_windows_net::AddressFamily::Ipv6)
+        .map_err(|_error| test_rpc::Error::Syscall)?;
+    Ok(ip
+        .into_iter()
+        .filter_map(|ip| match ip {
+            IpAddr::V6(ip) => Some(ip),
+            IpAddr::V4(_) => None,
+        })
+        .collect())
+}
+
 #[cfg(target_os = "windows")]
 fn get_interface_ip_for_family(
     interface: Interface,
     family: talpid_windows_net::AddressFamily,
 ) -> Result<Option<IpAddr>, ()> {
-    let interface = match interface {
-        Interface::NonTunnel => non_tunnel_interface(),
-        Interface::Tunnel => TUNNEL_INTERFACE,
-    };
-    let interface_alias = talpid_windows_net::luid_from_alias(interface).map_err(|error| {
+    let interface = get_interface_name(interface);
+    let interface_alias = talpid_windows_net::luid_from_alias(&interface).map_err(|error| {
         log::error!("Failed to obtain interface LUID: {error}");
     })?;
 
@@ -325,9 +799,34 @@ fn non_tunnel_interface() -> &'static str {
     "Ethernet Instance 0"
 }
 
+/// Return the interface that the default route in the main routing table goes through. This
+/// is not affected by the tunnel, since the daemon uses a separate routing table.
 #[cfg(target_os = "linux")]
 fn non_tunnel_interface() -> &'static str {
-    "ens3"
+    use once_cell::sync::OnceCell;
+
+    const DEFAULT_NON_TUNNEL_INTERFACE: &str = "ens3";
+
+    static INTERFACE: OnceCell<String> = OnceCell::new();
+    INTERFACE.get_or_init(|| {
+        default_route_interface().unwrap_or_else(|| {
+            log::warn!("Could not find default route. Assuming {DEFAULT_NON_TUNNEL_INTERFACE}");
+            DEFAULT_NON_TUNNEL_INTERFACE.to_owned()
+        })
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn default_route_interface() -> Option<String> {
+    let output = std::process::Command::new("ip")
+        .args(["-4", "route", "show", "default", "table", "main"])
+        .output()
+        .ok()?;
+    // Example: "default via 192.168.122.1 dev ens3 proto dhcp metric 100"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut words = stdout.split_whitespace();
+    words.find(|word| *word == "dev")?;
+    words.next().map(str::to_owned)
 }
 
 #[cfg(target_os = "macos")]
@@ -335,6 +834,369 @@ fn non_tunnel_interface() -> &'static str {
     "en0"
 }
 
+/// List all network interfaces using `getifaddrs`.
+#[cfg(unix)]
+pub async fn get_interfaces() -> Result<Vec<InterfaceInfo>, test_rpc::Error> {
+    use std::collections::BTreeMap;
+
+    let addrs = nix::ifaddrs::getifaddrs()
+        .map_err(|error| test_rpc::Error::ListInterfaces(error.to_string()))?;
+
+    let mut interfaces: BTreeMap<String, InterfaceInfo> = BTreeMap::new();
+    for addr in addrs {
+        let info = interfaces
+            .entry(addr.interface_name.clone())
+            .or_insert_with(|| InterfaceInfo {
+                index: nix::net::if_::if_nametoindex(addr.interface_name.as_str()).ok(),
+                name: addr.interface_name.clone(),
+                mac: None,
+                addresses: vec![],
+            });
+        let address = match addr.address {
+            Some(address) => address,
+            None => continue,
+        };
+        if let Some(sockaddr) = address.as_sockaddr_in() {
+            info.addresses
+                .push(IpAddr::V4(Ipv4Addr::from(sockaddr.ip())));
+        } else if let Some(sockaddr) = address.as_sockaddr_in6() {
+            info.addresses.push(IpAddr::V6(sockaddr.ip()));
+        } else if let Some(link) = address.as_link_addr() {
+            // Interfaces without a hardware address, such as loopback, report all zeros
+            info.mac = link.addr().filter(|mac| mac.iter().any(|byte| *byte != 0));
+        }
+    }
+
+    Ok(interfaces.into_values().collect())
+}
+
+/// List all network interfaces using `Get-NetAdapter` and `Get-NetIPAddress`.
+#[cfg(target_os = "windows")]
+pub async fn get_interfaces() -> Result<Vec<InterfaceInfo>, test_rpc::Error> {
+    const SCRIPT: &str = "ConvertTo-Json -Depth 3 -InputObject @(\
+        Get-NetAdapter -IncludeHidden | ForEach-Object { [PSCustomObject]@{ \
+            Name = $_.Name; \
+            Index = $_.ifIndex; \
+            Mac = $_.MacAddress; \
+            Addresses = @(Get-NetIPAddress -InterfaceIndex $_.ifIndex -ErrorAction SilentlyContinue \
+                | ForEach-Object { $_.IPAddress }) \
+        } })";
+
+    let output = Command::new("powershell")
+        .args(["-Command", SCRIPT])
+        .output()
+        .await
+        .map_err(|error| test_rpc::Error::ListInterfaces(format!("powershell: {error}")))?;
+    if !output.status.success() {
+        return Err(test_rpc::Error::ListInterfaces(format!(
+            "powershell failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    let adapters: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+        .map_err(|error| test_rpc::Error::ListInterfaces(error.to_string()))?;
+
+    Ok(adapters
+        .iter()
+        .filter_map(|adapter| {
+            Some(InterfaceInfo {
+                name: adapter["Name"].as_str()?.to_owned(),
+                index: adapter["Index"]
+                    .as_u64()
+                    .and_then(|index| u32::try_from(index).ok()),
+                // Example: "52-54-00-12-34-56"
+                mac: adapter["Mac"].as_str().and_then(parse_mac),
+                addresses: adapter["Addresses"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|addr| {
+                        // Strip the zone index of link-local addresses, e.g. "fe80::1%12"
+                        let addr = addr.as_str()?;
+                        addr.split('%').next()?.parse().ok()
+                    })
+                    .collect(),
+            })
+        })
+        .collect())
+}
+
+#[cfg(target_os = "windows")]
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let bytes = mac
+        .split(['-', ':'])
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    bytes.try_into().ok()
+}
+
+/// List all open TCP and UDP sockets using `ss`.
+#[cfg(target_os = "linux")]
+pub async fn list_connections() -> Result<Vec<ConnectionInfo>, test_rpc::Error> {
+    // Example line:
+    // tcp ESTAB 0 0 192.168.1.2:40000 1.1.1.1:443 users:(("curl",pid=1234,fd=5))
+    let output = connections_command_output("ss", &["-tunaH", "-p"]).await?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<_> = line.split_whitespace().collect();
+            let protocol = parse_socket_protocol(cols.first()?)?;
+            let state = cols.get(1)?;
+            let local_addr = parse_socket_addr(cols.get(4)?)?;
+            let remote_addr = parse_socket_addr(cols.get(5)?).filter(is_specified);
+
+            // Only the first owning process is reported
+            let process = cols.get(6);
+            let process_name = process
+                .and_then(|process| process.split('"').nth(1))
+                .map(str::to_owned);
+            let pid = process
+                .and_then(|process| process.split("pid=").nth(1))
+                .and_then(|pid| pid.split(|c: char| !c.is_ascii_digit()).next())
+                .and_then(|pid| pid.parse().ok());
+
+            Some(ConnectionInfo {
+                protocol,
+                local_addr,
+                remote_addr,
+                state: (*state != "UNCONN").then(|| state.to_string()),
+                pid,
+                process_name,
+            })
+        })
+        .collect())
+}
+
+/// List all open TCP and UDP sockets using `lsof`.
+#[cfg(target_os = "macos")]
+pub async fn list_connections() -> Result<Vec<ConnectionInfo>, test_rpc::Error> {
+    // Example line:
+    // curl 1234 root 5u IPv4 0x0 0t0 TCP 192.168.1.2:40000->1.1.1.1:443 (ESTABLISHED)
+    let output = connections_command_output("lsof", &["-nP", "-iTCP", "-iUDP"]).await?;
+
+    Ok(output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let cols: Vec<_> = line.split_whitespace().collect();
+            let protocol = parse_socket_protocol(cols.get(7)?)?;
+            let (local_addr, remote_addr) = match cols.get(8)?.split_once("->") {
+                Some((local, remote)) => (local, Some(remote)),
+                None => (*cols.get(8)?, None),
+            };
+            let state = cols
+                .get(9)
+                .map(|state| state.trim_matches(|c| c == '(' || c == ')').to_owned());
+
+            Some(ConnectionInfo {
+                protocol,
+                local_addr: parse_socket_addr(local_addr)?,
+                remote_addr: remote_addr.and_then(parse_socket_addr),
+                state,
+                pid: cols.get(1).and_then(|pid| pid.parse().ok()),
+                process_name: cols.first().map(|name| name.to_string()),
+            })
+        })
+        .collect())
+}
+
+/// List all open TCP and UDP sockets using `netstat`. Only the owning process ID is reported.
+#[cfg(target_os = "windows")]
+pub async fn list_connections() -> Result<Vec<ConnectionInfo>, test_rpc::Error> {
+    // Example lines:
+    //   TCP    192.168.1.2:40000    1.1.1.1:443    ESTABLISHED    1234
+    //   UDP    0.0.0.0:5353         *:*                           1234
+    let output = connections_command_output("netstat", &["-ano"]).await?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<_> = line.split_whitespace().collect();
+            let protocol = parse_socket_protocol(cols.first()?)?;
+            let (state, pid) = match protocol {
+                SocketProtocol::Tcp => (Some(cols.get(3)?.to_string()), cols.get(4)?),
+                SocketProtocol::Udp => (None, cols.get(3)?),
+            };
+
+            Some(ConnectionInfo {
+                protocol,
+                local_addr: parse_socket_addr(cols.get(1)?)?,
+                remote_addr: parse_socket_addr(cols.get(2)?).filter(is_specified),
+                state,
+                pid: pid.parse().ok(),
+                process_name: None,
+            })
+        })
+        .collect())
+}
+
+/// Read the traffic counters of `interface` from `/proc/net/dev`.
+#[cfg(target_os = "linux")]
+pub async fn get_interface_stats(interface: Interface) -> Result<IfStats, test_rpc::Error> {
+    // Example line:
+    //   ens3: 1234 12 0 0 0 0 0 0 5678 34 0 0 0 0 0 0
+    // The first eight counters are for received traffic, the next eight for transmitted traffic
+    let name = get_interface_name(interface);
+    let dev = tokio::fs::read_to_string("/proc/net/dev")
+        .await
+        .map_err(|error| test_rpc::Error::InterfaceStats(error.to_string()))?;
+    let timestamp = SystemTime::now();
+
+    let counters = dev
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(iface, _)| iface.trim() == name)
+        .map(|(_, counters)| parse_counters::<10>(counters.split_whitespace()))
+        .ok_or(test_rpc::Error::InterfaceNotFound)??;
+
+    Ok(IfStats {
+        rx_bytes: counters[0],
+        rx_packets: counters[1],
+        tx_bytes: counters[8],
+        tx_packets: counters[9],
+        timestamp,
+    })
+}
+
+/// Read the traffic counters of `interface` using `netstat`.
+#[cfg(target_os = "macos")]
+pub async fn get_interface_stats(interface: Interface) -> Result<IfStats, test_rpc::Error> {
+    // Example output:
+    // Name  Mtu   Network       Address            Ipkts Ierrs  Ibytes Opkts Oerrs  Obytes  Coll
+    // en0   1500  <Link#4>      52:54:00:12:34:56     12     0    1234    34     0    5678     0
+    // The address is missing for some interfaces, so the counters are parsed from the end
+    let name = get_interface_name(interface);
+    let output = interface_stats_command_output("netstat", &["-ibn", "-I", &name]).await?;
+    let timestamp = SystemTime::now();
+
+    let link = output
+        .lines()
+        .find(|line| line.contains("<Link#"))
+        .ok_or(test_rpc::Error::InterfaceNotFound)?;
+    let mut counters = parse_counters::<7>(link.split_whitespace().rev())?;
+    // [Ipkts, Ierrs, Ibytes, Opkts, Oerrs, Obytes, Coll]
+    counters.reverse();
+
+    Ok(IfStats {
+        rx_packets: counters[0],
+        rx_bytes: counters[2],
+        tx_packets: counters[3],
+        tx_bytes: counters[5],
+        timestamp,
+    })
+}
+
+/// Read the traffic counters of `interface` using `Get-NetAdapterStatistics`.
+#[cfg(target_os = "windows")]
+pub async fn get_interface_stats(interface: Interface) -> Result<IfStats, test_rpc::Error> {
+    let name = get_interface_name(interface);
+    let script = format!(
+        "$s = Get-NetAdapterStatistics -Name '{name}'; \
+        $rx = $s.ReceivedUnicastPackets + $s.ReceivedMulticastPackets + $s.ReceivedBroadcastPackets; \
+        $tx = $s.SentUnicastPackets + $s.SentMulticastPackets + $s.SentBroadcastPackets; \
+        \"$($s.ReceivedBytes) $rx $($s.SentBytes) $tx\""
+    );
+    let output = interface_stats_command_output("powershell", &["-Command", &script]).await?;
+    let timestamp = SystemTime::now();
+
+    let counters = parse_counters::<4>(output.split_whitespace())?;
+
+    Ok(IfStats {
+        rx_bytes: counters[0],
+        rx_packets: counters[1],
+        tx_bytes: counters[2],
+        tx_packets: counters[3],
+        timestamp,
+    })
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+async fn interface_stats_command_output(
+    program: &str,
+    args: &[&str],
+) -> Result<String, test_rpc::Error> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|error| test_rpc::Error::InterfaceStats(format!("{program}: {error}")))?;
+    if !output.status.success() {
+        return Err(test_rpc::Error::InterfaceStats(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse exactly `N` counters.
+fn parse_counters<'a, const N: usize>(
+    counters: impl Iterator<Item = &'a str>,
+) -> Result<[u64; N], test_rpc::Error> {
+    let counters = counters
+        .take(N)
+        .map(|counter| {
+            counter
+                .parse()
+                .map_err(|_| test_rpc::Error::InterfaceStats(format!("invalid counter: {counter}")))
+        })
+        .collect::<Result<Vec<u64>, _>>()?;
+    counters
+        .try_into()
+        .map_err(|_| test_rpc::Error::InterfaceStats("too few counters".to_string()))
+}
+
+async fn connections_command_output(
+    program: &str,
+    args: &[&str],
+) -> Result<String, test_rpc::Error> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|error| test_rpc::Error::ListConnections(format!("{program}: {error}")))?;
+    if !output.status.success() {
+        return Err(test_rpc::Error::ListConnections(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn parse_socket_protocol(protocol: &str) -> Option<SocketProtocol> {
+    match protocol.to_ascii_lowercase().as_str() {
+        "tcp" | "tcp6" => Some(SocketProtocol::Tcp),
+        "udp" | "udp6" => Some(SocketProtocol::Udp),
+        _ => None,
+    }
+}
+
+/// Parse addresses such as `1.2.3.4:80`, `[::1]:53`, `10.0.0.2%eth0:68`, `*:22`, and `*:*`.
+/// Wildcards are parsed as the unspecified address or port 0, respectively.
+fn parse_socket_addr(addr: &str) -> Option<SocketAddr> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    // Remove scope or interface identifiers
+    let host = host.split('%').next()?;
+
+    let ip = match host {
+        "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        host => host.parse().ok()?,
+    };
+    let port = match port {
+        "*" => 0,
+        port => port.parse().ok()?,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn is_specified(addr: &SocketAddr) -> bool {
+    !addr.ip().is_unspecified() || addr.port() != 0
+}
+
 fn result_from_output<E>(action: &'static str, output: Output, err: E) -> Result<(), E> {
     if output.status.success() {
         return Ok(());
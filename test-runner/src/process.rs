@@ -0,0 +1,176 @@
+//! Track processes started using `spawn`, so that their output can be read while they are
+//! running.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use test_rpc::{ProcessId, ProcessOutput};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::{mpsc, Notify},
+};
+
+/// Maximum time that `poll` waits for new output.
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Processes that have been started but not yet fully polled
+#[derive(Clone, Default)]
+pub struct Processes {
+    processes: Arc<Mutex<HashMap<ProcessId, Arc<TrackedProcess>>>>,
+    next_id: Arc<AtomicU32>,
+}
+
+struct TrackedProcess {
+    output: Mutex<ProcessOutput>,
+    notify: Notify,
+    kill_tx: mpsc::UnboundedSender<()>,
+}
+
+impl Processes {
+    pub fn spawn(
+        &self,
+        path: String,
+        args: Vec<String>,
+        env: BTreeMap<String, String>,
+    ) -> Result<ProcessId, test_rpc::Error> {
+        log::debug!("Spawn {} (args: {args:?})", path);
+
+        let mut cmd = Command::new(&path);
+        cmd.args(args);
+
+        // Make sure that PATH is updated
+        #[cfg(target_os = "windows")]
+        cmd.env("PATH", crate::sys::get_system_path_var()?);
+
+        cmd.envs(env);
+        cmd.stdin(std::process::Stdio::null());
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+        cmd.kill_on_drop(true);
+
+        let mut child = cmd.spawn().map_err(|error| {
+            log::error!("Failed to spawn {}: {error}", path);
+            test_rpc::Error::Syscall
+        })?;
+
+        let (kill_tx, mut kill_rx) = mpsc::unbounded_channel();
+        let process = Arc::new(TrackedProcess {
+            output: Mutex::new(ProcessOutput::default()),
+            notify: Notify::new(),
+            kill_tx,
+        });
+
+        let stdout = tokio::spawn(read_output(
+            child.stdout.take().unwrap(),
+            process.clone(),
+            |output| &mut output.stdout,
+        ));
+        let stderr = tokio::spawn(read_output(
+            child.stderr.take().unwrap(),
+            process.clone(),
+            |output| &mut output.stderr,
+        ));
+
+        let id = ProcessId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.processes.lock().unwrap().insert(id, process.clone());
+
+        tokio::spawn(async move {
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = kill_rx.recv() => {
+                    if let Err(error) = child.kill().await {
+                        log::error!("Failed to kill {path}: {error}");
+                    }
+                    child.wait().await
+                }
+            };
+            // Make sure that all output is available before reporting that the process exited
+            let _ = stdout.await;
+            let _ = stderr.await;
+
+            let code = match status {
+                Ok(status) => status.code(),
+                Err(error) => {
+                    log::error!("Failed to wait for {path}: {error}");
+                    None
+                }
+            };
+            log::debug!("Spawned process {path} exited: {code:?}");
+
+            let mut output = process.output.lock().unwrap();
+            output.exited = true;
+            output.code = code;
+            process.notify.notify_waiters();
+        });
+
+        Ok(id)
+    }
+
+    pub async fn poll(&self, id: ProcessId) -> Result<ProcessOutput, test_rpc::Error> {
+        let process = self.get(id)?;
+
+        let wait_for_output = async {
+            loop {
+                let notified = process.notify.notified();
+                {
+                    let output = process.output.lock().unwrap();
+                    if output.exited || !output.stdout.is_empty() || !output.stderr.is_empty() {
+                        return;
+                    }
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(POLL_TIMEOUT, wait_for_output).await;
+
+        let output = std::mem::take(&mut *process.output.lock().unwrap());
+        if output.exited {
+            self.processes.lock().unwrap().remove(&id);
+        }
+        Ok(output)
+    }
+
+    pub fn kill(&self, id: ProcessId) -> Result<(), test_rpc::Error> {
+        self.get(id)?
+            .kill_tx
+            .send(())
+            .map_err(|_| test_rpc::Error::KillProcess("The process has already exited".to_owned()))
+    }
+
+    fn get(&self, id: ProcessId) -> Result<Arc<TrackedProcess>, test_rpc::Error> {
+        self.processes
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(test_rpc::Error::UnknownProcess)
+    }
+}
+
+/// Append all data read from `reader` to the buffer returned by `buffer`.
+async fn read_output(
+    mut reader: impl AsyncRead + Unpin,
+    process: Arc<TrackedProcess>,
+    buffer: fn(&mut ProcessOutput) -> &mut Vec<u8>,
+) {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match reader.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(num_bytes) => {
+                buffer(&mut process.output.lock().unwrap()).extend_from_slice(&chunk[..num_bytes]);
+                process.notify.notify_waiters();
+            }
+            Err(error) => {
+                log::error!("Failed to read process output: {error}");
+                break;
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
 #[cfg(target_os = "windows")]
 use std::io;
+use std::{collections::HashMap, path::Path, time::SystemTime};
 use test_rpc::mullvad_daemon::Verbosity;
 
 #[cfg(target_os = "windows")]
@@ -304,6 +305,7 @@ pub async fn set_daemon_environment(env: HashMap<String, String>) -> Result<(),
     let mut file = tokio::fs::OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(override_path)
         .await
         .map_err(|e| test_rpc::Error::Service(e.to_string()))?;
@@ -472,6 +474,36 @@ pub async fn set_mullvad_daemon_service_state(on: bool) -> Result<(), test_rpc::
     Ok(())
 }
 
+#[cfg(unix)]
+pub async fn kill_process(process_name: &str) -> Result<(), test_rpc::Error> {
+    let status = tokio::process::Command::new("pkill")
+        .args(["-KILL", "-x", process_name])
+        .status()
+        .await
+        .map_err(|e| test_rpc::Error::KillProcess(e.to_string()))?;
+    if !status.success() {
+        return Err(test_rpc::Error::KillProcess(format!(
+            "pkill failed for {process_name}: {status}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub async fn kill_process(process_name: &str) -> Result<(), test_rpc::Error> {
+    let status = tokio::process::Command::new("taskkill")
+        .args(["/F", "/IM", process_name])
+        .status()
+        .await
+        .map_err(|e| test_rpc::Error::KillProcess(e.to_string()))?;
+    if !status.success() {
+        return Err(test_rpc::Error::KillProcess(format!(
+            "taskkill failed for {process_name}: {status}"
+        )));
+    }
+    Ok(())
+}
+
 #[cfg(target_os = "macos")]
 async fn set_launch_daemon_state(on: bool) -> Result<(), test_rpc::Error> {
     tokio::process::Command::new("launchctl")
@@ -529,3 +561,81 @@ async fn wait_for_service_state(awaited_state: ServiceState) -> Result<(), test_
     }
     Ok(())
 }
+
+#[cfg(target_os = "linux")]
+pub async fn set_system_time(time: SystemTime) -> Result<(), test_rpc::Error> {
+    let time = DateTime::<Utc>::from(time);
+    run_time_command("date", &["-u", "-s", &format!("@{}", time.timestamp())]).await
+}
+
+#[cfg(target_os = "macos")]
+pub async fn set_system_time(time: SystemTime) -> Result<(), test_rpc::Error> {
+    let time = DateTime::<Utc>::from(time);
+    run_time_command("date", &["-u", &time.format("%m%d%H%M%Y.%S").to_string()]).await
+}
+
+#[cfg(target_os = "windows")]
+pub async fn set_system_time(time: SystemTime) -> Result<(), test_rpc::Error> {
+    let time = DateTime::<Utc>::from(time);
+    let set_date = format!(
+        "Set-Date -Date ([DateTimeOffset]::FromUnixTimeSeconds({}).LocalDateTime)",
+        time.timestamp()
+    );
+    run_time_command("powershell", &["-Command", &set_date]).await
+}
+
+#[cfg(target_os = "linux")]
+pub async fn set_time_sync(enabled: bool) -> Result<(), test_rpc::Error> {
+    run_time_command("timedatectl", &["set-ntp", &enabled.to_string()]).await
+}
+
+#[cfg(target_os = "macos")]
+pub async fn set_time_sync(enabled: bool) -> Result<(), test_rpc::Error> {
+    let state = if enabled { "on" } else { "off" };
+    run_time_command("systemsetup", &["-setusingnetworktime", state]).await
+}
+
+#[cfg(target_os = "windows")]
+pub async fn set_time_sync(enabled: bool) -> Result<(), test_rpc::Error> {
+    let action = if enabled { "start" } else { "stop" };
+    run_time_command("net", &[action, "w32time"]).await
+}
+
+async fn run_time_command(program: &str, args: &[&str]) -> Result<(), test_rpc::Error> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| test_rpc::Error::SetSystemTime(e.to_string()))?;
+    if !output.status.success() {
+        return Err(test_rpc::Error::SetSystemTime(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
+
+pub async fn read_file(path: &Path) -> Result<Vec<u8>, test_rpc::Error> {
+    let metadata = tokio::fs::metadata(path).await.map_err(|error| {
+        log::error!("Failed to obtain metadata of {}: {error}", path.display());
+        test_rpc::Error::FileSystem(error.to_string())
+    })?;
+    if metadata.len() > test_rpc::MAX_FILE_SIZE as u64 {
+        return Err(test_rpc::Error::FileTooLarge);
+    }
+    tokio::fs::read(path).await.map_err(|error| {
+        log::error!("Failed to read {}: {error}", path.display());
+        test_rpc::Error::FileSystem(error.to_string())
+    })
+}
+
+pub async fn write_file(path: &Path, contents: &[u8]) -> Result<(), test_rpc::Error> {
+    if contents.len() > test_rpc::MAX_FILE_SIZE {
+        return Err(test_rpc::Error::FileTooLarge);
+    }
+    tokio::fs::write(path, contents).await.map_err(|error| {
+        log::error!("Failed to write {}: {error}", path.display());
+        test_rpc::Error::FileSystem(error.to_string())
+    })
+}